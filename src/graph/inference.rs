@@ -0,0 +1,222 @@
+use crate::graph::model::{
+    ModelGraph, DJ_INS, D_3_DEL, D_5_DEL, D_GENE, J_5_DEL, J_CHOICE, NB_EVENTS, VD_INS, V_3_DEL,
+    V_CHOICE,
+};
+use crate::shared::feature::{InfEvent, InsertionFeature, ResultInference};
+use crate::shared::utils::difference_as_i64;
+use crate::shared::{errors::FeatureError, InferenceParameters};
+use crate::shared::{ErrorDAlignment, ErrorJAlignment, ErrorVAlignment};
+use crate::vdj::{Model, Sequence};
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+
+// the loops over the events go in that order, the insertions are fixed
+// by the deletions
+const LOOP_ORDER: [usize; NB_EVENTS] = [
+    V_CHOICE, J_CHOICE, D_GENE, V_3_DEL, J_5_DEL, D_5_DEL, D_3_DEL, VD_INS, DJ_INS,
+];
+const NB_STAGES: usize = 7;
+
+pub const GRAPH_INFERENCE_ERROR: &str =
+    "The inference is not available for models with arbitrary dependences between \
+     the events (ModelStructure::Graph), only the generation and the evaluation are. \
+     Changing the model type to VDJ keeps the marginal distribution of each event \
+     and allows the inference.";
+
+fn stage(event: usize) -> usize {
+    LOOP_ORDER
+        .iter()
+        .position(|&x| x == event)
+        .unwrap()
+        .min(NB_STAGES - 1)
+}
+
+/// Evaluation of the sequences for a model with arbitrary dependences between
+/// the events. There's no simple factorization of the likelihood, so all the
+/// scenarios are explicitly enumerated (slow), and the inference is not supported.
+#[derive(Clone, Debug)]
+pub struct Features {
+    pub graph: Arc<ModelGraph>,
+    // only the inserted nucleotides, the insertion length is part of the graph
+    pub insvd: InsertionFeature,
+    pub insdj: InsertionFeature,
+    pub error: FeatureError,
+    pub log_likelihood: Option<f64>,
+    // indexes of the events whose probability can be computed at each stage of the loop
+    stages: Vec<Vec<usize>>,
+}
+
+impl Features {
+    pub fn new(model: &Model) -> Result<Features> {
+        let graph = model
+            .graph
+            .clone()
+            .ok_or(anyhow!("The model doesn't define a graph of events"))?;
+        let mut stages = vec![Vec::new(); NB_STAGES];
+        for (idx, ev) in graph.events.iter().enumerate() {
+            let last = ev
+                .parents
+                .iter()
+                .map(|&p| stage(p))
+                .chain(std::iter::once(stage(ev.event)))
+                .max()
+                .unwrap();
+            stages[last].push(idx);
+        }
+        Ok(Features {
+            insvd: InsertionFeature::new_sequence_only(
+                graph.size(VD_INS),
+                Arc::clone(&model.markov_chain_vd),
+            )?,
+            insdj: InsertionFeature::new_sequence_only(
+                graph.size(DJ_INS),
+                Arc::clone(&model.markov_chain_dj),
+            )?,
+            graph: Arc::new(graph),
+            error: model.error.get_feature()?,
+            log_likelihood: None,
+            stages,
+        })
+    }
+
+    fn stage_probability(&self, stage: usize, realization: &[usize; NB_EVENTS]) -> f64 {
+        self.stages[stage]
+            .iter()
+            .map(|&idx| self.graph.events[idx].probability(realization))
+            .product()
+    }
+
+    /// Sum over all the scenarios compatible with the alignments
+    pub fn infer(
+        &mut self,
+        sequence: &Sequence,
+        ip: &InferenceParameters,
+    ) -> Result<ResultInference> {
+        if sequence.sequence.is_protein() {
+            return Err(anyhow!(
+                "Amino-acid sequences are not supported by the graph models"
+            ));
+        }
+        let mut result = ResultInference::impossible();
+        let cutoff = ip.min_likelihood;
+        let mut r = [0; NB_EVENTS];
+        let seq_len = sequence.sequence.len() as i64;
+
+        for val in &sequence.v_genes {
+            r[V_CHOICE] = val.index;
+            for jal in &sequence.j_genes {
+                r[J_CHOICE] = jal.index;
+                for dal in &sequence.d_genes {
+                    r[D_GENE] = dal.index;
+                    let ll_genes = self.stage_probability(0, &r)
+                        * self.stage_probability(1, &r)
+                        * self.stage_probability(2, &r);
+                    if ll_genes <= cutoff {
+                        continue;
+                    }
+                    for delv in 0..self.graph.size(V_3_DEL) {
+                        r[V_3_DEL] = delv;
+                        let v_end = difference_as_i64(val.end_seq, delv);
+                        let ll_v = ll_genes
+                            * self.stage_probability(3, &r)
+                            * self.error.likelihood_v(&ErrorVAlignment { val, del: delv });
+                        if ll_v <= cutoff {
+                            continue;
+                        }
+                        for delj in 0..self.graph.size(J_5_DEL) {
+                            r[J_5_DEL] = delj;
+                            let j_start =
+                                jal.start_seq as i64 - jal.start_gene as i64 + delj as i64;
+                            if j_start < v_end {
+                                continue;
+                            }
+                            let ll_j = ll_v
+                                * self.stage_probability(4, &r)
+                                * self.error.likelihood_j(&ErrorJAlignment { jal, del: delj });
+                            if ll_j <= cutoff {
+                                continue;
+                            }
+                            for deld5 in 0..self.graph.size(D_5_DEL) {
+                                r[D_5_DEL] = deld5;
+                                let d_start = dal.pos + deld5 as i64;
+                                if d_start < v_end || d_start < 0 || d_start >= seq_len {
+                                    continue;
+                                }
+                                let ll_d5 = ll_j * self.stage_probability(5, &r);
+                                if ll_d5 <= cutoff {
+                                    continue;
+                                }
+                                for deld3 in 0..self.graph.size(D_3_DEL).min(dal.len() + 1) {
+                                    let d_end = dal.pos + (dal.len() - deld3) as i64;
+                                    if d_start > d_end || j_start < d_end || d_end > seq_len {
+                                        continue;
+                                    }
+                                    let (len_vd, len_dj) =
+                                        ((d_start - v_end) as usize, (j_start - d_end) as usize);
+                                    if len_vd >= self.graph.size(VD_INS)
+                                        || len_dj >= self.graph.size(DJ_INS)
+                                    {
+                                        continue;
+                                    }
+                                    r[D_3_DEL] = deld3;
+                                    r[VD_INS] = len_vd;
+                                    r[DJ_INS] = len_dj;
+
+                                    let ins_vd = sequence.get_subsequence(v_end, d_start);
+                                    let ins_dj = sequence.get_subsequence(d_end, j_start);
+                                    let ll = ll_d5
+                                        * self.stage_probability(6, &r)
+                                        * self.error.likelihood_d(&ErrorDAlignment {
+                                            dal,
+                                            deld5,
+                                            deld3,
+                                        })
+                                        * self
+                                            .insvd
+                                            .likelihood(&ins_vd, val.get_last_nucleotide(delv))
+                                            .to_scalar()?
+                                        * self
+                                            .insdj
+                                            .likelihood(&ins_dj, jal.get_first_nucleotide(delj))
+                                            .to_scalar()?;
+                                    if ll <= cutoff {
+                                        continue;
+                                    }
+
+                                    result.likelihood += ll;
+                                    if ip.store_best_event && ll > result.best_likelihood {
+                                        let event = InfEvent {
+                                            v_index: val.index,
+                                            v_start_gene: val.v_start_gene(),
+                                            j_index: jal.index,
+                                            j_start_seq: jal.start_seq as i64
+                                                - jal.start_gene as i64,
+                                            d_index: dal.index,
+                                            end_v: v_end,
+                                            start_d: d_start,
+                                            end_d: d_end,
+                                            start_j: j_start,
+                                            pos_d: dal.pos,
+                                            likelihood: ll,
+                                            ..Default::default()
+                                        };
+                                        result.set_best_event(event, ip);
+                                        result.best_likelihood = ll;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // add a small positive likelihood to deal with the case where result.likelihood is 0.
+        self.log_likelihood = Some((result.likelihood + ip.min_likelihood).log2());
+        Ok(result)
+    }
+
+    pub fn normalize(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
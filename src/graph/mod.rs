@@ -0,0 +1,9 @@
+//! Variation of the VDJ model where the recombination events follow an arbitrary
+//! Bayesian network (any IGoR model). Only generation and evaluation are supported.
+
+pub mod inference;
+pub mod model;
+
+// Re-exporting for public API
+pub use self::inference::Features;
+pub use self::model::{GraphEvent, ModelGraph, EVENTS};
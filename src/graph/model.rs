@@ -0,0 +1,372 @@
+use crate::shared::distributions::DiscreteDistribution;
+use crate::shared::parser::{Marginal, ParserMarginals};
+use anyhow::{anyhow, Result};
+use ndarray::{ArrayD, Axis, IxDyn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// The recombination events that can appear in the graph, in the order
+/// used to index a realization (`[usize; NB_EVENTS]`)
+pub const EVENTS: [&str; NB_EVENTS] = [
+    "v_choice", "d_gene", "j_choice", "v_3_del", "d_5_del", "d_3_del", "j_5_del", "vd_ins",
+    "dj_ins",
+];
+pub const NB_EVENTS: usize = 9;
+pub const V_CHOICE: usize = 0;
+pub const D_GENE: usize = 1;
+pub const J_CHOICE: usize = 2;
+pub const V_3_DEL: usize = 3;
+pub const D_5_DEL: usize = 4;
+pub const D_3_DEL: usize = 5;
+pub const J_5_DEL: usize = 6;
+pub const VD_INS: usize = 7;
+pub const DJ_INS: usize = 8;
+
+/// Name of the event in the IGoR params file (without the size)
+fn igor_name(event: usize) -> &'static str {
+    match event {
+        V_CHOICE => "GeneChoice_V_gene_Undefined_side_prio7",
+        D_GENE => "GeneChoice_D_gene_Undefined_side_prio6",
+        J_CHOICE => "GeneChoice_J_gene_Undefined_side_prio7",
+        V_3_DEL => "Deletion_V_gene_Three_prime_prio5",
+        D_5_DEL => "Deletion_D_gene_Five_prime_prio5",
+        D_3_DEL => "Deletion_D_gene_Three_prime_prio5",
+        J_5_DEL => "Deletion_J_gene_Five_prime_prio5",
+        VD_INS => "Insertion_VD_genes_Undefined_side_prio4",
+        DJ_INS => "Insertion_DJ_genes_Undefined_side_prio2",
+        _ => unreachable!(),
+    }
+}
+
+/// Dependences of each event that the "standard" VDJ model can deal with
+/// (either with `ModelStructure::VDJ` or `ModelStructure::VDJInsGivenGene`)
+fn standard_parents(event: usize) -> Vec<Vec<usize>> {
+    match event {
+        V_CHOICE => vec![vec![]],
+        D_GENE => vec![vec![V_CHOICE, J_CHOICE], vec![J_CHOICE]],
+        J_CHOICE => vec![vec![V_CHOICE], vec![]],
+        V_3_DEL => vec![vec![V_CHOICE]],
+        D_5_DEL => vec![vec![D_GENE]],
+        D_3_DEL => vec![vec![D_GENE, D_5_DEL]],
+        J_5_DEL => vec![vec![J_CHOICE]],
+        VD_INS => vec![vec![], vec![V_CHOICE]],
+        DJ_INS => vec![vec![], vec![J_CHOICE]],
+        _ => unreachable!(),
+    }
+}
+
+/// One node of the Bayesian network: P(event | parents). The axes of
+/// `probabilities` are the parents (in the order of `parents`) then the event.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GraphEvent {
+    pub event: usize, // index in `EVENTS`
+    pub parents: Vec<usize>,
+    pub probabilities: ArrayD<f64>,
+}
+
+impl GraphEvent {
+    /// Value of P(event | parents) for a given realization
+    pub fn probability(&self, realization: &[usize; NB_EVENTS]) -> f64 {
+        let mut flat_index = 0;
+        for (&parent, &stride) in self.parents.iter().zip(self.probabilities.strides()) {
+            flat_index += realization[parent] * stride as usize;
+        }
+        flat_index += realization[self.event];
+        self.probabilities.as_slice().unwrap()[flat_index]
+    }
+
+    pub fn size(&self) -> usize {
+        *self.probabilities.shape().last().unwrap()
+    }
+}
+
+/// Model where the recombination events follow an arbitrary Bayesian network
+/// (directed acyclic graph), as in the IGoR model files.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct ModelGraph {
+    // ordered such that the parents always come before their children
+    pub events: Vec<GraphEvent>,
+}
+
+impl ModelGraph {
+    /// Create the graph from a list of conditional distributions, check
+    /// that the dimensions are coherent and that the graph is acyclic.
+    pub fn new(mut events: Vec<GraphEvent>) -> Result<ModelGraph> {
+        for (ev, name) in EVENTS.iter().enumerate() {
+            if events.iter().filter(|x| x.event == ev).count() != 1 {
+                return Err(anyhow!("The model should contain exactly one {}", name));
+            }
+        }
+
+        // topological sort
+        let mut sorted: Vec<GraphEvent> = Vec::new();
+        while !events.is_empty() {
+            let Some(pos) = events.iter().position(|x| {
+                x.parents
+                    .iter()
+                    .all(|p| sorted.iter().any(|y| y.event == *p))
+            }) else {
+                return Err(anyhow!(
+                    "The dependences between the events of the model contain a cycle"
+                ));
+            };
+            sorted.push(events.remove(pos));
+        }
+
+        let mut graph = ModelGraph { events: sorted };
+        for ev in &graph.events {
+            if ev.probabilities.ndim() != ev.parents.len() + 1 {
+                return Err(anyhow!(
+                    "Wrong dimension for the event {}",
+                    EVENTS[ev.event]
+                ));
+            }
+            for (&parent, &dim) in ev.parents.iter().zip(ev.probabilities.shape()) {
+                if graph.size(parent) != dim {
+                    return Err(anyhow!(
+                        "Wrong dimension for the event {} (given {})",
+                        EVENTS[ev.event],
+                        EVENTS[parent]
+                    ));
+                }
+            }
+        }
+        graph.normalize()?;
+        Ok(graph)
+    }
+
+    /// Load the graph from the marginals of an IGoR model, the dependences
+    /// of each marginal define the edges of the graph.
+    pub fn from_marginals(pm: &ParserMarginals) -> Result<ModelGraph> {
+        let mut events = Vec::new();
+        for (ev, name) in EVENTS.iter().enumerate() {
+            let marginal = pm
+                .marginals
+                .get(*name)
+                .ok_or(anyhow!("Missing marginal {}", name))?;
+            let parents = marginal
+                .dependences
+                .iter()
+                .map(|d| {
+                    EVENTS.iter().position(|x| x == d).ok_or(anyhow!(
+                        "Unsupported dependence of {} on {}",
+                        name,
+                        d
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            events.push(GraphEvent {
+                event: ev,
+                parents,
+                probabilities: marginal.probabilities.as_standard_layout().to_owned(),
+            });
+        }
+        for name in ["vd_dinucl", "dj_dinucl"] {
+            if pm
+                .marginals
+                .get(name)
+                .is_some_and(|m| !m.dependences.is_empty())
+            {
+                return Err(anyhow!("Dependences of {} are not supported", name));
+            }
+        }
+        ModelGraph::new(events)
+    }
+
+    /// True if the dependences can be dealt with by the standard VDJ model
+    pub fn is_standard(&self) -> bool {
+        // P(V, D, J) is either P(V) P(J|V) P(D|V,J) or P(V) P(J) P(D|J)
+        let genes = (
+            &self.get(J_CHOICE).parents[..],
+            &self.get(D_GENE).parents[..],
+        );
+        (genes == (&[V_CHOICE], &[V_CHOICE, J_CHOICE]) || genes == (&[], &[J_CHOICE]))
+            && self
+                .events
+                .iter()
+                .all(|ev| standard_parents(ev.event).contains(&ev.parents))
+    }
+
+    /// Number of possible values of the event
+    pub fn size(&self, event: usize) -> usize {
+        self.get(event).size()
+    }
+
+    pub fn get(&self, event: usize) -> &GraphEvent {
+        self.events.iter().find(|x| x.event == event).unwrap()
+    }
+
+    /// Normalize each conditional distribution (for every value of the parents)
+    pub fn normalize(&mut self) -> Result<()> {
+        for ev in &mut self.events {
+            if ev.probabilities.iter().any(|&x| x < 0.) {
+                return Err(anyhow!("Negative probabilities for {}", EVENTS[ev.event]));
+            }
+            let last = Axis(ev.parents.len());
+            let sums = ev.probabilities.sum_axis(last).insert_axis(last);
+            ev.probabilities.zip_mut_with(
+                &sums.broadcast(ev.probabilities.raw_dim()).unwrap(),
+                |x, &s| {
+                    if s > 0. {
+                        *x /= s;
+                    }
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Same graph with uniform conditional distributions
+    pub fn uniform(&self) -> Result<ModelGraph> {
+        let mut graph = self.clone();
+        for ev in &mut graph.events {
+            ev.probabilities.fill(1.);
+        }
+        graph.normalize()?;
+        Ok(graph)
+    }
+
+    /// Probability of a full realization of the events
+    pub fn probability(&self, realization: &[usize; NB_EVENTS]) -> f64 {
+        self.events
+            .iter()
+            .map(|x| x.probability(realization))
+            .product()
+    }
+
+    /// Joint distribution of the `targets` events (axes in the same order)
+    pub fn marginal(&self, targets: &[usize]) -> ArrayD<f64> {
+        // only the ancestors of the targets are needed
+        let mut needed = [false; NB_EVENTS];
+        for &t in targets {
+            needed[t] = true;
+        }
+        for ev in self.events.iter().rev() {
+            if needed[ev.event] {
+                for &p in &ev.parents {
+                    needed[p] = true;
+                }
+            }
+        }
+        let order: Vec<&GraphEvent> = self.events.iter().filter(|x| needed[x.event]).collect();
+        let shape: Vec<usize> = targets.iter().map(|&t| self.size(t)).collect();
+        let mut result = ArrayD::zeros(IxDyn(&shape));
+        let mut realization = [0; NB_EVENTS];
+        Self::accumulate(&order, &mut realization, 1., targets, &mut result);
+        result
+    }
+
+    fn accumulate(
+        order: &[&GraphEvent],
+        realization: &mut [usize; NB_EVENTS],
+        proba: f64,
+        targets: &[usize],
+        result: &mut ArrayD<f64>,
+    ) {
+        let Some((first, rest)) = order.split_first() else {
+            let idx: Vec<usize> = targets.iter().map(|&t| realization[t]).collect();
+            result[IxDyn(&idx)] += proba;
+            return;
+        };
+        for value in 0..first.size() {
+            realization[first.event] = value;
+            let p = proba * first.probability(realization);
+            if p > 0. {
+                Self::accumulate(rest, realization, p, targets, result);
+            }
+        }
+    }
+
+    /// Keep only some values (e.g. a subset of the genes) of an event
+    pub fn filter(&self, event: usize, keep: &[usize]) -> Result<ModelGraph> {
+        let mut graph = self.clone();
+        for ev in &mut graph.events {
+            for (axis, &parent) in ev.parents.iter().enumerate() {
+                if parent == event {
+                    ev.probabilities = ev.probabilities.select(Axis(axis), keep);
+                }
+            }
+            if ev.event == event {
+                ev.probabilities = ev.probabilities.select(Axis(ev.parents.len()), keep);
+            }
+            ev.probabilities = ev.probabilities.as_standard_layout().to_owned();
+        }
+        graph.normalize()?;
+        Ok(graph)
+    }
+
+    /// Distributions used for the generation, for each event (in the order
+    /// of `events`) and each value of its parents
+    pub fn generative(&self) -> Result<Vec<Vec<DiscreteDistribution>>> {
+        self.events
+            .iter()
+            .map(|ev| {
+                ev.probabilities
+                    .as_slice()
+                    .unwrap()
+                    .chunks(ev.size())
+                    .map(DiscreteDistribution::new)
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Draw a realization of the events
+    pub fn generate<R: Rng>(
+        &self,
+        distributions: &[Vec<DiscreteDistribution>],
+        rng: &mut R,
+    ) -> [usize; NB_EVENTS] {
+        let mut realization = [0; NB_EVENTS];
+        for (ev, dists) in self.events.iter().zip(distributions) {
+            let mut index = 0;
+            for &p in &ev.parents {
+                index = index * self.size(p) + realization[p];
+            }
+            realization[ev.event] = dists[index].generate(rng);
+        }
+        realization
+    }
+
+    /// Marginals in the IGoR format (without the Markov chains)
+    pub fn write_marginal(&self, event: usize) -> Result<String> {
+        let ev = self.get(event);
+        Marginal::create(
+            ev.parents.iter().map(|&p| EVENTS[p]).collect(),
+            ev.probabilities.clone(),
+        )
+        .write()
+    }
+
+    /// Edges in the IGoR params format
+    pub fn write_edges(&self) -> String {
+        let mut result = String::new();
+        for ev in &self.events {
+            for &p in &ev.parents {
+                result.push_str(&format!(
+                    "%{}_size{};{}_size{}\n",
+                    igor_name(p),
+                    self.size(p),
+                    igor_name(ev.event),
+                    ev.size()
+                ));
+            }
+        }
+        result
+    }
+
+    /// Check if the graph is nearly identical to another one
+    pub fn similar_to(&self, other: &ModelGraph) -> bool {
+        self.events.len() == other.events.len()
+            && self.events.iter().all(|ev| {
+                let o = other.get(ev.event);
+                ev.parents == o.parents
+                    && ev.probabilities.shape() == o.probabilities.shape()
+                    && ev
+                        .probabilities
+                        .iter()
+                        .zip(o.probabilities.iter())
+                        .all(|(a, b)| (a - b).abs() <= 1e-4 * a.abs().max(b.abs()) + 1e-4)
+            })
+    }
+}
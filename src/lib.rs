@@ -1,7 +1,9 @@
 #![warn(clippy::large_types_passed_by_value)]
 
+pub mod graph;
 pub mod shared;
 pub mod v_dj;
+pub mod vddj;
 pub mod vdj;
 pub mod vj;
 
@@ -11,14 +13,18 @@ pub use crate::vdj::model::EntrySequence;
 pub use crate::shared::{
     errors::ErrorConstantRate, genes_matching, AlignmentParameters, AminoAcid, CategoricalFeature1,
     CategoricalFeature1g1, CategoricalFeature2, CategoricalFeature2g1, DAlignment, DNAMarkovChain,
-    Dna, DnaLike, Gene, InferenceParameters, InsertionFeature, Model, Modelable, VJAlignment,
+    Dna, DnaLike, Gene, GenerationConstraints, InferenceParameters, InsertionFeature, Model,
+    Modelable, VJAlignment,
 };
 
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
 use crate::shared::model::ModelStructure;
 
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
-use crate::shared::{errors::PyErrorParameters, Features};
+use crate::shared::{errors::PyErrorParameters, Features, InfEvent, ResultCompact};
+
+#[cfg(all(feature = "py_binds", feature = "pyo3"))]
+use crate::shared::io::{AirrReader, AirrRearrangement, AirrWriter};
 
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
 use crate::vdj::Sequence;
@@ -33,13 +39,13 @@ use kdam::{set_notebook, TqdmParallelIterator};
 use numpy::{IntoPyArray, PyArray1, PyArray2, PyArray3, PyArrayMethods};
 
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
-use pyo3::{prelude::*, types::PyDict};
+use pyo3::{prelude::*, types::PyDict, types::PyIterator};
 
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
 use rayon::{prelude::*, ThreadPoolBuilder};
 
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
-use std::{fs, path::Path};
+use std::{collections::VecDeque, fs, path::Path};
 
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
 use std::sync::atomic::Ordering;
@@ -64,6 +70,72 @@ fn notebook_mode() {
     crate::shared::utils::IN_NOTEBOOK.store(true, Ordering::SeqCst);
 }
 
+/// Convert a python object into an `EntrySequence`, accepted types are
+/// `Sequence`, `str` and `(str/Dna/AminoAcid, [Gene], [Gene])`
+#[cfg(all(feature = "py_binds", feature = "pyo3"))]
+fn extract_entry_sequence(sequence: &Bound<'_, PyAny>) -> Result<EntrySequence> {
+    if let Ok(s) = sequence.extract::<Sequence>() {
+        return Ok(EntrySequence::Aligned(s));
+    }
+    if let Ok(s) = sequence.extract::<String>() {
+        return Ok(EntrySequence::NucleotideSequence(DnaLike::from_dna(
+            Dna::from_string(&s).context("The sequence is not a valid DNA sequence. If it's an amino-acid sequence use evaluate(righor.AminoAcid(\"CAW\"), ...) instead.")?,
+        )));
+    }
+    if let Ok((s, v, j)) = sequence.extract::<(String, Vec<Gene>, Vec<Gene>)>() {
+        return Ok(EntrySequence::NucleotideCDR3((
+            DnaLike::from_dna(Dna::from_string(&s).context("The sequence is not a valid DNA sequence. If it's an amino-acid sequence use evaluate(righor.AminoAcid(\"CAW\"), ...) instead.")?),
+            v,
+            j,
+        )));
+    }
+    if let Ok((s, v, j)) = sequence.extract::<(AminoAcid, Vec<Gene>, Vec<Gene>)>() {
+        return Ok(EntrySequence::NucleotideCDR3((
+            DnaLike::from_amino_acid(s),
+            v,
+            j,
+        )));
+    }
+    if let Ok((s, v, j)) = sequence.extract::<(Dna, Vec<Gene>, Vec<Gene>)>() {
+        return Ok(EntrySequence::NucleotideCDR3((DnaLike::from_dna(s), v, j)));
+    }
+    Err(anyhow!(""))
+}
+
+#[cfg(all(feature = "py_binds", feature = "pyo3"))]
+/// Extract a list of sequences to infer on (aligned, nucleotides or cdr3 + V/J)
+fn extract_entry_sequences(seqs: &Bound<'_, PyAny>) -> Result<Vec<EntrySequence>> {
+    if let Ok(seq) = seqs.extract::<Vec<Sequence>>() {
+        return seq
+            .into_iter()
+            .map(|x| Ok(EntrySequence::Aligned(x)))
+            .collect::<Result<Vec<_>>>();
+    }
+    if let Ok(seq) = seqs.extract::<Vec<String>>() {
+        return seq
+            .into_iter()
+            .map(|x| {
+                Ok(EntrySequence::NucleotideSequence(DnaLike::from_dna(
+                    Dna::from_string(&x)?,
+                )))
+            })
+            .collect::<Result<Vec<_>>>();
+    }
+    if let Ok(seq) = seqs.extract::<Vec<(String, Vec<Gene>, Vec<Gene>)>>() {
+        return seq
+            .into_iter()
+            .map(|(x, v, j)| {
+                Ok(EntrySequence::NucleotideCDR3((
+                    DnaLike::from_dna(Dna::from_string(&x)?),
+                    v,
+                    j,
+                )))
+            })
+            .collect::<Result<Vec<_>>>();
+    }
+    Err(anyhow!("The sequences do not match any known types, available types are `Sequence`, `str` and `(str, [Gene], [Gene])`"))
+}
+
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
 #[pyclass(name = "Model")]
 #[derive(Debug, Clone)]
@@ -131,12 +203,74 @@ impl PyModel {
         }
     }
 
+    /// Same as `save_model`, but models with a second D gene (VDDJ) are
+    /// saved with righor-specific events: IGoR can't read these files.
+    pub fn save_model_righor(&self, directory: &str) -> Result<()> {
+        let path = Path::new(directory);
+        match fs::create_dir(path) {
+            Ok(()) => self.inner.save_model_righor(path),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     /// Save the model in json format
     pub fn save_json(&self, filename: &str) -> Result<()> {
         let path = Path::new(filename);
         self.inner.save_json(path)
     }
 
+    #[pyo3(signature = (filename, productive_only=false, use_junction=false))]
+    /// Read an AIRR Rearrangement TSV file. Return a list of
+    /// (sequence_id, sequence, duplicate_count) where sequence is either the full
+    /// nucleotide sequence or a (junction, [V genes], [J genes]) tuple, that
+    /// can be given directly to `evaluate` and `infer`.
+    /// Rows with unknown genes are skipped (with a warning).
+    pub fn read_airr(
+        &self,
+        py: Python,
+        filename: &str,
+        productive_only: bool,
+        use_junction: bool,
+    ) -> Result<Vec<(String, PyObject, usize)>> {
+        let file = fs::File::open(filename).with_context(|| format!("Cannot open {filename}"))?;
+        AirrReader::new(file, &self.inner, productive_only, use_junction)?
+            .map(|record| {
+                let record = record?;
+                let sequence = match record.sequence {
+                    EntrySequence::NucleotideSequence(x) => x.get_string().into_py(py),
+                    EntrySequence::NucleotideCDR3((x, v, j)) => (x.get_string(), v, j).into_py(py),
+                    EntrySequence::Aligned(x) => x.into_py(py),
+                };
+                Ok((record.sequence_id, sequence, record.duplicate_count))
+            })
+            .collect()
+    }
+
+    #[pyo3(signature = (filename, results, sequence_ids=None))]
+    /// Write the results of `evaluate` in a TSV file following the AIRR
+    /// Rearrangement schema (with additional pgen and likelihood columns).
+    /// If `sequence_ids` is not given, the sequences are numbered.
+    pub fn write_airr(
+        &self,
+        filename: &str,
+        results: Vec<crate::shared::ResultInference>,
+        sequence_ids: Option<Vec<String>>,
+    ) -> Result<()> {
+        let ids = sequence_ids.unwrap_or((0..results.len()).map(|x| x.to_string()).collect());
+        if ids.len() != results.len() {
+            return Err(anyhow!(
+                "The number of sequence ids ({}) and results ({}) differ",
+                ids.len(),
+                results.len()
+            ));
+        }
+        let mut writer = AirrWriter::new(fs::File::create(filename)?)?;
+        for (id, result) in ids.iter().zip(results.iter()) {
+            writer.write(&AirrRearrangement::from_result(id, result, &self.inner)?)?;
+        }
+        writer.flush()
+    }
+
     /// Save the model in json format
     #[staticmethod]
     pub fn load_json(filename: &str) -> Result<PyModel> {
@@ -155,14 +289,21 @@ impl PyModel {
         self.clone()
     }
 
-    #[pyo3(signature = (seed=None, available_v=None, available_j=None))]
+    #[pyo3(signature = (seed=None, available_v=None, available_j=None, constraints=None))]
     pub fn generator(
         &self,
         seed: Option<u64>,
         available_v: Option<Vec<Gene>>,
         available_j: Option<Vec<Gene>>,
+        constraints: Option<GenerationConstraints>,
     ) -> Result<Generator> {
-        Generator::new(&self.inner.clone(), seed, available_v, available_j)
+        Generator::new_with_constraints(
+            &self.inner.clone(),
+            seed,
+            available_v,
+            available_j,
+            constraints.unwrap_or_default(),
+        )
     }
 
     pub fn filter_vs(&self, vs: Vec<Gene>) -> Result<PyModel> {
@@ -196,51 +337,22 @@ impl PyModel {
         }
     }
 
-    #[pyo3(signature = (seqs, align_params=crate::shared::AlignmentParameters::default_evaluate(), inference_params=crate::shared::InferenceParameters::default_evaluate()))]
+    #[pyo3(signature = (seqs, align_params=crate::shared::AlignmentParameters::default_evaluate(), inference_params=crate::shared::InferenceParameters::default_evaluate(), weights=None))]
     /// Infer the model. str_seqs can be either a list of aligned sequences, a list of nucleotide sequences or a list of (cdr3, V, J) sequences.
-    /// Return the total log-likelihood
+    /// `weights` (optional) gives the weight of each sequence (e.g. clone counts).
+    /// Return the total (weighted) log-likelihood
     pub fn infer(
         &mut self,
         seqs: &Bound<'_, PyAny>,
         align_params: crate::shared::AlignmentParameters,
         inference_params: crate::shared::InferenceParameters,
+        weights: Option<Vec<f64>>,
     ) -> Result<f64> {
-        let opt_sequences: Result<Vec<EntrySequence>, _> = (|| {
-            if let Ok(seq) = seqs.extract::<Vec<Sequence>>() {
-                return seq
-                    .into_iter()
-                    .map(|x| Ok(EntrySequence::Aligned(x)))
-                    .collect::<Result<Vec<_>>>();
-            }
-            if let Ok(seq) = seqs.extract::<Vec<String>>() {
-                return seq
-                    .into_iter()
-                    .map(|x| {
-                        Ok(EntrySequence::NucleotideSequence(DnaLike::from_dna(
-                            Dna::from_string(&x)?,
-                        )))
-                    })
-                    .collect::<Result<Vec<_>>>();
-            }
-            if let Ok(seq) = seqs.extract::<Vec<(String, Vec<Gene>, Vec<Gene>)>>() {
-                return seq
-                    .into_iter()
-                    .map(|(x, v, j)| {
-                        Ok(EntrySequence::NucleotideCDR3((
-                            DnaLike::from_dna(Dna::from_string(&x)?),
-                            v,
-                            j,
-                        )))
-                    })
-                    .collect::<Result<Vec<_>>>();
-            }
-            Err(anyhow!("The sequences do not match any known types, available types are `Sequence`, `str` and `(str, [Gene], [Gene])`"))
-        })();
-
-        let sequences = opt_sequences?;
+        let sequences = extract_entry_sequences(seqs)?;
 
         let all_inferred = self.inner.infer(
             &sequences,
+            weights.as_deref(),
             self.features.clone(),
             &align_params,
             &inference_params,
@@ -251,6 +363,69 @@ impl PyModel {
         Ok(all_inferred.1)
     }
 
+    #[pyo3(signature = (seqs, align_params=crate::shared::AlignmentParameters::default_evaluate(), inference_params=crate::shared::InferenceParameters::default_evaluate(), weights=None, validation=None, fit_params=crate::shared::FitParameters::default()))]
+    /// Run expectation-maximization rounds until the log-likelihood converges
+    /// or `fit_params.max_iterations` is reached. The sequences are only aligned once.
+    /// `validation` (optional, same types as `seqs`) are held-out sequences whose mean
+    /// log-likelihood is computed after each round (see `fit_params.early_stopping`).
+    /// Return a `FitResult` containing the log-likelihood traces
+    pub fn fit(
+        &mut self,
+        seqs: &Bound<'_, PyAny>,
+        align_params: crate::shared::AlignmentParameters,
+        inference_params: crate::shared::InferenceParameters,
+        weights: Option<Vec<f64>>,
+        validation: Option<&Bound<'_, PyAny>>,
+        fit_params: crate::shared::FitParameters,
+    ) -> Result<crate::shared::FitResult> {
+        let sequences = extract_entry_sequences(seqs)?;
+        let validation = validation.map(extract_entry_sequences).transpose()?;
+        let (features, result) = self.inner.fit(
+            &sequences,
+            weights.as_deref(),
+            self.features.clone(),
+            validation.as_deref(),
+            &align_params,
+            &inference_params,
+            &fit_params,
+        )?;
+        self.features = Some(features);
+        Ok(result)
+    }
+
+    #[pyo3(signature = (seqs, align_params=crate::shared::AlignmentParameters::default_evaluate(), inference_params=crate::shared::InferenceParameters::default_evaluate(), weights=None, fit_params=crate::shared::FitParameters::default(), bootstrap_params=crate::shared::BootstrapParameters::default()))]
+    /// Bootstrap the inference: refit the model (starting from the current one) on
+    /// `bootstrap_params.n_resamples` resampled datasets. The model itself is not modified.
+    /// Return a dictionary {parameter name: array}, the first axis of each array
+    /// corresponding to `bootstrap_params.quantiles`.
+    pub fn bootstrap<'py>(
+        &self,
+        py: Python<'py>,
+        seqs: &Bound<'py, PyAny>,
+        align_params: crate::shared::AlignmentParameters,
+        inference_params: crate::shared::InferenceParameters,
+        weights: Option<Vec<f64>>,
+        fit_params: crate::shared::FitParameters,
+        bootstrap_params: crate::shared::BootstrapParameters,
+    ) -> Result<Bound<'py, PyDict>> {
+        let sequences = extract_entry_sequences(seqs)?;
+        let result = py.allow_threads(|| {
+            self.inner.bootstrap(
+                &sequences,
+                weights.as_deref(),
+                &align_params,
+                &inference_params,
+                &fit_params,
+                &bootstrap_params,
+            )
+        })?;
+        let dict = PyDict::new_bound(py);
+        for (name, quantiles) in result.parameters {
+            dict.set_item(name, quantiles.into_pyarray_bound(py))?;
+        }
+        Ok(dict)
+    }
+
     /// Align one nucleotide sequence and return a `Sequence` object
     pub fn align_sequence(
         &self,
@@ -299,36 +474,7 @@ impl PyModel {
         align_params: crate::shared::AlignmentParameters,
         infer_params: crate::shared::InferenceParameters,
     ) -> Result<PyObject> {
-        let opt_esequence: Result<EntrySequence> = (|| {
-            if let Ok(s) = sequence.extract::<Sequence>() {
-                return Ok(EntrySequence::Aligned(s));
-            }
-            if let Ok(s) = sequence.extract::<String>() {
-                return Ok(EntrySequence::NucleotideSequence(DnaLike::from_dna(
-                    Dna::from_string(&s).context("The sequence is not a valid DNA sequence. If it's an amino-acid sequence use evaluate(righor.AminoAcid(\"CAW\"), ...) instead.")?,
-                )));
-            }
-            if let Ok((s, v, j)) = sequence.extract::<(String, Vec<Gene>, Vec<Gene>)>() {
-                return Ok(EntrySequence::NucleotideCDR3((
-                    DnaLike::from_dna(Dna::from_string(&s).context("The sequence is not a valid DNA sequence. If it's an amino-acid sequence use evaluate(righor.AminoAcid(\"CAW\"), ...) instead.")
-
-		    ?),
-                    v,
-                    j,
-                )));
-            }
-            if let Ok((s, v, j)) = sequence.extract::<(AminoAcid, Vec<Gene>, Vec<Gene>)>() {
-                return Ok(EntrySequence::NucleotideCDR3((
-                    DnaLike::from_amino_acid(s),
-                    v,
-                    j,
-                )));
-            }
-            if let Ok((s, v, j)) = sequence.extract::<(Dna, Vec<Gene>, Vec<Gene>)>() {
-                return Ok(EntrySequence::NucleotideCDR3((DnaLike::from_dna(s), v, j)));
-            }
-            Err(anyhow!(""))
-        })();
+        let opt_esequence = extract_entry_sequence(sequence);
 
         if opt_esequence.is_ok() {
             let esequence = opt_esequence?;
@@ -418,6 +564,47 @@ impl PyModel {
         Err(combined_error)
     }
 
+    #[pyo3(signature = (sequence, nb_samples, align_params=crate::shared::AlignmentParameters::default_evaluate(), infer_params=crate::shared::InferenceParameters::default_evaluate(), seed=None))]
+    /// Draw `nb_samples` recombination scenarios of the sequence from the
+    /// posterior distribution P(scenario | sequence) (VDJ and VxDJ models).
+    pub fn sample_posterior(
+        &self,
+        sequence: &Bound<'_, PyAny>,
+        nb_samples: usize,
+        align_params: crate::shared::AlignmentParameters,
+        infer_params: crate::shared::InferenceParameters,
+        seed: Option<u64>,
+    ) -> Result<Vec<InfEvent>> {
+        self.inner.sample_posterior(
+            extract_entry_sequence(sequence)?,
+            nb_samples,
+            &align_params,
+            &infer_params,
+            seed,
+        )
+    }
+
+    #[pyo3(signature = (sequences, align_params=crate::shared::AlignmentParameters::default_evaluate(), infer_params=crate::shared::InferenceParameters::default_evaluate(), chunk_size=1000))]
+    /// Evaluate an iterable of sequences lazily, `chunk_size` sequences at a time.
+    /// Return an iterator over compact results (same order as the input), so
+    /// that neither the sequences nor the results need to fit in memory.
+    pub fn evaluate_stream(
+        &self,
+        sequences: &Bound<'_, PyAny>,
+        align_params: crate::shared::AlignmentParameters,
+        infer_params: crate::shared::InferenceParameters,
+        chunk_size: usize,
+    ) -> Result<PyEvaluationStream> {
+        Ok(PyEvaluationStream {
+            model: self.inner.clone(),
+            sequences: sequences.iter()?.unbind(),
+            align_params,
+            infer_params,
+            chunk_size: chunk_size.max(1),
+            buffer: VecDeque::new(),
+        })
+    }
+
     /// Recreate the full sequence from the CDR3/vgene/jgene
     pub fn recreate_full_sequence(&self, dna_cdr3: &Dna, vgene: &Gene, jgene: &Gene) -> Dna {
         match &self.inner {
@@ -506,6 +693,11 @@ impl PyModel {
         self.inner.set_error(value.s)
     }
     #[getter]
+    /// (V, D, J) error rates
+    pub fn get_segment_error_rates(&self) -> Result<(f64, f64, f64)> {
+        self.inner.get_segment_error_rates()
+    }
+    #[getter]
     pub fn get_d_segments(&self) -> Result<Vec<Gene>> {
         self.inner.get_d_segments()
     }
@@ -553,6 +745,38 @@ impl PyModel {
             .into())
     }
     #[getter]
+    pub fn get_p_ins_vd_given_v(&self, py: Python) -> Result<Option<Py<PyArray2<f64>>>> {
+        Ok(self
+            .inner
+            .get_p_ins_vd_given_v()?
+            .map(|x| x.into_pyarray_bound(py).into()))
+    }
+    #[getter]
+    pub fn get_p_ins_dj_given_j(&self, py: Python) -> Result<Option<Py<PyArray2<f64>>>> {
+        Ok(self
+            .inner
+            .get_p_ins_dj_given_j()?
+            .map(|x| x.into_pyarray_bound(py).into()))
+    }
+    #[setter]
+    pub fn set_p_ins_vd_given_v(
+        &mut self,
+        py: Python,
+        value: Option<Py<PyArray2<f64>>>,
+    ) -> Result<()> {
+        self.inner
+            .set_p_ins_vd_given_v(value.map(|x| x.bind(py).to_owned_array()))
+    }
+    #[setter]
+    pub fn set_p_ins_dj_given_j(
+        &mut self,
+        py: Python,
+        value: Option<Py<PyArray2<f64>>>,
+    ) -> Result<()> {
+        self.inner
+            .set_p_ins_dj_given_j(value.map(|x| x.bind(py).to_owned_array()))
+    }
+    #[getter]
     pub fn get_p_ins_vj(&self, py: Python) -> Result<Py<PyArray1<f64>>> {
         Ok(self
             .inner
@@ -648,6 +872,15 @@ impl PyModel {
             .set_p_del_d5_del_d3(value.bind(py).to_owned_array())
     }
     #[getter]
+    /// Order of the insertion Markov chains
+    pub fn get_markov_order(&self) -> usize {
+        self.inner.get_markov_order()
+    }
+    #[setter]
+    pub fn set_markov_order(&mut self, value: usize) -> Result<()> {
+        self.inner.set_markov_order(value)
+    }
+    #[getter]
     pub fn get_markov_coefficients_vd(&self, py: Python) -> Result<Py<PyArray2<f64>>> {
         Ok(self
             .inner
@@ -739,7 +972,26 @@ impl PyModel {
             .into())
     }
 
-    #[pyo3(signature = (num_monte_carlo=1_000_000, conserved_j_residues="ACDEFGHIKLMNPQRSTVWY", seed=None))]
+    /// Exact probability that a generated sequence is productive, ending
+    /// with one of the `conserved_j_residues` (default "FVW"), cached
+    #[pyo3(signature = (conserved_j_residues=None))]
+    pub fn get_norm_productive_exact(&self, conserved_j_residues: Option<&str>) -> Result<f64> {
+        self.inner.get_norm_productive_exact(conserved_j_residues)
+    }
+
+    /// pgen among the productive sequences, `pgen / get_norm_productive_exact`
+    #[pyo3(signature = (pgen, conserved_j_residues=None))]
+    pub fn get_pgen_productive(
+        &self,
+        pgen: f64,
+        conserved_j_residues: Option<&str>,
+    ) -> Result<f64> {
+        self.inner.get_pgen_productive(pgen, conserved_j_residues)
+    }
+
+    /// Monte Carlo estimate of `get_norm_productive_exact`, same default
+    /// conserved J residues ("FVW")
+    #[pyo3(signature = (num_monte_carlo=1_000_000, conserved_j_residues=None, seed=None))]
     pub fn get_norm_productive(
         &self,
         num_monte_carlo: Option<usize>,
@@ -755,6 +1007,45 @@ impl PyModel {
     }
 }
 
+/// Iterator returned by `Model.evaluate_stream`
+#[cfg(all(feature = "py_binds", feature = "pyo3"))]
+#[pyclass(name = "EvaluationStream")]
+pub struct PyEvaluationStream {
+    model: Model,
+    sequences: Py<PyIterator>,
+    align_params: crate::shared::AlignmentParameters,
+    infer_params: crate::shared::InferenceParameters,
+    chunk_size: usize,
+    buffer: VecDeque<Result<ResultCompact>>,
+}
+
+#[cfg(all(feature = "py_binds", feature = "pyo3"))]
+#[pymethods]
+impl PyEvaluationStream {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python) -> Result<Option<ResultCompact>> {
+        if self.buffer.is_empty() {
+            let mut chunk = Vec::with_capacity(self.chunk_size);
+            let mut iter = self.sequences.bind(py).clone();
+            for item in iter.by_ref().take(self.chunk_size) {
+                chunk.push(extract_entry_sequence(&item?).context("The sequence does not match any known types, available types are `Sequence`, `str` and `(str/Dna/AminoAcid, [Gene], [Gene])`.")?);
+            }
+            if chunk.is_empty() {
+                return Ok(None);
+            }
+            // release the GIL while the chunk is evaluated
+            let (model, ap, ip) = (&self.model, &self.align_params, &self.infer_params);
+            self.buffer = py
+                .allow_threads(|| model.evaluate_batch(chunk, ap, ip))
+                .into();
+        }
+        self.buffer.pop_front().transpose()
+    }
+}
+
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
 #[pymodule]
 #[pyo3(name = "_righor")]
@@ -775,6 +1066,9 @@ fn righor_py(py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<crate::vdj::Sequence>()?;
     m.add_class::<PyModel>()?;
     m.add_class::<crate::shared::GenerationResult>()?;
+    m.add_class::<crate::shared::ResultCompact>()?;
+    m.add_class::<crate::shared::PosteriorMarginals>()?;
+    m.add_class::<PyEvaluationStream>()?;
     m.add_class::<crate::vdj::Sequence>()?;
     m.add_class::<crate::shared::errors::PyErrorParameters>()?;
     m.add_class::<crate::Gene>()?;
@@ -784,6 +1078,11 @@ fn righor_py(py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<crate::shared::ModelStructure>()?;
     m.add_class::<crate::shared::parameters::InferenceParameters>()?;
     m.add_class::<crate::shared::parameters::AlignmentParameters>()?;
+    m.add_class::<crate::shared::parameters::FitParameters>()?;
+    m.add_class::<crate::shared::parameters::Pseudocounts>()?;
+    m.add_class::<crate::shared::parameters::BootstrapParameters>()?;
+    m.add_class::<crate::shared::parameters::GenerationConstraints>()?;
+    m.add_class::<crate::shared::FitResult>()?;
     m.add_function(wrap_pyfunction!(set_number_threads, m)?)?;
     m.add_function(wrap_pyfunction!(notebook_mode, m)?)?;
     m.add_submodule(&vdj_submod)?;
@@ -1,26 +1,631 @@
 #![warn(clippy::all)]
+//! Command-line interface for righor.
+//!
+//! ```text
+//! righor generate --species human --chain trb -n 1000 --functional > seqs.tsv
+//! righor evaluate --species human --chain trb -i seqs.fasta -o results.tsv
+//! righor infer --species human --chain trb -i seqs.fasta --iterations 10 --output-dir new_model
+//! ```
 
-pub mod shared;
-pub mod v_dj;
-pub mod vdj;
-pub mod vj;
+use anyhow::{anyhow, Context, Result};
+use rayon::prelude::*;
+use righor::shared::io::{AirrReader, AirrRearrangement, AirrWriter, FastaReader, TsvReader};
+use righor::shared::utils::send_warning;
+use righor::shared::{FitParameters, Pseudocounts};
+use righor::{AlignmentParameters, Dna, DnaLike, EntrySequence, InferenceParameters, Model};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
-use righor::shared::genes_matching;
-use righor::shared::Model;
-use righor::AminoAcid;
-use righor::EntrySequence;
-use righor::{AlignmentParameters, InferenceParameters};
+const USAGE: &str = "\
+Usage: righor <COMMAND> [OPTIONS]
 
-use std::path::Path;
+Commands:
+  generate   Generate sequences from a model
+  evaluate   Evaluate sequences (pgen, likelihood, most likely V/D/J genes and their posterior, error rate)
+  infer      Run expectation-maximization rounds and save the inferred model
 
-fn main() -> Result<()> {
-    let mut model = Model::load_from_name(
-        "human",
-        "trb",
+Model selection (one of):
+  --species <NAME> --chain <NAME> [--id <ID>] [--model-dir <DIR>]
+      (default model directory: $RIGHOR_MODEL_DIR, or righor_models next to
+      the executable or in ../share/righor)
+  --params <FILE> --marginals <FILE> --v-anchors <FILE> --j-anchors <FILE>
+  --json <FILE>
+
+Input (evaluate / infer):
+  -i, --input <FILE>       FASTA, TSV (with a header), AIRR TSV or one sequence per line ('-' for stdin, default)
+      --format <FORMAT>    fasta, tsv, airr or lines (default: guessed from the file)
+      --column <NAME>      TSV column containing the sequences (default: sequence)
+      --id-column <NAME>   TSV column containing the sequence ids (default: sequence_id)
+      --productive-only    AIRR: only keep the productive sequences
+      --use-junction       AIRR: use the junction and the V/J calls instead of the full sequence
+
+Output:
+  -o, --output <FILE>      TSV output ('-' for stdout, default)
+
+generate:
+  -n, --number <N>         Number of sequences to generate (default: 1)
+      --functional         Only generate productive sequences
+      --without-errors     Ignore the error model of the model
+      --seed <SEED>        Random seed
+
+evaluate:
+      --left-v-cutoff <N>  Cut the V gene to its last N nucleotides for the alignment
+      --chunk-size <N>     Number of sequences evaluated (and kept in memory) at once (default: 10000)
+      --airr               Write the results following the AIRR Rearrangement schema
+
+infer:
+      --iterations <N>     Maximal number of expectation-maximization rounds (default: 10)
+      --tolerance <X>      Stop when the log-likelihood per sequence improves by less than X (default: 0)
+      --checkpoint <FILE>  Save the model (json) in FILE during the inference
+      --checkpoint-every <N> Save the checkpoint every N rounds (default: 1)
+      --validation <FILE>  Held-out sequences (same format as the input), their log-likelihood is reported after each round
+      --early-stopping     Stop when the validation log-likelihood decreases
+      --pseudocount <X>    Pseudocount added to every marginal when updating the model (default: 0)
+      --uniform            Start from a uniform model
+      --output-dir <DIR>   Save the inferred model in the IGoR format in DIR
+      --output-json <FILE> Save the inferred model in the json format
+      --left-v-cutoff <N>  Cut the V gene to its last N nucleotides for the alignment
+
+Other:
+  -t, --threads <N>        Number of threads (default: all of them)
+  -h, --help               Print this message
+";
+
+/// Options given on the command line, stored by (long) name
+struct Options {
+    values: HashMap<String, String>,
+    flags: Vec<String>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Options> {
+        const FLAGS: [&str; 8] = [
+            "functional",
+            "without-errors",
+            "uniform",
+            "early-stopping",
+            "airr",
+            "productive-only",
+            "use-junction",
+            "help",
+        ];
+        let mut values = HashMap::new();
+        let mut flags = Vec::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            let name = match arg.as_str() {
+                "-i" => "input",
+                "-o" => "output",
+                "-n" => "number",
+                "-t" => "threads",
+                "-h" => "help",
+                a if a.starts_with("--") => &a[2..],
+                a => return Err(anyhow!("Unexpected argument: {}\n\n{}", a, USAGE)),
+            };
+            if FLAGS.contains(&name) {
+                flags.push(name.to_string());
+            } else {
+                let value = iter
+                    .next()
+                    .ok_or(anyhow!("Missing value for option --{}", name))?;
+                values.insert(name.to_string(), value.clone());
+            }
+        }
+        Ok(Options { values, flags })
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(|x| x.as_str())
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|x| x == name)
+    }
+
+    fn parse_value<T: std::str::FromStr>(&self, name: &str, default: T) -> Result<T> {
+        match self.get(name) {
+            Some(v) => v
+                .parse::<T>()
+                .map_err(|_| anyhow!("Invalid value for option --{}: {}", name, v)),
+            None => Ok(default),
+        }
+    }
+
+    /// Check that all the options given are known by the subcommand
+    fn check_known(&self, known: &[&str]) -> Result<()> {
+        for name in self.values.keys().chain(self.flags.iter()) {
+            if !known.contains(&name.as_str()) {
+                return Err(anyhow!("Unknown option --{}\n\n{}", name, USAGE));
+            }
+        }
+        Ok(())
+    }
+}
+
+const MODEL_OPTIONS: [&str; 9] = [
+    "species",
+    "chain",
+    "id",
+    "model-dir",
+    "params",
+    "marginals",
+    "v-anchors",
+    "j-anchors",
+    "json",
+];
+
+const INPUT_OPTIONS: [&str; 6] = [
+    "input",
+    "format",
+    "column",
+    "id-column",
+    "productive-only",
+    "use-junction",
+];
+
+fn load_model(opts: &Options) -> Result<Model> {
+    if let Some(json) = opts.get("json") {
+        return Model::load_json(Path::new(json));
+    }
+    if let Some(params) = opts.get("params") {
+        let missing = |name: &str| anyhow!("--params requires --{} as well", name);
+        return Model::load_from_files(
+            Path::new(params),
+            Path::new(opts.get("marginals").ok_or(missing("marginals"))?),
+            Path::new(opts.get("v-anchors").ok_or(missing("v-anchors"))?),
+            Path::new(opts.get("j-anchors").ok_or(missing("j-anchors"))?),
+        );
+    }
+    let (Some(species), Some(chain)) = (opts.get("species"), opts.get("chain")) else {
+        return Err(anyhow!(
+            "No model given, use --species/--chain, --params/--marginals/--v-anchors/--j-anchors or --json"
+        ));
+    };
+    let model_dir = match opts.get("model-dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => default_model_dir()?,
+    };
+    Model::load_from_name(
+        species,
+        chain,
+        opts.get("id").map(|x| x.to_string()),
+        &model_dir,
+    )
+}
+
+/// Environment variable giving the default `--model-dir`
+const MODEL_DIR_VAR: &str = "RIGHOR_MODEL_DIR";
+
+/// Default directory of the models (containing `models.json`): the
+/// `RIGHOR_MODEL_DIR` environment variable, otherwise `righor_models` next to
+/// the executable or in `../share/righor` (relative to the executable)
+fn default_model_dir() -> Result<PathBuf> {
+    let has_models = |dir: &Path| dir.join("models.json").is_file();
+    if let Some(dir) = std::env::var_os(MODEL_DIR_VAR) {
+        let dir = PathBuf::from(dir);
+        if has_models(&dir) {
+            return Ok(dir);
+        }
+        return Err(anyhow!(
+            "No models found in {} (from {MODEL_DIR_VAR}), use --model-dir <DIR> \
+             with the directory containing models.json",
+            dir.display()
+        ));
+    }
+    let exe = std::env::current_exe().context("Cannot locate the righor executable")?;
+    let exe = std::fs::canonicalize(&exe).unwrap_or(exe);
+    let exe_dir = exe
+        .parent()
+        .ok_or(anyhow!("Cannot locate the righor executable"))?;
+    let candidates = [
+        exe_dir.join("righor_models"),
+        exe_dir.join("../share/righor/righor_models"),
+    ];
+    candidates
+        .iter()
+        .find(|dir| has_models(dir))
+        .cloned()
+        .ok_or(anyhow!(
+            "No models found ({MODEL_DIR_VAR} is not set, no righor_models directory \
+             next to the executable or in ../share/righor), use --model-dir <DIR> \
+             with the directory containing models.json"
+        ))
+}
+
+fn open_output(opts: &Options) -> Result<Box<dyn Write>> {
+    Ok(match opts.get("output") {
+        None | Some("-") => Box::new(BufWriter::new(io::stdout().lock())),
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).with_context(|| format!("Cannot create {path}"))?,
+        )),
+    })
+}
+
+/// (sequence id, sequence, number of copies of the sequence)
+type SequenceReader<'a> = Box<dyn Iterator<Item = Result<(String, EntrySequence, usize)>> + 'a>;
+
+/// Open the input sequences, return an iterator that reads the file lazily.
+fn open_sequences<'a>(opts: &Options, input: &str, model: &'a Model) -> Result<SequenceReader<'a>> {
+    let mut reader: BufReader<Box<dyn Read>> = if input == "-" {
+        BufReader::new(Box::new(io::stdin()))
+    } else {
+        BufReader::new(Box::new(
+            File::open(input).with_context(|| format!("Cannot open {input}"))?,
+        ))
+    };
+
+    let format = match opts.get("format") {
+        Some(f) => f.to_lowercase(),
+        None => {
+            let extension = Path::new(input)
+                .extension()
+                .map(|x| x.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            // only look at the beginning of the file
+            let start = String::from_utf8_lossy(reader.fill_buf()?).to_string();
+            let first_line = start.lines().find(|l| !l.trim().is_empty());
+            let airr_header = first_line.is_some_and(|l| {
+                let columns: Vec<&str> = l.split('\t').map(|x| x.trim()).collect();
+                columns.contains(&"sequence_id") && columns.contains(&"v_call")
+            });
+            match extension.as_str() {
+                "fa" | "fasta" | "fna" | "fas" => "fasta".to_string(),
+                _ if airr_header => "airr".to_string(),
+                "tsv" | "tab" => "tsv".to_string(),
+                _ if first_line.is_some_and(|l| l.starts_with('>')) => "fasta".to_string(),
+                _ if first_line.is_some_and(|l| l.contains('\t')) => "tsv".to_string(),
+                _ => "lines".to_string(),
+            }
+        }
+    };
+
+    let to_entry = |record: Result<(String, String)>| {
+        let (id, seq) = record?;
+        let entry = to_entry_sequence(&id, &seq)?;
+        Ok((id, entry, 1))
+    };
+    match format.as_str() {
+        "fasta" => Ok(Box::new(FastaReader::new(reader).map(to_entry))),
+        "tsv" => Ok(Box::new(
+            TsvReader::new(
+                reader,
+                opts.get("column").unwrap_or("sequence"),
+                opts.get("id-column").unwrap_or("sequence_id"),
+            )?
+            .map(to_entry),
+        )),
+        "airr" => Ok(Box::new(
+            AirrReader::new(
+                reader,
+                model,
+                opts.flag("productive-only"),
+                opts.flag("use-junction"),
+            )?
+            .map(|r| r.map(|x| (x.sequence_id, x.sequence, x.duplicate_count))),
+        )),
+        "lines" => Ok(Box::new(
+            reader
+                .lines()
+                .filter(|l| l.as_ref().map_or(true, |x| !x.trim().is_empty()))
+                .enumerate()
+                .map(|(ii, l)| Ok((ii.to_string(), l?.trim().to_uppercase())))
+                .map(to_entry),
+        )),
+        f => Err(anyhow!(
+            "Unknown input format {}, available formats are fasta, tsv, airr and lines",
+            f
+        )),
+    }
+}
+
+fn to_entry_sequence(id: &str, seq: &str) -> Result<EntrySequence> {
+    Ok(EntrySequence::NucleotideSequence(DnaLike::from_dna(
+        Dna::from_string(seq)
+            .with_context(|| format!("Sequence {id} is not a valid nucleotide sequence"))?,
+    )))
+}
+
+/// Empty TSV field for missing values
+fn optional_field<T: ToString>(value: Option<T>) -> String {
+    value.map(|x| x.to_string()).unwrap_or_default()
+}
+
+fn alignment_parameters(opts: &Options) -> Result<AlignmentParameters> {
+    let mut align_params = AlignmentParameters::default_evaluate();
+    align_params.left_v_cutoff = opts.parse_value("left-v-cutoff", align_params.left_v_cutoff)?;
+    Ok(align_params)
+}
+
+fn generate(opts: &Options) -> Result<()> {
+    let model = load_model(opts)?;
+    let number: usize = opts.parse_value("number", 1)?;
+    let seed: Option<u64> = match opts.get("seed") {
+        Some(_) => Some(opts.parse_value("seed", 0)?),
+        None => None,
+    };
+    let functional = opts.flag("functional");
+    let mut generator = righor::Generator::new(&model, seed, None, None)?;
+
+    let mut out = open_output(opts)?;
+    if opts.flag("without-errors") {
+        writeln!(out, "junction_aa\tv_gene\tj_gene\tjunction_nt")?;
+        for r in generator.generate_many_without_errors(number, functional)? {
+            writeln!(out, "{}", r.join("\t"))?;
+        }
+    } else {
+        writeln!(out, "junction_aa\tv_gene\tj_gene\tjunction_nt\tfull_seq")?;
+        for r in generator.generate_many(number, functional)? {
+            writeln!(out, "{}", r.join("\t"))?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Read the next `chunk_size` sequences, return their ids and the sequences
+fn next_chunk(
+    sequences: &mut SequenceReader,
+    chunk_size: usize,
+) -> Result<(Vec<String>, Vec<EntrySequence>)> {
+    let mut ids = Vec::with_capacity(chunk_size);
+    let mut chunk = Vec::with_capacity(chunk_size);
+    for record in sequences.by_ref().take(chunk_size.max(1)) {
+        let (id, seq, _) = record?;
+        chunk.push(seq);
+        ids.push(id);
+    }
+    Ok((ids, chunk))
+}
+
+fn evaluate(opts: &Options) -> Result<()> {
+    let model = load_model(opts)?;
+    let align_params = alignment_parameters(opts)?;
+    let infer_params = InferenceParameters::default_evaluate();
+    let chunk_size: usize = opts.parse_value("chunk-size", 10000)?;
+    let mut sequences = open_sequences(opts, opts.get("input").unwrap_or("-"), &model)?;
+    let mut out = open_output(opts)?;
+
+    // only keep one chunk of sequences in memory at a time
+    if opts.flag("airr") {
+        let mut writer = AirrWriter::new(out)?;
+        loop {
+            let (ids, chunk) = next_chunk(&mut sequences, chunk_size)?;
+            if chunk.is_empty() {
+                break;
+            }
+            let rows = ids
+                .par_iter()
+                .zip(chunk.into_par_iter())
+                .map(|(id, seq)| {
+                    let result = model
+                        .evaluate(seq, &align_params, &infer_params)
+                        .with_context(|| format!("Cannot evaluate sequence {id}"))?;
+                    AirrRearrangement::from_result(id, &result, &model)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for row in &rows {
+                writer.write(row)?;
+            }
+        }
+        writer.flush()?;
+        return Ok(());
+    }
+
+    writeln!(
+        out,
+        "sequence_id\tpgen\tlikelihood\tv_gene\td_gene\tj_gene\tjunction_nt\tjunction_aa\t\
+         error_rate\tv_errors\td_errors\tj_errors\tv_posterior\td_posterior\tj_posterior"
+    )?;
+    loop {
+        let (ids, chunk) = next_chunk(&mut sequences, chunk_size)?;
+        if chunk.is_empty() {
+            break;
+        }
+        let results = model.evaluate_batch(chunk, &align_params, &infer_params);
+        for (id, result) in ids.iter().zip(results) {
+            let r = result.with_context(|| format!("Cannot evaluate sequence {id}"))?;
+            writeln!(
+                out,
+                "{id}\t{:e}\t{:e}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                r.pgen,
+                r.likelihood,
+                r.v_name.unwrap_or_default(),
+                r.d_name.unwrap_or_default(),
+                r.j_name.unwrap_or_default(),
+                r.n_junction.unwrap_or_default(),
+                r.aa_junction.unwrap_or_default(),
+                optional_field(r.error_rate),
+                optional_field(r.nb_errors_v),
+                optional_field(r.nb_errors_d),
+                optional_field(r.nb_errors_j),
+                optional_field(r.v_posterior),
+                optional_field(r.d_posterior),
+                optional_field(r.j_posterior),
+            )?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn infer(opts: &Options) -> Result<()> {
+    let (output_dir, output_json) = (opts.get("output-dir"), opts.get("output-json"));
+    if output_dir.is_none() && output_json.is_none() {
+        return Err(anyhow!(
+            "Nowhere to save the inferred model, use --output-dir or --output-json"
+        ));
+    }
+
+    let mut model = load_model(opts)?;
+    if opts.flag("uniform") {
+        model = model.uniform()?;
+    }
+    let align_params = alignment_parameters(opts)?;
+    let infer_params = InferenceParameters {
+        pseudocounts: Pseudocounts::uniform(opts.parse_value("pseudocount", 0.)?),
+        ..Default::default()
+    };
+
+    // align the sequences once, the alignments don't change between rounds
+    let sequences = open_sequences(opts, opts.get("input").unwrap_or("-"), &model)?
+        .collect::<Result<Vec<_>>>()?;
+    let aligned = sequences
+        .par_iter()
+        .map(|(id, seq, _)| {
+            let alignment = model
+                .align_entry_sequence(seq, &align_params)
+                .with_context(|| format!("Cannot align sequence {id}"))?;
+            Ok(EntrySequence::Aligned(alignment))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // sequences seen multiple times (AIRR duplicate_count) weigh more
+    let weights: Vec<f64> = sequences
+        .iter()
+        .map(|(_, _, count)| *count as f64)
+        .collect();
+    let nb_invalid = aligned
+        .iter()
+        .filter(|s| match s {
+            EntrySequence::Aligned(x) => !x.valid_alignment,
+            _ => false,
+        })
+        .count();
+    if nb_invalid > 0 {
+        send_warning(&format!(
+            "{nb_invalid} sequences out of {} could not be aligned",
+            aligned.len()
+        ));
+    }
+
+    let fit_params = FitParameters {
+        tolerance: opts.parse_value("tolerance", 0.)?,
+        max_iterations: opts.parse_value("iterations", 10)?,
+        checkpoint_every: opts.parse_value("checkpoint-every", 1)?,
+        checkpoint_path: opts.get("checkpoint").map(|x| x.to_string()),
+        early_stopping: opts.flag("early-stopping"),
+    };
+    if fit_params.early_stopping && opts.get("validation").is_none() {
+        return Err(anyhow!(
+            "--early-stopping needs a validation set (--validation)"
+        ));
+    }
+    // held-out sequences, same format as the input
+    let validation = match opts.get("validation") {
+        Some(file) => Some(
+            open_sequences(opts, file, &model)?
+                .map(|r| r.map(|(_, seq, _)| seq))
+                .collect::<Result<Vec<_>>>()?,
+        ),
+        None => None,
+    };
+
+    let (_, fit) = model.fit(
+        &aligned,
+        Some(&weights),
         None,
-        &Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/righor.data")),
+        validation.as_deref(),
+        &align_params,
+        &infer_params,
+        &fit_params,
     )?;
+    let total_weight: f64 = weights.iter().sum();
+    for (ii, log_likelihood) in fit.log_likelihoods.iter().enumerate() {
+        match fit.validation_log_likelihoods.get(ii) {
+            Some(val) => eprintln!(
+                "Iteration {}: log-likelihood {:.6e} (per sequence: training {:.4}, validation {:.4})",
+                ii + 1,
+                log_likelihood,
+                log_likelihood / total_weight,
+                val
+            ),
+            None => eprintln!(
+                "Iteration {}: log-likelihood {:.6e}",
+                ii + 1,
+                log_likelihood
+            ),
+        }
+    }
+    if fit.converged {
+        eprintln!("Converged after {} iterations", fit.log_likelihoods.len());
+    }
+    if fit.stopped_early {
+        eprintln!(
+            "Validation log-likelihood decreased, stopped after {} iterations (keeping the previous model)",
+            fit.log_likelihoods.len()
+        );
+    }
 
+    if let Some(dir) = output_dir {
+        std::fs::create_dir_all(dir)?;
+        model.save_model(Path::new(dir))?;
+    }
+    if let Some(json) = output_json {
+        model.save_json(Path::new(json))?;
+    }
     Ok(())
 }
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(command) = args.first() else {
+        eprint!("{USAGE}");
+        std::process::exit(1);
+    };
+    if command == "-h" || command == "--help" {
+        print!("{USAGE}");
+        return Ok(());
+    }
+
+    let opts = Options::parse(&args[1..])?;
+    if opts.flag("help") {
+        print!("{USAGE}");
+        return Ok(());
+    }
+
+    if let Some(threads) = opts.get("threads") {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(
+                threads
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid number of threads: {}", threads))?,
+            )
+            .build_global()?;
+    }
+
+    let mut known: Vec<&str> = vec!["output", "threads"];
+    known.extend(MODEL_OPTIONS);
+    match command.as_str() {
+        "generate" => {
+            known.extend(["number", "functional", "without-errors", "seed"]);
+            opts.check_known(&known)?;
+            generate(&opts)
+        }
+        "evaluate" => {
+            known.extend(INPUT_OPTIONS);
+            known.extend(["left-v-cutoff", "chunk-size", "airr"]);
+            opts.check_known(&known)?;
+            evaluate(&opts)
+        }
+        "infer" => {
+            known.extend(INPUT_OPTIONS);
+            known.extend([
+                "left-v-cutoff",
+                "iterations",
+                "tolerance",
+                "checkpoint",
+                "checkpoint-every",
+                "validation",
+                "early-stopping",
+                "pseudocount",
+                "uniform",
+                "output-dir",
+                "output-json",
+            ]);
+            opts.check_known(&known)?;
+            infer(&opts)
+        }
+        c => Err(anyhow!("Unknown command {}\n\n{}", c, USAGE)),
+    }
+}
@@ -1,9 +1,11 @@
 use crate::shared::errors::MAX_NB_ERRORS;
 use crate::shared::nucleotides_inv;
+use crate::shared::sequence::compatible_nucleotides;
 use crate::shared::sequence::Dna;
 use crate::shared::sequence::SequenceType;
 use crate::shared::DnaLike;
 use crate::vdj::model::Model as ModelVDJ;
+use bio::alignment::AlignmentOperation;
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
 use pyo3::prelude::*;
 use std::sync::Arc;
@@ -52,6 +54,14 @@ pub struct VJAlignment {
     // the length of this vector is the maximum number of v/j deletion.
     // so here [5,4,3,2,2,1,1,1,1,1,1,0,0,...]
     // score is the score of the alignment according to the alignment process
+    // mismatches contains the (gene position, sequence nucleotide) pairs that
+    // disagree in the aligned region, sorted by gene position (empty for
+    // amino-acid sequences). Used by the context-dependent error model.
+    // cigar is the run-length encoded gapped alignment, in the gene frame:
+    // 'M' aligned, 'I' nucleotide only in the sequence, 'D' nucleotide only
+    // in the gene. Empty for ungapped alignments. Inserted/deleted nucleotides
+    // count as errors; a deletion value that would remove an indel is impossible
+    // (the position of the gene end in the sequence would be unknown).
     pub index: usize,      // index of the gene in the model
     pub start_seq: usize,  // this is the start of the alignment in the sequence indexing
     pub end_seq: usize,    // end of the alignment in the sequence indexing
@@ -63,6 +73,44 @@ pub struct VJAlignment {
     pub max_del: Option<usize>,
     pub gene_sequence: Dna, // v/j gene sequence (with pal insertions)
     pub sequence_type: SequenceType,
+    pub mismatches: Vec<(usize, u8)>,
+    pub cigar: Vec<(char, usize)>,
+}
+
+/// Run-length encode the operations of a gene/sequence alignment
+/// in the gene frame (see `VJAlignment`), `gene_is_x` is true if the gene
+/// was the first sequence given to the aligner.
+pub fn cigar_from_operations(
+    operations: &[AlignmentOperation],
+    gene_is_x: bool,
+) -> Vec<(char, usize)> {
+    let mut cigar: Vec<(char, usize)> = Vec::new();
+    for op in operations {
+        // `Del` is a gap in x, `Ins` a gap in y
+        let c = match op {
+            AlignmentOperation::Match | AlignmentOperation::Subst => 'M',
+            AlignmentOperation::Del => {
+                if gene_is_x {
+                    'I'
+                } else {
+                    'D'
+                }
+            }
+            AlignmentOperation::Ins => {
+                if gene_is_x {
+                    'D'
+                } else {
+                    'I'
+                }
+            }
+            AlignmentOperation::Xclip(_) | AlignmentOperation::Yclip(_) => continue,
+        };
+        match cigar.last_mut() {
+            Some((last, n)) if *last == c => *n += 1,
+            _ => cigar.push((c, 1)),
+        }
+    }
+    cigar
 }
 
 #[cfg_attr(all(feature = "py_binds", feature = "pyo3"), pymethods)]
@@ -98,8 +146,51 @@ impl VJAlignment {
             .collect()
     }
 
+    pub fn cigar_string(&self) -> String {
+        if self.cigar.is_empty() {
+            return format!("{}M", self.end_gene - self.start_gene);
+        }
+        self.cigar
+            .iter()
+            .map(|(c, n)| format!("{n}{c}"))
+            .collect::<String>()
+    }
+
+    pub fn nb_insertions(&self) -> usize {
+        self.cigar
+            .iter()
+            .filter(|(c, _)| *c == 'I')
+            .map(|(_, n)| n)
+            .sum()
+    }
+
+    pub fn nb_deletions(&self) -> usize {
+        self.cigar
+            .iter()
+            .filter(|(c, _)| *c == 'D')
+            .map(|(_, n)| n)
+            .sum()
+    }
+
+    pub fn nb_indels(&self) -> usize {
+        self.nb_insertions() + self.nb_deletions()
+    }
+
+    /// Position in the V gene of the first nucleotide of the sequence,
+    /// counted from the end of the alignment (so that it stays valid when
+    /// the alignment contains indels)
+    pub fn v_start_gene(&self) -> usize {
+        (self.start_gene + self.nb_deletions()).saturating_sub(self.nb_insertions())
+    }
+
     pub fn precompute_errors_v(&mut self, seq: &DnaLike) {
         self.errors = vec![0; self.max_del.unwrap()];
+        if self.nb_indels() > 0 {
+            if let Some(dna) = seq.as_dna() {
+                self.precompute_errors_v_gapped(dna);
+                return;
+            }
+        }
         for del_v in 0..self.errors.len() {
             if self.end_seq > del_v + seq.len() {
                 // large number (ugly hack, but shouldn't create issues)
@@ -120,10 +211,27 @@ impl VJAlignment {
         }
         // no problems here
         self.errors_extended = None;
+
+        self.mismatches = match seq.as_dna() {
+            None => vec![],
+            Some(dna) => (self.start_seq..self.end_seq.min(dna.len()))
+                .map(|idx_seq| (self.start_gene + idx_seq - self.start_seq, dna.seq[idx_seq]))
+                .filter(|&(idx_gene, nt)| {
+                    idx_gene < self.gene_sequence.len()
+                        && !compatible_nucleotides(nt, self.gene_sequence.seq[idx_gene])
+                })
+                .collect(),
+        };
     }
 
     pub fn precompute_errors_j(&mut self, seq: &DnaLike) {
         self.errors = vec![0; self.max_del.unwrap()];
+        if self.nb_indels() > 0 {
+            if let Some(dna) = seq.as_dna() {
+                self.precompute_errors_j_gapped(dna);
+                return;
+            }
+        }
         let mut errors_extended = vec![[0; 16]; self.max_del.unwrap()];
 
         for del_j in 0..self.errors.len() {
@@ -160,6 +268,22 @@ impl VJAlignment {
             }
             self.errors_extended = Some(errors_extended.clone());
         }
+
+        // the J region can start before the aligned part (if del_j < start_gene)
+        self.mismatches = match seq.as_dna() {
+            None => vec![],
+            Some(dna) => (self.start_gene.saturating_sub(self.start_seq)..self.end_gene)
+                .map(|idx_gene| (idx_gene, idx_gene + self.start_seq - self.start_gene))
+                .filter(|&(idx_gene, idx_seq)| {
+                    idx_seq < dna.len()
+                        && !compatible_nucleotides(
+                            dna.seq[idx_seq],
+                            self.gene_sequence.seq[idx_gene],
+                        )
+                })
+                .map(|(idx_gene, idx_seq)| (idx_gene, dna.seq[idx_seq]))
+                .collect(),
+        };
     }
 
     pub fn length_with_deletion(&self, del_left: usize, del_right: usize) -> usize {
@@ -208,6 +332,144 @@ impl VJAlignment {
     }
 }
 
+impl VJAlignment {
+    /// Set the gapped alignment. Gaps at the extremities are not indels
+    /// (they are deletions, or nucleotides outside of the gene) and are
+    /// removed from the alignment.
+    pub fn set_cigar(&mut self, mut cigar: Vec<(char, usize)>) {
+        while let Some(&(c, n)) = cigar.first().filter(|(c, _)| *c != 'M') {
+            if c == 'I' {
+                self.start_seq += n;
+            } else {
+                self.start_gene += n;
+            }
+            cigar.remove(0);
+        }
+        while let Some(&(c, n)) = cigar.last().filter(|(c, _)| *c != 'M') {
+            if c == 'I' {
+                self.end_seq -= n;
+            } else {
+                self.end_gene -= n;
+            }
+            cigar.pop();
+        }
+        self.cigar = if cigar.len() > 1 { cigar } else { Vec::new() };
+    }
+
+    /// Walk through the gapped alignment, return (operation, gene position,
+    /// sequence position). For 'I' the gene position is the one of the next
+    /// gene nucleotide, for 'D' the sequence position is the next one.
+    fn operations(&self) -> Vec<(char, usize, usize)> {
+        let mut operations = Vec::new();
+        let (mut idx_gene, mut idx_seq) = (self.start_gene, self.start_seq);
+        for &(op, n) in &self.cigar {
+            for _ in 0..n {
+                operations.push((op, idx_gene, idx_seq));
+                match op {
+                    'M' => {
+                        idx_gene += 1;
+                        idx_seq += 1;
+                    }
+                    'I' => idx_seq += 1,
+                    _ => idx_gene += 1,
+                }
+            }
+        }
+        operations
+    }
+
+    fn is_mismatch(&self, dna: &Dna, idx_gene: usize, idx_seq: usize) -> bool {
+        !compatible_nucleotides(dna.seq[idx_seq], self.gene_sequence.seq[idx_gene])
+    }
+
+    fn precompute_errors_v_gapped(&mut self, dna: &Dna) {
+        let operations = self.operations();
+        for del_v in 0..self.errors.len() {
+            if self.end_seq > del_v + dna.len() {
+                self.errors[del_v] = MAX_NB_ERRORS;
+                continue;
+            }
+            let cut = self.end_gene.saturating_sub(del_v);
+            let mut nb_errors = 0;
+            for &(op, idx_gene, idx_seq) in &operations {
+                match op {
+                    'M' if idx_gene < cut => {
+                        if self.is_mismatch(dna, idx_gene, idx_seq) {
+                            nb_errors += 1;
+                        }
+                    }
+                    'M' => (),
+                    _ if idx_gene >= cut => {
+                        nb_errors = MAX_NB_ERRORS;
+                        break;
+                    }
+                    _ => nb_errors += 1,
+                }
+            }
+            self.errors[del_v] = nb_errors;
+        }
+        self.errors_extended = None;
+
+        self.mismatches = operations
+            .iter()
+            .filter(|&&(op, idx_gene, idx_seq)| {
+                op == 'M' && self.is_mismatch(dna, idx_gene, idx_seq)
+            })
+            .map(|&(_, idx_gene, idx_seq)| (idx_gene, dna.seq[idx_seq]))
+            .collect();
+    }
+
+    fn precompute_errors_j_gapped(&mut self, dna: &Dna) {
+        let operations = self.operations();
+        // before the aligned part, the J gene is assumed ungapped
+        let head = (self.start_gene.saturating_sub(self.start_seq)..self.start_gene)
+            .map(|idx_gene| ('M', idx_gene, idx_gene + self.start_seq - self.start_gene))
+            .filter(|&(_, idx_gene, idx_seq)| {
+                idx_seq < dna.len() && self.is_mismatch(dna, idx_gene, idx_seq)
+            })
+            .collect::<Vec<_>>();
+
+        for del_j in 0..self.errors.len() {
+            if (del_j as i64 - self.start_gene as i64 + self.start_seq as i64) < 0 {
+                self.errors[del_j] = MAX_NB_ERRORS;
+                continue;
+            }
+            let mut nb_errors = head.iter().filter(|(_, g, _)| *g >= del_j).count();
+            for &(op, idx_gene, idx_seq) in &operations {
+                match op {
+                    'M' if idx_gene >= del_j => {
+                        if self.is_mismatch(dna, idx_gene, idx_seq) {
+                            nb_errors += 1;
+                        }
+                    }
+                    'M' => (),
+                    // an insertion just before the first nucleotide kept also
+                    // shifts the start of the J gene
+                    'I' if idx_gene <= del_j => {
+                        nb_errors = MAX_NB_ERRORS;
+                        break;
+                    }
+                    'D' if idx_gene < del_j => {
+                        nb_errors = MAX_NB_ERRORS;
+                        break;
+                    }
+                    _ => nb_errors += 1,
+                }
+            }
+            self.errors[del_j] = nb_errors;
+        }
+        self.errors_extended = Some(vec![[0; 16]; self.errors.len()]);
+
+        self.mismatches = head
+            .iter()
+            .chain(operations.iter().filter(|&&(op, idx_gene, idx_seq)| {
+                op == 'M' && self.is_mismatch(dna, idx_gene, idx_seq)
+            }))
+            .map(|&(_, idx_gene, idx_seq)| (idx_gene, dna.seq[idx_seq]))
+            .collect();
+    }
+}
+
 #[cfg_attr(all(feature = "py_binds", feature = "pyo3"), pyclass)]
 #[derive(Clone, Debug)]
 pub struct DAlignment {
@@ -255,6 +517,24 @@ impl DAlignment {
         self.len() - deld5 - deld3
     }
 
+    /// (position in the D gene, sequence nucleotide) for every disagreement
+    /// in the region left after deletion. Empty for amino-acid sequences.
+    pub fn mismatches(&self, deld5: usize, deld3: usize) -> Vec<(usize, u8)> {
+        let Some(dna) = self.sequence.as_dna() else {
+            return vec![];
+        };
+        (deld5..self.len().saturating_sub(deld3))
+            .filter_map(|idx_d| {
+                let idx_seq = self.pos + idx_d as i64;
+                if idx_seq < 0 || idx_seq as usize >= dna.len() {
+                    return None;
+                }
+                let nt = dna.seq[idx_seq as usize];
+                (!compatible_nucleotides(nt, self.dseq.seq[idx_d])).then_some((idx_d, nt))
+            })
+            .collect()
+    }
+
     pub fn valid_extremities(&self, deld5: usize, deld3: usize) -> Vec<(usize, usize)> {
         debug_assert!(deld5 + deld3 <= self.len());
         let cut_d = self
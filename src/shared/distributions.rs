@@ -122,15 +122,31 @@ impl Default for ErrorDistribution {
 pub struct MarkovDNA {
     // initial_distribution: DiscreteDistribution, // first nucleotide, ACGT order
     transition_matrix: Vec<DiscreteDistribution>, // Markov matrix, ACGT order
+    // transitions of order 2, 3, ..., one distribution per context
+    higher_order: Vec<Vec<DiscreteDistribution>>,
 }
 
 impl MarkovDNA {
     pub fn new(transition_probs: &Array2<f64>) -> Result<Self> {
-        let mut transition_matrix = Vec::with_capacity(transition_probs.dim().0);
-        for probs in transition_probs.axis_iter(Axis(0)) {
-            transition_matrix.push(DiscreteDistribution::new(&probs.to_vec())?);
-        }
-        Ok(MarkovDNA { transition_matrix })
+        Self::new_higher_order(transition_probs, &[])
+    }
+
+    /// `higher_order[k - 2]` contains the (4^k, 4) transition matrix of order k
+    /// (see `DNAMarkovChain`)
+    pub fn new_higher_order(
+        transition_probs: &Array2<f64>,
+        higher_order: &[Array2<f64>],
+    ) -> Result<Self> {
+        let rows = |probs: &Array2<f64>| -> Result<Vec<DiscreteDistribution>> {
+            probs
+                .axis_iter(Axis(0))
+                .map(|row| DiscreteDistribution::new(&row.to_vec()))
+                .collect()
+        };
+        Ok(MarkovDNA {
+            transition_matrix: rows(transition_probs)?,
+            higher_order: higher_order.iter().map(rows).collect::<Result<_>>()?,
+        })
     }
 
     pub fn generate<R: Rng>(&mut self, length: usize, previous_nucleotide: u8, rng: &mut R) -> Dna {
@@ -138,8 +154,27 @@ impl MarkovDNA {
             seq: Vec::with_capacity(length),
         };
         let mut current_state = nucleotides_inv(previous_nucleotide);
+        if self.higher_order.is_empty() {
+            for _ in 0..length {
+                current_state = self.transition_matrix[current_state].generate(rng);
+                dna.seq.push(NUCLEOTIDES[current_state]);
+            }
+            return dna;
+        }
+
+        // the context is made of the (at most) k last nucleotides
+        let mut known = vec![current_state];
         for _ in 0..length {
-            current_state = self.transition_matrix[current_state].generate(rng);
+            let k = known.len().min(self.higher_order.len() + 1);
+            let ctx = known[known.len() - k..]
+                .iter()
+                .fold(0, |acc, &x| 4 * acc + x);
+            current_state = if k == 1 {
+                self.transition_matrix[ctx].generate(rng)
+            } else {
+                self.higher_order[k - 2][ctx].generate(rng)
+            };
+            known.push(current_state);
             dna.seq.push(NUCLEOTIDES[current_state]);
         }
         dna
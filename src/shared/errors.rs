@@ -1,23 +1,34 @@
 use crate::shared::alignment::ErrorAlignment;
-use crate::shared::alignment::{ErrorDAlignment, ErrorJAlignment, ErrorVAlignment};
+use crate::shared::alignment::{ErrorDAlignment, ErrorJAlignment, ErrorVAlignment, VJAlignment};
 /// Contains all the error models defined and their features (for inference)
 use crate::shared::distributions::{HistogramDistribution, UniformError};
 use crate::shared::feature::Feature;
-use crate::shared::sequence::Dna;
-use crate::shared::StaticEvent;
+use crate::shared::sequence::{degenerate_dna_to_vec, nucleotides_inv, Dna, NUCLEOTIDES};
+use crate::shared::{SequenceType, StaticEvent};
 use anyhow::{anyhow, Result};
 
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
 use pyo3::prelude::*;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
 
 pub const MAX_NB_ERRORS: usize = 10042;
 
+/// Number of distinct 5-mer contexts, with `N` standing for
+/// the positions that fall outside of the gene.
+pub const NB_CONTEXTS: usize = 3125;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ErrorParameters {
     ConstantRate(ErrorConstantRate),
     UniformRate(ErrorUniformRate),
+    ContextRate(ErrorContextRate),
+    SubstitutionMatrix(ErrorSubstitutionMatrix),
+    IndelRate(ErrorIndelRate),
+    SegmentRate(ErrorSegmentRate),
 }
 
 impl From<ErrorConstantRate> for ErrorParameters {
@@ -32,6 +43,30 @@ impl From<ErrorUniformRate> for ErrorParameters {
     }
 }
 
+impl From<ErrorContextRate> for ErrorParameters {
+    fn from(err: ErrorContextRate) -> Self {
+        ErrorParameters::ContextRate(err)
+    }
+}
+
+impl From<ErrorSubstitutionMatrix> for ErrorParameters {
+    fn from(err: ErrorSubstitutionMatrix) -> Self {
+        ErrorParameters::SubstitutionMatrix(err)
+    }
+}
+
+impl From<ErrorIndelRate> for ErrorParameters {
+    fn from(err: ErrorIndelRate) -> Self {
+        ErrorParameters::IndelRate(err)
+    }
+}
+
+impl From<ErrorSegmentRate> for ErrorParameters {
+    fn from(err: ErrorSegmentRate) -> Self {
+        ErrorParameters::SegmentRate(err)
+    }
+}
+
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
 #[pyclass(name = "ErrorParameters")]
 #[derive(Clone, Debug, Default)]
@@ -64,6 +99,37 @@ impl PyErrorParameters {
                         .sum::<f64>()
                 )
             }
+            ErrorParameters::ContextRate(x) => {
+                format!(
+                    "Context Error model [the mutation probability depends on the 5-mer \
+                        centered on the nucleotide].\
+                        \nAverage error rate = {}",
+                    x.mean_error_rate()
+                )
+            }
+            ErrorParameters::SubstitutionMatrix(x) => {
+                format!(
+                    "Substitution matrix Error model [P(read nucleotide | germline nucleotide), \
+                        rows and columns ordered as A, C, G, T].\
+                        \nMatrix = {:?}",
+                    x.matrix
+                )
+            }
+            ErrorParameters::IndelRate(x) => {
+                format!(
+                    "Indel Error model [constant substitution rate, single-nucleotide \
+                        insertions/deletions in the V and J genes].\
+                        \nError rate = {}, Indel rate = {}",
+                    x.error_rate, x.indel_rate
+                )
+            }
+            ErrorParameters::SegmentRate(x) => {
+                format!(
+                    "Segment Error model [independent error rates for the V, D and J genes].\
+                        \nV error rate = {}, D error rate = {}, J error rate = {}",
+                    x.v_error_rate, x.d_error_rate, x.j_error_rate
+                )
+            }
         }
     }
 
@@ -83,23 +149,112 @@ impl PyErrorParameters {
         })
     }
 
+    #[staticmethod]
+    #[pyo3(signature = (error_rate = 0.1))]
+    /// Context-dependent error model, initialized with the same rate for all contexts
+    fn context_error(error_rate: f64) -> PyResult<PyErrorParameters> {
+        Ok(PyErrorParameters {
+            s: ErrorParameters::ContextRate(ErrorContextRate::from_error_rate(error_rate)?),
+        })
+    }
+
+    #[staticmethod]
+    /// Error model with a 4x4 matrix P(read nucleotide | germline nucleotide)
+    /// (rows and columns ordered as A, C, G, T)
+    fn substitution_error(matrix: [[f64; 4]; 4]) -> PyResult<PyErrorParameters> {
+        Ok(PyErrorParameters {
+            s: ErrorParameters::SubstitutionMatrix(ErrorSubstitutionMatrix::new(matrix)?),
+        })
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (error_rate, indel_rate = 0.001))]
+    /// Constant error rate, with insertions/deletions (indel_rate per nucleotide)
+    /// in the V and J genes
+    fn indel_error(error_rate: f64, indel_rate: f64) -> PyResult<PyErrorParameters> {
+        Ok(PyErrorParameters {
+            s: ErrorParameters::IndelRate(ErrorIndelRate::new(error_rate, indel_rate)?),
+        })
+    }
+
+    #[staticmethod]
+    /// Independent error rates for the V, D and J genes
+    fn segment_error(
+        v_error_rate: f64,
+        d_error_rate: f64,
+        j_error_rate: f64,
+    ) -> PyResult<PyErrorParameters> {
+        Ok(PyErrorParameters {
+            s: ErrorParameters::SegmentRate(ErrorSegmentRate::new(
+                v_error_rate,
+                d_error_rate,
+                j_error_rate,
+            )?),
+        })
+    }
+
+    #[getter]
+    /// (V, D, J) error rates
+    fn get_segment_error_rates(&self) -> PyResult<(f64, f64, f64)> {
+        Ok(self.s.segment_error_rates()?)
+    }
+
+    #[getter]
+    fn get_indel_rate(&self) -> PyResult<f64> {
+        match &self.s {
+            ErrorParameters::IndelRate(x) => Ok(x.indel_rate),
+            _ => Ok(0.),
+        }
+    }
+
+    #[getter]
+    fn get_substitution_matrix(&self) -> PyResult<[[f64; 4]; 4]> {
+        match &self.s {
+            ErrorParameters::SubstitutionMatrix(x) => Ok(x.matrix),
+            _ => Err(anyhow!("No substitution matrix in this Error model."))?,
+        }
+    }
+
+    /// Mutation probability of the central nucleotide of a 5-mer (ex: "AGCTA")
+    fn mutability(&self, context: &str) -> PyResult<f64> {
+        match &self.s {
+            ErrorParameters::ContextRate(x) => Ok(x.get_mutability(context)?),
+            _ => Err(anyhow!("No context mutability in this Error model."))?,
+        }
+    }
+
     #[getter]
     fn get_error_rate(&self) -> PyResult<f64> {
         match &self.s {
             ErrorParameters::ConstantRate(x) => Ok(x.error_rate),
+            ErrorParameters::IndelRate(x) => Ok(x.error_rate),
             ErrorParameters::UniformRate(_) => {
                 Err(anyhow!("No generic error rate in an uniform Error model."))?
             }
+            ErrorParameters::ContextRate(_) => {
+                Err(anyhow!("No generic error rate in a context Error model."))?
+            }
+            ErrorParameters::SubstitutionMatrix(_) => Err(anyhow!(
+                "No generic error rate in a substitution matrix Error model."
+            ))?,
+            ErrorParameters::SegmentRate(_) => Err(anyhow!(
+                "No generic error rate in a segment Error model, use `segment_error_rates`."
+            ))?,
         }
     }
 
     #[getter]
     fn get_probability_distribution(&self) -> PyResult<(Vec<f64>, Vec<f64>)> {
         match &self.s {
-            ErrorParameters::ConstantRate(_) => Err(anyhow!(
+            ErrorParameters::ConstantRate(_) | ErrorParameters::IndelRate(_) => Err(anyhow!(
                 "No (stored) number error distribution in a constant error-rate Error model."
             ))?,
             ErrorParameters::UniformRate(x) => Ok((x.bins.clone(), x.probas.clone())),
+            ErrorParameters::ContextRate(_)
+            | ErrorParameters::SubstitutionMatrix(_)
+            | ErrorParameters::SegmentRate(_) => Err(anyhow!(
+                "No (stored) number error distribution in this Error model."
+            ))?,
         }
     }
 }
@@ -111,24 +266,55 @@ impl Default for ErrorParameters {
 }
 
 impl ErrorParameters {
-    /// Apply the error to the generated sequence
+    /// Apply the error to the generated sequence, `segments` contains the
+    /// V and J regions of the sequence (the rest is D gene / insertions),
+    /// `contexts` the germline context of each nucleotide (`None` for the
+    /// inserted ones, see `StaticEvent::germline_contexts`)
     pub fn apply_to_sequence<R: Rng>(
         &mut self,
         full_seq: &Dna,
+        segments: &[Range<usize>; 2],
+        contexts: &[Option<usize>],
         event: &mut StaticEvent,
         rng: &mut R,
     ) {
         match self {
             ErrorParameters::ConstantRate(err) => err.apply_to_sequence(full_seq, event, rng),
             ErrorParameters::UniformRate(err) => err.apply_to_sequence(full_seq, event, rng),
+            ErrorParameters::ContextRate(err) => {
+                err.apply_to_sequence(full_seq, contexts, event, rng)
+            }
+            ErrorParameters::SubstitutionMatrix(err) => err.apply_to_sequence(full_seq, event, rng),
+            ErrorParameters::IndelRate(err) => err.apply_to_sequence(full_seq, event, rng),
+            ErrorParameters::SegmentRate(err) => {
+                err.apply_to_sequence(full_seq, segments, event, rng)
+            }
         };
     }
 
+    /// Add insertions/deletions to the generated sequence, only in the
+    /// `allowed` regions (error-free sequence coordinates).
+    /// Does nothing for the substitution-only models.
+    pub fn apply_indels<R: Rng>(
+        &self,
+        allowed: &[Range<usize>],
+        event: &mut StaticEvent,
+        rng: &mut R,
+    ) {
+        if let ErrorParameters::IndelRate(err) = self {
+            err.apply_indels(allowed, event, rng)
+        }
+    }
+
     /// write the error in igor format
     pub fn write(&self) -> String {
         match self {
             ErrorParameters::ConstantRate(err) => err.write(),
             ErrorParameters::UniformRate(err) => err.write(),
+            ErrorParameters::ContextRate(err) => err.write(),
+            ErrorParameters::SubstitutionMatrix(err) => err.write(),
+            ErrorParameters::IndelRate(err) => err.write(),
+            ErrorParameters::SegmentRate(err) => err.write(),
         }
     }
 
@@ -136,6 +322,25 @@ impl ErrorParameters {
         match self {
             ErrorParameters::ConstantRate(err) => err.no_error(),
             ErrorParameters::UniformRate(_) => false,
+            ErrorParameters::ContextRate(err) => err.no_error(),
+            ErrorParameters::SubstitutionMatrix(err) => err.no_error(),
+            ErrorParameters::IndelRate(err) => err.no_error(),
+            ErrorParameters::SegmentRate(err) => err.no_error(),
+        }
+    }
+
+    /// (V, D, J) error rates, the same for the three genes
+    /// if the error model is not segment-specific
+    pub fn segment_error_rates(&self) -> Result<(f64, f64, f64)> {
+        match self {
+            ErrorParameters::ConstantRate(err) => {
+                Ok((err.error_rate, err.error_rate, err.error_rate))
+            }
+            ErrorParameters::IndelRate(err) => Ok((err.error_rate, err.error_rate, err.error_rate)),
+            ErrorParameters::SegmentRate(err) => {
+                Ok((err.v_error_rate, err.d_error_rate, err.j_error_rate))
+            }
+            _ => Err(anyhow!("No per-gene error rates in this Error model.")),
         }
     }
 
@@ -153,20 +358,44 @@ impl ErrorParameters {
             ErrorParameters::UniformRate(x) => {
                 ErrorParameters::UniformRate(ErrorUniformRate::uniform(x)?)
             }
+            ErrorParameters::ContextRate(x) => {
+                ErrorParameters::ContextRate(ErrorContextRate::uniform(x)?)
+            }
+            ErrorParameters::SubstitutionMatrix(x) => {
+                ErrorParameters::SubstitutionMatrix(ErrorSubstitutionMatrix::uniform(x)?)
+            }
+            ErrorParameters::IndelRate(x) => {
+                ErrorParameters::IndelRate(ErrorIndelRate::uniform(x)?)
+            }
+            ErrorParameters::SegmentRate(x) => {
+                ErrorParameters::SegmentRate(ErrorSegmentRate::uniform(x)?)
+            }
         })
     }
 
     /// Check if two error parameters are close enough
     pub fn similar(e1: ErrorParameters, e2: ErrorParameters) -> bool {
-        match e1 {
-            ErrorParameters::ConstantRate(ee1) => match e2 {
-                ErrorParameters::ConstantRate(ee2) => ErrorConstantRate::similar(&ee1, &ee2),
-                ErrorParameters::UniformRate(_) => false,
-            },
-            ErrorParameters::UniformRate(ee1) => match e2 {
-                ErrorParameters::ConstantRate(_) => false,
-                ErrorParameters::UniformRate(ee2) => ErrorUniformRate::similar(&ee1, &ee2),
-            },
+        match (e1, e2) {
+            (ErrorParameters::ConstantRate(ee1), ErrorParameters::ConstantRate(ee2)) => {
+                ErrorConstantRate::similar(&ee1, &ee2)
+            }
+            (ErrorParameters::UniformRate(ee1), ErrorParameters::UniformRate(ee2)) => {
+                ErrorUniformRate::similar(&ee1, &ee2)
+            }
+            (ErrorParameters::ContextRate(ee1), ErrorParameters::ContextRate(ee2)) => {
+                ErrorContextRate::similar(&ee1, &ee2)
+            }
+            (
+                ErrorParameters::SubstitutionMatrix(ee1),
+                ErrorParameters::SubstitutionMatrix(ee2),
+            ) => ErrorSubstitutionMatrix::similar(&ee1, &ee2),
+            (ErrorParameters::IndelRate(ee1), ErrorParameters::IndelRate(ee2)) => {
+                ErrorIndelRate::similar(&ee1, &ee2)
+            }
+            (ErrorParameters::SegmentRate(ee1), ErrorParameters::SegmentRate(ee2)) => {
+                ErrorSegmentRate::similar(&ee1, &ee2)
+            }
+            _ => false,
         }
     }
 
@@ -177,12 +406,20 @@ impl ErrorParameters {
                 Ok(FeatureError::ConstantRate(err.get_feature()?))
             }
             ErrorParameters::UniformRate(err) => Ok(FeatureError::UniformRate(err.get_feature()?)),
+            ErrorParameters::ContextRate(err) => Ok(FeatureError::ContextRate(err.get_feature()?)),
+            // the substitution matrix is a context model that only looks at the germline nucleotide
+            ErrorParameters::SubstitutionMatrix(err) => {
+                Ok(FeatureError::ContextRate(err.get_feature()?))
+            }
+            ErrorParameters::IndelRate(err) => Ok(FeatureError::IndelRate(err.get_feature()?)),
+            ErrorParameters::SegmentRate(err) => Ok(FeatureError::SegmentRate(err.get_feature()?)),
         }
     }
 
-    /// Update the model from a list of errors
+    /// Update the model from a list of errors, each weighted by `weights`
     pub fn update_error(
         features: Vec<FeatureError>,
+        weights: &[f64],
         model: &mut ErrorParameters,
     ) -> Result<Vec<FeatureError>> {
         Ok(match model {
@@ -191,6 +428,7 @@ impl ErrorParameters {
                     .into_iter()
                     .filter_map(|el| el.try_into().ok())
                     .collect(),
+                weights,
                 m,
             )?
             .into_iter()
@@ -201,11 +439,56 @@ impl ErrorParameters {
                     .into_iter()
                     .filter_map(|el| el.try_into().ok())
                     .collect(),
+                weights,
                 m,
             )?
             .into_iter()
             .map(FeatureError::UniformRate)
             .collect(),
+            ErrorParameters::ContextRate(m) => ErrorContextRate::update_error(
+                features
+                    .into_iter()
+                    .filter_map(|el| el.try_into().ok())
+                    .collect(),
+                weights,
+                m,
+            )?
+            .into_iter()
+            .map(FeatureError::ContextRate)
+            .collect(),
+            ErrorParameters::SubstitutionMatrix(m) => ErrorSubstitutionMatrix::update_error(
+                features
+                    .into_iter()
+                    .filter_map(|el| el.try_into().ok())
+                    .collect(),
+                weights,
+                m,
+            )?
+            .into_iter()
+            .map(FeatureError::ContextRate)
+            .collect(),
+            ErrorParameters::IndelRate(m) => ErrorIndelRate::update_error(
+                features
+                    .into_iter()
+                    .filter_map(|el| el.try_into().ok())
+                    .collect(),
+                weights,
+                m,
+            )?
+            .into_iter()
+            .map(FeatureError::IndelRate)
+            .collect(),
+            ErrorParameters::SegmentRate(m) => ErrorSegmentRate::update_error(
+                features
+                    .into_iter()
+                    .filter_map(|el| el.try_into().ok())
+                    .collect(),
+                weights,
+                m,
+            )?
+            .into_iter()
+            .map(FeatureError::SegmentRate)
+            .collect(),
         })
     }
 }
@@ -274,16 +557,18 @@ impl ErrorConstantRate {
 
     pub fn update_error(
         features: Vec<FeatureErrorConstant>,
+        weights: &[f64],
         error: &mut ErrorConstantRate,
     ) -> Result<Vec<FeatureErrorConstant>> {
         let mut len = 1;
-        let mut iter = features.iter().clone();
-        let first_feat = iter.next().ok_or(anyhow!("Cannot average empty vector"))?;
-        let mut sum_err = first_feat.total_errors_dirty;
-        let mut sum_length = first_feat.total_lengths_dirty;
-        for feat in iter {
-            sum_err += feat.total_errors_dirty;
-            sum_length += feat.total_lengths_dirty;
+        let mut iter = features.iter().zip(weights.iter());
+        let (first_feat, first_weight) =
+            iter.next().ok_or(anyhow!("Cannot average empty vector"))?;
+        let mut sum_err = first_weight * first_feat.total_errors_dirty;
+        let mut sum_length = first_weight * first_feat.total_lengths_dirty;
+        for (feat, weight) in iter {
+            sum_err += weight * feat.total_errors_dirty;
+            sum_length += weight * feat.total_lengths_dirty;
             len += 1;
         }
         let error_rate = if sum_length != 0. {
@@ -435,10 +720,11 @@ impl ErrorUniformRate {
 
     fn update_error(
         features: Vec<FeatureErrorUniform>,
+        weights: &[f64],
         error: &mut ErrorUniformRate,
     ) -> Result<Vec<FeatureErrorUniform>> {
-        let mut counts = vec![0usize; error.bins.len() - 1];
-        for feat in &features {
+        let mut counts = vec![0f64; error.bins.len() - 1];
+        for (feat, weight) in features.iter().zip(weights.iter()) {
             let error_rate = if feat.error_dirty > 0. {
                 feat.error_dirty / feat.total_likelihood_dirty
             } else {
@@ -449,14 +735,14 @@ impl ErrorUniformRate {
                 .bins
                 .binary_search_by(|&bin| bin.partial_cmp(&error_rate).unwrap());
             match idx {
-                Ok(i) => counts[i] += 1,
-                Err(i) => counts[i - 1] += 1,
+                Ok(i) => counts[i] += weight,
+                Err(i) => counts[i - 1] += weight,
             }
         }
 
         // Convert counts to probabilities
-        let total = features.len() as f64;
-        let probas: Vec<f64> = counts.iter().map(|&count| count as f64 / total).collect();
+        let total: f64 = weights.iter().sum();
+        let probas: Vec<f64> = counts.iter().map(|&count| count / total).collect();
 
         error.probas = probas;
         error.init_generation()?;
@@ -466,112 +752,940 @@ impl ErrorUniformRate {
     }
 }
 
-#[derive(Clone, Debug)]
-pub enum FeatureError {
-    ConstantRate(FeatureErrorConstant),
-    UniformRate(FeatureErrorUniform),
+/// Index of the 5-mer centered on `idx`, positions outside of `seq` count as `N`
+pub fn context_index(seq: &[u8], idx: usize) -> usize {
+    let mut ctx = 0;
+    for pos in (idx as i64 - 2)..=(idx as i64 + 2) {
+        let code = if pos < 0 || pos as usize >= seq.len() {
+            4
+        } else {
+            nucleotides_inv(seq[pos as usize]).min(4)
+        };
+        ctx = 5 * ctx + code;
+    }
+    ctx
 }
 
-impl Default for FeatureError {
-    fn default() -> FeatureError {
-        FeatureError::ConstantRate(FeatureErrorConstant::default())
-    }
+/// Inverse of `context_index`, return the 5-mer as a string
+pub fn context_to_string(ctx: usize) -> String {
+    (0..5)
+        .rev()
+        .map(|k| NUCLEOTIDES[(ctx / 5_usize.pow(k)) % 5] as char)
+        .collect()
 }
 
-impl TryFrom<FeatureError> for FeatureErrorConstant {
-    type Error = anyhow::Error;
-    fn try_from(value: FeatureError) -> Result<Self> {
-        if let FeatureError::ConstantRate(v) = value {
-            Ok(v)
-        } else {
-            Err(anyhow!("Wrong feature type"))
-        }
+/// Parse a 5-mer (ex: "AGCTN") into its context index
+pub fn context_from_str(context: &str) -> Result<usize> {
+    let seq = context.as_bytes();
+    if seq.len() != 5 || !seq.iter().all(|x| b"ACGTN".contains(x)) {
+        return Err(anyhow!(
+            "Invalid context {}, expected a 5-mer of ACGTN",
+            context
+        ));
     }
+    Ok(context_index(seq, 2))
 }
 
-impl TryFrom<FeatureError> for FeatureErrorUniform {
-    type Error = anyhow::Error;
-    fn try_from(value: FeatureError) -> Result<Self> {
-        if let FeatureError::UniformRate(v) = value {
-            Ok(v)
-        } else {
-            Err(anyhow!("Wrong feature type"))
-        }
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Context-dependent hypermutation model (S5F-like). The probability that
+/// a nucleotide is mutated, and the nucleotide it is mutated into, depend
+/// on the 5-mer of the germline sequence centered on it.
+pub struct ErrorContextRate {
+    /// Mutation probability of the central nucleotide, for each context
+    pub mutability: Vec<f64>,
+    /// Probability of the target nucleotide (A, C, G, T) given that a
+    /// mutation happened, for each context
+    pub substitution: Vec<[f64; 4]>,
+}
+
+impl Default for ErrorContextRate {
+    fn default() -> ErrorContextRate {
+        ErrorContextRate::from_error_rate(0.).unwrap()
     }
 }
 
-impl FeatureError {
-    pub fn scale_dirty(&mut self, factor: f64) {
-        match self {
-            FeatureError::ConstantRate(f) => f.scale_dirty(factor),
-            FeatureError::UniformRate(f) => f.scale_dirty(factor),
+impl ErrorContextRate {
+    pub fn new(mutability: Vec<f64>, substitution: Vec<[f64; 4]>) -> Result<ErrorContextRate> {
+        if mutability.len() != NB_CONTEXTS || substitution.len() != NB_CONTEXTS {
+            return Err(anyhow!(
+                "Error in ErrorContextRate creation. Expected {} contexts.",
+                NB_CONTEXTS
+            ));
         }
+        if mutability.iter().any(|x| !(0. ..=1.).contains(x))
+            || substitution
+                .iter()
+                .flatten()
+                .any(|x| !(0. ..=1.).contains(x))
+        {
+            return Err(anyhow!(
+                "Error in ErrorContextRate creation. Negative/NaN/larger than one probability."
+            ));
+        }
+        Ok(ErrorContextRate {
+            mutability,
+            substitution,
+        })
     }
 
-    pub fn remove_error(&mut self) -> Result<()> {
-        *self = FeatureError::ConstantRate(FeatureErrorConstant::new(0.)?);
-        Ok(())
+    /// Context-independent model, equivalent to a constant error rate
+    pub fn from_error_rate(error_rate: f64) -> Result<ErrorContextRate> {
+        let substitution = (0..NB_CONTEXTS)
+            .map(|ctx| {
+                let center = (ctx / 25) % 5;
+                let mut row = [1. / 3.; 4];
+                if center < 4 {
+                    row[center] = 0.;
+                } else {
+                    row = [1. / 4.; 4];
+                }
+                row
+            })
+            .collect();
+        ErrorContextRate::new(vec![error_rate; NB_CONTEXTS], substitution)
     }
 
-    pub fn likelihood(&self, observation: ErrorAlignment) -> f64 {
-        match self {
-            FeatureError::ConstantRate(f) => f.likelihood(observation),
-            FeatureError::UniformRate(f) => f.likelihood(observation),
-        }
+    pub fn get_mutability(&self, context: &str) -> Result<f64> {
+        Ok(self.mutability[context_from_str(context)?])
     }
 
-    pub fn dirty_update_v_fragment(&mut self, observation: &ErrorVAlignment, likelihood: f64) {
-        match self {
-            FeatureError::ConstantRate(f) => f.dirty_update_v_fragment(observation, likelihood),
-            FeatureError::UniformRate(f) => f.dirty_update_v_fragment(observation, likelihood),
+    /// Average mutability over the contexts that don't contain `N`
+    pub fn mean_error_rate(&self) -> f64 {
+        let (sum, count) = (0..NB_CONTEXTS)
+            .filter(|&ctx| !context_to_string(ctx).contains('N'))
+            .fold((0., 0.), |(s, c), ctx| (s + self.mutability[ctx], c + 1.));
+        sum / count
+    }
+
+    /// Mutate the nucleotides of the genes according to their germline
+    /// context (same definition as during the inference). The inserted
+    /// nucleotides have no germline context, they are mutated with the
+    /// average rate, uniformly towards the three other nucleotides.
+    fn apply_to_sequence<R: Rng>(
+        &self,
+        full_seq: &Dna,
+        contexts: &[Option<usize>],
+        event: &mut StaticEvent,
+        rng: &mut R,
+    ) {
+        let mut errors = Vec::new();
+        let mut mean_rate = None;
+        for ((idx, nucleotide), ctx) in full_seq.seq.iter().enumerate().zip(contexts) {
+            let target = match ctx {
+                Some(ctx) => {
+                    if rng.gen::<f64>() >= self.mutability[*ctx] {
+                        continue;
+                    }
+                    let u = rng.gen::<f64>();
+                    let mut cumulative = 0.;
+                    let mut target = 3;
+                    for (b, p) in self.substitution[*ctx].iter().enumerate() {
+                        cumulative += p;
+                        if u < cumulative {
+                            target = b;
+                            break;
+                        }
+                    }
+                    NUCLEOTIDES[target]
+                }
+                None => {
+                    let rate = *mean_rate.get_or_insert_with(|| self.mean_error_rate());
+                    if rng.gen::<f64>() >= rate {
+                        continue;
+                    }
+                    let others: Vec<u8> = NUCLEOTIDES[..4]
+                        .iter()
+                        .copied()
+                        .filter(|x| x != nucleotide)
+                        .collect();
+                    others[rng.gen_range(0..others.len())]
+                }
+            };
+            if target != *nucleotide {
+                errors.push((idx, target));
+            }
         }
+        event.set_errors(errors);
     }
 
-    pub fn dirty_update_j_fragment(&mut self, observation: &ErrorJAlignment, likelihood: f64) {
-        match self {
-            FeatureError::ConstantRate(f) => f.dirty_update_j_fragment(observation, likelihood),
-            FeatureError::UniformRate(f) => f.dirty_update_j_fragment(observation, likelihood),
+    fn write(&self) -> String {
+        format!(
+            "@ErrorRate\n\
+             #ContextErrorRate\n\
+             {}\n",
+            (0..NB_CONTEXTS)
+                .map(|ctx| format!(
+                    "%{};{};{}",
+                    context_to_string(ctx),
+                    self.mutability[ctx],
+                    self.substitution[ctx].map(|x| x.to_string()).join(";")
+                ))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+    }
+
+    pub fn load(str_vec: &[String]) -> Result<ErrorContextRate> {
+        if !str_vec[1].starts_with("#ContextErrorRate") {
+            return Err(anyhow!("Wrong error type"));
+        }
+        let mut error = ErrorContextRate::default();
+        for s in str_vec.iter().skip(2) {
+            let parts: Vec<&str> = s[1..].split(';').collect();
+            if parts.len() != 6 {
+                return Err(anyhow!("Invalid format (context error rate): {}", s));
+            }
+            let ctx = context_from_str(parts[0])?;
+            let values = parts[1..]
+                .iter()
+                .map(|x| {
+                    x.parse::<f64>()
+                        .map_err(|_| anyhow!(format!("Failed to parse '{}'", x)))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            error.mutability[ctx] = values[0];
+            error.substitution[ctx] = [values[1], values[2], values[3], values[4]];
         }
+        ErrorContextRate::new(error.mutability, error.substitution)
     }
 
-    pub fn dirty_update_d_fragment(&mut self, observation: &ErrorDAlignment, likelihood: f64) {
-        match self {
-            FeatureError::ConstantRate(f) => f.dirty_update_d_fragment(observation, likelihood),
-            FeatureError::UniformRate(f) => f.dirty_update_d_fragment(observation, likelihood),
+    fn no_error(&self) -> bool {
+        self.mutability.iter().all(|&x| x == 0.)
+    }
+
+    fn uniform(&self) -> Result<ErrorContextRate> {
+        ErrorContextRate::from_error_rate(0.1)
+    }
+
+    fn similar(e1: &Self, e2: &Self) -> bool {
+        e1.mutability
+            .iter()
+            .zip(e2.mutability.iter())
+            .all(|(x, y)| (x - y).abs() < 1e-4)
+            && e1
+                .substitution
+                .iter()
+                .flatten()
+                .zip(e2.substitution.iter().flatten())
+                .all(|(x, y)| (x - y).abs() < 1e-4)
+    }
+
+    pub fn get_feature(&self) -> Result<FeatureErrorContext> {
+        FeatureErrorContext::new(self)
+    }
+
+    fn update_error(
+        features: Vec<FeatureErrorContext>,
+        weights: &[f64],
+        error: &mut ErrorContextRate,
+    ) -> Result<Vec<FeatureErrorContext>> {
+        let counts = FeatureErrorContext::aggregate_counts(&features, weights);
+
+        // contexts that were never observed keep their previous values
+        let mut mutability = error.mutability.clone();
+        let mut substitution = error.substitution.clone();
+        for (ctx, c) in counts.iter().enumerate() {
+            let mutated: f64 = c[1..].iter().sum();
+            if c[0] > 0. {
+                mutability[ctx] = (mutated / c[0]).min(1.);
+            }
+            if mutated > 0. {
+                substitution[ctx] = [c[1], c[2], c[3], c[4]].map(|x| x / mutated);
+            }
         }
+
+        *error = ErrorContextRate::new(mutability, substitution)?;
+        let context_feat = error.get_feature()?;
+        Ok(vec![context_feat; features.len()])
     }
 }
 
-// Most basic error feature
-#[derive(Default, Clone, Debug)]
-#[cfg_attr(all(feature = "py_binds", feature = "pyo3"), pyclass(get_all, set_all))]
-pub struct FeatureErrorConstant {
-    pub error_rate: f64,
-    logrs3: f64,
-    log1mr: f64,
-    // total_lengths: f64, // For each sequence, this saves Σ P(E) L(S(E))
-    // total_errors: f64,  // For each sequence, this saves Σ P(E) N_{err}(S(E))
-    // useful for dirty updating
-    total_lengths_dirty: f64,
-    total_errors_dirty: f64,
-    total_probas_dirty: f64, // For each sequence, this saves Σ P(E)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Position-independent error model with a full substitution matrix:
+/// `matrix[g][o]` is the probability of reading `o` when the germline
+/// nucleotide is `g` (both ordered as A, C, G, T). Allows for a
+/// transition/transversion bias, the constant error rate `r` corresponds to
+/// `1 - r` on the diagonal and `r/3` elsewhere.
+pub struct ErrorSubstitutionMatrix {
+    pub matrix: [[f64; 4]; 4],
 }
 
-impl FeatureErrorConstant {
-    pub fn new(error_rate: f64) -> Result<FeatureErrorConstant> {
-        if !(0. ..1.).contains(&error_rate) || (error_rate.is_nan()) || (error_rate.is_infinite()) {
-            return Err(anyhow!(
-                "Error in FeatureErrorConstant Feature creation. Negative/NaN/infinite error rate."
-            ));
-        }
-        Ok(FeatureErrorConstant {
-            error_rate,
-            logrs3: (error_rate / 3.).log2(),
-            log1mr: (1. - error_rate).log2(),
-            total_lengths_dirty: 0.,
-            total_errors_dirty: 0.,
-            total_probas_dirty: 0.,
-        })
+impl Default for ErrorSubstitutionMatrix {
+    fn default() -> ErrorSubstitutionMatrix {
+        ErrorSubstitutionMatrix::from_error_rate(0.).unwrap()
+    }
+}
+
+impl ErrorSubstitutionMatrix {
+    pub fn new(matrix: [[f64; 4]; 4]) -> Result<ErrorSubstitutionMatrix> {
+        if matrix.iter().flatten().any(|x| !(0. ..=1.).contains(x))
+            || matrix
+                .iter()
+                .any(|row| (row.iter().sum::<f64>() - 1.).abs() > 1e-6)
+        {
+            return Err(anyhow!(
+                "Error in ErrorSubstitutionMatrix creation. \
+                 Each row should be a probability distribution."
+            ));
+        }
+        Ok(ErrorSubstitutionMatrix { matrix })
+    }
+
+    /// Uniform substitutions, equivalent to a constant error rate
+    pub fn from_error_rate(error_rate: f64) -> Result<ErrorSubstitutionMatrix> {
+        let mut matrix = [[error_rate / 3.; 4]; 4];
+        for (g, row) in matrix.iter_mut().enumerate() {
+            row[g] = 1. - error_rate;
+        }
+        ErrorSubstitutionMatrix::new(matrix)
+    }
+
+    /// Equivalent context model, where the context is only the germline nucleotide
+    fn to_context(&self) -> Result<ErrorContextRate> {
+        let mut error = ErrorContextRate::from_error_rate(0.)?;
+        for ctx in 0..NB_CONTEXTS {
+            let center = (ctx / 25) % 5;
+            if center == 4 {
+                continue;
+            }
+            let row = self.matrix[center];
+            error.mutability[ctx] = 1. - row[center];
+            if row[center] < 1. {
+                error.substitution[ctx] = [0, 1, 2, 3].map(|o| {
+                    if o == center {
+                        0.
+                    } else {
+                        row[o] / (1. - row[center])
+                    }
+                });
+            }
+        }
+        Ok(error)
+    }
+
+    fn apply_to_sequence<R: Rng>(&self, full_seq: &Dna, event: &mut StaticEvent, rng: &mut R) {
+        let mut errors = Vec::new();
+        for (idx, nucleotide) in full_seq.seq.iter().enumerate() {
+            let germline = nucleotides_inv(*nucleotide);
+            if germline > 3 {
+                continue;
+            }
+            let u = rng.gen::<f64>();
+            let mut cumulative = 0.;
+            let mut target = 3;
+            for (b, p) in self.matrix[germline].iter().enumerate() {
+                cumulative += p;
+                if u < cumulative {
+                    target = b;
+                    break;
+                }
+            }
+            if target != germline {
+                errors.push((idx, NUCLEOTIDES[target]));
+            }
+        }
+        event.set_errors(errors);
+    }
+
+    fn write(&self) -> String {
+        format!(
+            "@ErrorRate\n\
+             #SubstitutionErrorRate\n\
+             {}\n",
+            self.matrix
+                .iter()
+                .enumerate()
+                .map(|(g, row)| format!(
+                    "%{};{}",
+                    NUCLEOTIDES[g] as char,
+                    row.map(|x| x.to_string()).join(";")
+                ))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+    }
+
+    pub fn load(str_vec: &[String]) -> Result<ErrorSubstitutionMatrix> {
+        if !str_vec[1].starts_with("#SubstitutionErrorRate") {
+            return Err(anyhow!("Wrong error type"));
+        }
+        if str_vec.len() != 6 {
+            return Err(anyhow!("Invalid format (substitution error rate)"));
+        }
+        let mut matrix = [[0.; 4]; 4];
+        for s in str_vec.iter().skip(2) {
+            let parts: Vec<&str> = s[1..].split(';').collect();
+            let germline = match parts[0] {
+                "A" | "C" | "G" | "T" => nucleotides_inv(parts[0].as_bytes()[0]),
+                _ => return Err(anyhow!("Invalid format (substitution error rate): {}", s)),
+            };
+            if parts.len() != 5 {
+                return Err(anyhow!("Invalid format (substitution error rate): {}", s));
+            }
+            for (o, x) in parts[1..].iter().enumerate() {
+                matrix[germline][o] = x
+                    .parse::<f64>()
+                    .map_err(|_| anyhow!(format!("Failed to parse '{}'", x)))?;
+            }
+        }
+        ErrorSubstitutionMatrix::new(matrix)
+    }
+
+    fn no_error(&self) -> bool {
+        (0..4).all(|g| self.matrix[g][g] == 1.)
+    }
+
+    fn uniform(&self) -> Result<ErrorSubstitutionMatrix> {
+        ErrorSubstitutionMatrix::from_error_rate(0.1)
+    }
+
+    fn similar(e1: &Self, e2: &Self) -> bool {
+        e1.matrix
+            .iter()
+            .flatten()
+            .zip(e2.matrix.iter().flatten())
+            .all(|(x, y)| (x - y).abs() < 1e-4)
+    }
+
+    pub fn get_feature(&self) -> Result<FeatureErrorContext> {
+        FeatureErrorContext::new(&self.to_context()?)
+    }
+
+    fn update_error(
+        features: Vec<FeatureErrorContext>,
+        weights: &[f64],
+        error: &mut ErrorSubstitutionMatrix,
+    ) -> Result<Vec<FeatureErrorContext>> {
+        // pool all the contexts that share the same germline nucleotide
+        let mut counts = [[0f64; 5]; 4];
+        for (ctx, c) in FeatureErrorContext::aggregate_counts(&features, weights)
+            .iter()
+            .enumerate()
+        {
+            let center = (ctx / 25) % 5;
+            if center < 4 {
+                for k in 0..5 {
+                    counts[center][k] += c[k];
+                }
+            }
+        }
+
+        // nucleotides that were never observed keep their previous values
+        let mut matrix = error.matrix;
+        for (g, c) in counts.iter().enumerate() {
+            if c[0] > 0. {
+                let mutated: f64 = c[1..].iter().sum();
+                for o in 0..4 {
+                    matrix[g][o] = if o == g {
+                        1. - mutated / c[0]
+                    } else {
+                        c[o + 1] / c[0]
+                    };
+                }
+            }
+        }
+
+        *error = ErrorSubstitutionMatrix::new(matrix)?;
+        let feat = error.get_feature()?;
+        Ok(vec![feat; features.len()])
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Constant substitution rate, plus single-nucleotide insertions and
+/// deletions in the V and J genes, each happening with probability
+/// `indel_rate / 2` per nucleotide.
+pub struct ErrorIndelRate {
+    pub error_rate: f64,
+    pub indel_rate: f64,
+    #[serde(skip)]
+    gen: UniformError,
+}
+
+impl Default for ErrorIndelRate {
+    fn default() -> ErrorIndelRate {
+        ErrorIndelRate::new(0., 0.).unwrap()
+    }
+}
+
+impl ErrorIndelRate {
+    pub fn new(error_rate: f64, indel_rate: f64) -> Result<ErrorIndelRate> {
+        if !(0. ..1.).contains(&error_rate) || !(0. ..1.).contains(&indel_rate) {
+            return Err(anyhow!(
+                "Error in ErrorIndelRate creation. Rates should be in [0, 1)."
+            ));
+        }
+        Ok(ErrorIndelRate {
+            error_rate,
+            indel_rate,
+            gen: UniformError::new(),
+        })
+    }
+
+    fn apply_to_sequence<R: Rng>(&self, full_seq: &Dna, event: &mut StaticEvent, rng: &mut R) {
+        ErrorConstantRate::new(self.error_rate).apply_to_sequence(full_seq, event, rng);
+    }
+
+    fn apply_indels<R: Rng>(&self, allowed: &[Range<usize>], event: &mut StaticEvent, rng: &mut R) {
+        let mut indels = Vec::new();
+        for range in allowed {
+            for idx in range.clone() {
+                if self.gen.is_error(self.indel_rate, rng) {
+                    if rng.gen::<bool>() {
+                        indels.push((idx, 1, Dna::new()));
+                    } else {
+                        let nt = Dna::from_string(
+                            &(self.gen.random_nucleotide(rng) as char).to_string(),
+                        )
+                        .unwrap();
+                        indels.push((idx, 0, nt));
+                    }
+                }
+            }
+        }
+        event.set_indels(indels);
+    }
+
+    fn write(&self) -> String {
+        format!(
+            "@ErrorRate\n\
+             #IndelErrorRate\n\
+             {}\n\
+             {}\n",
+            self.error_rate, self.indel_rate
+        )
+    }
+
+    pub fn load(str_vec: &[String]) -> Result<ErrorIndelRate> {
+        if !str_vec[1].starts_with("#IndelErrorRate") {
+            return Err(anyhow!("Wrong error type"));
+        }
+        if str_vec.len() != 4 {
+            return Err(anyhow!("Invalid format (indel error rate)"));
+        }
+        let rates = str_vec[2..]
+            .iter()
+            .map(|x| {
+                x.parse::<f64>()
+                    .map_err(|_| anyhow!(format!("Failed to parse '{}'", x)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        ErrorIndelRate::new(rates[0], rates[1])
+    }
+
+    fn no_error(&self) -> bool {
+        self.error_rate == 0. && self.indel_rate == 0.
+    }
+
+    fn uniform(&self) -> Result<ErrorIndelRate> {
+        ErrorIndelRate::new(0.1, 0.01)
+    }
+
+    fn similar(e1: &Self, e2: &Self) -> bool {
+        (e1.error_rate - e2.error_rate).abs() < 1e-4 && (e1.indel_rate - e2.indel_rate).abs() < 1e-4
+    }
+
+    pub fn get_feature(&self) -> Result<FeatureErrorIndel> {
+        FeatureErrorIndel::new(self.error_rate, self.indel_rate)
+    }
+
+    fn update_error(
+        features: Vec<FeatureErrorIndel>,
+        weights: &[f64],
+        error: &mut ErrorIndelRate,
+    ) -> Result<Vec<FeatureErrorIndel>> {
+        let (mut sum_err, mut sum_length) = (0., 0.);
+        let (mut sum_indels, mut sum_vj_length) = (0., 0.);
+        for (feat, weight) in features.iter().zip(weights.iter()) {
+            sum_err += weight * feat.total_errors_dirty;
+            sum_length += weight * feat.total_lengths_dirty;
+            // no indels in D
+            sum_indels += weight * feat.total_indels_dirty;
+            sum_vj_length += weight * feat.total_vj_lengths_dirty;
+        }
+        if sum_length != 0. && sum_vj_length != 0. {
+            *error = ErrorIndelRate::new(sum_err / sum_length, sum_indels / sum_vj_length)?;
+        }
+        let feat = error.get_feature()?;
+        Ok(vec![feat; features.len()])
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Constant error rate within each gene, but independent rates for V, D and J
+/// (hypermutated V genes). The non-templated insertions use the D rate.
+pub struct ErrorSegmentRate {
+    pub v_error_rate: f64,
+    pub d_error_rate: f64,
+    pub j_error_rate: f64,
+    #[serde(skip)]
+    gen: UniformError,
+}
+
+impl Default for ErrorSegmentRate {
+    fn default() -> ErrorSegmentRate {
+        ErrorSegmentRate::new(0., 0., 0.).unwrap()
+    }
+}
+
+impl ErrorSegmentRate {
+    pub fn new(
+        v_error_rate: f64,
+        d_error_rate: f64,
+        j_error_rate: f64,
+    ) -> Result<ErrorSegmentRate> {
+        if [v_error_rate, d_error_rate, j_error_rate]
+            .iter()
+            .any(|r| !(0. ..1.).contains(r))
+        {
+            return Err(anyhow!(
+                "Error in ErrorSegmentRate creation. Rates should be in [0, 1)."
+            ));
+        }
+        Ok(ErrorSegmentRate {
+            v_error_rate,
+            d_error_rate,
+            j_error_rate,
+            gen: UniformError::new(),
+        })
+    }
+
+    fn apply_to_sequence<R: Rng>(
+        &self,
+        full_seq: &Dna,
+        segments: &[Range<usize>; 2],
+        event: &mut StaticEvent,
+        rng: &mut R,
+    ) {
+        let mut errors = Vec::new();
+        for (idx, nucleotide) in full_seq.seq.iter().enumerate() {
+            let error_rate = if segments[0].contains(&idx) {
+                self.v_error_rate
+            } else if segments[1].contains(&idx) {
+                self.j_error_rate
+            } else {
+                self.d_error_rate
+            };
+            if self.gen.is_error(error_rate * 4. / 3., rng) {
+                let a = self.gen.random_nucleotide(rng);
+                if a != *nucleotide {
+                    errors.push((idx, a));
+                }
+            }
+        }
+        event.set_errors(errors);
+    }
+
+    fn write(&self) -> String {
+        format!(
+            "@ErrorRate\n\
+             #SegmentErrorRate\n\
+             %V;{}\n\
+             %D;{}\n\
+             %J;{}\n",
+            self.v_error_rate, self.d_error_rate, self.j_error_rate
+        )
+    }
+
+    pub fn load(str_vec: &[String]) -> Result<ErrorSegmentRate> {
+        if !str_vec[1].starts_with("#SegmentErrorRate") {
+            return Err(anyhow!("Wrong error type"));
+        }
+        let mut rates = HashMap::new();
+        for s in str_vec.iter().skip(2) {
+            let (gene, rate) = s
+                .strip_prefix('%')
+                .and_then(|x| x.split_once(';'))
+                .ok_or(anyhow!("Invalid format (segment error rate): {}", s))?;
+            let rate = rate
+                .parse::<f64>()
+                .map_err(|_| anyhow!(format!("Failed to parse '{}'", rate)))?;
+            rates.insert(gene.to_string(), rate);
+        }
+        let get = |gene: &str| {
+            rates.get(gene).copied().ok_or(anyhow!(
+                "Invalid format (segment error rate): no {} rate",
+                gene
+            ))
+        };
+        ErrorSegmentRate::new(get("V")?, get("D")?, get("J")?)
+    }
+
+    fn no_error(&self) -> bool {
+        self.v_error_rate == 0. && self.d_error_rate == 0. && self.j_error_rate == 0.
+    }
+
+    fn uniform(&self) -> Result<ErrorSegmentRate> {
+        ErrorSegmentRate::new(0.1, 0.1, 0.1)
+    }
+
+    fn similar(e1: &Self, e2: &Self) -> bool {
+        (e1.v_error_rate - e2.v_error_rate).abs() < 1e-4
+            && (e1.d_error_rate - e2.d_error_rate).abs() < 1e-4
+            && (e1.j_error_rate - e2.j_error_rate).abs() < 1e-4
+    }
+
+    pub fn get_feature(&self) -> Result<FeatureErrorSegment> {
+        Ok(FeatureErrorSegment {
+            v: FeatureErrorConstant::new(self.v_error_rate)?,
+            d: FeatureErrorConstant::new(self.d_error_rate)?,
+            j: FeatureErrorConstant::new(self.j_error_rate)?,
+        })
+    }
+
+    fn update_error(
+        features: Vec<FeatureErrorSegment>,
+        weights: &[f64],
+        error: &mut ErrorSegmentRate,
+    ) -> Result<Vec<FeatureErrorSegment>> {
+        // each gene is updated as a constant error model
+        let rate = |previous: f64, get: &dyn Fn(&FeatureErrorSegment) -> &FeatureErrorConstant| {
+            let mut sum_err = 0.;
+            let mut sum_length = 0.;
+            for (feat, weight) in features.iter().zip(weights.iter()) {
+                sum_err += weight * get(feat).total_errors_dirty;
+                sum_length += weight * get(feat).total_lengths_dirty;
+            }
+            // genes that were never observed keep their previous rate
+            if sum_length != 0. {
+                sum_err / sum_length
+            } else {
+                previous
+            }
+        };
+        *error = ErrorSegmentRate::new(
+            rate(error.v_error_rate, &|f| &f.v),
+            rate(error.d_error_rate, &|f| &f.d),
+            rate(error.j_error_rate, &|f| &f.j),
+        )?;
+        let feat = error.get_feature()?;
+        Ok(vec![feat; features.len()])
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum FeatureError {
+    ConstantRate(FeatureErrorConstant),
+    UniformRate(FeatureErrorUniform),
+    ContextRate(FeatureErrorContext),
+    IndelRate(FeatureErrorIndel),
+    SegmentRate(FeatureErrorSegment),
+}
+
+impl Default for FeatureError {
+    fn default() -> FeatureError {
+        FeatureError::ConstantRate(FeatureErrorConstant::default())
+    }
+}
+
+impl TryFrom<FeatureError> for FeatureErrorConstant {
+    type Error = anyhow::Error;
+    fn try_from(value: FeatureError) -> Result<Self> {
+        if let FeatureError::ConstantRate(v) = value {
+            Ok(v)
+        } else {
+            Err(anyhow!("Wrong feature type"))
+        }
+    }
+}
+
+impl TryFrom<FeatureError> for FeatureErrorUniform {
+    type Error = anyhow::Error;
+    fn try_from(value: FeatureError) -> Result<Self> {
+        if let FeatureError::UniformRate(v) = value {
+            Ok(v)
+        } else {
+            Err(anyhow!("Wrong feature type"))
+        }
+    }
+}
+
+impl TryFrom<FeatureError> for FeatureErrorContext {
+    type Error = anyhow::Error;
+    fn try_from(value: FeatureError) -> Result<Self> {
+        if let FeatureError::ContextRate(v) = value {
+            Ok(v)
+        } else {
+            Err(anyhow!("Wrong feature type"))
+        }
+    }
+}
+
+impl TryFrom<FeatureError> for FeatureErrorIndel {
+    type Error = anyhow::Error;
+    fn try_from(value: FeatureError) -> Result<Self> {
+        if let FeatureError::IndelRate(v) = value {
+            Ok(v)
+        } else {
+            Err(anyhow!("Wrong error type"))
+        }
+    }
+}
+
+impl TryFrom<FeatureError> for FeatureErrorSegment {
+    type Error = anyhow::Error;
+    fn try_from(value: FeatureError) -> Result<Self> {
+        if let FeatureError::SegmentRate(v) = value {
+            Ok(v)
+        } else {
+            Err(anyhow!("Wrong error type"))
+        }
+    }
+}
+
+impl FeatureError {
+    pub fn scale_dirty(&mut self, factor: f64) {
+        match self {
+            FeatureError::ConstantRate(f) => f.scale_dirty(factor),
+            FeatureError::UniformRate(f) => f.scale_dirty(factor),
+            FeatureError::ContextRate(f) => f.scale_dirty(factor),
+            FeatureError::IndelRate(f) => f.scale_dirty(factor),
+            FeatureError::SegmentRate(f) => {
+                f.v.scale_dirty(factor);
+                f.d.scale_dirty(factor);
+                f.j.scale_dirty(factor);
+            }
+        }
+    }
+
+    pub fn remove_error(&mut self) -> Result<()> {
+        *self = FeatureError::ConstantRate(FeatureErrorConstant::new(0.)?);
+        Ok(())
+    }
+
+    /// Posterior mean error rate of the sequence the feature was last
+    /// inferred on, i.e. the expected number of errors divided by the
+    /// expected number of germline nucleotides, both averaged over all the
+    /// scenarios. For `UniformRate`, this is the per-sequence rate used to
+    /// fill the error histogram (estimated on the V gene).
+    /// Return `None` if no scenario was recorded (amino-acid sequence,
+    /// deletions not inferred).
+    pub fn posterior_error_rate(&self) -> Option<f64> {
+        let (errors, lengths) = match self {
+            FeatureError::ConstantRate(f) => f.posterior_counts(),
+            FeatureError::UniformRate(f) => (f.error_dirty, f.total_likelihood_dirty),
+            FeatureError::ContextRate(f) => f.posterior_counts(),
+            FeatureError::IndelRate(f) => (f.total_errors_dirty, f.total_lengths_dirty),
+            FeatureError::SegmentRate(f) => [&f.v, &f.d, &f.j]
+                .iter()
+                .map(|x| x.posterior_counts())
+                .fold((0., 0.), |(e, l), (ex, lx)| (e + ex, l + lx)),
+        };
+        if lengths > 0. {
+            Some(errors / lengths)
+        } else {
+            None
+        }
+    }
+
+    /// Likelihood of a number of errors, whatever their position
+    pub fn likelihood(&self, observation: ErrorAlignment) -> f64 {
+        match self {
+            FeatureError::ConstantRate(f) => f.likelihood(observation),
+            FeatureError::UniformRate(f) => f.likelihood(observation),
+            FeatureError::ContextRate(f) => f.likelihood(observation),
+            FeatureError::IndelRate(f) => f.likelihood(observation),
+            // the position of the errors is unknown, use the D (junction) rate
+            FeatureError::SegmentRate(f) => f.d.likelihood(observation),
+        }
+    }
+
+    /// Error likelihood of the V gene once `del` nucleotides are removed
+    pub fn likelihood_v(&self, observation: &ErrorVAlignment) -> f64 {
+        match self {
+            FeatureError::ContextRate(f) => f.likelihood_v(observation),
+            FeatureError::IndelRate(f) => f.likelihood_vj(observation.val, observation.del, 0),
+            FeatureError::SegmentRate(f) => {
+                f.v.likelihood(observation.val.errors(observation.del, 0))
+            }
+            _ => self.likelihood(observation.val.errors(observation.del, 0)),
+        }
+    }
+
+    /// Error likelihood of the J gene once `del` nucleotides are removed
+    pub fn likelihood_j(&self, observation: &ErrorJAlignment) -> f64 {
+        match self {
+            FeatureError::ContextRate(f) => f.likelihood_j(observation),
+            FeatureError::IndelRate(f) => f.likelihood_vj(observation.jal, 0, observation.del),
+            FeatureError::SegmentRate(f) => {
+                f.j.likelihood(observation.jal.errors(0, observation.del))
+            }
+            _ => self.likelihood(observation.jal.errors(0, observation.del)),
+        }
+    }
+
+    /// Error likelihood of the D gene once `deld5`/`deld3` nucleotides are removed
+    pub fn likelihood_d(&self, observation: &ErrorDAlignment) -> f64 {
+        match self {
+            FeatureError::ContextRate(f) => f.likelihood_d(observation),
+            FeatureError::SegmentRate(f) => {
+                f.d.likelihood(observation.dal.errors(observation.deld5, observation.deld3))
+            }
+            _ => self.likelihood(observation.dal.errors(observation.deld5, observation.deld3)),
+        }
+    }
+
+    pub fn dirty_update_v_fragment(&mut self, observation: &ErrorVAlignment, likelihood: f64) {
+        match self {
+            FeatureError::ConstantRate(f) => f.dirty_update_v_fragment(observation, likelihood),
+            FeatureError::UniformRate(f) => f.dirty_update_v_fragment(observation, likelihood),
+            FeatureError::ContextRate(f) => f.dirty_update_v_fragment(observation, likelihood),
+            FeatureError::IndelRate(f) => f.dirty_update_v_fragment(observation, likelihood),
+            FeatureError::SegmentRate(f) => f.v.dirty_update_v_fragment(observation, likelihood),
+        }
+    }
+
+    pub fn dirty_update_j_fragment(&mut self, observation: &ErrorJAlignment, likelihood: f64) {
+        match self {
+            FeatureError::ConstantRate(f) => f.dirty_update_j_fragment(observation, likelihood),
+            FeatureError::UniformRate(f) => f.dirty_update_j_fragment(observation, likelihood),
+            FeatureError::ContextRate(f) => f.dirty_update_j_fragment(observation, likelihood),
+            FeatureError::IndelRate(f) => f.dirty_update_j_fragment(observation, likelihood),
+            FeatureError::SegmentRate(f) => f.j.dirty_update_j_fragment(observation, likelihood),
+        }
+    }
+
+    pub fn dirty_update_d_fragment(&mut self, observation: &ErrorDAlignment, likelihood: f64) {
+        match self {
+            FeatureError::ConstantRate(f) => f.dirty_update_d_fragment(observation, likelihood),
+            FeatureError::UniformRate(f) => f.dirty_update_d_fragment(observation, likelihood),
+            FeatureError::ContextRate(f) => f.dirty_update_d_fragment(observation, likelihood),
+            FeatureError::IndelRate(f) => f.dirty_update_d_fragment(observation, likelihood),
+            FeatureError::SegmentRate(f) => f.d.dirty_update_d_fragment(observation, likelihood),
+        }
+    }
+}
+
+// Most basic error feature
+#[derive(Default, Clone, Debug)]
+#[cfg_attr(all(feature = "py_binds", feature = "pyo3"), pyclass(get_all, set_all))]
+pub struct FeatureErrorConstant {
+    pub error_rate: f64,
+    logrs3: f64,
+    log1mr: f64,
+    // total_lengths: f64, // For each sequence, this saves Σ P(E) L(S(E))
+    // total_errors: f64,  // For each sequence, this saves Σ P(E) N_{err}(S(E))
+    // useful for dirty updating
+    total_lengths_dirty: f64,
+    total_errors_dirty: f64,
+    total_probas_dirty: f64, // For each sequence, this saves Σ P(E)
+}
+
+impl FeatureErrorConstant {
+    pub fn new(error_rate: f64) -> Result<FeatureErrorConstant> {
+        if !(0. ..1.).contains(&error_rate) || (error_rate.is_nan()) || (error_rate.is_infinite()) {
+            return Err(anyhow!(
+                "Error in FeatureErrorConstant Feature creation. Negative/NaN/infinite error rate."
+            ));
+        }
+        Ok(FeatureErrorConstant {
+            error_rate,
+            logrs3: (error_rate / 3.).log2(),
+            log1mr: (1. - error_rate).log2(),
+            total_lengths_dirty: 0.,
+            total_errors_dirty: 0.,
+            total_probas_dirty: 0.,
+        })
     }
 }
 
@@ -646,6 +1760,142 @@ impl FeatureErrorConstant {
     pub fn get_parameters(&self) -> Result<ErrorConstantRate> {
         Ok(ErrorConstantRate::new(self.error_rate))
     }
+
+    /// (Σ P(E) N_{err}(S(E)), Σ P(E) L(S(E))) for the sequence
+    fn posterior_counts(&self) -> (f64, f64) {
+        (self.total_errors_dirty, self.total_lengths_dirty)
+    }
+}
+
+#[derive(Default, Clone, Debug)]
+/// One constant-rate feature per gene
+pub struct FeatureErrorSegment {
+    pub v: FeatureErrorConstant,
+    pub d: FeatureErrorConstant,
+    pub j: FeatureErrorConstant,
+}
+
+#[derive(Default, Clone, Debug)]
+/// Constant substitution rate with insertions/deletions in the V/J alignments
+pub struct FeatureErrorIndel {
+    substitutions: FeatureErrorConstant,
+    pub indel_rate: f64,
+    log_indel: f64,
+    log1mrho: f64,
+    // useful for dirty updating
+    total_lengths_dirty: f64,
+    total_errors_dirty: f64, // substitutions only
+    total_vj_lengths_dirty: f64,
+    total_indels_dirty: f64,
+}
+
+impl Feature<ErrorAlignment> for FeatureErrorIndel {
+    fn dirty_update(&mut self, _observation: ErrorAlignment, _likelihood: f64) {
+        unimplemented!();
+    }
+
+    /// Substitutions only (no indels in D or in amino-acid sequences)
+    fn likelihood(&self, observation: ErrorAlignment) -> f64 {
+        self.substitutions.likelihood(observation)
+    }
+
+    fn scale_dirty(&mut self, factor: f64) {
+        self.total_errors_dirty *= factor;
+        self.total_indels_dirty *= factor;
+        self.total_lengths_dirty *= factor;
+        self.total_vj_lengths_dirty *= factor;
+    }
+}
+
+impl FeatureErrorIndel {
+    pub fn new(error_rate: f64, indel_rate: f64) -> Result<FeatureErrorIndel> {
+        if !(0. ..1.).contains(&indel_rate) {
+            return Err(anyhow!(
+                "Error in FeatureErrorIndel Feature creation. Negative/NaN/infinite indel rate."
+            ));
+        }
+        Ok(FeatureErrorIndel {
+            substitutions: FeatureErrorConstant::new(error_rate)?,
+            indel_rate,
+            log_indel: (indel_rate / 2.).log2(),
+            log1mrho: (1. - indel_rate).log2(),
+            total_lengths_dirty: 0.,
+            total_errors_dirty: 0.,
+            total_vj_lengths_dirty: 0.,
+            total_indels_dirty: 0.,
+        })
+    }
+
+    pub fn get_error_rate(&self) -> f64 {
+        self.substitutions.error_rate
+    }
+
+    /// (nb of substitutions, nb of indels, length) of a V/J alignment
+    fn counts(al: &VJAlignment, del_left: usize, del_right: usize) -> (usize, usize, usize) {
+        let errors = al.errors(del_left, del_right);
+        let nb_indels = al.nb_indels();
+        (
+            errors.nb_errors.saturating_sub(nb_indels),
+            nb_indels,
+            errors.sequence_length,
+        )
+    }
+
+    /// likelihood = (r/3)^s (1-r)^(L-s) (1-ρ)^(L-n) (ρ/2)^n (1/4)^(nb inserted nt)
+    pub fn likelihood_vj(&self, al: &VJAlignment, del_left: usize, del_right: usize) -> f64 {
+        if al.nb_errors(del_left + del_right) == MAX_NB_ERRORS {
+            return 0.;
+        }
+        let (nb_subst, nb_indels, length) = Self::counts(al, del_left, del_right);
+        let subst = self.substitutions.likelihood(ErrorAlignment {
+            nb_errors: nb_subst,
+            sequence_length: length,
+        });
+        if nb_indels == 0 {
+            return subst * (length as f64 * self.log1mrho).exp2();
+        }
+        if self.indel_rate == 0. {
+            return 0.;
+        }
+        subst
+            * ((length.saturating_sub(nb_indels) as f64) * self.log1mrho
+                + (nb_indels as f64) * self.log_indel
+                - 2. * al.nb_insertions() as f64)
+                .exp2()
+    }
+
+    fn dirty_update_vj(
+        &mut self,
+        al: &VJAlignment,
+        del_left: usize,
+        del_right: usize,
+        likelihood: f64,
+    ) {
+        let (nb_subst, nb_indels, length) = Self::counts(al, del_left, del_right);
+        self.total_lengths_dirty += likelihood * length as f64;
+        self.total_vj_lengths_dirty += likelihood * length as f64;
+        self.total_errors_dirty += likelihood * nb_subst as f64;
+        self.total_indels_dirty += likelihood * nb_indels as f64;
+    }
+
+    pub fn dirty_update_v_fragment(&mut self, observation: &ErrorVAlignment, likelihood: f64) {
+        self.dirty_update_vj(observation.val, observation.del, 0, likelihood);
+    }
+
+    pub fn dirty_update_j_fragment(&mut self, observation: &ErrorJAlignment, likelihood: f64) {
+        self.dirty_update_vj(observation.jal, 0, observation.del, likelihood);
+    }
+
+    pub fn dirty_update_d_fragment(&mut self, observation: &ErrorDAlignment, likelihood: f64) {
+        self.total_lengths_dirty += likelihood
+            * (observation
+                .dal
+                .length_with_deletion(observation.deld5, observation.deld3) as f64);
+        self.total_errors_dirty += likelihood
+            * (observation
+                .dal
+                .nb_errors(observation.deld5, observation.deld3) as f64);
+    }
 }
 
 #[derive(Default, Clone, Debug)]
@@ -730,3 +1980,269 @@ impl FeatureErrorUniform {
 
     pub fn dirty_update_d_fragment(&mut self, _observation: &ErrorDAlignment, _likelihood: f64) {}
 }
+
+#[derive(Default, Debug)]
+/// log2 of the per-position probabilities of the context error model,
+/// shared between all the sequences' features
+struct ContextScores {
+    log_no_mutation: Vec<f64>,
+    log_mutation: Vec<[f64; 4]>,
+    mean_error_rate: f64,
+}
+
+#[derive(Default, Clone, Debug)]
+/// Context-dependent error feature, each position contributes
+/// (1 - m(ctx)) if unmutated and m(ctx) * s(ctx, nt) if mutated into nt
+pub struct FeatureErrorContext {
+    scores: Arc<ContextScores>,
+    // useful for dirty updating: for each context,
+    // [Σ P(E), Σ P(E) (mutated to A), ... (mutated to T)]
+    counts_dirty: HashMap<usize, [f64; 5]>,
+}
+
+impl Feature<ErrorAlignment> for FeatureErrorContext {
+    fn dirty_update(&mut self, _observation: ErrorAlignment, _likelihood: f64) {
+        unimplemented!();
+    }
+
+    /// Fallback when the position of the errors is unknown (amino-acid
+    /// sequences), use the average error rate of the model.
+    fn likelihood(&self, observation: ErrorAlignment) -> f64 {
+        let r = self.scores.mean_error_rate;
+        if observation.nb_errors == MAX_NB_ERRORS {
+            return 0.;
+        }
+        if r == 0. {
+            return if observation.nb_errors != 0 { 0. } else { 1. };
+        }
+        ((observation.nb_errors as f64) * (r / 3.).log2()
+            + ((observation.sequence_length - observation.nb_errors) as f64) * (1. - r).log2())
+        .exp2()
+    }
+
+    fn scale_dirty(&mut self, factor: f64) {
+        for c in self.counts_dirty.values_mut() {
+            for x in c.iter_mut() {
+                *x *= factor;
+            }
+        }
+    }
+}
+
+impl FeatureErrorContext {
+    pub fn new(error: &ErrorContextRate) -> Result<FeatureErrorContext> {
+        let scores = ContextScores {
+            log_no_mutation: error.mutability.iter().map(|m| (1. - m).log2()).collect(),
+            log_mutation: error
+                .mutability
+                .iter()
+                .zip(error.substitution.iter())
+                .map(|(m, s)| s.map(|x| (m * x).log2()))
+                .collect(),
+            mean_error_rate: error.mean_error_rate(),
+        };
+        Ok(FeatureErrorContext {
+            scores: Arc::new(scores),
+            counts_dirty: HashMap::new(),
+        })
+    }
+
+    pub fn get_error_rate(&self) -> f64 {
+        self.scores.mean_error_rate
+    }
+
+    /// (expected number of mutations, expected number of germline nucleotides)
+    fn posterior_counts(&self) -> (f64, f64) {
+        self.counts_dirty.values().fold((0., 0.), |(err, len), c| {
+            (err + c[1..].iter().sum::<f64>(), len + c[0])
+        })
+    }
+
+    /// Sum the dirty counts of all the sequences, for each context
+    /// [Σ P(E), Σ P(E) (mutated to A), ... (mutated to T)]
+    fn aggregate_counts(features: &[FeatureErrorContext], weights: &[f64]) -> Vec<[f64; 5]> {
+        let mut counts = vec![[0f64; 5]; NB_CONTEXTS];
+        for (feat, weight) in features.iter().zip(weights.iter()) {
+            for (&ctx, c) in feat.counts_dirty.iter() {
+                for k in 0..5 {
+                    counts[ctx][k] += weight * c[k];
+                }
+            }
+        }
+        counts
+    }
+
+    /// log2 probability that the germline nucleotide with context `ctx` is
+    /// read as `nt` (possibly degenerate)
+    fn log_mutation(&self, ctx: usize, nt: u8) -> f64 {
+        let idx = nucleotides_inv(nt);
+        if idx < 4 {
+            return self.scores.log_mutation[ctx][idx];
+        }
+        degenerate_dna_to_vec(nt)
+            .iter()
+            .map(|&b| self.scores.log_mutation[ctx][b].exp2())
+            .sum::<f64>()
+            .log2()
+    }
+
+    /// log2-likelihood of the positions `range` of `gene`, `mismatches` being
+    /// the sorted (gene position, observed nucleotide) errors.
+    fn log_likelihood(&self, gene: &[u8], range: Range<usize>, mismatches: &[(usize, u8)]) -> f64 {
+        let start = range.start;
+        let mut errors = mismatches
+            .iter()
+            .skip_while(|(idx, _)| *idx < start)
+            .peekable();
+        let mut ll = 0.;
+        for idx in range {
+            let ctx = context_index(gene, idx);
+            ll += match errors.next_if(|(i, _)| *i == idx) {
+                Some(&(_, nt)) => self.log_mutation(ctx, nt),
+                None => self.scores.log_no_mutation[ctx],
+            };
+        }
+        ll
+    }
+
+    fn dirty_update_segment(
+        &mut self,
+        gene: &[u8],
+        range: Range<usize>,
+        mismatches: &[(usize, u8)],
+        likelihood: f64,
+    ) {
+        let start = range.start;
+        let mut errors = mismatches
+            .iter()
+            .skip_while(|(idx, _)| *idx < start)
+            .peekable();
+        for idx in range {
+            let counts = self
+                .counts_dirty
+                .entry(context_index(gene, idx))
+                .or_insert([0.; 5]);
+            counts[0] += likelihood;
+            if let Some(&(_, nt)) = errors.next_if(|(i, _)| *i == idx) {
+                // degenerate nucleotides are split between the possible targets
+                let germline = nucleotides_inv(gene[idx]);
+                let targets: Vec<usize> = degenerate_dna_to_vec(nt)
+                    .into_iter()
+                    .filter(|&b| b != germline)
+                    .collect();
+                for &b in &targets {
+                    counts[b + 1] += likelihood / targets.len() as f64;
+                }
+            }
+        }
+    }
+
+    fn range_v(observation: &ErrorVAlignment) -> Range<usize> {
+        let val = observation.val;
+        val.start_gene
+            ..val
+                .end_gene
+                .saturating_sub(observation.del)
+                .max(val.start_gene)
+    }
+
+    fn range_j(observation: &ErrorJAlignment) -> Range<usize> {
+        let jal = observation.jal;
+        observation.del.min(jal.end_gene)..jal.end_gene
+    }
+
+    fn range_d(observation: &ErrorDAlignment) -> Range<usize> {
+        let len = observation.dal.len();
+        observation.deld5.min(len)
+            ..len
+                .saturating_sub(observation.deld3)
+                .max(observation.deld5.min(len))
+    }
+
+    pub fn likelihood_v(&self, observation: &ErrorVAlignment) -> f64 {
+        let val = observation.val;
+        if val.sequence_type == SequenceType::Protein {
+            return self.likelihood(val.errors(observation.del, 0));
+        }
+        if val.nb_errors(observation.del) == MAX_NB_ERRORS {
+            return 0.;
+        }
+        self.log_likelihood(
+            &val.gene_sequence.seq,
+            Self::range_v(observation),
+            &val.mismatches,
+        )
+        .exp2()
+    }
+
+    pub fn likelihood_j(&self, observation: &ErrorJAlignment) -> f64 {
+        let jal = observation.jal;
+        if jal.sequence_type == SequenceType::Protein {
+            return self.likelihood(jal.errors(0, observation.del));
+        }
+        if jal.nb_errors(observation.del) == MAX_NB_ERRORS {
+            return 0.;
+        }
+        self.log_likelihood(
+            &jal.gene_sequence.seq,
+            Self::range_j(observation),
+            &jal.mismatches,
+        )
+        .exp2()
+    }
+
+    pub fn likelihood_d(&self, observation: &ErrorDAlignment) -> f64 {
+        let dal = observation.dal;
+        if dal.sequence_type == SequenceType::Protein {
+            return self.likelihood(dal.errors(observation.deld5, observation.deld3));
+        }
+        if dal.pos + (observation.deld5 as i64) < 0 {
+            return 0.;
+        }
+        self.log_likelihood(
+            &dal.dseq.seq,
+            Self::range_d(observation),
+            &dal.mismatches(observation.deld5, observation.deld3),
+        )
+        .exp2()
+    }
+
+    pub fn dirty_update_v_fragment(&mut self, observation: &ErrorVAlignment, likelihood: f64) {
+        let val = observation.val;
+        if val.sequence_type == SequenceType::Protein {
+            return;
+        }
+        self.dirty_update_segment(
+            &val.gene_sequence.seq,
+            Self::range_v(observation),
+            &val.mismatches,
+            likelihood,
+        );
+    }
+
+    pub fn dirty_update_j_fragment(&mut self, observation: &ErrorJAlignment, likelihood: f64) {
+        let jal = observation.jal;
+        if jal.sequence_type == SequenceType::Protein {
+            return;
+        }
+        self.dirty_update_segment(
+            &jal.gene_sequence.seq,
+            Self::range_j(observation),
+            &jal.mismatches,
+            likelihood,
+        );
+    }
+
+    pub fn dirty_update_d_fragment(&mut self, observation: &ErrorDAlignment, likelihood: f64) {
+        let dal = observation.dal;
+        if dal.sequence_type == SequenceType::Protein {
+            return;
+        }
+        self.dirty_update_segment(
+            &dal.dseq.seq,
+            Self::range_d(observation),
+            &dal.mismatches(observation.deld5, observation.deld3),
+            likelihood,
+        );
+    }
+}
@@ -116,6 +116,23 @@ impl PyStaticEvent {
         }
     }
 
+    /// Index of the second D gene (`None` if the model has a single D)
+    #[getter]
+    fn get_d2_index(&self) -> PyResult<Option<usize>> {
+        match &self.s {
+            StaticEvent::VDJ(x) => Ok(x.d2_index),
+            StaticEvent::VJ(_) => Err(anyhow!("No D index in a VJ model"))?,
+        }
+    }
+
+    #[getter]
+    fn get_insdd(&self) -> PyResult<Dna> {
+        match &self.s {
+            StaticEvent::VDJ(x) => Ok(x.insdd.clone()),
+            StaticEvent::VJ(_) => Err(anyhow!("No DD insertions in a VJ model"))?,
+        }
+    }
+
     #[getter]
     fn get_insdj(&self) -> PyResult<Dna> {
         match &self.s {
@@ -163,6 +180,13 @@ impl StaticEvent {
         }
     }
 
+    pub fn set_indels(&mut self, indels: Vec<(usize, usize, Dna)>) {
+        match self {
+            StaticEvent::VDJ(x) => x.indels = indels,
+            StaticEvent::VJ(x) => x.indels = indels,
+        }
+    }
+
     pub fn to_sequence(&mut self, model: Model) -> Result<Dna> {
         match self {
             StaticEvent::VDJ(x) => match model {
@@ -1,15 +1,20 @@
+use crate::graph::inference::GRAPH_INFERENCE_ERROR;
 use crate::shared::likelihood::Likelihood;
 use crate::shared::sequence::Dna;
 use crate::shared::utils::{Normalize, Normalize2, Normalize3};
 use crate::shared::DNAMarkovChain;
 use crate::shared::ModelStructure;
-use crate::shared::{errors::FeatureError, DnaLike, InferenceParameters};
+use crate::shared::{
+    errors::{FeatureError, MAX_NB_ERRORS},
+    DnaLike, InferenceParameters, VJAlignment,
+};
 use crate::vdj::Model as ModelVDJ;
-use crate::{v_dj, vdj};
+use crate::{graph, v_dj, vddj, vdj, vj};
 use anyhow::{anyhow, Result};
+use rand::Rng;
 use std::sync::Arc;
 
-use ndarray::{Array1, Array2, Array3};
+use ndarray::{linalg::kron, Array1, Array2, Array3};
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
 use pyo3::prelude::*;
 use std::fmt::Debug;
@@ -78,19 +83,26 @@ impl CategoricalFeature1 {
             "Probabilities larger than one !"
         );
     }
+    /// Weighted average of the (dirty) features, `weights` contains the
+    /// weight of each feature. `pseudocount` is added to the expected counts
+    /// of every category before normalization (0 for maximum likelihood).
     pub fn average(
-        mut iter: impl Iterator<Item = CategoricalFeature1> + Clone,
+        iter: impl Iterator<Item = CategoricalFeature1> + Clone,
+        weights: &[f64],
+        pseudocount: f64,
     ) -> Result<Vec<CategoricalFeature1>> {
         let mut len = 1;
-        let mut average_proba = iter
-            .next()
-            .ok_or(anyhow!("Cannot average empty vector"))?
-            .probas_dirty;
-        for feat in iter {
-            average_proba += &feat.probas_dirty;
+        let mut iter = iter.zip(weights.iter());
+        let (first_feat, &first_weight) =
+            iter.next().ok_or(anyhow!("Cannot average empty vector"))?;
+        let mut total_weight = first_weight;
+        let mut average_proba = first_feat.probas_dirty * first_weight;
+        for (feat, &weight) in iter {
+            average_proba.scaled_add(weight, &feat.probas_dirty);
+            total_weight += weight;
             len += 1;
         }
-        let new_feat = CategoricalFeature1::new(&(average_proba / (len as f64)))?;
+        let new_feat = CategoricalFeature1::new(&((average_proba + pseudocount) / total_weight))?;
         Ok(vec![new_feat; len])
     }
 }
@@ -135,19 +147,25 @@ impl CategoricalFeature1g1 {
             "Probabilities larger than one !"
         );
     }
+    /// Weighted average of the (dirty) features, `weights` contains the
+    /// weight of each feature. `pseudocount` is added to the expected counts
+    /// of every category before normalization (0 for maximum likelihood).
     pub fn average(
-        mut iter: impl Iterator<Item = CategoricalFeature1g1> + Clone,
+        iter: impl Iterator<Item = CategoricalFeature1g1> + Clone,
+        weights: &[f64],
+        pseudocount: f64,
     ) -> Result<CategoricalFeature1g1> {
-        let mut len = 1;
-        let mut average_proba = iter
-            .next()
-            .ok_or(anyhow!("Cannot average empty vector"))?
-            .probas_dirty;
-        for feat in iter {
-            average_proba += &feat.probas_dirty;
-            len += 1;
+        let mut iter = iter.zip(weights.iter());
+        let (first_feat, &first_weight) =
+            iter.next().ok_or(anyhow!("Cannot average empty vector"))?;
+        let mut total_weight = first_weight;
+        let mut average_proba = first_feat.probas_dirty * first_weight;
+        for (feat, &weight) in iter {
+            average_proba.scaled_add(weight, &feat.probas_dirty);
+            total_weight += weight;
         }
-        let average_feat = CategoricalFeature1g1::new(&(average_proba / (f64::from(len))))?;
+        let average_feat =
+            CategoricalFeature1g1::new(&((average_proba + pseudocount) / total_weight))?;
         Ok(average_feat)
     }
 }
@@ -193,20 +211,25 @@ impl CategoricalFeature1g2 {
             "Probabilities larger than one !"
         );
     }
+    /// Weighted average of the (dirty) features, `weights` contains the
+    /// weight of each feature. `pseudocount` is added to the expected counts
+    /// of every category before normalization (0 for maximum likelihood).
     pub fn average(
-        mut iter: impl Iterator<Item = CategoricalFeature1g2> + Clone,
+        iter: impl Iterator<Item = CategoricalFeature1g2> + Clone,
+        weights: &[f64],
+        pseudocount: f64,
     ) -> Result<CategoricalFeature1g2> {
-        let mut len = 1;
-
-        let mut average_proba = iter
-            .next()
-            .ok_or(anyhow!("Cannot average empty vector"))?
-            .probas_dirty;
-        for feat in iter {
-            average_proba += &feat.probas_dirty;
-            len += 1;
+        let mut iter = iter.zip(weights.iter());
+        let (first_feat, &first_weight) =
+            iter.next().ok_or(anyhow!("Cannot average empty vector"))?;
+        let mut total_weight = first_weight;
+        let mut average_proba = first_feat.probas_dirty * first_weight;
+        for (feat, &weight) in iter {
+            average_proba.scaled_add(weight, &feat.probas_dirty);
+            total_weight += weight;
         }
-        let average_feat = CategoricalFeature1g2::new(&(average_proba / (f64::from(len))))?;
+        let average_feat =
+            CategoricalFeature1g2::new(&((average_proba + pseudocount) / total_weight))?;
         Ok(average_feat)
     }
 }
@@ -255,19 +278,25 @@ impl CategoricalFeature2 {
             "Probabilities larger than one !"
         );
     }
+    /// Weighted average of the (dirty) features, `weights` contains the
+    /// weight of each feature. `pseudocount` is added to the expected counts
+    /// of every category before normalization (0 for maximum likelihood).
     pub fn average(
-        mut iter: impl Iterator<Item = CategoricalFeature2> + Clone,
+        iter: impl Iterator<Item = CategoricalFeature2> + Clone,
+        weights: &[f64],
+        pseudocount: f64,
     ) -> Result<CategoricalFeature2> {
-        let mut len = 1;
-        let mut average_proba = iter
-            .next()
-            .ok_or(anyhow!("Cannot average empty vector"))?
-            .probas_dirty;
-        for feat in iter {
-            average_proba += &feat.probas_dirty;
-            len += 1;
+        let mut iter = iter.zip(weights.iter());
+        let (first_feat, &first_weight) =
+            iter.next().ok_or(anyhow!("Cannot average empty vector"))?;
+        let mut total_weight = first_weight;
+        let mut average_proba = first_feat.probas_dirty * first_weight;
+        for (feat, &weight) in iter {
+            average_proba.scaled_add(weight, &feat.probas_dirty);
+            total_weight += weight;
         }
-        let average_feat = CategoricalFeature2::new(&(average_proba / (f64::from(len))))?;
+        let average_feat =
+            CategoricalFeature2::new(&((average_proba + pseudocount) / total_weight))?;
         Ok(average_feat)
     }
 }
@@ -313,17 +342,25 @@ impl CategoricalFeature2g1 {
             "Probabilities larger than one !"
         );
     }
+    /// Weighted average of the (dirty) features, `weights` contains the
+    /// weight of each feature. `pseudocount` is added to the expected counts
+    /// of every category before normalization (0 for maximum likelihood).
     pub fn average(
-        mut iter: impl Iterator<Item = CategoricalFeature2g1> + Clone,
+        iter: impl Iterator<Item = CategoricalFeature2g1> + Clone,
+        weights: &[f64],
+        pseudocount: f64,
     ) -> Result<CategoricalFeature2g1> {
-        let mut average_proba = iter
-            .next()
-            .ok_or(anyhow!("Cannot average empty vector"))?
-            .probas_dirty;
-        for feat in iter {
-            average_proba += &feat.probas_dirty;
-        }
-        let average_feat = CategoricalFeature2g1::new(&(average_proba))?;
+        let mut iter = iter.zip(weights.iter());
+        let (first_feat, &first_weight) =
+            iter.next().ok_or(anyhow!("Cannot average empty vector"))?;
+        let mut total_weight = first_weight;
+        let mut average_proba = first_feat.probas_dirty * first_weight;
+        for (feat, &weight) in iter {
+            average_proba.scaled_add(weight, &feat.probas_dirty);
+            total_weight += weight;
+        }
+        let average_feat =
+            CategoricalFeature2g1::new(&((average_proba + pseudocount) / total_weight))?;
         Ok(average_feat)
     }
 }
@@ -372,19 +409,25 @@ impl CategoricalFeature3 {
             "Probabilities larger than one !"
         );
     }
+    /// Weighted average of the (dirty) features, `weights` contains the
+    /// weight of each feature. `pseudocount` is added to the expected counts
+    /// of every category before normalization (0 for maximum likelihood).
     pub fn average(
-        mut iter: impl Iterator<Item = CategoricalFeature3> + Clone,
+        iter: impl Iterator<Item = CategoricalFeature3> + Clone,
+        weights: &[f64],
+        pseudocount: f64,
     ) -> Result<CategoricalFeature3> {
-        let mut len = 1;
-        let mut average_proba = iter
-            .next()
-            .ok_or(anyhow!("Cannot average empty vector"))?
-            .probas_dirty;
-        for feat in iter {
-            average_proba += &feat.probas_dirty;
-            len += 1;
+        let mut iter = iter.zip(weights.iter());
+        let (first_feat, &first_weight) =
+            iter.next().ok_or(anyhow!("Cannot average empty vector"))?;
+        let mut total_weight = first_weight;
+        let mut average_proba = first_feat.probas_dirty * first_weight;
+        for (feat, &weight) in iter {
+            average_proba.scaled_add(weight, &feat.probas_dirty);
+            total_weight += weight;
         }
-        let average_feat = CategoricalFeature3::new(&(average_proba / (f64::from(len))))?;
+        let average_feat =
+            CategoricalFeature3::new(&((average_proba + pseudocount) / total_weight))?;
         Ok(average_feat)
     }
 }
@@ -400,6 +443,7 @@ pub struct InsertionFeature {
 
     // for updating
     pub transition_matrix_dirty: Array2<f64>,
+    pub higher_order_dirty: Vec<Array2<f64>>,
     pub length_distribution_dirty: Array1<f64>,
 }
 
@@ -423,6 +467,19 @@ impl InsertionFeature {
             &self
                 .transition
                 .update(observation, first_nucleotide, likelihood);
+        if !self.higher_order_dirty.is_empty() {
+            for (dirty, counts) in
+                self.higher_order_dirty
+                    .iter_mut()
+                    .zip(self.transition.update_higher_order(
+                        observation,
+                        first_nucleotide,
+                        likelihood,
+                    ))
+            {
+                *dirty += &counts;
+            }
+        }
     }
 
     /// - observation: sequence of interest
@@ -441,6 +498,9 @@ impl InsertionFeature {
     pub fn scale_dirty(&mut self, factor: f64) {
         self.length_distribution_dirty *= factor;
         self.transition_matrix_dirty *= factor;
+        for dirty in self.higher_order_dirty.iter_mut() {
+            *dirty *= factor;
+        }
     }
 }
 
@@ -448,26 +508,28 @@ impl InsertionFeature {
     pub fn correct_for_error(&self, err: &FeatureError) -> InsertionFeature {
         // The error rate make the inferred value of the transition rate wrong
         // we correct it using the current error rate estimate.
-
-        match err {
-            FeatureError::ConstantRate(f) => {
-                let rho = 4. * f.error_rate / 3.;
-                let matrix = 1. / (1. - rho) * (Array2::eye(4) - rho / 4. * Array2::ones((4, 4)));
-                let mut insfeat = self.clone();
-                // we just modify the "dirty" matrix (no choice ...)
-                insfeat.transition_matrix_dirty =
-                    matrix.dot(&insfeat.transition_matrix_dirty.dot(&matrix));
-                insfeat
-            }
-            FeatureError::UniformRate(f) => {
-                let mut insfeat = self.clone();
-                let rho = 4. * f.get_error_rate() / 3.;
-                let matrix = 1. / (1. - rho) * (Array2::eye(4) - rho / 4. * Array2::ones((4, 4)));
-                insfeat.transition_matrix_dirty =
-                    matrix.dot(&insfeat.transition_matrix_dirty.dot(&matrix));
-                insfeat
-            }
+        let error_rate = match err {
+            FeatureError::ConstantRate(f) => f.error_rate,
+            FeatureError::UniformRate(f) => f.get_error_rate(),
+            // insertions have no germline context, use the average rate
+            FeatureError::ContextRate(f) => f.get_error_rate(),
+            FeatureError::IndelRate(f) => f.get_error_rate(),
+            // the insertions share the D error rate
+            FeatureError::SegmentRate(f) => f.d.error_rate,
+        };
+        let rho = 4. * error_rate / 3.;
+        let matrix = 1. / (1. - rho) * (Array2::eye(4) - rho / 4. * Array2::ones((4, 4)));
+        let mut insfeat = self.clone();
+        // we just modify the "dirty" matrix (no choice ...)
+        insfeat.transition_matrix_dirty = matrix.dot(&insfeat.transition_matrix_dirty.dot(&matrix));
+        // same thing for the higher orders, every nucleotide of the context
+        // is corrected independently
+        let mut context_matrix = matrix.clone();
+        for dirty in insfeat.higher_order_dirty.iter_mut() {
+            context_matrix = kron(&context_matrix, &matrix);
+            *dirty = context_matrix.dot(&dirty.dot(&matrix));
         }
+        insfeat
     }
 
     pub fn check(&self) {
@@ -488,6 +550,11 @@ impl InsertionFeature {
         let dim = transition.transition_matrix.dim();
         let m = InsertionFeature {
             length_distribution: length_distribution.normalize_distribution()?,
+            higher_order_dirty: transition
+                .higher_order
+                .iter()
+                .map(|x| Array2::<f64>::zeros(x.dim()))
+                .collect(),
             transition,
             transition_matrix_dirty: Array2::<f64>::zeros(dim),
             length_distribution_dirty: Array1::<f64>::zeros(length_distribution.dim()),
@@ -496,33 +563,60 @@ impl InsertionFeature {
         Ok(m)
     }
 
+    /// Insertion feature that only scores the inserted nucleotides, the
+    /// insertion length (up to `max_nb_insertions`) is dealt with separately
+    pub fn new_sequence_only(
+        max_nb_insertions: usize,
+        transition: Arc<DNAMarkovChain>,
+    ) -> Result<InsertionFeature> {
+        let mut m = Self::new(&Array1::ones(max_nb_insertions), transition)?;
+        m.length_distribution.fill(1.);
+        Ok(m)
+    }
+
     pub fn normalize(&self) -> Result<Self> {
         Self::new(&self.length_distribution, self.transition.clone())
     }
 
-    pub fn get_parameters(&self) -> (Array1<f64>, Array2<f64>) {
-        (
-            self.length_distribution.clone(),
-            self.transition.transition_matrix.clone(),
-        )
+    pub fn get_parameters(&self) -> (Array1<f64>, Arc<DNAMarkovChain>) {
+        (self.length_distribution.clone(), self.transition.clone())
     }
 
     pub fn max_nb_insertions(&self) -> usize {
         self.length_distribution.len()
     }
 
+    /// Weighted average of the (dirty) features, `weights` contains the
+    /// weight of each feature. The pseudocounts are added to the expected counts
+    /// of every insertion length and of every nucleotide transition.
     pub fn average(
-        mut iter: impl Iterator<Item = InsertionFeature> + Clone,
+        iter: impl Iterator<Item = InsertionFeature> + Clone,
+        weights: &[f64],
+        pseudocount_length: f64,
+        pseudocount_transition: f64,
     ) -> Result<InsertionFeature> {
-        let mut len = 1;
-        let first_feat = iter.next().ok_or(anyhow!("Cannot average empty vector"))?;
-        let mut average_length = first_feat.length_distribution_dirty;
-        let mut average_mat = first_feat.transition_matrix_dirty;
+        let mut iter = iter.zip(weights.iter());
+        let (first_feat, &first_weight) =
+            iter.next().ok_or(anyhow!("Cannot average empty vector"))?;
+        let mut total_weight = first_weight;
+        let mut average_length = first_feat.length_distribution_dirty * first_weight;
+        let mut average_mat = first_feat.transition_matrix_dirty * first_weight;
+        let mut average_higher_order: Vec<Array2<f64>> = first_feat
+            .higher_order_dirty
+            .iter()
+            .map(|x| x * first_weight)
+            .collect();
         let direction = first_feat.transition.reverse;
-        for feat in iter {
-            average_mat += &feat.transition_matrix_dirty;
-            average_length += &feat.length_distribution_dirty;
-            len += 1;
+        for (feat, &weight) in iter {
+            average_mat.scaled_add(weight, &feat.transition_matrix_dirty);
+            average_length.scaled_add(weight, &feat.length_distribution_dirty);
+            for (avg, dirty) in average_higher_order
+                .iter_mut()
+                .zip(feat.higher_order_dirty.iter())
+            {
+                avg.scaled_add(weight, dirty);
+            }
+            total_weight += weight;
         }
         // the error rate correction can make some value of the transition matrix negative
         // (shouldn't happen in theory, but that's life)
@@ -530,13 +624,26 @@ impl InsertionFeature {
         // normalisation should take care of the rest.
         let sum = average_mat.clone().sum();
         average_mat.mapv_inplace(|a| if a < 0.0 { 1e-4 * sum } else { a });
+        average_mat += pseudocount_transition;
+        average_length += pseudocount_length;
+        for avg in average_higher_order.iter_mut() {
+            let sum = avg.sum();
+            avg.mapv_inplace(|a| if a < 0.0 { 1e-4 * sum } else { a });
+            *avg += pseudocount_transition;
+        }
+
+        let average_higher_order: Vec<Array2<f64>> = average_higher_order
+            .into_iter()
+            .map(|avg| avg / total_weight)
+            .collect();
 
-        let transition = Arc::new(DNAMarkovChain::new(
-            &(average_mat / (f64::from(len))),
+        let transition = Arc::new(DNAMarkovChain::new_higher_order(
+            &(average_mat / total_weight),
+            &average_higher_order,
             direction,
         )?);
 
-        let average_feat = InsertionFeature::new(&(average_length / (f64::from(len))), transition)?;
+        let average_feat = InsertionFeature::new(&(average_length / total_weight), transition)?;
         Ok(average_feat)
     }
 }
@@ -555,17 +662,32 @@ pub struct InfEvent {
     pub end_d: i64,
     pub start_j: i64,
     pub pos_d: i64,
+    // second D gene (`ModelStructure::VDDJ` only)
+    pub d2_index: Option<usize>,
+    pub start_d2: i64,
+    pub end_d2: i64,
+    pub pos_d2: i64,
 
     // sequences (only added after the inference is over)
     // need DnaLike container because : they can be aa sequence
     // & DnaLike is not a type that can work with pypi
     pub ins_vd: Option<DnaLike>,
+    pub ins_dd: Option<DnaLike>,
     pub ins_dj: Option<DnaLike>,
     pub d_segment: Option<DnaLike>,
     pub sequence: Option<DnaLike>,
     pub junction: Option<DnaLike>,
     pub full_sequence: Option<Dna>,
     pub reconstructed_sequence: Option<Dna>,
+    // number of mismatches between the sequence and the V/D/J genes
+    // (once deleted), None for amino-acid sequences
+    pub nb_errors_v: Option<usize>,
+    pub nb_errors_d: Option<usize>,
+    pub nb_errors_j: Option<usize>,
+    // gapped alignments of the V and J genes (see `VJAlignment::cigar`),
+    // starting at the first nucleotide of the sequence, empty if ungapped
+    pub v_cigar: Vec<(char, usize)>,
+    pub j_cigar: Vec<(char, usize)>,
     // likelihood (pgen + perror)
     pub likelihood: f64,
 }
@@ -594,6 +716,33 @@ pub struct ResultInference {
     pub best_likelihood: f64,
     pub features: Option<Features>,
     pub human_readable: Option<ResultHuman>,
+    // posterior mean error rate of the sequence (see
+    // `FeatureError::posterior_error_rate`)
+    pub error_rate: Option<f64>,
+    // most likely scenarios, by decreasing likelihood
+    // (`InferenceParameters::store_top_k`)
+    pub top_events: Vec<InfEvent>,
+    // posterior marginals of the features given the sequence
+    pub marginals: Option<PosteriorMarginals>,
+}
+
+/// Posterior marginals P(. | sequence) of an evaluated sequence, given by the
+/// expected counts of the inference. The marginals of the features that are
+/// not inferred (see `InferenceParameters::infer_features`) are zero.
+#[cfg_attr(all(feature = "py_binds", feature = "pyo3"), pyclass)]
+#[derive(Default, Clone, Debug)]
+pub struct PosteriorMarginals {
+    // V, D and J genes (first D gene for the VDDJ models, empty D for the VJ models)
+    pub v: Array1<f64>,
+    pub d: Array1<f64>,
+    pub j: Array1<f64>,
+    // number of deletions
+    pub del_v: Array1<f64>,
+    pub del_j: Array1<f64>,
+    pub del_d: Array2<f64>, // d5, d3
+    // insertion lengths (VJ insertions in `ins_vd` for the VJ models)
+    pub ins_vd: Array1<f64>,
+    pub ins_dj: Array1<f64>,
 }
 
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
@@ -638,6 +787,49 @@ pub struct ResultHuman {
     pub v_name: String,
     pub j_name: String,
     pub d_name: String,
+    pub error_rate: Option<f64>,
+    pub nb_errors_v: Option<usize>,
+    pub nb_errors_d: Option<usize>,
+    pub nb_errors_j: Option<usize>,
+}
+
+/// Light-weight version of `ResultInference`, without the features and the
+/// inferred event, used when evaluating a large number of sequences
+#[derive(Default, Clone, Debug)]
+#[cfg_attr(all(feature = "py_binds", feature = "pyo3"), pyclass(get_all))]
+pub struct ResultCompact {
+    pub likelihood: f64,
+    pub pgen: f64,
+    pub likelihood_ratio_best: Option<f64>,
+    pub n_junction: Option<String>,
+    pub aa_junction: Option<String>,
+    pub v_name: Option<String>,
+    pub d_name: Option<String>,
+    pub j_name: Option<String>,
+    pub error_rate: Option<f64>,
+    pub nb_errors_v: Option<usize>,
+    pub nb_errors_d: Option<usize>,
+    pub nb_errors_j: Option<usize>,
+    // posterior probability of the V, D and J genes
+    pub v_posterior: Option<f64>,
+    pub d_posterior: Option<f64>,
+    pub j_posterior: Option<f64>,
+}
+
+#[cfg(all(feature = "py_binds", feature = "pyo3"))]
+#[pymethods]
+impl ResultCompact {
+    fn __repr__(&self) -> String {
+        format!(
+            "ResultCompact(likelihood={:.2e}, pgen={:.2e}, junction={}, V={}, D={}, J={})",
+            self.likelihood,
+            self.pgen,
+            self.n_junction.clone().unwrap_or_default(),
+            self.v_name.clone().unwrap_or_default(),
+            self.d_name.clone().unwrap_or_default(),
+            self.j_name.clone().unwrap_or_default()
+        )
+    }
 }
 
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
@@ -662,6 +854,32 @@ impl ResultInference {
     pub fn get_reconstructed_sequence(&self) -> String {
         self.human_readable.clone().unwrap().reconstructed_seq
     }
+    #[getter]
+    pub fn get_error_rate(&self) -> Option<f64> {
+        self.error_rate
+    }
+    /// Number of mismatches in the V, D and J genes for the most likely event
+    #[getter]
+    pub fn get_nb_errors(&self) -> Option<(usize, usize, usize)> {
+        let ev = self.best_event.as_ref()?;
+        Some((ev.nb_errors_v?, ev.nb_errors_d?, ev.nb_errors_j?))
+    }
+    /// Posterior marginals of the genes, deletions and insertion lengths
+    #[getter]
+    pub fn get_marginals(&self) -> Option<PosteriorMarginals> {
+        self.marginals.clone()
+    }
+    /// Most likely scenarios (`InferenceParameters.store_top_k`)
+    #[getter]
+    pub fn get_top_events(&self) -> Vec<InfEvent> {
+        self.top_events.clone()
+    }
+    /// Posterior probability of each of the `top_events`
+    #[getter]
+    #[pyo3(name = "top_probabilities")]
+    pub fn py_top_probabilities(&self) -> Vec<f64> {
+        self.top_probabilities()
+    }
 }
 
 impl ResultInference {
@@ -743,10 +961,44 @@ impl ResultInference {
             v_name: model.get_v_gene(&best_event),
             d_name: model.get_d_gene(&best_event),
             j_name: model.get_j_gene(&best_event),
+            error_rate: self.error_rate,
+            nb_errors_v: best_event.nb_errors_v,
+            nb_errors_d: best_event.nb_errors_d,
+            nb_errors_j: best_event.nb_errors_j,
         });
         Ok(())
     }
 
+    /// Drop the features & events, only keep the summary of the result
+    pub fn to_compact(&self) -> ResultCompact {
+        let (v_posterior, d_posterior, j_posterior) = self.best_genes_posterior();
+        match &self.human_readable {
+            Some(rh) => ResultCompact {
+                likelihood: self.likelihood,
+                pgen: self.pgen,
+                likelihood_ratio_best: Some(rh.likelihood_ratio_best),
+                n_junction: Some(rh.n_junction.clone()),
+                aa_junction: Some(rh.aa_junction.clone()),
+                v_name: Some(rh.v_name.clone()),
+                d_name: Some(rh.d_name.clone()),
+                j_name: Some(rh.j_name.clone()),
+                error_rate: rh.error_rate,
+                nb_errors_v: rh.nb_errors_v,
+                nb_errors_d: rh.nb_errors_d,
+                nb_errors_j: rh.nb_errors_j,
+                v_posterior,
+                d_posterior,
+                j_posterior,
+            },
+            None => ResultCompact {
+                likelihood: self.likelihood,
+                pgen: self.pgen,
+                error_rate: self.error_rate,
+                ..Default::default()
+            },
+        }
+    }
+
     pub fn impossible() -> ResultInference {
         ResultInference {
             likelihood: 0.,
@@ -755,6 +1007,9 @@ impl ResultInference {
             best_likelihood: 0.,
             features: None,
             human_readable: None,
+            error_rate: None,
+            top_events: Vec::new(),
+            marginals: None,
         }
     }
     pub fn set_best_event(&mut self, ev: InfEvent, ip: &InferenceParameters) {
@@ -765,103 +1020,233 @@ impl ResultInference {
     pub fn get_best_event(&self) -> Option<InfEvent> {
         self.best_event.clone()
     }
+    /// Posterior probability of the V, D and J genes of the best event
+    pub fn best_genes_posterior(&self) -> (Option<f64>, Option<f64>, Option<f64>) {
+        let (Some(m), Some(ev)) = (&self.marginals, &self.best_event) else {
+            return (None, None, None);
+        };
+        (
+            m.v.get(ev.v_index).copied(),
+            m.d.get(ev.d_index).copied(),
+            m.j.get(ev.j_index).copied(),
+        )
+    }
+    /// Posterior probability of each of the `top_events`
+    pub fn top_probabilities(&self) -> Vec<f64> {
+        self.top_events
+            .iter()
+            .map(|ev| ev.likelihood / self.likelihood)
+            .collect()
+    }
     /// I just store the necessary stuff in the Event variable while looping
     /// Fill event add enough to be able to completely recreate the sequence
     pub fn fill_event(&mut self, model: &vdj::Model, sequence: &vdj::Sequence) -> Result<()> {
-        if self.best_event.is_some() {
-            let mut event = self.best_event.clone().unwrap();
-            event.ins_vd = Some(
-                sequence
-                    .sequence
-                    .extract_padded_subsequence(event.end_v, event.start_d),
-            );
+        if let Some(event) = &mut self.best_event {
+            Self::complete_event(event, model, sequence)?;
+            self.load_human(model)?;
+        }
+        Ok(())
+    }
 
-            event.ins_dj = Some(
-                sequence
-                    .sequence
-                    .extract_padded_subsequence(event.end_d, event.start_j),
-            );
-            event.d_segment = Some(
+    /// Add the inserted sequences, the junction and the reconstructed
+    /// sequence to an event found during the inference
+    pub fn complete_event(
+        event: &mut InfEvent,
+        model: &vdj::Model,
+        sequence: &vdj::Sequence,
+    ) -> Result<()> {
+        event.ins_vd = Some(
+            sequence
+                .sequence
+                .extract_padded_subsequence(event.end_v, event.start_d),
+        );
+
+        // with two D genes, the DJ insertion starts after D2
+        let end_last_d = if event.d2_index.is_some() {
+            event.ins_dd = Some(
                 sequence
                     .sequence
-                    .extract_padded_subsequence(event.start_d, event.end_d),
+                    .extract_padded_subsequence(event.end_d, event.start_d2),
             );
+            event.end_d2
+        } else {
+            event.end_d
+        };
+        event.ins_dj = Some(
+            sequence
+                .sequence
+                .extract_padded_subsequence(end_last_d, event.start_j),
+        );
+        event.d_segment = Some(
+            sequence
+                .sequence
+                .extract_padded_subsequence(event.start_d, event.end_d),
+        );
 
-            event.sequence = Some(sequence.sequence.clone());
+        event.sequence = Some(sequence.sequence.clone());
 
-            let cdr3_pos_v = model.seg_vs[event.v_index]
-                .cdr3_pos
-                .ok_or(anyhow!("Gene not loaded correctly"))?;
-            let cdr3_pos_j = model.seg_js[event.j_index]
-                .cdr3_pos
-                .ok_or(anyhow!("Gene not loaded correctly"))?;
+        let cdr3_pos_v = model.seg_vs[event.v_index]
+            .cdr3_pos
+            .ok_or(anyhow!("Gene not loaded correctly"))?;
+        let cdr3_pos_j = model.seg_js[event.j_index]
+            .cdr3_pos
+            .ok_or(anyhow!("Gene not loaded correctly"))?;
 
-            let start_cdr3 = cdr3_pos_v as i64 - event.v_start_gene as i64;
+        let start_cdr3 = cdr3_pos_v as i64 - event.v_start_gene as i64;
 
-            // careful, cdr3_pos_j does not! include the palindromic insertions
-            // or the last nucleotide
-            let end_cdr3 = event.j_start_seq + cdr3_pos_j as i64 - model.range_del_j.0 + 3;
+        // careful, cdr3_pos_j does not! include the palindromic insertions
+        // or the last nucleotide
+        let end_cdr3 = event.j_start_seq + cdr3_pos_j as i64 - model.range_del_j.0 + 3;
 
-            event.junction = Some(
-                sequence
-                    .sequence
-                    .extract_padded_subsequence(start_cdr3, end_cdr3),
-            );
+        event.junction = Some(
+            sequence
+                .sequence
+                .extract_padded_subsequence(start_cdr3, end_cdr3),
+        );
 
-            let gene_v = model.seg_vs[event.v_index]
-                .clone()
-                .seq_with_pal
-                .ok_or(anyhow!("Model not loaded correctly"))?;
+        let gene_v = model.seg_vs[event.v_index]
+            .clone()
+            .seq_with_pal
+            .ok_or(anyhow!("Model not loaded correctly"))?;
 
-            let gene_j = model.seg_js[event.j_index]
-                .clone()
-                .seq_with_pal
-                .ok_or(anyhow!("Model not loaded correctly"))?;
+        let gene_j = model.seg_js[event.j_index]
+            .clone()
+            .seq_with_pal
+            .ok_or(anyhow!("Model not loaded correctly"))?;
 
-            let gene_d = model.seg_ds[event.d_index]
-                .clone()
-                .seq_with_pal
-                .ok_or(anyhow!("Model not loaded correctly"))?;
+        let gene_d = model.seg_ds[event.d_index]
+            .clone()
+            .seq_with_pal
+            .ok_or(anyhow!("Model not loaded correctly"))?;
 
-            let gene_v_cut = DnaLike::from_dna(gene_v.extract_subsequence(0, event.v_start_gene));
+        let gene_v_cut = DnaLike::from_dna(gene_v.extract_subsequence(0, event.v_start_gene));
 
-            let mut full_seq = gene_v_cut.extended(&sequence.sequence);
+        let mut full_seq = gene_v_cut.extended(&sequence.sequence);
 
-            full_seq = full_seq.extended_with_dna(&gene_j.extract_subsequence(
-                (sequence.sequence.len() as i64 - event.j_start_seq) as usize,
-                gene_j.len(),
-            ));
+        full_seq = full_seq.extended_with_dna(&gene_j.extract_subsequence(
+            (sequence.sequence.len() as i64 - event.j_start_seq) as usize,
+            gene_j.len(),
+        ));
 
-            event.full_sequence = Some(full_seq.to_dna());
+        event.full_sequence = Some(full_seq.to_dna());
 
-            let mut reconstructed_seq =
-                gene_v.extract_subsequence(0, (event.end_v + event.v_start_gene as i64) as usize);
-            reconstructed_seq.extend(&event.ins_vd.clone().unwrap().to_dna());
+        let mut reconstructed_seq =
+            gene_v.extract_subsequence(0, (event.end_v + event.v_start_gene as i64) as usize);
+        reconstructed_seq.extend(&event.ins_vd.clone().unwrap().to_dna());
 
-            reconstructed_seq.extend(&gene_d.extract_subsequence(
-                (-event.pos_d + event.start_d) as usize,
-                (-event.pos_d + event.end_d) as usize,
-            ));
-            //            reconstructed_seq.extend(&event.d_segment.clone().unwrap());
-            reconstructed_seq.extend(&event.ins_dj.clone().unwrap().to_dna());
-            reconstructed_seq.extend(&gene_j.extract_padded_subsequence(
-                event.start_j - event.j_start_seq,
-                gene_j.len() as i64,
+        reconstructed_seq.extend(&gene_d.extract_subsequence(
+            (-event.pos_d + event.start_d) as usize,
+            (-event.pos_d + event.end_d) as usize,
+        ));
+        if let Some(d2_index) = event.d2_index {
+            let gene_d2 = model.seg_ds[d2_index]
+                .clone()
+                .seq_with_pal
+                .ok_or(anyhow!("Model not loaded correctly"))?;
+            reconstructed_seq.extend(&event.ins_dd.clone().unwrap().to_dna());
+            reconstructed_seq.extend(&gene_d2.extract_subsequence(
+                (-event.pos_d2 + event.start_d2) as usize,
+                (-event.pos_d2 + event.end_d2) as usize,
             ));
-            event.reconstructed_sequence = Some(reconstructed_seq);
-            self.best_event = Some(event);
-            self.load_human(model)?;
+        }
+        //            reconstructed_seq.extend(&event.d_segment.clone().unwrap());
+        reconstructed_seq.extend(&event.ins_dj.clone().unwrap().to_dna());
+        reconstructed_seq.extend(
+            &gene_j
+                .extract_padded_subsequence(event.start_j - event.j_start_seq, gene_j.len() as i64),
+        );
+        event.reconstructed_sequence = Some(reconstructed_seq);
+
+        let cigar_from_start = |al: &VJAlignment| -> Vec<(char, usize)> {
+            // the gapped alignments start with a match (see `set_cigar`)
+            let mut cigar = al.cigar.clone();
+            if let Some((_, n)) = cigar.first_mut() {
+                *n += al.start_seq;
+            }
+            cigar
+        };
+        event.v_cigar = sequence
+            .v_genes
+            .iter()
+            .find(|v| v.index == event.v_index && v.v_start_gene() == event.v_start_gene)
+            .map_or(Vec::new(), cigar_from_start);
+        event.j_cigar = sequence
+            .j_genes
+            .iter()
+            .find(|j| {
+                j.index == event.j_index
+                    && j.start_seq as i64 - j.start_gene as i64 == event.j_start_seq
+            })
+            .map_or(Vec::new(), cigar_from_start);
+
+        if !sequence.sequence.is_protein() {
+            if let Some((nb_v, nb_d, nb_j)) = Self::count_errors(event, sequence) {
+                event.nb_errors_v = Some(nb_v);
+                event.nb_errors_d = Some(nb_d);
+                event.nb_errors_j = Some(nb_j);
+            }
         }
         Ok(())
     }
 }
 
+impl ResultInference {
+    /// Number of mismatches in the V, D and J genes (after deletion)
+    /// for the event, using the alignments of the sequence (the errors
+    /// of both D genes are counted together in VDDJ models)
+    fn count_errors(event: &InfEvent, sequence: &vdj::Sequence) -> Option<(usize, usize, usize)> {
+        let val = sequence
+            .v_genes
+            .iter()
+            .find(|v| v.index == event.v_index && v.v_start_gene() == event.v_start_gene)?;
+        let jal = sequence.j_genes.iter().find(|j| {
+            j.index == event.j_index
+                && j.start_seq as i64 - j.start_gene as i64 == event.j_start_seq
+        })?;
+        let dal = sequence
+            .d_genes
+            .iter()
+            .find(|d| d.index == event.d_index && d.pos == event.pos_d)?;
+
+        let delv = usize::try_from(val.end_seq as i64 - event.end_v).ok()?;
+        let delj = usize::try_from(event.start_j - event.j_start_seq).ok()?;
+        let deld5 = usize::try_from(event.start_d - event.pos_d).ok()?;
+        let deld3 = usize::try_from(event.pos_d + dal.len() as i64 - event.end_d).ok()?;
+
+        let mut errors = (
+            val.nb_errors(delv),
+            dal.nb_errors(deld5, deld3),
+            jal.nb_errors(delj),
+        );
+        if let Some(d2_index) = event.d2_index {
+            let dal2 = sequence
+                .d_genes
+                .iter()
+                .find(|d| d.index == d2_index && d.pos == event.pos_d2)?;
+            let deld2_5 = usize::try_from(event.start_d2 - event.pos_d2).ok()?;
+            let deld2_3 = usize::try_from(event.pos_d2 + dal2.len() as i64 - event.end_d2).ok()?;
+            let errors_d2 = dal2.nb_errors(deld2_5, deld2_3);
+            if errors_d2 == MAX_NB_ERRORS {
+                return None;
+            }
+            errors.1 += errors_d2;
+        }
+        if errors.0 == MAX_NB_ERRORS || errors.1 == MAX_NB_ERRORS || errors.2 == MAX_NB_ERRORS {
+            return None;
+        }
+        Some(errors)
+    }
+}
+
 #[derive(Clone, Debug)]
 /// Generic "features" object that contains all the features of
 /// the model
 pub enum Features {
     VDJ(vdj::Features),
     VxDJ(v_dj::Features),
+    Graph(graph::Features),
+    VJ(vj::Features),
+    VDDJ(Box<vddj::Features>),
 }
 
 impl Features {
@@ -873,6 +1258,55 @@ impl Features {
         match self {
             Features::VDJ(x) => x.infer(sequence, ip),
             Features::VxDJ(x) => x.infer(sequence, ip),
+            Features::Graph(x) => x.infer(sequence, ip),
+            Features::VJ(x) => x.infer(sequence, ip),
+            Features::VDDJ(x) => x.infer(sequence, ip),
+        }
+    }
+
+    /// Draw `nb_samples` scenarios from the posterior P(scenario | sequence)
+    pub fn sample_posterior<R: Rng>(
+        &self,
+        sequence: &vdj::Sequence,
+        nb_samples: usize,
+        ip: &InferenceParameters,
+        rng: &mut R,
+    ) -> Result<Vec<InfEvent>> {
+        match self {
+            Features::VDJ(x) => x.sample_posterior(sequence, nb_samples, ip, rng),
+            Features::VxDJ(x) => x.sample_posterior(sequence, nb_samples, ip, rng),
+            _ => Err(anyhow!(
+                "Posterior sampling is only available for the VDJ and VxDJ models"
+            )),
+        }
+    }
+
+    /// Posterior marginals of the sequence, read from the expected counts
+    /// after `infer` (not available for the graph models)
+    pub fn posterior_marginals(&self) -> Option<PosteriorMarginals> {
+        match self {
+            Features::VDJ(x) => Some(x.posterior_marginals()),
+            Features::VxDJ(x) => Some(x.posterior_marginals()),
+            Features::VJ(x) => Some(x.posterior_marginals()),
+            Features::VDDJ(x) => Some(x.posterior_marginals()),
+            Features::Graph(_) => None,
+        }
+    }
+
+    /// Return the `k` most likely scenarios, sorted by decreasing likelihood
+    pub fn top_events(
+        &self,
+        sequence: &vdj::Sequence,
+        k: usize,
+        ip: &InferenceParameters,
+    ) -> Result<Vec<InfEvent>> {
+        match self {
+            Features::VDJ(x) => x.top_events(sequence, k, ip),
+            Features::VxDJ(x) => x.top_events(sequence, k, ip),
+            Features::VJ(x) => x.top_events(sequence, k, ip),
+            _ => Err(anyhow!(
+                "The most likely scenarios are only available for the VDJ, VxDJ and VJ models"
+            )),
         }
     }
 
@@ -880,6 +1314,9 @@ impl Features {
         match self {
             Features::VDJ(x) => x.normalize(),
             Features::VxDJ(x) => x.normalize(),
+            Features::Graph(x) => x.normalize(),
+            Features::VJ(x) => x.normalize(),
+            Features::VDDJ(x) => x.normalize(),
         }
     }
 
@@ -887,22 +1324,38 @@ impl Features {
         match self {
             Features::VDJ(x) => &x.error,
             Features::VxDJ(x) => &x.error,
+            Features::Graph(x) => &x.error,
+            Features::VJ(x) => &x.error,
+            Features::VDDJ(x) => &x.error,
         }
     }
     pub fn error_mut(&mut self) -> &mut FeatureError {
         match self {
             Features::VDJ(x) => &mut x.error,
             Features::VxDJ(x) => &mut x.error,
+            Features::Graph(x) => &mut x.error,
+            Features::VJ(x) => &mut x.error,
+            Features::VDDJ(x) => &mut x.error,
         }
     }
 
+    /// Update the model from the features of each sequence, `weights`
+    /// contains the weight of each sequence.
     pub fn update(
         features: Vec<Features>,
+        weights: &[f64],
         model: &mut ModelVDJ,
         ip: &InferenceParameters,
     ) -> Result<(Vec<Features>, f64)> {
+        if weights.len() != features.len() {
+            return Err(anyhow!(
+                "The number of weights ({}) and sequences ({}) differ",
+                weights.len(),
+                features.len()
+            ));
+        }
         Ok(match model.model_type {
-            ModelStructure::VDJ => {
+            ModelStructure::VDJ | ModelStructure::VDJInsGivenGene => {
                 let feats = vdj::Features::update(
                     features
                         .into_iter()
@@ -914,6 +1367,7 @@ impl Features {
                             }
                         })
                         .collect(),
+                    weights,
                     model,
                     ip,
                 )?;
@@ -931,11 +1385,38 @@ impl Features {
                             }
                         })
                         .collect(),
+                    weights,
                     model,
                     ip,
                 )?;
                 (feats.0.into_iter().map(Features::VxDJ).collect(), feats.1)
             }
+            ModelStructure::VDDJ => {
+                let feats = vddj::Features::update(
+                    features
+                        .into_iter()
+                        .filter_map(|x| {
+                            if let Features::VDDJ(f) = x {
+                                Some(*f)
+                            } else {
+                                None
+                            }
+                        })
+                        .collect(),
+                    weights,
+                    model,
+                    ip,
+                )?;
+                (
+                    feats
+                        .0
+                        .into_iter()
+                        .map(|f| Features::VDDJ(Box::new(f)))
+                        .collect(),
+                    feats.1,
+                )
+            }
+            ModelStructure::Graph => Err(anyhow!(GRAPH_INFERENCE_ERROR))?,
         })
     }
 }
@@ -0,0 +1,859 @@
+//! Read sequences from FASTA/TSV/AIRR files without loading the whole file in memory,
+//! write evaluation results following the AIRR Rearrangement schema.
+use crate::shared::utils::send_warning;
+use crate::shared::{genes_matching, Dna, DnaLike, Gene, Model, ResultInference};
+use crate::EntrySequence;
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Lines, Read, Write};
+
+/// Iterate over the records of a FASTA file, return (sequence id, sequence)
+/// Sequences are converted to upper case.
+pub struct FastaReader<R: BufRead> {
+    lines: Lines<R>,
+    next_id: Option<String>,
+}
+
+impl<R: BufRead> FastaReader<R> {
+    pub fn new(reader: R) -> FastaReader<R> {
+        FastaReader {
+            lines: reader.lines(),
+            next_id: None,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for FastaReader<R> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut current: Option<(String, String)> =
+            self.next_id.take().map(|id| (id, String::new()));
+        for line in self.lines.by_ref() {
+            let line = match line {
+                Ok(l) => l,
+                Err(e) => return Some(Err(e.into())),
+            };
+            let line = line.trim();
+            if let Some(header) = line.strip_prefix('>') {
+                let id = header.split_whitespace().next().unwrap_or("").to_string();
+                if current.is_some() {
+                    self.next_id = Some(id);
+                    return current.map(Ok);
+                }
+                current = Some((id, String::new()));
+            } else if !line.is_empty() {
+                match current.as_mut() {
+                    Some((_, seq)) => seq.push_str(&line.to_uppercase()),
+                    None => {
+                        return Some(Err(anyhow!(
+                            "Invalid fasta file, the first line should start with '>'"
+                        )))
+                    }
+                }
+            }
+        }
+        current.map(Ok)
+    }
+}
+
+/// Iterate over the rows of a TSV file (with a header), return
+/// (sequence id, sequence). If the id column is absent, the id is
+/// the index of the row.
+pub struct TsvReader<R: Read> {
+    records: csv::StringRecordsIntoIter<R>,
+    idx_seq: usize,
+    idx_id: Option<usize>,
+    row: usize,
+}
+
+impl<R: Read> TsvReader<R> {
+    pub fn new(reader: R, column: &str, id_column: &str) -> Result<TsvReader<R>> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .flexible(true)
+            .from_reader(reader);
+        let headers = rdr.headers()?.clone();
+        let idx_seq = headers
+            .iter()
+            .position(|h| h == column)
+            .ok_or(anyhow!("The TSV file has no \"{}\" column", column))?;
+        let idx_id = headers.iter().position(|h| h == id_column);
+        Ok(TsvReader {
+            records: rdr.into_records(),
+            idx_seq,
+            idx_id,
+            row: 0,
+        })
+    }
+}
+
+impl<R: Read> Iterator for TsvReader<R> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match self.records.next()? {
+            Ok(r) => r,
+            Err(e) => return Some(Err(e.into())),
+        };
+        let row = self.row;
+        self.row += 1;
+        let Some(seq) = record.get(self.idx_seq) else {
+            return Some(Err(anyhow!("Row {} has no sequence field", row)));
+        };
+        let id = match self.idx_id.and_then(|i| record.get(i)) {
+            Some(id) => id.to_string(),
+            None => row.to_string(),
+        };
+        Some(Ok((id, seq.trim().to_uppercase())))
+    }
+}
+
+/// One row of an AIRR Rearrangement TSV file
+#[derive(Clone, Debug)]
+pub struct AirrRecord {
+    pub sequence_id: String,
+    pub sequence: EntrySequence,
+    pub productive: Option<bool>,
+    pub duplicate_count: usize,
+}
+
+/// Iterate over the rows of an AIRR Rearrangement TSV file.
+/// A row with a `sequence` is read as a full nucleotide sequence, otherwise
+/// (or if `use_junction` is set) the `junction` and the `v_call`/`j_call`
+/// columns are used. Rows with unknown genes are skipped, with a warning.
+pub struct AirrReader<'a, R: Read> {
+    records: csv::StringRecordsIntoIter<R>,
+    model: &'a Model,
+    columns: HashMap<String, usize>,
+    productive_only: bool,
+    use_junction: bool,
+    // gene names already resolved by `genes_matching`
+    known_genes: HashMap<String, Vec<Gene>>,
+    unknown_genes: HashSet<String>,
+    skipped: Vec<String>,
+    row: usize,
+    finished: bool,
+}
+
+impl<'a, R: Read> AirrReader<'a, R> {
+    /// `productive_only`: only keep the rows with `productive` equal to true
+    /// `use_junction`: ignore the `sequence` column, and use the junction + V/J calls
+    pub fn new(
+        reader: R,
+        model: &'a Model,
+        productive_only: bool,
+        use_junction: bool,
+    ) -> Result<AirrReader<'a, R>> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .flexible(true)
+            .from_reader(reader);
+        let columns: HashMap<String, usize> = rdr
+            .headers()?
+            .iter()
+            .enumerate()
+            .map(|(ii, h)| (h.to_string(), ii))
+            .collect();
+        let has_junction = ["junction", "v_call", "j_call"]
+            .iter()
+            .all(|c| columns.contains_key(*c));
+        if use_junction && !has_junction {
+            return Err(anyhow!(
+                "The AIRR file needs the \"junction\", \"v_call\" and \"j_call\" columns"
+            ));
+        }
+        if !columns.contains_key("sequence") && !has_junction {
+            return Err(anyhow!(
+                "The AIRR file needs either a \"sequence\" column or the \"junction\", \"v_call\" and \"j_call\" columns"
+            ));
+        }
+        if productive_only && !columns.contains_key("productive") {
+            return Err(anyhow!("The AIRR file has no \"productive\" column"));
+        }
+        Ok(AirrReader {
+            records: rdr.into_records(),
+            model,
+            columns,
+            productive_only,
+            use_junction,
+            known_genes: HashMap::new(),
+            unknown_genes: HashSet::new(),
+            skipped: Vec::new(),
+            row: 0,
+            finished: false,
+        })
+    }
+
+    /// Ids of the rows skipped so far (unknown genes or invalid sequences)
+    pub fn skipped(&self) -> &[String] {
+        &self.skipped
+    }
+
+    /// Resolve a (possibly comma-separated) AIRR gene call
+    fn genes(&mut self, call: &str) -> Vec<Gene> {
+        let mut genes: Vec<Gene> = Vec::new();
+        for name in call.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            if !self.known_genes.contains_key(name) {
+                let mut matching = genes_matching(name, self.model, true).unwrap_or_default();
+                if matching.is_empty() {
+                    matching = genes_matching(name, self.model, false).unwrap_or_default();
+                }
+                self.known_genes.insert(name.to_string(), matching);
+            }
+            for g in &self.known_genes[name] {
+                if !genes.iter().any(|x| x.name == g.name) {
+                    genes.push(g.clone());
+                }
+            }
+        }
+        genes
+    }
+
+    /// Convert one row, return None if the row is filtered out
+    fn parse(
+        &mut self,
+        record: &csv::StringRecord,
+        sequence_id: &str,
+    ) -> Result<Option<AirrRecord>> {
+        let field = |name: &str| {
+            self.columns
+                .get(name)
+                .and_then(|&i| record.get(i))
+                .map(|x| x.trim())
+                .unwrap_or("")
+        };
+        let productive = match field("productive").to_uppercase().as_str() {
+            "T" | "TRUE" => Some(true),
+            "F" | "FALSE" => Some(false),
+            _ => None,
+        };
+        if self.productive_only && productive != Some(true) {
+            return Ok(None);
+        }
+        let duplicate_count = match field("duplicate_count") {
+            "" => 1,
+            x => x
+                .parse::<usize>()
+                .with_context(|| format!("Invalid duplicate_count for sequence {sequence_id}"))?,
+        };
+
+        let (sequence, junction) = (
+            field("sequence").to_uppercase(),
+            field("junction").to_uppercase(),
+        );
+        let (v_call, j_call) = (field("v_call").to_string(), field("j_call").to_string());
+        let entry = if !self.use_junction && !sequence.is_empty() {
+            EntrySequence::NucleotideSequence(DnaLike::from_dna(Dna::from_string(&sequence)?))
+        } else if !junction.is_empty() {
+            let vgenes = self.genes(&v_call);
+            let jgenes = self.genes(&j_call);
+            for (call, genes) in [(&v_call, &vgenes), (&j_call, &jgenes)] {
+                if genes.is_empty() {
+                    // only warn once per unknown gene
+                    if self.unknown_genes.insert(call.clone()) {
+                        send_warning(&format!(
+                            "Unknown gene \"{call}\" (sequence {sequence_id}), the sequences with this gene are ignored"
+                        ));
+                    }
+                    self.skipped.push(sequence_id.to_string());
+                    return Ok(None);
+                }
+            }
+            EntrySequence::NucleotideCDR3((
+                DnaLike::from_dna(Dna::from_string(&junction)?),
+                vgenes,
+                jgenes,
+            ))
+        } else {
+            return Err(anyhow!("Empty sequence and junction"));
+        };
+
+        Ok(Some(AirrRecord {
+            sequence_id: sequence_id.to_string(),
+            sequence: entry,
+            productive,
+            duplicate_count,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for AirrReader<'_, R> {
+    type Item = Result<AirrRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let record = match self.records.next() {
+                Some(Ok(r)) => r,
+                Some(Err(e)) => return Some(Err(e.into())),
+                None => {
+                    if !self.finished && !self.skipped.is_empty() {
+                        send_warning(&format!(
+                            "{} rows of the AIRR file were skipped",
+                            self.skipped.len()
+                        ));
+                    }
+                    self.finished = true;
+                    return None;
+                }
+            };
+            let row = self.row;
+            self.row += 1;
+            let sequence_id = match self.columns.get("sequence_id").and_then(|&i| record.get(i)) {
+                Some(id) => id.to_string(),
+                None => row.to_string(),
+            };
+            match self.parse(&record, &sequence_id) {
+                Ok(Some(r)) => return Some(Ok(r)),
+                Ok(None) => continue,
+                Err(e) => {
+                    send_warning(&format!("Sequence {sequence_id} is skipped: {e}"));
+                    self.skipped.push(sequence_id);
+                }
+            }
+        }
+    }
+}
+
+/// Columns of the AIRR Rearrangement TSV, the last two are righor-specific
+pub const AIRR_COLUMNS: [&str; 43] = [
+    "sequence_id",
+    "sequence",
+    "rev_comp",
+    "productive",
+    "vj_in_frame",
+    "stop_codon",
+    "v_call",
+    "d_call",
+    "d2_call",
+    "j_call",
+    "sequence_alignment",
+    "germline_alignment",
+    "junction",
+    "junction_aa",
+    "junction_length",
+    "v_cigar",
+    "d_cigar",
+    "d2_cigar",
+    "j_cigar",
+    "v_sequence_start",
+    "v_sequence_end",
+    "v_germline_start",
+    "v_germline_end",
+    "d_sequence_start",
+    "d_sequence_end",
+    "d_germline_start",
+    "d_germline_end",
+    "d2_sequence_start",
+    "d2_sequence_end",
+    "d2_germline_start",
+    "d2_germline_end",
+    "j_sequence_start",
+    "j_sequence_end",
+    "j_germline_start",
+    "j_germline_end",
+    "np1",
+    "np1_length",
+    "np2",
+    "np2_length",
+    "np3",
+    "np3_length",
+    "pgen",
+    "likelihood",
+];
+
+/// Position of a gene in the sequence and in the germline,
+/// 0-based, end excluded (AIRR uses 1-based positions, end included)
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct AirrSegment {
+    pub sequence_start: usize,
+    pub sequence_end: usize,
+    pub germline_start: usize,
+    /// Gapped alignment between `sequence_start..sequence_end` and the
+    /// germline (M/I/D operations), empty if the alignment has no indels
+    pub cigar: Vec<(char, usize)>,
+}
+
+impl AirrSegment {
+    /// Germline part of the gene that appears in the sequence between
+    /// `start` and `end`, `offset` is the position of the first nucleotide of
+    /// the (non-palindromic) gene in the sequence.
+    fn new(start: i64, end: i64, offset: i64, gene_length: usize) -> Option<AirrSegment> {
+        let sequence_start = start.max(offset).max(0);
+        let sequence_end = end.min(offset + gene_length as i64);
+        if sequence_end <= sequence_start {
+            return None;
+        }
+        Some(AirrSegment {
+            sequence_start: sequence_start as usize,
+            sequence_end: sequence_end as usize,
+            germline_start: (sequence_start - offset) as usize,
+            cigar: Vec::new(),
+        })
+    }
+
+    /// Same as `new` for a gapped alignment: `cigar` (M/I/D) starts at the
+    /// position 0 of the sequence, aligned with the position `gene_start` of
+    /// the (non-palindromic) gene. Only the part between `start` and `end`
+    /// and inside the gene is kept.
+    fn from_cigar(
+        cigar: &[(char, usize)],
+        gene_start: i64,
+        start: i64,
+        end: i64,
+        gene_length: usize,
+    ) -> Option<AirrSegment> {
+        let (mut idx_seq, mut idx_gene) = (0, gene_start);
+        let mut operations = Vec::new();
+        for &(op, n) in cigar {
+            for _ in 0..n {
+                let in_sequence = (start..end).contains(&idx_seq);
+                let in_gene = (0..gene_length as i64).contains(&idx_gene);
+                match op {
+                    'M' => {
+                        if in_sequence && in_gene {
+                            operations.push((op, idx_seq, idx_gene));
+                        }
+                        idx_seq += 1;
+                        idx_gene += 1;
+                    }
+                    'I' => {
+                        if in_sequence {
+                            operations.push((op, idx_seq, idx_gene));
+                        }
+                        idx_seq += 1;
+                    }
+                    _ => {
+                        if in_gene {
+                            operations.push((op, idx_seq, idx_gene));
+                        }
+                        idx_gene += 1;
+                    }
+                }
+            }
+        }
+        // the alignment starts and ends with a match
+        let first = operations.iter().position(|x| x.0 == 'M')?;
+        let last = operations.iter().rposition(|x| x.0 == 'M')?;
+        let operations = &operations[first..=last];
+
+        let mut cigar: Vec<(char, usize)> = Vec::new();
+        for &(op, _, _) in operations {
+            match cigar.last_mut() {
+                Some((c, n)) if *c == op => *n += 1,
+                _ => cigar.push((op, 1)),
+            }
+        }
+        Some(AirrSegment {
+            sequence_start: operations[0].1 as usize,
+            sequence_end: operations[operations.len() - 1].1 as usize + 1,
+            germline_start: operations[0].2 as usize,
+            cigar: if cigar.len() > 1 { cigar } else { Vec::new() },
+        })
+    }
+
+    fn germline_end(&self) -> usize {
+        if self.cigar.is_empty() {
+            return self.germline_start + self.sequence_end - self.sequence_start;
+        }
+        self.germline_start
+            + self
+                .cigar
+                .iter()
+                .filter(|(c, _)| *c != 'I')
+                .map(|(_, n)| n)
+                .sum::<usize>()
+    }
+
+    /// Number of (inserted, deleted) nucleotides in the alignment
+    fn indels(&self) -> (usize, usize) {
+        self.cigar.iter().fold((0, 0), |(i, d), &(c, n)| match c {
+            'I' => (i + n, d),
+            'D' => (i, d + n),
+            _ => (i, d),
+        })
+    }
+
+    /// Operations of the alignment (M/I/D)
+    fn operations(&self) -> Vec<(char, usize)> {
+        if self.cigar.is_empty() {
+            vec![('M', self.sequence_end - self.sequence_start)]
+        } else {
+            self.cigar.clone()
+        }
+    }
+
+    /// CIGAR string with respect to the sequence
+    fn cigar(&self, sequence_length: usize) -> String {
+        std::iter::once((self.sequence_start, 'S'))
+            .chain(std::iter::once((self.germline_start, 'N')))
+            .chain(self.operations().into_iter().map(|(c, n)| (n, c)))
+            .chain(std::iter::once((
+                sequence_length.saturating_sub(self.sequence_end),
+                'S',
+            )))
+            .filter(|(n, _)| *n > 0)
+            .map(|(n, c)| format!("{n}{c}"))
+            .collect()
+    }
+
+    /// Append the aligned sequence and germline (`-` for the gaps) of the segment
+    fn align(&self, sequence: &[u8], gene: &[u8], aligned: &mut (Vec<u8>, Vec<u8>)) {
+        let (mut idx_seq, mut idx_gene) = (self.sequence_start, self.germline_start);
+        for (op, n) in self.operations() {
+            for _ in 0..n {
+                match op {
+                    'M' => {
+                        aligned.0.push(sequence[idx_seq]);
+                        aligned.1.push(gene[idx_gene]);
+                        idx_seq += 1;
+                        idx_gene += 1;
+                    }
+                    'I' => {
+                        aligned.0.push(sequence[idx_seq]);
+                        aligned.1.push(b'-');
+                        idx_seq += 1;
+                    }
+                    _ => {
+                        aligned.0.push(b'-');
+                        aligned.1.push(gene[idx_gene]);
+                        idx_gene += 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// One row of the AIRR Rearrangement schema, built from the best
+/// scenario of an evaluated sequence.
+#[derive(Default, Clone, Debug)]
+pub struct AirrRearrangement {
+    pub sequence_id: String,
+    pub sequence: String,
+    pub productive: Option<bool>,
+    pub vj_in_frame: Option<bool>,
+    pub stop_codon: Option<bool>,
+    pub v_call: String,
+    pub d_call: String,
+    pub d2_call: String,
+    pub j_call: String,
+    pub sequence_alignment: String,
+    pub germline_alignment: String,
+    pub junction: String,
+    pub junction_aa: String,
+    pub v: Option<AirrSegment>,
+    pub d: Option<AirrSegment>,
+    pub d2: Option<AirrSegment>,
+    pub j: Option<AirrSegment>,
+    pub np1: String,
+    pub np2: String,
+    pub np3: String,
+    pub pgen: f64,
+    pub likelihood: f64,
+}
+
+impl AirrRearrangement {
+    /// Build the AIRR row from the result of `Model::evaluate`. The result
+    /// must contain the (filled) best event, otherwise only the id, pgen and
+    /// likelihood are set.
+    pub fn from_result(
+        sequence_id: &str,
+        result: &ResultInference,
+        model: &Model,
+    ) -> Result<AirrRearrangement> {
+        let mut airr = AirrRearrangement {
+            sequence_id: sequence_id.to_string(),
+            pgen: result.pgen,
+            likelihood: result.likelihood,
+            ..Default::default()
+        };
+        let Some(event) = result.best_event.as_ref() else {
+            return Ok(airr);
+        };
+        let (vdj_model, has_d) = match model {
+            Model::VDJ(x) => (x, true),
+            Model::VJ(x) => (&x.inner, false),
+        };
+        let sequence = event
+            .sequence
+            .as_ref()
+            .ok_or(anyhow!("The best event was not filled"))?;
+        let length = sequence.len() as i64;
+
+        airr.sequence = sequence.get_string();
+        airr.v_call = vdj_model.get_v_gene(event);
+        airr.j_call = vdj_model.get_j_gene(event);
+        if has_d {
+            airr.d_call = vdj_model.seg_ds[event.d_index].name.clone();
+            if let Some(d2_index) = event.d2_index {
+                airr.d2_call = vdj_model.seg_ds[d2_index].name.clone();
+            }
+        }
+
+        // the alignments continue (without indels) up to the end of the sequence
+        let extended = |cigar: &[(char, usize)]| -> Vec<(char, usize)> {
+            cigar
+                .iter()
+                .copied()
+                .chain(std::iter::once(('M', sequence.len())))
+                .collect()
+        };
+        // the genes with palindromic insertions start before the actual genes
+        let len_v = vdj_model.seg_vs[event.v_index].seq.len();
+        airr.v = if event.v_cigar.is_empty() {
+            AirrSegment::new(0, event.end_v, -(event.v_start_gene as i64), len_v)
+        } else {
+            // `v_start_gene` is valid after the indels
+            let shift = event.v_cigar.iter().fold(0, |acc, &(c, n)| match c {
+                'I' => acc + n as i64,
+                'D' => acc - n as i64,
+                _ => acc,
+            });
+            AirrSegment::from_cigar(
+                &extended(&event.v_cigar),
+                event.v_start_gene as i64 + shift,
+                0,
+                event.end_v,
+                len_v,
+            )
+        };
+        if has_d {
+            airr.d = AirrSegment::new(
+                event.start_d,
+                event.end_d,
+                event.pos_d - vdj_model.range_del_d5.0,
+                vdj_model.seg_ds[event.d_index].seq.len(),
+            );
+            if let Some(d2_index) = event.d2_index {
+                airr.d2 = AirrSegment::new(
+                    event.start_d2,
+                    event.end_d2,
+                    event.pos_d2 - vdj_model.range_del_d5.0,
+                    vdj_model.seg_ds[d2_index].seq.len(),
+                );
+            }
+        }
+        let len_j = vdj_model.seg_js[event.j_index].seq.len();
+        airr.j = if event.j_cigar.is_empty() {
+            AirrSegment::new(
+                event.start_j,
+                length,
+                event.j_start_seq - vdj_model.range_del_j.0,
+                len_j,
+            )
+        } else {
+            AirrSegment::from_cigar(
+                &extended(&event.j_cigar),
+                vdj_model.range_del_j.0 - event.j_start_seq,
+                event.start_j,
+                length,
+                len_j,
+            )
+        };
+
+        // everything that is not germline is part of the N/P regions
+        let end_v = airr.v.as_ref().map_or(0, |x| x.sequence_end as i64);
+        let start_j = airr.j.as_ref().map_or(length, |x| x.sequence_start as i64);
+        match (&airr.d, &airr.d2) {
+            (Some(d), Some(d2)) => {
+                airr.np1 = sequence
+                    .extract_padded_subsequence(end_v, d.sequence_start as i64)
+                    .get_string();
+                airr.np2 = sequence
+                    .extract_padded_subsequence(d.sequence_end as i64, d2.sequence_start as i64)
+                    .get_string();
+                airr.np3 = sequence
+                    .extract_padded_subsequence(d2.sequence_end as i64, start_j)
+                    .get_string();
+            }
+            (Some(d), None) => {
+                airr.np1 = sequence
+                    .extract_padded_subsequence(end_v, d.sequence_start as i64)
+                    .get_string();
+                airr.np2 = sequence
+                    .extract_padded_subsequence(d.sequence_end as i64, start_j)
+                    .get_string();
+            }
+            (None, _) => {
+                airr.np1 = sequence
+                    .extract_padded_subsequence(end_v, start_j.max(end_v))
+                    .get_string();
+            }
+        }
+
+        airr.sequence_alignment = airr.sequence.clone();
+        if let Some(reconstructed) = &event.reconstructed_sequence {
+            // the reconstructed sequence is aligned with the sequence after the
+            // indels of V and before the ones of J
+            let germline = |start: usize, end: usize, shift: i64| {
+                let offset = event.v_start_gene as i64 + shift;
+                reconstructed
+                    .extract_padded_subsequence(start as i64 + offset, end as i64 + offset)
+                    .seq
+            };
+            let gapped = [
+                (&airr.v, &vdj_model.seg_vs[event.v_index].seq, true),
+                (&airr.j, &vdj_model.seg_js[event.j_index].seq, false),
+            ];
+            let seq = airr.sequence.as_bytes();
+            let mut aligned = (Vec::new(), Vec::new());
+            let (mut pos, mut shift) = (0, 0);
+            for (segment, gene, is_v) in gapped {
+                let Some(segment) = segment.as_ref().filter(|x| !x.cigar.is_empty()) else {
+                    continue;
+                };
+                let (insertions, deletions) = segment.indels();
+                if is_v {
+                    shift = insertions as i64 - deletions as i64;
+                }
+                aligned.0.extend(&seq[pos..segment.sequence_start]);
+                aligned
+                    .1
+                    .extend(germline(pos, segment.sequence_start, shift));
+                segment.align(seq, &gene.seq, &mut aligned);
+                pos = segment.sequence_end;
+                shift = if is_v {
+                    0
+                } else {
+                    deletions as i64 - insertions as i64
+                };
+            }
+            aligned.0.extend(&seq[pos..]);
+            aligned.1.extend(germline(pos, seq.len(), shift));
+            airr.sequence_alignment = String::from_utf8(aligned.0)?;
+            airr.germline_alignment = String::from_utf8(aligned.1)?;
+        }
+
+        if let Some(junction) = &event.junction {
+            airr.junction = junction.get_string();
+            let in_frame = junction.len() % 3 == 0;
+            airr.vj_in_frame = Some(in_frame);
+            if in_frame {
+                airr.junction_aa = junction.translate()?.to_string();
+                let stop = airr.junction_aa.contains('*');
+                airr.stop_codon = Some(stop);
+                airr.productive = Some(!stop);
+            } else {
+                airr.productive = Some(false);
+            }
+        }
+        Ok(airr)
+    }
+
+    /// Return the fields of the row, in the order of `AIRR_COLUMNS`
+    pub fn to_record(&self) -> Vec<String> {
+        let boolean = |x: Option<bool>| match x {
+            Some(true) => "T".to_string(),
+            Some(false) => "F".to_string(),
+            None => String::new(),
+        };
+        let length = self.sequence.len();
+        let segment = |x: &Option<AirrSegment>| match x {
+            Some(s) => [
+                s.cigar(length),
+                (s.sequence_start + 1).to_string(),
+                s.sequence_end.to_string(),
+                (s.germline_start + 1).to_string(),
+                s.germline_end().to_string(),
+            ],
+            None => Default::default(),
+        };
+        let [v_cigar, v_seq_start, v_seq_end, v_germ_start, v_germ_end] = segment(&self.v);
+        let [d_cigar, d_seq_start, d_seq_end, d_germ_start, d_germ_end] = segment(&self.d);
+        let [d2_cigar, d2_seq_start, d2_seq_end, d2_germ_start, d2_germ_end] = segment(&self.d2);
+        let [j_cigar, j_seq_start, j_seq_end, j_germ_start, j_germ_end] = segment(&self.j);
+        let filled = !self.sequence.is_empty();
+        let length_or_empty = |x: &String| {
+            if filled {
+                x.len().to_string()
+            } else {
+                String::new()
+            }
+        };
+
+        vec![
+            self.sequence_id.clone(),
+            self.sequence.clone(),
+            if filled {
+                "F".to_string()
+            } else {
+                String::new()
+            },
+            boolean(self.productive),
+            boolean(self.vj_in_frame),
+            boolean(self.stop_codon),
+            self.v_call.clone(),
+            self.d_call.clone(),
+            self.d2_call.clone(),
+            self.j_call.clone(),
+            self.sequence_alignment.clone(),
+            self.germline_alignment.clone(),
+            self.junction.clone(),
+            self.junction_aa.clone(),
+            length_or_empty(&self.junction),
+            v_cigar,
+            d_cigar,
+            d2_cigar,
+            j_cigar,
+            v_seq_start,
+            v_seq_end,
+            v_germ_start,
+            v_germ_end,
+            d_seq_start,
+            d_seq_end,
+            d_germ_start,
+            d_germ_end,
+            d2_seq_start,
+            d2_seq_end,
+            d2_germ_start,
+            d2_germ_end,
+            j_seq_start,
+            j_seq_end,
+            j_germ_start,
+            j_germ_end,
+            self.np1.clone(),
+            length_or_empty(&self.np1),
+            self.np2.clone(),
+            // np2 only exists if there is a D gene
+            match self.d {
+                Some(_) => self.np2.len().to_string(),
+                None => String::new(),
+            },
+            self.np3.clone(),
+            // np3 only exists if there is a second D gene
+            match self.d2 {
+                Some(_) => self.np3.len().to_string(),
+                None => String::new(),
+            },
+            format!("{:e}", self.pgen),
+            format!("{:e}", self.likelihood),
+        ]
+    }
+}
+
+/// Write AIRR Rearrangement rows in a TSV file (header included)
+pub struct AirrWriter<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: Write> AirrWriter<W> {
+    pub fn new(writer: W) -> Result<AirrWriter<W>> {
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .quote_style(csv::QuoteStyle::Never)
+            .from_writer(writer);
+        writer.write_record(AIRR_COLUMNS)?;
+        Ok(AirrWriter { writer })
+    }
+
+    pub fn write(&mut self, rearrangement: &AirrRearrangement) -> Result<()> {
+        self.writer.write_record(rearrangement.to_record())?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
@@ -10,7 +10,7 @@ use crate::shared::sequence::{AminoAcid, Dna, DnaLike, DnaLikeEnum};
 use crate::shared::{
     amino_acids::DegenerateCodon, nucleotides_inv, utils::normalize_transition_matrix,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use ndarray::Array2;
 use serde::{de, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::HashMap;
@@ -18,6 +18,10 @@ use std::collections::HashMap;
 #[derive(Clone, PartialEq, Debug, Default)]
 pub struct DNAMarkovChain {
     pub transition_matrix: Array2<f64>,
+    // transition matrices of order 2, 3, ... (shape (4^k, 4)), the row is
+    // the context (the k previous nucleotides, oldest first, in base 4).
+    // Empty for a first-order chain. Only used with non-degenerate dna.
+    pub higher_order: Vec<Array2<f64>>,
     // pre-computed data for dealing with the degenerate nucleotides
     pub degenerate_matrix: Vec<Matrix4>, // likelihood matrix for the degenerate dna
     aa_lone_rev: HashMap<(u8, usize, usize, usize), Matrix16>,
@@ -38,8 +42,9 @@ impl Serialize for DNAMarkovChain {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("DNAMarkovChain", 1)?;
+        let mut state = serializer.serialize_struct("DNAMarkovChain", 3)?;
         state.serialize_field("transition_matrix", &self.transition_matrix)?;
+        state.serialize_field("higher_order", &self.higher_order)?;
         state.serialize_field("reverse", &self.reverse)?;
         state.end()
     }
@@ -55,19 +60,22 @@ impl<'de> Deserialize<'de> for DNAMarkovChain {
         #[derive(Deserialize)]
         struct MyStructData {
             pub transition_matrix: Array2<f64>,
+            #[serde(default)]
+            pub higher_order: Vec<Array2<f64>>,
             pub reverse: bool,
         }
 
         // Deserialize `field` and initialize `computed_field`
         let data = MyStructData::deserialize(deserializer)?;
 
-        DNAMarkovChain::new(&data.transition_matrix, data.reverse).map_err(de::Error::custom)
+        DNAMarkovChain::new_higher_order(&data.transition_matrix, &data.higher_order, data.reverse)
+            .map_err(de::Error::custom)
     }
 }
 
 impl DNAMarkovChain {
     pub fn reinitialize(&mut self) -> Result<Self> {
-        Self::new(&self.transition_matrix, self.reverse)
+        Self::new_higher_order(&self.transition_matrix, &self.higher_order, self.reverse)
     }
 
     pub fn new(transition_matrix: &Array2<f64>, reverse: bool) -> Result<DNAMarkovChain> {
@@ -79,6 +87,77 @@ impl DNAMarkovChain {
         Ok(mc)
     }
 
+    /// Markov chain of order `higher_order.len() + 1`, `higher_order[k - 2]`
+    /// is the (4^k, 4) transition matrix of order k.
+    /// When fewer than k nucleotides are known (start of the insertion),
+    /// the matrix of the largest available order is used, so the first
+    /// inserted nucleotide only depends on the flanking one.
+    pub fn new_higher_order(
+        transition_matrix: &Array2<f64>,
+        higher_order: &[Array2<f64>],
+        reverse: bool,
+    ) -> Result<DNAMarkovChain> {
+        let mut mc = DNAMarkovChain::new(transition_matrix, reverse)?;
+        for (ii, matrix) in higher_order.iter().enumerate() {
+            if matrix.dim() != (4_usize.pow(ii as u32 + 2), 4) {
+                return Err(anyhow!(
+                    "Wrong shape for the order {} Markov transition matrix",
+                    ii + 2
+                ));
+            }
+            let mut normalized = normalize_transition_matrix(matrix)?;
+            // contexts never observed fall back on the lower order
+            let previous = mc.higher_order.last().unwrap_or(&mc.transition_matrix);
+            for ctx in 0..normalized.dim().0 {
+                if normalized.row(ctx).sum() == 0. {
+                    let fallback = previous.row(ctx % previous.dim().0).to_owned();
+                    normalized.row_mut(ctx).assign(&fallback);
+                }
+            }
+            mc.higher_order.push(normalized);
+        }
+        Ok(mc)
+    }
+
+    /// Same chain, with the order changed to `order`. The new higher-order
+    /// transitions are initialized from the lower order ones (the
+    /// likelihood of the insertions is unchanged).
+    pub fn with_order(&self, order: usize) -> Result<DNAMarkovChain> {
+        if order == 0 {
+            return Err(anyhow!(
+                "The order of the Markov chain should be at least 1"
+            ));
+        }
+        let mut higher_order = self.higher_order.clone();
+        higher_order.truncate(order - 1);
+        while higher_order.len() < order - 1 {
+            let previous = higher_order.last().unwrap_or(&self.transition_matrix);
+            // the context "x_1 ... x_k" gets the transitions of "x_2 ... x_k"
+            let nb_contexts = 4 * previous.dim().0;
+            higher_order.push(Array2::from_shape_fn((nb_contexts, 4), |(ctx, nt)| {
+                previous[[ctx % previous.dim().0, nt]]
+            }));
+        }
+        Self::new_higher_order(&self.transition_matrix, &higher_order, self.reverse)
+    }
+
+    pub fn order(&self) -> usize {
+        self.higher_order.len() + 1
+    }
+
+    /// Transition matrix used when `nb_known` nucleotides precede the
+    /// current one, and the number of nucleotides used as context
+    fn matrix_given_known(&self, nb_known: usize) -> (&Array2<f64>, usize) {
+        let k = nb_known.min(self.order());
+        if k <= 1 {
+            (&self.transition_matrix, 1)
+        } else {
+            (&self.higher_order[k - 2], k)
+        }
+    }
+
+    /// Degenerate and amino-acid sequences fall back on the first-order
+    /// matrix, the higher orders are only used with plain DNA
     pub fn likelihood(&self, sequence: &DnaLike, first_nucleotide: usize) -> Likelihood {
         match sequence.clone().into() {
             DnaLikeEnum::Known(s) => self.likelihood_dna(&s, first_nucleotide),
@@ -122,6 +201,14 @@ impl DNAMarkovChain {
             new_s.reverse();
         }
 
+        if !self.higher_order.is_empty() {
+            let mut proba = 1.;
+            self.for_each_transition(&new_s, first, |matrix, ctx, nt| {
+                proba *= matrix[[ctx, nt]];
+            });
+            return Likelihood::Scalar(proba);
+        }
+
         let mut proba = self.transition_matrix[[first, nucleotides_inv(new_s.seq[0])]];
         for ii in 1..new_s.len() {
             proba *= self.transition_matrix[[
@@ -132,6 +219,62 @@ impl DNAMarkovChain {
         Likelihood::Scalar(proba)
     }
 
+    /// Call `f(matrix, context, nucleotide)` for each nucleotide of `s`
+    /// (already in the direction of the chain), with the highest-order
+    /// transition matrix available.
+    fn for_each_transition<F: FnMut(&Array2<f64>, usize, usize)>(
+        &self,
+        s: &Dna,
+        first: usize,
+        mut f: F,
+    ) {
+        // known nucleotides, `first` then the sequence
+        let nts: Vec<usize> = std::iter::once(first)
+            .chain(s.seq.iter().map(|&x| nucleotides_inv(x)))
+            .collect();
+        for ii in 1..nts.len() {
+            let (matrix, k) = self.matrix_given_known(ii);
+            let ctx = nts[ii - k..ii].iter().fold(0, |acc, &x| 4 * acc + x);
+            f(matrix, ctx, nts[ii]);
+        }
+    }
+
+    /// Dirty counts of the higher-order transitions (one matrix per order,
+    /// like `higher_order`). Each transition is counted in the matrix that
+    /// scores it (see `matrix_given_known`), the k-th inserted nucleotide
+    /// (k < order) in the order k matrix, the following ones in the highest
+    /// order matrix.
+    pub fn update_higher_order(
+        &self,
+        sequence: &DnaLike,
+        first: usize,
+        likelihood: f64,
+    ) -> Vec<Array2<f64>> {
+        let mut counts: Vec<Array2<f64>> = self
+            .higher_order
+            .iter()
+            .map(|m| Array2::zeros(m.dim()))
+            .collect();
+        // degenerate and amino-acid sequences only use the first order
+        let DnaLikeEnum::Known(mut s) = sequence.clone().into() else {
+            return counts;
+        };
+        if self.reverse {
+            s.reverse();
+        }
+        let nts: Vec<usize> = std::iter::once(first)
+            .chain(s.seq.iter().map(|&x| nucleotides_inv(x)))
+            .collect();
+        for ii in 1..nts.len() {
+            let k = ii.min(self.order());
+            if k >= 2 {
+                let ctx = nts[ii - k..ii].iter().fold(0, |acc, &x| 4 * acc + x);
+                counts[k - 2][[ctx, nts[ii]]] += likelihood;
+            }
+        }
+        counts
+    }
+
     pub fn update_dna(&self, s: &Dna, first: usize, likelihood: f64) -> Array2<f64> {
         let mut transition_mat = Array2::zeros((4, 4));
         let mut new_s = s.clone();
@@ -139,6 +282,11 @@ impl DNAMarkovChain {
             new_s.reverse();
         }
         transition_mat[[first, nucleotides_inv(new_s.seq[0])]] += likelihood;
+        // with a higher-order chain, only the first transition uses the
+        // first-order matrix (see `update_higher_order`)
+        if !self.higher_order.is_empty() {
+            return transition_mat;
+        }
         for ii in 1..new_s.len() {
             transition_mat[[
                 nucleotides_inv(new_s.seq[ii - 1]),
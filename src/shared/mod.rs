@@ -7,6 +7,7 @@ pub mod errors;
 pub mod event;
 pub mod feature;
 pub mod gene;
+pub mod io;
 pub mod likelihood;
 pub mod markov_chain;
 pub mod model;
@@ -22,7 +23,8 @@ pub use errors::{ErrorParameters, FeatureError};
 pub use event::StaticEvent;
 pub use feature::{
     CategoricalFeature1, CategoricalFeature1g1, CategoricalFeature1g2, CategoricalFeature2,
-    CategoricalFeature2g1, Feature, Features, InfEvent, InsertionFeature, ResultInference,
+    CategoricalFeature2g1, Feature, Features, InfEvent, InsertionFeature, PosteriorMarginals,
+    ResultCompact, ResultInference,
 };
 
 pub use alignment::{
@@ -34,7 +36,13 @@ pub use likelihood::{
     LikelihoodType,
 };
 pub use markov_chain::DNAMarkovChain;
-pub use model::{GenerationResult, Generator, Model, ModelStructure, Modelable};
-pub use parameters::{AlignmentParameters, InferenceParameters};
+pub use model::{
+    BootstrapResult, EvaluationStream, FitResult, GenerationResult, Generator, Model,
+    ModelStructure, Modelable,
+};
+pub use parameters::{
+    AlignmentParameters, BootstrapParameters, FitParameters, GenerationConstraints,
+    InferenceParameters, Pseudocounts,
+};
 pub use sequence::{nucleotides_inv, AminoAcid, Dna, DnaLike, SequenceType};
 pub use utils::RecordModel;
@@ -1,20 +1,25 @@
 use crate::shared::alignment::VJAlignment;
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
 use crate::shared::event::PyStaticEvent;
-use crate::shared::gene::Gene;
+use crate::shared::gene::{Gene, ModelGen};
 use crate::shared::markov_chain::DNAMarkovChain;
 use crate::shared::sequence::Dna;
 use crate::shared::utils::get_batches;
-use crate::shared::ResultInference;
+use crate::shared::utils::quantile;
 use crate::shared::StaticEvent;
-use crate::shared::{AlignmentParameters, ErrorParameters, Features, InferenceParameters};
+use crate::shared::{
+    AlignmentParameters, BootstrapParameters, ErrorParameters, Features, FitParameters,
+    GenerationConstraints, InfEvent, InferenceParameters,
+};
+use crate::shared::{ResultCompact, ResultInference};
 use crate::vdj::model::EntrySequence;
 use crate::vdj::Sequence;
 use crate::vdj::{display_j_alignment, display_v_alignment};
 use ndarray::array;
 
-use ndarray::{Array1, Array2, Array3};
-use std::collections::HashSet;
+use ndarray::{Array1, Array2, Array3, ArrayD, Axis};
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
 use std::sync::Arc;
 
 use crate::shared::errors::ErrorConstantRate;
@@ -29,6 +34,7 @@ use rand::RngCore;
 use rand::{Rng, SeedableRng};
 use rayon::current_num_threads;
 use rayon::prelude::*;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 
@@ -94,6 +100,55 @@ impl GenerationResult {
     }
 }
 
+/// Summary of a run of `Model::fit`
+#[cfg_attr(all(feature = "py_binds", feature = "pyo3"), pyclass(get_all))]
+#[derive(Default, Clone, Debug)]
+pub struct FitResult {
+    /// Total log-likelihood of the sequences at each round
+    pub log_likelihoods: Vec<f64>,
+    /// Mean log-likelihood of the validation sequences after each round
+    pub validation_log_likelihoods: Vec<f64>,
+    /// True if the fit stopped because the improvement was below the tolerance
+    pub converged: bool,
+    /// True if the fit stopped because the validation log-likelihood decreased
+    pub stopped_early: bool,
+}
+
+#[cfg(all(feature = "py_binds", feature = "pyo3"))]
+#[pymethods]
+impl FitResult {
+    fn __repr__(&self) -> String {
+        format!(
+            "FitResult(iterations={}, converged={}, stopped_early={}, log_likelihood={:?}, validation_log_likelihood={:?})",
+            self.log_likelihoods.len(),
+            self.converged,
+            self.stopped_early,
+            self.log_likelihoods.last(),
+            self.validation_log_likelihoods.last()
+        )
+    }
+}
+
+/// Result of `Model::bootstrap`
+#[derive(Default, Clone, Debug)]
+pub struct BootstrapResult {
+    /// The quantiles computed
+    pub quantiles: Vec<f64>,
+    /// For each parameter (e.g. "p_ins_vd"), an array whose first axis runs over
+    /// `quantiles`, the other axes have the shape of the parameter
+    pub parameters: Vec<(String, ArrayD<f64>)>,
+}
+
+impl BootstrapResult {
+    /// Quantiles of one parameter
+    pub fn get(&self, name: &str) -> Option<&ArrayD<f64>> {
+        self.parameters
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, x)| x)
+    }
+}
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Model {
@@ -172,6 +227,15 @@ impl Model {
         }
     }
 
+    /// Save the data in igor format, extended for the models with a second
+    /// D gene (the files can then only be read by righor)
+    pub fn save_model_righor(&self, directory: &Path) -> Result<()> {
+        match self {
+            Model::VDJ(x) => x.save_model_righor(directory),
+            Model::VJ(x) => x.save_model(directory),
+        }
+    }
+
     /// Save the data in json format
     pub fn save_json(&self, filename: &Path) -> Result<()> {
         match self {
@@ -237,6 +301,49 @@ impl Model {
         })
     }
 
+    /// Draw `nb_samples` recombination scenarios of the sequence from the
+    /// posterior distribution P(scenario | sequence) (VDJ models only)
+    pub fn sample_posterior(
+        &self,
+        sequence: EntrySequence,
+        nb_samples: usize,
+        alignment_params: &AlignmentParameters,
+        inference_params: &InferenceParameters,
+        seed: Option<u64>,
+    ) -> Result<Vec<InfEvent>> {
+        let mut rng = match seed {
+            Some(s) => SmallRng::seed_from_u64(s),
+            None => SmallRng::from_entropy(),
+        };
+        match self {
+            Model::VDJ(x) => x.sample_posterior(
+                sequence,
+                nb_samples,
+                alignment_params,
+                inference_params,
+                &mut rng,
+            ),
+            Model::VJ(_) => Err(anyhow!(
+                "Posterior sampling is only available for the VDJ and VxDJ models"
+            )),
+        }
+    }
+
+    /// Return the model conditioned on the gene, insertion and deletion
+    /// constraints, and the probability of these constraints
+    pub fn constrain(&self, constraints: &GenerationConstraints) -> Result<(Model, f64)> {
+        Ok(match self {
+            Model::VDJ(x) => {
+                let (m, proba) = x.constrain(constraints)?;
+                (Model::VDJ(m), proba)
+            }
+            Model::VJ(x) => {
+                let (m, proba) = x.constrain(constraints)?;
+                (Model::VJ(m), proba)
+            }
+        })
+    }
+
     pub fn uniform(&self) -> Result<Model> {
         Ok(match self {
             Model::VDJ(x) => Model::VDJ(x.uniform()?),
@@ -257,13 +364,340 @@ impl Model {
     pub fn infer(
         &mut self,
         sequences: &[EntrySequence],
+        weights: Option<&[f64]>,
         features: Option<Vec<Features>>,
         alignment_params: &AlignmentParameters,
         inference_params: &InferenceParameters,
     ) -> Result<(Vec<Features>, f64)> {
         match self {
-            Model::VDJ(x) => x.infer(sequences, features, alignment_params, inference_params),
-            Model::VJ(x) => x.infer(sequences, features, alignment_params, inference_params),
+            Model::VDJ(x) => x.infer(
+                sequences,
+                weights,
+                features,
+                alignment_params,
+                inference_params,
+            ),
+            Model::VJ(x) => x.infer(
+                sequences,
+                weights,
+                features,
+                alignment_params,
+                inference_params,
+            ),
+        }
+    }
+
+    /// Run expectation-maximization rounds until the improvement of the
+    /// log-likelihood (per sequence) drops below `fit_params.tolerance`, or
+    /// `fit_params.max_iterations` is reached. The sequences are aligned once
+    /// at the start. If `fit_params.checkpoint_path` is set, the model is saved
+    /// there (json) every `fit_params.checkpoint_every` rounds, so that an
+    /// interrupted fit can be resumed by loading it back.
+    /// If `validation` sequences are given, their mean log-likelihood under the
+    /// updated model is computed after each round. With `fit_params.early_stopping`,
+    /// the fit stops (and the previous model is restored) as soon as it decreases.
+    /// Return the features and the log-likelihood traces.
+    #[allow(clippy::too_many_arguments)]
+    pub fn fit(
+        &mut self,
+        sequences: &[EntrySequence],
+        weights: Option<&[f64]>,
+        features: Option<Vec<Features>>,
+        validation: Option<&[EntrySequence]>,
+        alignment_params: &AlignmentParameters,
+        inference_params: &InferenceParameters,
+        fit_params: &FitParameters,
+    ) -> Result<(Vec<Features>, FitResult)> {
+        // align once, the alignments are reused in every round
+        let aligned = self.align_once(sequences, alignment_params)?;
+        let validation = validation
+            .map(|v| self.align_once(v, alignment_params))
+            .transpose()?;
+        let total_weight = weights.map_or(sequences.len() as f64, |w| w.iter().sum());
+
+        let mut features = features;
+        let mut result = FitResult::default();
+        // model (and features) with the best validation log-likelihood so far
+        let mut best: Option<(Model, Option<Vec<Features>>)> = None;
+        for iteration in 1..=fit_params.max_iterations {
+            let (new_features, log_likelihood) = self.infer(
+                &aligned,
+                weights,
+                features,
+                alignment_params,
+                inference_params,
+            )?;
+            features = Some(new_features);
+            result.log_likelihoods.push(log_likelihood);
+
+            if let Some(val) = &validation {
+                let val_log_likelihood =
+                    self.mean_log_likelihood(val, alignment_params, inference_params)?;
+                let best_so_far = result
+                    .validation_log_likelihoods
+                    .iter()
+                    .all(|&previous| val_log_likelihood >= previous);
+                result.validation_log_likelihoods.push(val_log_likelihood);
+                if fit_params.early_stopping {
+                    if best_so_far {
+                        best = Some((self.clone(), features.clone()));
+                    } else if let Some((model, feats)) = best.take() {
+                        *self = model;
+                        features = feats;
+                        result.stopped_early = true;
+                        break;
+                    }
+                }
+            }
+
+            if let Some(path) = &fit_params.checkpoint_path {
+                if fit_params.checkpoint_every > 0
+                    && iteration.is_multiple_of(fit_params.checkpoint_every)
+                {
+                    self.save_json(Path::new(path))?;
+                }
+            }
+            if let [.., previous, last] = result.log_likelihoods[..] {
+                if (last - previous) / total_weight < fit_params.tolerance {
+                    result.converged = true;
+                    break;
+                }
+            }
+        }
+        Ok((features.unwrap_or_default(), result))
+    }
+
+    /// Bootstrap estimate of the uncertainty of the model parameters.
+    /// The sequences (aligned once) are resampled with replacement
+    /// `bootstrap_params.n_resamples` times and, for each resample, the model is
+    /// fitted (`fit`) starting from the current model. A sequence drawn `k` times
+    /// has weight `k * weights[i]`. Resamples run in parallel, each one with
+    /// its own seed (`bootstrap_params.seed + i`).
+    /// Return the requested quantiles of every marginal of the model.
+    pub fn bootstrap(
+        &self,
+        sequences: &[EntrySequence],
+        weights: Option<&[f64]>,
+        alignment_params: &AlignmentParameters,
+        inference_params: &InferenceParameters,
+        fit_params: &FitParameters,
+        bootstrap_params: &BootstrapParameters,
+    ) -> Result<BootstrapResult> {
+        if sequences.is_empty() || bootstrap_params.n_resamples == 0 {
+            return Err(anyhow!("Cannot bootstrap without sequences or resamples"));
+        }
+        if bootstrap_params
+            .quantiles
+            .iter()
+            .any(|q| !(0. ..=1.).contains(q))
+        {
+            return Err(anyhow!("The quantiles should be between 0 and 1"));
+        }
+        let aligned = self.align_once(sequences, alignment_params)?;
+        let weights = weights.map_or(vec![1.; sequences.len()], |w| w.to_vec());
+
+        let resamples = (0..bootstrap_params.n_resamples)
+            .into_par_iter()
+            .map(|ii| {
+                let mut rng =
+                    SmallRng::seed_from_u64(bootstrap_params.seed.wrapping_add(ii as u64));
+                let mut counts = vec![0.; sequences.len()];
+                for _ in 0..sequences.len() {
+                    counts[rng.gen_range(0..sequences.len())] += 1.;
+                }
+                let resampled_weights: Vec<f64> =
+                    counts.iter().zip(&weights).map(|(c, w)| c * w).collect();
+                let mut model = self.clone();
+                model.fit(
+                    &aligned,
+                    Some(&resampled_weights),
+                    None,
+                    None,
+                    alignment_params,
+                    inference_params,
+                    fit_params,
+                )?;
+                model.marginals()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut result = BootstrapResult {
+            quantiles: bootstrap_params.quantiles.clone(),
+            parameters: Vec::new(),
+        };
+        for (idx, (name, first)) in resamples[0].iter().enumerate() {
+            let mut shape = vec![bootstrap_params.quantiles.len()];
+            shape.extend_from_slice(first.shape());
+            let mut quantiles = ArrayD::<f64>::zeros(shape);
+            let stacked = ndarray::stack(
+                Axis(0),
+                &resamples
+                    .iter()
+                    .map(|r| r[idx].1.view())
+                    .collect::<Vec<_>>(),
+            )?;
+            for (mut out, values) in quantiles
+                .lanes_mut(Axis(0))
+                .into_iter()
+                .zip(stacked.lanes(Axis(0)))
+            {
+                let values = values.to_vec();
+                for (o, &q) in out.iter_mut().zip(&bootstrap_params.quantiles) {
+                    *o = quantile(&values, q);
+                }
+            }
+            result.parameters.push((name.clone(), quantiles));
+        }
+        Ok(result)
+    }
+
+    /// All the (inferable) marginals of the model, with their names
+    pub fn marginals(&self) -> Result<Vec<(String, ArrayD<f64>)>> {
+        let mut marginals = match self {
+            Model::VDJ(x) => vec![
+                ("p_vdj".to_string(), x.p_vdj.clone().into_dyn()),
+                ("p_v".to_string(), x.p_v.clone().into_dyn()),
+                ("p_dj".to_string(), x.p_dj.clone().into_dyn()),
+                ("p_ins_vd".to_string(), x.p_ins_vd.clone().into_dyn()),
+                ("p_ins_dj".to_string(), x.p_ins_dj.clone().into_dyn()),
+                (
+                    "p_del_v_given_v".to_string(),
+                    x.p_del_v_given_v.clone().into_dyn(),
+                ),
+                (
+                    "p_del_j_given_j".to_string(),
+                    x.p_del_j_given_j.clone().into_dyn(),
+                ),
+                (
+                    "p_del_d5_del_d3".to_string(),
+                    x.p_del_d5_del_d3.clone().into_dyn(),
+                ),
+                (
+                    "markov_coefficients_vd".to_string(),
+                    self.get_markov_coefficients_vd()?.into_dyn(),
+                ),
+                (
+                    "markov_coefficients_dj".to_string(),
+                    self.get_markov_coefficients_dj()?.into_dyn(),
+                ),
+            ],
+            Model::VJ(x) => vec![
+                ("p_v".to_string(), x.p_v.clone().into_dyn()),
+                ("p_j_given_v".to_string(), x.p_j_given_v.clone().into_dyn()),
+                ("p_ins_vj".to_string(), x.p_ins_vj.clone().into_dyn()),
+                (
+                    "p_del_v_given_v".to_string(),
+                    x.p_del_v_given_v.clone().into_dyn(),
+                ),
+                (
+                    "p_del_j_given_j".to_string(),
+                    x.p_del_j_given_j.clone().into_dyn(),
+                ),
+                (
+                    "markov_coefficients_vj".to_string(),
+                    self.get_markov_coefficients_vj()?.into_dyn(),
+                ),
+            ],
+        };
+        if let Model::VDJ(x) = self {
+            if let Some(p) = &x.p_ins_vd_given_v {
+                marginals.push(("p_ins_vd_given_v".to_string(), p.clone().into_dyn()));
+            }
+            if let Some(p) = &x.p_ins_dj_given_j {
+                marginals.push(("p_ins_dj_given_j".to_string(), p.clone().into_dyn()));
+            }
+        }
+        match self.get_error() {
+            ErrorParameters::ConstantRate(e) => marginals.push((
+                "error_rate".to_string(),
+                Array1::from_elem(1, e.error_rate).into_dyn(),
+            )),
+            ErrorParameters::ContextRate(e) => marginals.push((
+                "error_mutability".to_string(),
+                Array1::from_vec(e.mutability).into_dyn(),
+            )),
+            ErrorParameters::SubstitutionMatrix(e) => marginals.push((
+                "error_substitution_matrix".to_string(),
+                Array2::from_shape_fn((4, 4), |(g, o)| e.matrix[g][o]).into_dyn(),
+            )),
+            ErrorParameters::IndelRate(e) => {
+                marginals.push((
+                    "error_rate".to_string(),
+                    Array1::from_elem(1, e.error_rate).into_dyn(),
+                ));
+                marginals.push((
+                    "error_indel_rate".to_string(),
+                    Array1::from_elem(1, e.indel_rate).into_dyn(),
+                ));
+            }
+            ErrorParameters::SegmentRate(e) => marginals.push((
+                "error_segment_rates".to_string(),
+                Array1::from_vec(vec![e.v_error_rate, e.d_error_rate, e.j_error_rate]).into_dyn(),
+            )),
+            ErrorParameters::UniformRate(_) => {}
+        }
+        Ok(marginals)
+    }
+
+    /// Mean log-likelihood (log2, as in `infer`) of a set of sequences under the model
+    pub fn mean_log_likelihood(
+        &self,
+        sequences: &[EntrySequence],
+        alignment_params: &AlignmentParameters,
+        inference_params: &InferenceParameters,
+    ) -> Result<f64> {
+        if sequences.is_empty() {
+            return Err(anyhow!("Cannot compute the log-likelihood of an empty set"));
+        }
+        let mut ip = inference_params.clone();
+        ip.compute_pgen = false;
+        ip.store_best_event = false;
+        let total = sequences
+            .par_iter()
+            .map(|s| {
+                let result = self.evaluate(s.clone(), alignment_params, &ip)?;
+                Ok((result.likelihood + ip.min_likelihood).log2())
+            })
+            .collect::<Result<Vec<f64>>>()?
+            .iter()
+            .sum::<f64>();
+        Ok(total / sequences.len() as f64)
+    }
+
+    /// Align all the sequences, without copying them if they're already aligned
+    fn align_once<'a>(
+        &self,
+        sequences: &'a [EntrySequence],
+        alignment_params: &AlignmentParameters,
+    ) -> Result<Cow<'a, [EntrySequence]>> {
+        if sequences
+            .iter()
+            .all(|s| matches!(s, EntrySequence::Aligned(_)))
+        {
+            return Ok(Cow::Borrowed(sequences));
+        }
+        Ok(Cow::Owned(
+            sequences
+                .par_iter()
+                .map(|s| {
+                    Ok(EntrySequence::Aligned(
+                        self.align_entry_sequence(s, alignment_params)?,
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?,
+        ))
+    }
+
+    /// Align an `EntrySequence` (no-op if the sequence is already aligned,
+    /// error if it was aligned with an incompatible model)
+    pub fn align_entry_sequence(
+        &self,
+        sequence: &EntrySequence,
+        align_params: &AlignmentParameters,
+    ) -> Result<Sequence> {
+        match self {
+            Model::VDJ(x) => sequence.align(x, align_params),
+            Model::VJ(x) => sequence.align(&x.inner, align_params),
         }
     }
 
@@ -322,6 +756,48 @@ impl Model {
         }
     }
 
+    /// Evaluate a batch of sequences in parallel and return the compact
+    /// results, in the same order as the input
+    pub fn evaluate_batch(
+        &self,
+        sequences: Vec<EntrySequence>,
+        alignment_params: &AlignmentParameters,
+        inference_params: &InferenceParameters,
+    ) -> Vec<Result<ResultCompact>> {
+        sequences
+            .into_par_iter()
+            .map(|seq| {
+                let mut result = self
+                    .evaluate(seq, alignment_params, inference_params)?
+                    .to_compact();
+                if let Model::VJ(_) = self {
+                    // the D gene of a VJ model is a placeholder
+                    result.d_name = None;
+                }
+                Ok(result)
+            })
+            .collect()
+    }
+
+    /// Evaluate a (potentially very long) stream of sequences, `chunk_size`
+    /// sequences at a time. The results are returned in the same order as the input.
+    pub fn evaluate_stream<I: IntoIterator<Item = EntrySequence>>(
+        &self,
+        sequences: I,
+        alignment_params: &AlignmentParameters,
+        inference_params: &InferenceParameters,
+        chunk_size: usize,
+    ) -> EvaluationStream<'_, I::IntoIter> {
+        EvaluationStream {
+            model: self,
+            sequences: sequences.into_iter(),
+            alignment_params: alignment_params.clone(),
+            inference_params: inference_params.clone(),
+            chunk_size: chunk_size.max(1),
+            buffer: VecDeque::new(),
+        }
+    }
+
     pub fn display_v_alignment(
         seq: &Dna,
         v_al: &VJAlignment,
@@ -380,9 +856,34 @@ impl Model {
         }
     }
 
+    /// Change the structure of the model. Leaving `ModelStructure::VDJInsGivenGene`
+    /// drops the gene dependence of the insertion lengths (the marginals are kept),
+    /// leaving `ModelStructure::VDDJ` drops the second D gene.
     pub fn set_model_type(&mut self, value: ModelStructure) -> Result<()> {
         match self {
-            Model::VDJ(x) => x.model_type = value,
+            Model::VDJ(x) => {
+                if value != ModelStructure::VDJInsGivenGene {
+                    x.p_ins_vd_given_v = None;
+                    x.p_ins_dj_given_j = None;
+                }
+                // the marginals of the graph are kept
+                if value != ModelStructure::Graph {
+                    x.graph = None;
+                }
+                if value != ModelStructure::VDDJ {
+                    x.d2 = None;
+                }
+                x.model_type = value;
+            }
+            Model::VJ(_) if value == ModelStructure::VDJInsGivenGene => Err(anyhow!(
+                "Insertion lengths conditioned on the genes are only available for VDJ models"
+            ))?,
+            Model::VJ(_) if value == ModelStructure::Graph => Err(anyhow!(
+                "Arbitrary dependences between the events are only available for VDJ models"
+            ))?,
+            Model::VJ(_) if value == ModelStructure::VDDJ => {
+                Err(anyhow!("A second D gene is only available for VDJ models"))?
+            }
             Model::VJ(x) => x.inner.model_type = value,
         }
         self.initialize()
@@ -395,6 +896,11 @@ impl Model {
         }
     }
 
+    /// (V, D, J) error rates of the model
+    pub fn get_segment_error_rates(&self) -> Result<(f64, f64, f64)> {
+        self.get_error().segment_error_rates()
+    }
+
     pub fn set_error(&mut self, value: ErrorParameters) -> Result<()> {
         match self {
             Model::VDJ(x) => x.error = value,
@@ -433,7 +939,10 @@ impl Model {
 
     pub fn set_p_vdj(&mut self, value: Array3<f64>) -> Result<()> {
         match self {
-            Model::VDJ(x) => x.p_vdj = value,
+            Model::VDJ(x) => {
+                x.p_vdj = value;
+                x.clear_norm_productive();
+            }
             Model::VJ(_) => Err(anyhow!("VJ Model don't have D segments"))?,
         }
         Ok(())
@@ -480,6 +989,44 @@ impl Model {
         })
     }
 
+    /// P(ins_vd | V), dimension (`nb_insertions`, `nb_v_genes`), `None` if the
+    /// VD insertion length doesn't depend on the V gene
+    pub fn get_p_ins_vd_given_v(&self) -> Result<Option<Array2<f64>>> {
+        Ok(match self {
+            Model::VDJ(x) => x.p_ins_vd_given_v.clone(),
+            Model::VJ(_) => Err(anyhow!("VJ Model don't have VD inserts"))?,
+        })
+    }
+
+    /// P(ins_dj | J), dimension (`nb_insertions`, `nb_j_genes`), `None` if the
+    /// DJ insertion length doesn't depend on the J gene
+    pub fn get_p_ins_dj_given_j(&self) -> Result<Option<Array2<f64>>> {
+        Ok(match self {
+            Model::VDJ(x) => x.p_ins_dj_given_j.clone(),
+            Model::VJ(_) => Err(anyhow!("VJ Model don't have DJ inserts"))?,
+        })
+    }
+
+    /// Require `ModelStructure::VDJInsGivenGene`
+    pub fn set_p_ins_vd_given_v(&mut self, value: Option<Array2<f64>>) -> Result<()> {
+        match self {
+            Model::VDJ(x) => x.p_ins_vd_given_v = value,
+            Model::VJ(_) => Err(anyhow!("VJ Model don't have VD inserts"))?,
+        }
+        self.initialize()?;
+        Ok(())
+    }
+
+    /// Require `ModelStructure::VDJInsGivenGene`
+    pub fn set_p_ins_dj_given_j(&mut self, value: Option<Array2<f64>>) -> Result<()> {
+        match self {
+            Model::VDJ(x) => x.p_ins_dj_given_j = value,
+            Model::VJ(_) => Err(anyhow!("VJ Model don't have DJ inserts"))?,
+        }
+        self.initialize()?;
+        Ok(())
+    }
+
     pub fn get_p_ins_vj(&self) -> Result<Array1<f64>> {
         Ok(match self {
             Model::VJ(x) => x.p_ins_vj.clone(),
@@ -606,10 +1153,33 @@ impl Model {
         }
     }
 
+    /// Order of the Markov chains of the inserted nucleotides
+    pub fn get_markov_order(&self) -> usize {
+        match self {
+            Model::VDJ(x) => x.get_markov_order(),
+            Model::VJ(x) => x.inner.get_markov_order(),
+        }
+    }
+
+    /// Change the order of the insertion Markov chains (1 is IGoR's model)
+    pub fn set_markov_order(&mut self, order: usize) -> Result<()> {
+        match self {
+            Model::VDJ(x) => x.set_markov_order(order)?,
+            // the inner model only uses its VD chain
+            Model::VJ(x) => {
+                x.inner.markov_chain_vd = Arc::new(x.inner.markov_chain_vd.with_order(order)?)
+            }
+        }
+        self.initialize()
+    }
+
     pub fn set_markov_coefficients_vd(&mut self, value: Array2<f64>) -> Result<()> {
         match self {
             Model::VDJ(x) => {
-                x.markov_chain_vd = Arc::new(DNAMarkovChain::new(&value, false)?);
+                // the higher orders are reset from the new coefficients
+                x.markov_chain_vd = Arc::new(
+                    DNAMarkovChain::new(&value, false)?.with_order(x.markov_chain_vd.order())?,
+                );
             }
             Model::VJ(_) => Err(anyhow!("VJ model does not have VD insertions."))?,
         }
@@ -627,7 +1197,9 @@ impl Model {
     pub fn set_markov_coefficients_dj(&mut self, value: Array2<f64>) -> Result<()> {
         match self {
             Model::VDJ(x) => {
-                x.markov_chain_dj = Arc::new(DNAMarkovChain::new(&value, true)?);
+                x.markov_chain_dj = Arc::new(
+                    DNAMarkovChain::new(&value, true)?.with_order(x.markov_chain_dj.order())?,
+                );
             }
             Model::VJ(_) => Err(anyhow!("VJ model does not have DJ insertions."))?,
         }
@@ -680,6 +1252,30 @@ impl Model {
         }
     }
 
+    /// Exact probability that a generated sequence is productive
+    /// (see `vdj::Model::get_norm_productive_exact`)
+    pub fn get_norm_productive_exact(&self, conserved_j_residues: Option<&str>) -> Result<f64> {
+        match self {
+            Model::VDJ(x) => x.get_norm_productive_exact(conserved_j_residues),
+            Model::VJ(x) => x.inner.get_norm_productive_exact(conserved_j_residues),
+        }
+    }
+
+    /// pgen normalized by the (cached) exact productive fraction
+    /// (see `vdj::Model::get_pgen_productive`)
+    pub fn get_pgen_productive(
+        &self,
+        pgen: f64,
+        conserved_j_residues: Option<&str>,
+    ) -> Result<f64> {
+        match self {
+            Model::VDJ(x) => x.get_pgen_productive(pgen, conserved_j_residues),
+            Model::VJ(x) => x.inner.get_pgen_productive(pgen, conserved_j_residues),
+        }
+    }
+
+    /// Monte Carlo estimate of the probability that a generated sequence is
+    /// productive, from `num_monte_carlo` generated sequences (default 10^6)
     pub fn get_norm_productive(
         &self,
         num_monte_carlo: Option<usize>,
@@ -775,10 +1371,56 @@ impl Model {
     }
 }
 
+impl ModelGen for Model {
+    fn get_v_segments(&self) -> Vec<Gene> {
+        Model::get_v_segments(self)
+    }
+    fn get_j_segments(&self) -> Vec<Gene> {
+        Model::get_j_segments(self)
+    }
+}
+
+/// Iterator over the (compact) evaluation results of a stream of sequences.
+/// Only `chunk_size` sequences are held in memory at any given time, each
+/// chunk is evaluated in parallel.
+pub struct EvaluationStream<'a, I: Iterator<Item = EntrySequence>> {
+    model: &'a Model,
+    sequences: I,
+    alignment_params: AlignmentParameters,
+    inference_params: InferenceParameters,
+    chunk_size: usize,
+    buffer: VecDeque<Result<ResultCompact>>,
+}
+
+impl<I: Iterator<Item = EntrySequence>> Iterator for EvaluationStream<'_, I> {
+    type Item = Result<ResultCompact>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() {
+            let chunk: Vec<EntrySequence> = self.sequences.by_ref().take(self.chunk_size).collect();
+            if chunk.is_empty() {
+                return None;
+            }
+            self.buffer = self
+                .model
+                .evaluate_batch(chunk, &self.alignment_params, &self.inference_params)
+                .into();
+        }
+        self.buffer.pop_front()
+    }
+}
+
 #[cfg_attr(all(feature = "py_binds", feature = "pyo3"), pyclass)]
 pub struct Generator {
     model: Model,
     rng: SmallRng,
+    constraints: GenerationConstraints,
+    motif: Option<Regex>,
+    // probability of the gene/insertion/deletion constraints
+    conditioning_probability: f64,
+    // number of draws and of accepted sequences (sequence-level constraints)
+    nb_attempts: usize,
+    nb_accepted: usize,
 }
 
 impl Generator {
@@ -787,6 +1429,27 @@ impl Generator {
         seed: Option<u64>,
         available_v: Option<Vec<Gene>>,
         available_j: Option<Vec<Gene>>,
+    ) -> Result<Generator> {
+        Generator::new_with_constraints(
+            model,
+            seed,
+            available_v,
+            available_j,
+            GenerationConstraints::default(),
+        )
+    }
+
+    /// Create a generator whose sequences satisfy `constraints`. The
+    /// constraints on the D gene, insertions and deletions are sampled
+    /// directly from the conditioned model, the ones on the junction are
+    /// enforced by rejection (at most `constraints.max_attempts` draws per
+    /// sequence).
+    pub fn new_with_constraints(
+        model: &Model,
+        seed: Option<u64>,
+        available_v: Option<Vec<Gene>>,
+        available_j: Option<Vec<Gene>>,
+        constraints: GenerationConstraints,
     ) -> Result<Generator> {
         let rng = match seed {
             Some(s) => SmallRng::seed_from_u64(s),
@@ -802,74 +1465,173 @@ impl Generator {
         if available_j.is_some() {
             internal_model = internal_model.filter_js(available_j.unwrap())?;
         }
+        let (internal_model, conditioning_probability) = internal_model.constrain(&constraints)?;
+        let motif = constraints
+            .junction_aa_motif
+            .as_deref()
+            .map(Regex::new)
+            .transpose()?;
         Ok(Generator {
             model: internal_model,
             rng,
+            constraints,
+            motif,
+            conditioning_probability,
+            nb_attempts: 0,
+            nb_accepted: 0,
         })
     }
+
+    fn record(&mut self, nb_attempts: usize, nb_accepted: usize) {
+        self.nb_attempts += nb_attempts;
+        self.nb_accepted += nb_accepted;
+    }
 }
+
+/// Draw sequences until one satisfies the sequence-level constraints, return
+/// it with the number of draws needed
+fn generate_constrained<F>(
+    constraints: &GenerationConstraints,
+    motif: &Option<Regex>,
+    mut draw: F,
+) -> Result<(GenerationResult, usize)>
+where
+    F: FnMut() -> Result<GenerationResult>,
+{
+    for attempt in 1..=constraints.max_attempts {
+        let result = draw()?;
+        let accepted = match &result.junction_aa {
+            Some(aa) => {
+                constraints.junction_aa_length.is_none_or(|l| aa.len() == l)
+                    && motif.as_ref().is_none_or(|re| re.is_match(aa))
+            }
+            // out-of-frame junctions can't satisfy the amino-acid constraints
+            None => !constraints.has_predicates(),
+        };
+        if accepted {
+            return Ok((result, attempt));
+        }
+    }
+    Err(anyhow!(
+        "No sequence satisfying the constraints after {} attempts",
+        constraints.max_attempts
+    ))
+}
+
 #[cfg_attr(all(feature = "py_binds", feature = "pyo3"), pymethods)]
 impl Generator {
     pub fn generate(&mut self, functional: bool) -> Result<GenerationResult> {
-        self.model.generate(functional, &mut self.rng)
-    }
-    pub fn generate_without_errors(&mut self, functional: bool) -> GenerationResult {
-        self.model
-            .generate_without_errors(functional, &mut self.rng)
-    }
-
-    pub fn generate_many(&mut self, num_monte_carlo: usize, functional: bool) -> Vec<[String; 5]> {
+        let (result, nb_attempts) = generate_constrained(&self.constraints, &self.motif, || {
+            self.model.generate(functional, &mut self.rng)
+        })?;
+        self.record(nb_attempts, 1);
+        Ok(result)
+    }
+    pub fn generate_without_errors(&mut self, functional: bool) -> Result<GenerationResult> {
+        let (result, nb_attempts) = generate_constrained(&self.constraints, &self.motif, || {
+            Ok(self
+                .model
+                .generate_without_errors(functional, &mut self.rng))
+        })?;
+        self.record(nb_attempts, 1);
+        Ok(result)
+    }
+
+    pub fn generate_many(
+        &mut self,
+        num_monte_carlo: usize,
+        functional: bool,
+    ) -> Result<Vec<[String; 5]>> {
         let num_threads = current_num_threads();
         let batches: Vec<usize> = get_batches(num_monte_carlo, num_threads);
         let seeds: Vec<u64> = (0..num_threads).map(|_| self.rng.next_u64()).collect();
+        let (constraints, motif) = (&self.constraints, &self.motif);
 
-        seeds
+        let results = seeds
             .into_par_iter()
             .enumerate()
             .flat_map_iter(|(idx, s)| {
                 let mut child_rng = SmallRng::seed_from_u64(s);
                 let mut child_model = self.model.clone();
-                (0..batches[idx]).into_iter().map(move |_| {
-                    let gen_result = child_model.generate(functional, &mut child_rng).unwrap();
-                    [
-                        gen_result.junction_aa.unwrap_or("Out-of-frame".to_string()),
-                        gen_result.v_gene,
-                        gen_result.j_gene,
-                        gen_result.junction_nt,
-                        gen_result.full_seq,
-                    ]
+                (0..batches[idx]).map(move |_| {
+                    generate_constrained(constraints, motif, || {
+                        child_model.generate(functional, &mut child_rng)
+                    })
                 })
             })
-            .collect::<Vec<[String; 5]>>()
+            .collect::<Result<Vec<_>>>()?;
+
+        self.record(results.iter().map(|(_, n)| n).sum(), results.len());
+        Ok(results
+            .into_iter()
+            .map(|(gen_result, _)| {
+                [
+                    gen_result.junction_aa.unwrap_or("Out-of-frame".to_string()),
+                    gen_result.v_gene,
+                    gen_result.j_gene,
+                    gen_result.junction_nt,
+                    gen_result.full_seq,
+                ]
+            })
+            .collect())
     }
 
     pub fn generate_many_without_errors(
         &mut self,
         num_monte_carlo: usize,
         functional: bool,
-    ) -> Vec<[String; 4]> {
+    ) -> Result<Vec<[String; 4]>> {
         let num_threads = current_num_threads();
         let batches: Vec<usize> = get_batches(num_monte_carlo, num_threads);
         let seeds: Vec<u64> = (0..num_threads).map(|_| self.rng.next_u64()).collect();
+        let (constraints, motif) = (&self.constraints, &self.motif);
 
-        seeds
+        let results = seeds
             .into_par_iter()
             .enumerate()
             .flat_map_iter(|(idx, s)| {
                 let mut child_rng = SmallRng::seed_from_u64(s);
                 let mut child_model = self.model.clone();
-                (0..batches[idx]).into_iter().map(move |_| {
-                    let gen_result =
-                        child_model.generate_without_errors(functional, &mut child_rng);
-                    [
-                        gen_result.junction_aa.unwrap_or("Out-of-frame".to_string()),
-                        gen_result.v_gene,
-                        gen_result.j_gene,
-                        gen_result.junction_nt,
-                    ]
+                (0..batches[idx]).map(move |_| {
+                    generate_constrained(constraints, motif, || {
+                        Ok(child_model.generate_without_errors(functional, &mut child_rng))
+                    })
                 })
             })
-            .collect::<Vec<[String; 4]>>()
+            .collect::<Result<Vec<_>>>()?;
+
+        self.record(results.iter().map(|(_, n)| n).sum(), results.len());
+        Ok(results
+            .into_iter()
+            .map(|(gen_result, _)| {
+                [
+                    gen_result.junction_aa.unwrap_or("Out-of-frame".to_string()),
+                    gen_result.v_gene,
+                    gen_result.j_gene,
+                    gen_result.junction_nt,
+                ]
+            })
+            .collect())
+    }
+
+    /// Probability of the constraints on the genes, insertions and deletions
+    /// (relative to the model restricted to the available V/J genes)
+    pub fn conditioning_probability(&self) -> f64 {
+        self.conditioning_probability
+    }
+
+    /// Fraction of the draws so far that satisfied the sequence-level
+    /// constraints (NaN before the first sequence)
+    pub fn acceptance_rate(&self) -> f64 {
+        self.nb_accepted as f64 / self.nb_attempts as f64
+    }
+
+    /// Estimate of the probability mass of the sequences satisfying all the
+    /// constraints, `conditioning_probability * acceptance_rate`. Only valid
+    /// if the sequences were generated with `functional = false` (otherwise
+    /// it mixes in the rejection of the non-functional sequences).
+    pub fn constrained_probability(&self) -> f64 {
+        self.conditioning_probability * self.acceptance_rate()
     }
 }
 
@@ -879,6 +1641,14 @@ pub enum ModelStructure {
     VDJ,
     #[default]
     VxDJ,
+    /// Same as `VDJ`, but the insertion lengths depend on the flanking gene:
+    /// P(ins_dj | J) and (optionally) P(ins_vd | V)
+    VDJInsGivenGene,
+    /// Arbitrary dependences between the events (any IGoR model), only
+    /// generation and evaluation are available, not the inference
+    Graph,
+    /// Same as `VDJ`, with a second D gene (D1-insDD-D2 instead of D)
+    VDDJ,
 }
 
 /// Generic trait to include all the models
@@ -953,9 +1723,12 @@ pub trait Modelable {
     ) -> Result<ResultInference>;
 
     /// Run one round of expectation-maximization on the current model and return the next model.
+    /// `weights` (optional) gives the weight of each sequence in the inference (e.g. clone
+    /// counts), the log-likelihood returned is the weighted sum of the sequences log-likelihoods.
     fn infer(
         &mut self,
         sequences: &[EntrySequence],
+        weights: Option<&[f64]>,
         features: Option<Vec<Features>>,
         alignment_params: &AlignmentParameters,
         inference_params: &InferenceParameters,
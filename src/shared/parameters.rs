@@ -2,7 +2,9 @@
 
 //use crate::shared::sequence::SequenceType;
 
-use bio::alignment::{pairwise, Alignment};
+use crate::shared::Gene;
+use anyhow::{anyhow, Result};
+use bio::alignment::{pairwise, Alignment, AlignmentOperation};
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
 use pyo3::prelude::*;
 
@@ -15,6 +17,9 @@ pub struct AlignmentParameters {
     pub min_score_j: i32,
     pub max_error_d: usize,
     pub left_v_cutoff: usize,
+    // maximal number of inserted/deleted nucleotides in the V/J alignments
+    // (0: only ungapped alignments are kept)
+    pub max_indels: usize,
 }
 
 #[cfg_attr(all(feature = "py_binds", feature = "pyo3"), pyclass(get_all, set_all))]
@@ -28,6 +33,8 @@ pub struct InferredFeatures {
     pub del_j: bool,
     pub ins_vd: bool,
     pub ins_dj: bool,
+    /// Insertions between the two D genes (`ModelStructure::VDDJ`)
+    pub ins_dd: bool,
 }
 
 impl Default for InferredFeatures {
@@ -39,13 +46,103 @@ impl Default for InferredFeatures {
             del_j: true,
             ins_vd: true,
             ins_dj: true,
+            ins_dd: true,
         }
     }
 }
 
 impl InferredFeatures {
     pub fn any(&self) -> bool {
-        self.genes || self.del_d || self.del_v || self.del_j || self.ins_vd || self.ins_dj
+        self.genes
+            || self.del_d
+            || self.del_v
+            || self.del_j
+            || self.ins_vd
+            || self.ins_dj
+            || self.ins_dd
+    }
+}
+
+#[cfg_attr(all(feature = "py_binds", feature = "pyo3"), pyclass(get_all, set_all))]
+#[derive(Clone, Debug, Default)]
+/// Pseudocounts added to the expected counts of each feature during the
+/// maximization step, in units of sequences. A pseudocount `c` is equivalent
+/// to a symmetric Dirichlet prior of concentration `1 + c` (MAP estimate).
+/// All zeros (the default) gives the maximum likelihood estimate.
+pub struct Pseudocounts {
+    pub genes: f64,
+    pub del_d: f64,
+    pub del_v: f64,
+    pub del_j: f64,
+    pub ins_vd: f64,
+    pub ins_dj: f64,
+    pub ins_dd: f64,
+    /// Transition matrices of the insertions (Markov chains)
+    pub markov_vd: f64,
+    pub markov_dj: f64,
+    pub markov_dd: f64,
+}
+
+impl Pseudocounts {
+    /// Same pseudocount for every feature
+    pub fn uniform(pseudocount: f64) -> Pseudocounts {
+        Pseudocounts {
+            genes: pseudocount,
+            del_d: pseudocount,
+            del_v: pseudocount,
+            del_j: pseudocount,
+            ins_vd: pseudocount,
+            ins_dj: pseudocount,
+            ins_dd: pseudocount,
+            markov_vd: pseudocount,
+            markov_dj: pseudocount,
+            markov_dd: pseudocount,
+        }
+    }
+
+    pub fn check(&self) -> Result<()> {
+        let all = [
+            self.genes,
+            self.del_d,
+            self.del_v,
+            self.del_j,
+            self.ins_vd,
+            self.ins_dj,
+            self.ins_dd,
+            self.markov_vd,
+            self.markov_dj,
+            self.markov_dd,
+        ];
+        if all.iter().any(|&x| !x.is_finite() || x < 0.) {
+            return Err(anyhow!("The pseudocounts should be positive"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "py_binds", feature = "pyo3"))]
+#[pymethods]
+impl Pseudocounts {
+    #[new]
+    #[pyo3(signature = (pseudocount=0.))]
+    /// Create pseudocounts, all equal to `pseudocount`
+    pub fn py_new(pseudocount: f64) -> Self {
+        Pseudocounts::uniform(pseudocount)
+    }
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!(
+            "Pseudocounts(genes={}, del_d={}, del_v={}, del_j={}, ins_vd={}, ins_dj={}, ins_dd={}, markov_vd={}, markov_dj={}, markov_dd={})",
+            self.genes,
+            self.del_d,
+            self.del_v,
+            self.del_j,
+            self.ins_vd,
+            self.ins_dj,
+            self.ins_dd,
+            self.markov_vd,
+            self.markov_dj,
+            self.markov_dd
+        ))
     }
 }
 
@@ -62,9 +159,16 @@ pub struct InferenceParameters {
     pub min_ratio_likelihood: f64,
     /// If true store the highest likelihood event
     pub store_best_event: bool,
+    /// Number of most likely scenarios stored in `ResultInference::top_events`
+    /// during the evaluation (0 to disable, the default). The list stays empty
+    /// when the search is not available (amino-acid or degenerate sequences,
+    /// VDDJ and Graph models)
+    pub store_top_k: usize,
     /// If true and `store_best_event` is true, compute the pgen of the sequence
     /// (pgen is computed by default if the model error rate is 0)
     pub compute_pgen: bool,
+    /// Pseudocounts (Dirichlet priors) used when updating the model
+    pub pseudocounts: Pseudocounts,
 }
 
 impl Default for AlignmentParameters {
@@ -74,6 +178,7 @@ impl Default for AlignmentParameters {
             min_score_j: 0,
             max_error_d: 200,
             left_v_cutoff: 600, // long cutoff by default, to avoid issues
+            max_indels: 0,
         }
     }
 }
@@ -82,14 +187,18 @@ impl Default for AlignmentParameters {
 #[pymethods]
 impl AlignmentParameters {
     #[new]
-    pub fn py_new() -> Self {
-        AlignmentParameters::default()
+    #[pyo3(signature = (max_indels=0))]
+    pub fn py_new(max_indels: usize) -> Self {
+        AlignmentParameters {
+            max_indels,
+            ..Default::default()
+        }
     }
 
     fn __repr__(&self) -> PyResult<String> {
         Ok(format!(
-            "AlignmentParameters(min_score_v={}, min_score_j={}, max_error_d={}. left_v_cutoff={})",
-            self.min_score_v, self.min_score_j, self.max_error_d, self.left_v_cutoff
+            "AlignmentParameters(min_score_v={}, min_score_j={}, max_error_d={}. left_v_cutoff={}, max_indels={})",
+            self.min_score_v, self.min_score_j, self.max_error_d, self.left_v_cutoff, self.max_indels
         ))
     }
 
@@ -105,12 +214,14 @@ impl AlignmentParameters {
         min_score_j: i32,
         max_error_d: usize,
         left_v_cutoff: usize,
+        max_indels: usize,
     ) -> Self {
         Self {
             min_score_v,
             min_score_j,
             max_error_d,
             left_v_cutoff, // shorten the V gene for alignment (improve speed)
+            max_indels,
         }
     }
 
@@ -121,10 +232,20 @@ impl AlignmentParameters {
         }
     }
 
+    /// (gap open, gap extend) penalties, gaps are cheaper if indels are allowed
+    fn gap_penalties(&self, gap_open: i32, gap_extend: i32) -> (i32, i32) {
+        if self.max_indels == 0 {
+            (gap_open, gap_extend)
+        } else {
+            (-25, -10)
+        }
+    }
+
     pub fn get_scoring(&self) -> pairwise::Scoring<Box<dyn Fn(u8, u8) -> i32>> {
+        let (gap_open, gap_extend) = self.gap_penalties(-100, -20);
         pairwise::Scoring {
-            gap_open: -100,
-            gap_extend: -20,
+            gap_open,
+            gap_extend,
             // TODO: deal better with possible IUPAC codes
             match_fn: Box::new(|a: u8, b: u8| {
                 if a == b {
@@ -144,9 +265,10 @@ impl AlignmentParameters {
     }
 
     pub fn get_scoring_local(&self) -> pairwise::Scoring<Box<dyn Fn(u8, u8) -> i32>> {
+        let (gap_open, gap_extend) = self.gap_penalties(-50, -10);
         pairwise::Scoring {
-            gap_open: -50,
-            gap_extend: -10,
+            gap_open,
+            gap_extend,
             // TODO: deal better with possible IUPAC codes
             match_fn: Box::new(|a: u8, b: u8| {
                 if a == b {
@@ -166,13 +288,23 @@ impl AlignmentParameters {
     }
 
     pub fn valid_v_alignment(&self, al: &Alignment) -> bool {
-        // right now: no insert
-        al.xend - al.xstart == al.yend - al.ystart
+        self.valid_indels(al)
     }
 
     pub fn valid_j_alignment(&self, al: &Alignment) -> bool {
-        // right now: no insert
-        al.score > self.min_score_j && al.xend - al.xstart == al.yend - al.ystart
+        al.score > self.min_score_j && self.valid_indels(al)
+    }
+
+    /// No more than `max_indels` inserted/deleted nucleotides in the alignment
+    fn valid_indels(&self, al: &Alignment) -> bool {
+        if self.max_indels == 0 {
+            return al.xend - al.xstart == al.yend - al.ystart;
+        }
+        al.operations
+            .iter()
+            .filter(|op| matches!(op, AlignmentOperation::Ins | AlignmentOperation::Del))
+            .count()
+            <= self.max_indels
     }
 }
 
@@ -184,7 +316,7 @@ impl InferenceParameters {
         InferenceParameters::default()
     }
     fn __repr__(&self) -> PyResult<String> {
-        Ok(format!("InferenceParameters(min_likelihood={:.3e}, min_ratio_likelihood={:.3e}, infer={}, store_best_event={}, compute_pgen={})", self.min_likelihood, self.min_ratio_likelihood, self.infer_features.any(), self.store_best_event, self.compute_pgen))
+        Ok(format!("InferenceParameters(min_likelihood={:.3e}, min_ratio_likelihood={:.3e}, infer={}, store_best_event={}, store_top_k={}, compute_pgen={})", self.min_likelihood, self.min_ratio_likelihood, self.infer_features.any(), self.store_best_event, self.store_top_k, self.compute_pgen))
     }
     fn __str__(&self) -> PyResult<String> {
         // This is what will be shown when you use print() in Python
@@ -198,8 +330,10 @@ impl Default for InferenceParameters {
             min_likelihood: (-400.0f64).exp2(),
             min_ratio_likelihood: (-100.0f64).exp2(),
             store_best_event: true,
+            store_top_k: 0,
             compute_pgen: true,
             infer_features: InferredFeatures::default(),
+            pseudocounts: Pseudocounts::default(),
         }
     }
 }
@@ -211,6 +345,7 @@ impl InferenceParameters {
         self.infer_features.del_j = false;
         self.infer_features.ins_vd = false;
         self.infer_features.ins_dj = false;
+        self.infer_features.ins_dd = false;
         self.infer_features.genes = false;
     }
 
@@ -228,3 +363,201 @@ impl InferenceParameters {
         }
     }
 }
+
+#[cfg_attr(all(feature = "py_binds", feature = "pyo3"), pyclass(get_all, set_all))]
+#[derive(Clone, Debug)]
+/// Parameters of the expectation-maximization driver (`Model::fit`)
+pub struct FitParameters {
+    /// Stop when the improvement of the log-likelihood (per sequence) between
+    /// two rounds is smaller than `tolerance`
+    pub tolerance: f64,
+    /// Maximal number of expectation-maximization rounds
+    pub max_iterations: usize,
+    /// Save the model (json format) in `checkpoint_path` every `checkpoint_every` rounds
+    pub checkpoint_every: usize,
+    pub checkpoint_path: Option<String>,
+    /// Stop as soon as the log-likelihood of the validation sequences decreases
+    /// (and keep the model of the previous round)
+    pub early_stopping: bool,
+}
+
+impl Default for FitParameters {
+    fn default() -> FitParameters {
+        FitParameters {
+            tolerance: 1e-3,
+            max_iterations: 20,
+            checkpoint_every: 1,
+            checkpoint_path: None,
+            early_stopping: false,
+        }
+    }
+}
+
+#[cfg(all(feature = "py_binds", feature = "pyo3"))]
+#[pymethods]
+impl FitParameters {
+    #[new]
+    #[pyo3(signature = (tolerance=1e-3, max_iterations=20, checkpoint_every=1, checkpoint_path=None, early_stopping=false))]
+    pub fn py_new(
+        tolerance: f64,
+        max_iterations: usize,
+        checkpoint_every: usize,
+        checkpoint_path: Option<String>,
+        early_stopping: bool,
+    ) -> Self {
+        FitParameters {
+            tolerance,
+            max_iterations,
+            checkpoint_every,
+            checkpoint_path,
+            early_stopping,
+        }
+    }
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!(
+            "FitParameters(tolerance={:.3e}, max_iterations={}, checkpoint_every={}, checkpoint_path={:?}, early_stopping={})",
+            self.tolerance,
+            self.max_iterations,
+            self.checkpoint_every,
+            self.checkpoint_path,
+            self.early_stopping
+        ))
+    }
+}
+
+#[cfg_attr(all(feature = "py_binds", feature = "pyo3"), pyclass(get_all, set_all))]
+#[derive(Clone, Debug)]
+/// Parameters of the bootstrap (`Model::bootstrap`)
+pub struct BootstrapParameters {
+    /// Number of resampled datasets
+    pub n_resamples: usize,
+    /// Quantiles (between 0 and 1) returned for each parameter
+    pub quantiles: Vec<f64>,
+    /// Resample `i` uses the seed `seed + i`, so results are reproducible
+    pub seed: u64,
+}
+
+impl Default for BootstrapParameters {
+    fn default() -> BootstrapParameters {
+        BootstrapParameters {
+            n_resamples: 100,
+            quantiles: vec![0.025, 0.5, 0.975],
+            seed: 0,
+        }
+    }
+}
+
+#[cfg(all(feature = "py_binds", feature = "pyo3"))]
+#[pymethods]
+impl BootstrapParameters {
+    #[new]
+    #[pyo3(signature = (n_resamples=100, quantiles=vec![0.025, 0.5, 0.975], seed=0))]
+    pub fn py_new(n_resamples: usize, quantiles: Vec<f64>, seed: u64) -> Self {
+        BootstrapParameters {
+            n_resamples,
+            quantiles,
+            seed,
+        }
+    }
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!(
+            "BootstrapParameters(n_resamples={}, quantiles={:?}, seed={})",
+            self.n_resamples, self.quantiles, self.seed
+        ))
+    }
+}
+
+#[cfg_attr(all(feature = "py_binds", feature = "pyo3"), pyclass(get_all, set_all))]
+#[derive(Clone, Debug)]
+/// Constraints on the generated sequences (`Generator::new_with_constraints`).
+/// The constraints on the genes, insertions and deletions are applied by
+/// sampling from the conditioned model, the ones on the junction by rejection.
+pub struct GenerationConstraints {
+    /// D genes allowed (first D gene for the VDDJ models)
+    pub d_genes: Option<Vec<Gene>>,
+    /// Allowed numbers of inserted nucleotides (inclusive bounds),
+    /// `ins_vj` is only used by the VJ models
+    pub ins_vd: Option<(usize, usize)>,
+    pub ins_dj: Option<(usize, usize)>,
+    pub ins_vj: Option<(usize, usize)>,
+    /// Allowed numbers of deleted nucleotides (inclusive bounds, negative
+    /// numbers are palindromic insertions)
+    pub del_v: Option<(i64, i64)>,
+    pub del_j: Option<(i64, i64)>,
+    /// Length of the amino-acid junction (from the conserved C to the F/W)
+    pub junction_aa_length: Option<usize>,
+    /// Regular expression that the amino-acid junction should match
+    pub junction_aa_motif: Option<String>,
+    /// Maximal number of draws for one sequence before giving up
+    pub max_attempts: usize,
+}
+
+impl Default for GenerationConstraints {
+    fn default() -> GenerationConstraints {
+        GenerationConstraints {
+            d_genes: None,
+            ins_vd: None,
+            ins_dj: None,
+            ins_vj: None,
+            del_v: None,
+            del_j: None,
+            junction_aa_length: None,
+            junction_aa_motif: None,
+            max_attempts: 100_000,
+        }
+    }
+}
+
+impl GenerationConstraints {
+    /// True if some constraints are applied by rejection
+    pub fn has_predicates(&self) -> bool {
+        self.junction_aa_length.is_some() || self.junction_aa_motif.is_some()
+    }
+}
+
+#[cfg(all(feature = "py_binds", feature = "pyo3"))]
+#[pymethods]
+impl GenerationConstraints {
+    #[new]
+    #[pyo3(signature = (d_genes=None, ins_vd=None, ins_dj=None, ins_vj=None, del_v=None, del_j=None, junction_aa_length=None, junction_aa_motif=None, max_attempts=100_000))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn py_new(
+        d_genes: Option<Vec<Gene>>,
+        ins_vd: Option<(usize, usize)>,
+        ins_dj: Option<(usize, usize)>,
+        ins_vj: Option<(usize, usize)>,
+        del_v: Option<(i64, i64)>,
+        del_j: Option<(i64, i64)>,
+        junction_aa_length: Option<usize>,
+        junction_aa_motif: Option<String>,
+        max_attempts: usize,
+    ) -> Self {
+        GenerationConstraints {
+            d_genes,
+            ins_vd,
+            ins_dj,
+            ins_vj,
+            del_v,
+            del_j,
+            junction_aa_length,
+            junction_aa_motif,
+            max_attempts,
+        }
+    }
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!(
+            "GenerationConstraints(d_genes={:?}, ins_vd={:?}, ins_dj={:?}, ins_vj={:?}, del_v={:?}, del_j={:?}, junction_aa_length={:?}, junction_aa_motif={:?}, max_attempts={})",
+            self.d_genes
+                .as_ref()
+                .map(|x| x.iter().map(|g| g.name.clone()).collect::<Vec<_>>()),
+            self.ins_vd,
+            self.ins_dj,
+            self.ins_vj,
+            self.del_v,
+            self.del_j,
+            self.junction_aa_length,
+            self.junction_aa_motif,
+            self.max_attempts
+        ))
+    }
+}
@@ -1,12 +1,15 @@
 // Parser for the marginals and params files
 
-use crate::shared::errors::{ErrorConstantRate, ErrorUniformRate};
+use crate::shared::errors::{
+    ErrorConstantRate, ErrorContextRate, ErrorIndelRate, ErrorSegmentRate, ErrorSubstitutionMatrix,
+    ErrorUniformRate,
+};
 use crate::shared::gene::Gene;
 use crate::shared::sequence::Dna;
 use crate::shared::ErrorParameters;
 use anyhow::{anyhow, Result};
 use csv::Reader;
-use ndarray::{ArrayD, IxDyn};
+use ndarray::{ArrayD, Dimension, IxDyn};
 use regex::Regex;
 use std::collections::HashMap;
 use std::fs::File;
@@ -112,34 +115,31 @@ impl Marginal {
             }
             result.pop(); // remove last comma
             result.push('\n');
-        } else if self.dimensions.len() == 2 {
-            for i in 0..self.dimensions[0] {
-                result.push_str(&format!("#[{},{}]\n%", self.dependences[0], i));
-                for k in 0..self.dimensions[1] {
-                    let prob = self.probabilities[[i, k]];
+        } else {
+            // one line per value of the dependences (last axis excluded)
+            let nb_dep = self.dependences.len();
+            for idx in ndarray::indices(&self.dimensions[..nb_dep]) {
+                let idx = idx.slice();
+                result.push('#');
+                result.push_str(
+                    &self
+                        .dependences
+                        .iter()
+                        .zip(idx)
+                        .map(|(d, i)| format!("[{d},{i}]"))
+                        .collect::<Vec<String>>()
+                        .join(","),
+                );
+                result.push_str("\n%");
+                for k in 0..self.dimensions[nb_dep] {
+                    let mut full_idx = idx.to_vec();
+                    full_idx.push(k);
+                    let prob = self.probabilities[IxDyn(&full_idx)];
                     result.push_str(&format!("{prob},"));
                 }
-                result.pop(); // remove last comma
+                result.pop(); // Remove the last comma
                 result.push('\n');
             }
-        } else if self.dimensions.len() == 3 {
-            for i in 0..self.dimensions[0] {
-                for j in 0..self.dimensions[1] {
-                    result.push_str(&format!(
-                        "#[{},{}],[{},{}]\n%",
-                        self.dependences[0], i, self.dependences[1], j,
-                    ));
-
-                    for k in 0..self.dimensions[2] {
-                        let prob = self.probabilities[[i, j, k]];
-                        result.push_str(&format!("{prob},"));
-                    }
-                    result.pop(); // Remove the last comma
-                    result.push('\n');
-                }
-            }
-        } else {
-            return Err(anyhow!("Not implemented."));
         }
         Ok(result)
     }
@@ -330,6 +330,15 @@ impl ParserParams {
                 return Err(anyhow!("Invalid format (error rate)"))?;
             }
             self.error = ErrorParameters::UniformRate(ErrorUniformRate::load(str_data)?);
+        } else if str_data[1].starts_with("#ContextErrorRate") {
+            self.error = ErrorParameters::ContextRate(ErrorContextRate::load(str_data)?);
+        } else if str_data[1].starts_with("#SubstitutionErrorRate") {
+            self.error =
+                ErrorParameters::SubstitutionMatrix(ErrorSubstitutionMatrix::load(str_data)?);
+        } else if str_data[1].starts_with("#SegmentErrorRate") {
+            self.error = ErrorParameters::SegmentRate(ErrorSegmentRate::load(str_data)?);
+        } else if str_data[1].starts_with("#IndelErrorRate") {
+            self.error = ErrorParameters::IndelRate(ErrorIndelRate::load(str_data)?);
         } else {
             return Err(anyhow!("Invalid format (error rate)"))?;
         }
@@ -3,7 +3,7 @@ use crate::shared::distributions::calc_steady_state_dist;
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
 use crate::shared::feature::{
     CategoricalFeature1, CategoricalFeature1g1, CategoricalFeature2, CategoricalFeature2g1,
-    CategoricalFeature3, InsertionFeature,
+    CategoricalFeature3, InsertionFeature, PosteriorMarginals,
 };
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
 use numpy::{IntoPyArray, PyArray1, PyArray2, PyArray3, PyArrayMethods};
@@ -92,3 +92,40 @@ impl InsertionFeature {
             .into()
     }
 }
+
+#[cfg(all(feature = "py_binds", feature = "pyo3"))]
+#[pymethods]
+impl PosteriorMarginals {
+    #[getter]
+    fn get_v(&self, py: Python) -> Py<PyArray1<f64>> {
+        self.v.to_owned().into_pyarray_bound(py).into()
+    }
+    #[getter]
+    fn get_d(&self, py: Python) -> Py<PyArray1<f64>> {
+        self.d.to_owned().into_pyarray_bound(py).into()
+    }
+    #[getter]
+    fn get_j(&self, py: Python) -> Py<PyArray1<f64>> {
+        self.j.to_owned().into_pyarray_bound(py).into()
+    }
+    #[getter]
+    fn get_del_v(&self, py: Python) -> Py<PyArray1<f64>> {
+        self.del_v.to_owned().into_pyarray_bound(py).into()
+    }
+    #[getter]
+    fn get_del_j(&self, py: Python) -> Py<PyArray1<f64>> {
+        self.del_j.to_owned().into_pyarray_bound(py).into()
+    }
+    #[getter]
+    fn get_del_d(&self, py: Python) -> Py<PyArray2<f64>> {
+        self.del_d.to_owned().into_pyarray_bound(py).into()
+    }
+    #[getter]
+    fn get_ins_vd(&self, py: Python) -> Py<PyArray1<f64>> {
+        self.ins_vd.to_owned().into_pyarray_bound(py).into()
+    }
+    #[getter]
+    fn get_ins_dj(&self, py: Python) -> Py<PyArray1<f64>> {
+        self.ins_dj.to_owned().into_pyarray_bound(py).into()
+    }
+}
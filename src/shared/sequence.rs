@@ -3,7 +3,7 @@ use crate::shared::amino_acids::{DegenerateCodon, DegenerateCodonSequence};
 
 use crate::shared::AlignmentParameters;
 use anyhow::{anyhow, Result};
-use bio::alignment::{pairwise, Alignment};
+use bio::alignment::{pairwise, Alignment, AlignmentOperation};
 use phf::phf_map;
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
 use pyo3::prelude::*;
@@ -937,14 +937,23 @@ impl Dna {
             return None;
         }
 
+        // the part of the sequence before the cut is assumed to be ungapped
+        let mut operations = vec![AlignmentOperation::Match; cutal.ystart];
+        operations.extend(cutal.operations.iter().filter(|op| {
+            !matches!(
+                op,
+                AlignmentOperation::Xclip(_) | AlignmentOperation::Yclip(_)
+            )
+        }));
+
         let alignment = bio::alignment::Alignment {
             ystart: 0, // that's where V start in the sequence, so always 0
             xstart: start_vcut + cutal.xstart - cutal.ystart,
-            // this doesn't work with indels
             xend: start_vcut + cutal.xend,
             yend: cutal.yend,
             ylen: seq.len(),
             xlen: v.len(),
+            operations,
             ..Default::default() // the other values are meaningless in that context
         };
 
@@ -1305,6 +1314,14 @@ impl DnaLike {
         self.inner.clone().to_dnas()
     }
 
+    /// Borrow the nucleotide sequence, `None` for a reverse-translated protein
+    pub fn as_dna(&self) -> Option<&Dna> {
+        match &self.inner {
+            DnaLikeEnum::Known(s) | DnaLikeEnum::Ambiguous(s) => Some(s),
+            DnaLikeEnum::Protein(_) => None,
+        }
+    }
+
     pub fn from_amino_acid(seq: AminoAcid) -> DnaLike {
         DnaLike {
             inner: DnaLikeEnum::from_amino_acid(seq),
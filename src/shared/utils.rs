@@ -330,6 +330,29 @@ pub fn sorted_and_complete_0start(arr: &[i64]) -> bool {
     true
 }
 
+/// Weight of each of the `nb_sequences` sequences used in the inference
+/// (1 by default), check that the given weights are valid
+pub fn sequence_weights(weights: Option<&[f64]>, nb_sequences: usize) -> Result<Vec<f64>> {
+    match weights {
+        Some(w) => {
+            if w.len() != nb_sequences {
+                return Err(anyhow!(
+                    "The number of weights ({}) and sequences ({}) differ",
+                    w.len(),
+                    nb_sequences
+                ));
+            }
+            if w.iter().any(|&x| !x.is_finite() || x < 0.) || w.iter().sum::<f64>() <= 0. {
+                return Err(anyhow!(
+                    "The weights should be positive, with a non-zero sum"
+                ));
+            }
+            Ok(w.to_vec())
+        }
+        None => Ok(vec![1.; nb_sequences]),
+    }
+}
+
 /// Return a vector with a tuple (f64, T) inserted, so that the vector stays sorted
 /// along the first element of the pair
 /// # Arguments
@@ -402,3 +425,16 @@ pub fn get_batches(n: usize, num_batches: usize) -> Vec<usize> {
     batches.extend_from_slice(&vec![quotient + 1; remainder]);
     batches
 }
+
+/// Quantile `q` (between 0 and 1) of a set of values, with linear
+/// interpolation between the closest ranks. Return NaN if `values` is empty.
+pub fn quantile(values: &[f64], q: f64) -> f64 {
+    if values.is_empty() {
+        return f64::NAN;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let pos = q.clamp(0., 1.) * (sorted.len() - 1) as f64;
+    let (lo, hi) = (pos.floor() as usize, pos.ceil() as usize);
+    sorted[lo] + (pos - lo as f64) * (sorted[hi] - sorted[lo])
+}
@@ -1,17 +1,18 @@
-use crate::shared::feature::Feature;
+use crate::shared::feature::{CategoricalFeature3, Feature, PosteriorMarginals};
 use crate::shared::model::Modelable;
+use crate::shared::InfEvent;
 use crate::shared::{errors::FeatureError, InferenceParameters, ResultInference};
 use crate::shared::{
     CategoricalFeature1g1, CategoricalFeature2, CategoricalFeature2g1, ErrorParameters,
     InsertionFeature,
 };
-use crate::shared::{DNAMarkovChain, InfEvent};
 use crate::v_dj::AggregatedFeatureStartDAndJ;
 use crate::vdj::{
-    AggregatedFeatureEndV, AggregatedFeatureSpanD, FeatureDJ, FeatureVD, Model, Sequence,
+    self, AggregatedFeatureEndV, AggregatedFeatureSpanD, FeatureDJ, FeatureVD, Model, Sequence,
 };
 use anyhow::Result;
 use ndarray::Axis;
+use rand::Rng;
 use std::cmp;
 use std::sync::Arc;
 
@@ -32,11 +33,13 @@ impl Features {
     /// Update the model from a vector of features and return an updated vector of features
     pub fn update(
         features: Vec<Features>,
+        weights: &[f64],
         model: &mut Model,
         ip: &InferenceParameters,
     ) -> Result<(Vec<Features>, f64)> {
         let errors = &mut ErrorParameters::update_error(
             features.iter().map(|a| a.error.clone()).collect(),
+            weights,
             &mut model.error,
         )?;
 
@@ -45,18 +48,44 @@ impl Features {
                 .iter()
                 .zip(errors.iter())
                 .map(|(f, e)| f.insvd.correct_for_error(e).clone()),
+            weights,
+            ip.pseudocounts.ins_vd,
+            ip.pseudocounts.markov_vd,
         )?;
         let insdj = InsertionFeature::average(
             features
                 .iter()
                 .zip(errors.iter())
                 .map(|(f, e)| f.insdj.correct_for_error(e).clone()),
+            weights,
+            ip.pseudocounts.ins_dj,
+            ip.pseudocounts.markov_dj,
+        )?;
+        let delv = CategoricalFeature1g1::average(
+            features.iter().map(|a| a.delv.clone()),
+            weights,
+            ip.pseudocounts.del_v,
+        )?;
+        let delj = CategoricalFeature1g1::average(
+            features.iter().map(|a| a.delj.clone()),
+            weights,
+            ip.pseudocounts.del_j,
+        )?;
+        let deld = CategoricalFeature2g1::average(
+            features.iter().map(|a| a.deld.clone()),
+            weights,
+            ip.pseudocounts.del_d,
+        )?;
+        let vj = CategoricalFeature2::average(
+            features.iter().map(|a| a.vj.clone()),
+            weights,
+            ip.pseudocounts.genes,
+        )?;
+        let d_given_j = CategoricalFeature1g1::average(
+            features.iter().map(|a| a.d.clone()),
+            weights,
+            ip.pseudocounts.genes,
         )?;
-        let delv = CategoricalFeature1g1::average(features.iter().map(|a| a.delv.clone()))?;
-        let delj = CategoricalFeature1g1::average(features.iter().map(|a| a.delj.clone()))?;
-        let deld = CategoricalFeature2g1::average(features.iter().map(|a| a.deld.clone()))?;
-        let vj = CategoricalFeature2::average(features.iter().map(|a| a.vj.clone()))?;
-        let d_given_j = CategoricalFeature1g1::average(features.iter().map(|a| a.d.clone()))?;
         let p_vdj =
             vj.clone().probas.insert_axis(Axis(1)) * d_given_j.clone().probas.insert_axis(Axis(0));
 
@@ -76,15 +105,19 @@ impl Features {
         let (p_vd, mc_vd) = insvd.get_parameters();
         if ip.infer_features.ins_vd {
             model.p_ins_vd = p_vd;
-            model.markov_chain_vd = Arc::new(DNAMarkovChain::new(&mc_vd, false)?);
+            model.markov_chain_vd = mc_vd;
         }
         let (p_dj, mc_dj) = insdj.get_parameters();
         if ip.infer_features.ins_dj {
             model.p_ins_dj = p_dj;
-            model.markov_chain_dj = Arc::new(DNAMarkovChain::new(&mc_dj, true)?);
+            model.markov_chain_dj = mc_dj;
         }
 
-        let sum_log_likelihood = features.iter().map(|x| x.log_likelihood.unwrap()).sum();
+        let sum_log_likelihood = features
+            .iter()
+            .zip(weights.iter())
+            .map(|(x, w)| w * x.log_likelihood.unwrap())
+            .sum();
 
         // Now update the features vector
         let mut new_features = Vec::new();
@@ -228,6 +261,7 @@ impl Features {
 
         if result.likelihood > 0. {
             self.cleanup(result.likelihood)?;
+            result.error_rate = self.error.posterior_error_rate();
         }
         // add a small positive likelihood to deal with the case where result.likelihood is 0.
         self.log_likelihood = Some((result.likelihood + ip.min_likelihood).log2());
@@ -236,6 +270,48 @@ impl Features {
         Ok(result)
     }
 
+    /// Draw `nb_samples` scenarios from the posterior P(scenario | sequence).
+    pub fn sample_posterior<R: Rng>(
+        &self,
+        sequence: &Sequence,
+        nb_samples: usize,
+        ip: &InferenceParameters,
+        rng: &mut R,
+    ) -> Result<Vec<InfEvent>> {
+        self.to_vdj()?
+            .sample_posterior(sequence, nb_samples, ip, rng)
+    }
+
+    /// Return the `k` most likely scenarios, sorted by decreasing likelihood.
+    pub fn top_events(
+        &self,
+        sequence: &Sequence,
+        k: usize,
+        ip: &InferenceParameters,
+    ) -> Result<Vec<InfEvent>> {
+        self.to_vdj()?.top_events(sequence, k, ip)
+    }
+
+    /// P(V, D, J) = P(V, J) P(D | J) defines the same scenarios as the VDJ
+    /// model, the sampling and the search of the most likely scenarios are
+    /// done by the equivalent VDJ features.
+    fn to_vdj(&self) -> Result<vdj::Features> {
+        let p_vdj = self.vj.probas.clone().insert_axis(Axis(1))
+            * self.d.probas.clone().insert_axis(Axis(0));
+        Ok(vdj::Features {
+            vdj: CategoricalFeature3::new(&p_vdj)?,
+            delv: self.delv.clone(),
+            delj: self.delj.clone(),
+            deld: self.deld.clone(),
+            insvd: self.insvd.clone(),
+            insdj: self.insdj.clone(),
+            insvd_given_v: None,
+            insdj_given_j: None,
+            error: self.error.clone(),
+            log_likelihood: None,
+        })
+    }
+
     //    pub fn average(features: Vec<Features>) -> Result<Features> {}
 }
 
@@ -347,6 +423,21 @@ impl Features {
 }
 
 impl Features {
+    /// Posterior marginals of the sequence, from the expected counts
+    /// (once `infer` has been called)
+    pub fn posterior_marginals(&self) -> PosteriorMarginals {
+        PosteriorMarginals {
+            v: self.vj.probas_dirty.sum_axis(Axis(1)),
+            d: self.d.probas_dirty.sum_axis(Axis(1)),
+            j: self.vj.probas_dirty.sum_axis(Axis(0)),
+            del_v: self.delv.probas_dirty.sum_axis(Axis(1)),
+            del_j: self.delj.probas_dirty.sum_axis(Axis(1)),
+            del_d: self.deld.probas_dirty.sum_axis(Axis(2)),
+            ins_vd: self.insvd.length_distribution_dirty.clone(),
+            ins_dj: self.insdj.length_distribution_dirty.clone(),
+        }
+    }
+
     pub fn normalize(&mut self) -> Result<()> {
         self.vj = self.vj.normalize()?;
         self.d = self.d.normalize()?;
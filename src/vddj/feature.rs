@@ -0,0 +1,143 @@
+use crate::shared::{
+    nucleotides_inv, DnaLike, InferenceParameters, InsertionFeature, Likelihood,
+    LikelihoodInsContainer,
+};
+use crate::vdj::Sequence;
+
+/// Insertions between the two D genes. The first inserted nucleotide depends
+/// on the nucleotide of the sequence just before the insertion (the last
+/// nucleotide of D1 when it's not fully deleted).
+#[derive(Debug)]
+pub struct FeatureDD {
+    // (end_d1, start_d2, previous_nuc)
+    likelihood: LikelihoodInsContainer,
+    dirty_likelihood: LikelihoodInsContainer,
+}
+
+impl FeatureDD {
+    pub fn new(
+        sequence: &Sequence,
+        feat_insdd: &InsertionFeature,
+        deld3_max: usize,
+        deld5_max: usize,
+        ip: &InferenceParameters,
+    ) -> Option<FeatureDD> {
+        if sequence.d_genes.is_empty() {
+            return None;
+        }
+        let min_end_d = sequence
+            .d_genes
+            .iter()
+            .map(|x| x.pos + x.len() as i64)
+            .min()
+            .unwrap()
+            - deld3_max as i64
+            + 1;
+        let max_end_d = sequence
+            .d_genes
+            .iter()
+            .map(|x| x.pos + x.len() as i64)
+            .max()
+            .unwrap();
+        let min_start_d = sequence.d_genes.iter().map(|x| x.pos).min().unwrap();
+        let max_start_d =
+            sequence.d_genes.iter().map(|x| x.pos).max().unwrap() + deld5_max as i64 - 1;
+
+        let mut likelihoods = LikelihoodInsContainer::zeros(
+            (min_end_d, min_start_d),
+            (max_end_d + 1, max_start_d + 1),
+            sequence.sequence_type,
+        );
+
+        for ed in min_end_d..=max_end_d {
+            // there is always a V gene before the D genes
+            if ed < 1 || ed > sequence.sequence.len() as i64 {
+                continue;
+            }
+            let previous_nuc = Self::previous_nucleotide(&sequence.sequence, ed);
+            for sd in ed..=max_start_d {
+                if sd < sequence.sequence.len() as i64
+                    && ((sd - ed) as usize) < feat_insdd.max_nb_insertions()
+                {
+                    let ins_dd = sequence.get_subsequence(ed, sd);
+                    let likelihood = feat_insdd.likelihood(&ins_dd, previous_nuc);
+                    if likelihood.max() > ip.min_likelihood {
+                        likelihoods.add_to((ed, sd), previous_nuc, likelihood);
+                    }
+                }
+            }
+        }
+
+        Some(FeatureDD {
+            dirty_likelihood: LikelihoodInsContainer::zeros(
+                likelihoods.dim().0,
+                likelihoods.dim().1,
+                sequence.sequence_type,
+            ),
+            likelihood: likelihoods,
+        })
+    }
+
+    /// Nucleotide of the sequence just before position `ed`
+    pub fn previous_nucleotide(sequence: &DnaLike, ed: i64) -> usize {
+        nucleotides_inv(sequence.extract_padded_subsequence(ed - 1, ed).to_dna().seq[0])
+    }
+
+    pub fn max_ed(&self) -> i64 {
+        self.likelihood.max().0
+    }
+
+    pub fn min_ed(&self) -> i64 {
+        self.likelihood.min().0
+    }
+
+    pub fn max_sd(&self) -> i64 {
+        self.likelihood.max().1
+    }
+
+    pub fn min_sd(&self) -> i64 {
+        self.likelihood.min().1
+    }
+
+    pub fn likelihood(&self, ed: i64, sd: i64, previous_nuc: usize) -> Likelihood {
+        self.likelihood.get((ed, sd), previous_nuc)
+    }
+
+    pub fn dirty_update(&mut self, ed: i64, sd: i64, previous_nuc: usize, likelihood: f64) {
+        self.dirty_likelihood
+            .add_to((ed, sd), previous_nuc, Likelihood::Scalar(likelihood));
+    }
+
+    pub fn disaggregate(
+        &self,
+        sequence: &DnaLike,
+        feat_insdd: &mut InsertionFeature,
+        ip: &InferenceParameters,
+    ) {
+        if !ip.infer_features.ins_dd {
+            return;
+        }
+        for ed in self.min_ed().max(1)..self.max_ed() {
+            if ed > sequence.len() as i64 {
+                continue;
+            }
+            let previous_nuc = Self::previous_nucleotide(sequence, ed);
+            for sd in self.min_sd().max(ed)..self.max_sd() {
+                if sd < sequence.len() as i64
+                    && ((sd - ed) as usize) < feat_insdd.max_nb_insertions()
+                {
+                    let ll = self.likelihood(ed, sd, previous_nuc).to_scalar().unwrap();
+                    let updated_ll = self
+                        .dirty_likelihood
+                        .get((ed, sd), previous_nuc)
+                        .to_scalar()
+                        .unwrap();
+                    if ll > ip.min_likelihood && updated_ll > 0. {
+                        let ins_dd = sequence.extract_padded_subsequence(ed, sd);
+                        feat_insdd.dirty_update(&ins_dd, previous_nuc, updated_ll);
+                    }
+                }
+            }
+        }
+    }
+}
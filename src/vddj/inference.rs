@@ -0,0 +1,853 @@
+use crate::shared::feature::{
+    CategoricalFeature1g1, CategoricalFeature2g1, CategoricalFeature3, Feature, InfEvent,
+    InsertionFeature, PosteriorMarginals, ResultInference,
+};
+use crate::shared::utils::difference_as_i64;
+use crate::shared::Modelable;
+use crate::shared::{errors::FeatureError, ErrorParameters, InferenceParameters};
+use crate::shared::{ErrorDAlignment, ErrorJAlignment, ErrorVAlignment};
+use crate::vddj::feature::FeatureDD;
+use crate::vdj::inference::transition;
+use crate::vdj::{
+    AggregatedFeatureEndV, AggregatedFeatureSpanD, AggregatedFeatureStartJ, FeatureDJ, FeatureVD,
+    Model, Sequence,
+};
+use anyhow::{anyhow, Result};
+use ndarray::{Array1, Array2, Axis};
+use std::cmp;
+use std::sync::Arc;
+
+/// Features of the VDDJ model. Two D genes (D1 then D2) are chosen among the
+/// same D segments, with an extra insertion region between them.
+#[derive(Default, Clone, Debug)]
+pub struct Features {
+    pub vdj: CategoricalFeature3,  // v, d1, j
+    pub d2: CategoricalFeature1g1, // d2 | d1
+    pub delv: CategoricalFeature1g1,
+    pub delj: CategoricalFeature1g1,
+    pub deld: CategoricalFeature2g1,  // d5, d3, d1
+    pub deld2: CategoricalFeature2g1, // d5, d3, d2
+    pub insvd: InsertionFeature,
+    pub insdd: InsertionFeature,
+    pub insdj: InsertionFeature,
+    pub error: FeatureError,
+    pub log_likelihood: Option<f64>,
+}
+
+impl Features {
+    pub fn new(model: &Model) -> Result<Features> {
+        let d2 = model
+            .d2
+            .as_ref()
+            .ok_or(anyhow!("The model doesn't define a second D gene"))?;
+        Ok(Features {
+            vdj: CategoricalFeature3::new(&model.p_vdj)?,
+            d2: CategoricalFeature1g1::new(&d2.p_d2_given_d1)?,
+            delv: CategoricalFeature1g1::new(&model.p_del_v_given_v)?,
+            delj: CategoricalFeature1g1::new(&model.p_del_j_given_j)?,
+            deld: CategoricalFeature2g1::new(&model.p_del_d5_del_d3)?,
+            deld2: CategoricalFeature2g1::new(&d2.p_del_d5_del_d3)?,
+            insvd: InsertionFeature::new(&model.p_ins_vd, Arc::clone(&model.markov_chain_vd))?,
+            insdd: InsertionFeature::new(&d2.p_ins_dd, Arc::clone(&d2.markov_chain_dd))?,
+            insdj: InsertionFeature::new(&model.p_ins_dj, Arc::clone(&model.markov_chain_dj))?,
+            error: model.error.get_feature()?,
+            log_likelihood: None,
+        })
+    }
+
+    /// Update the model from a vector of features and "average" the features.
+    /// Return the new features and the total log likelihood
+    pub fn update(
+        features: Vec<Features>,
+        weights: &[f64],
+        model: &mut Model,
+        ip: &InferenceParameters,
+    ) -> Result<(Vec<Features>, f64)> {
+        let errors = &mut ErrorParameters::update_error(
+            features.iter().map(|a| a.error.clone()).collect(),
+            weights,
+            &mut model.error,
+        )?;
+
+        let insvd = InsertionFeature::average(
+            features
+                .iter()
+                .zip(errors.iter())
+                .map(|(f, e)| f.insvd.correct_for_error(e).clone()),
+            weights,
+            ip.pseudocounts.ins_vd,
+            ip.pseudocounts.markov_vd,
+        )?;
+        let insdd = InsertionFeature::average(
+            features
+                .iter()
+                .zip(errors.iter())
+                .map(|(f, e)| f.insdd.correct_for_error(e).clone()),
+            weights,
+            ip.pseudocounts.ins_dd,
+            ip.pseudocounts.markov_dd,
+        )?;
+        let insdj = InsertionFeature::average(
+            features
+                .iter()
+                .zip(errors.iter())
+                .map(|(f, e)| f.insdj.correct_for_error(e).clone()),
+            weights,
+            ip.pseudocounts.ins_dj,
+            ip.pseudocounts.markov_dj,
+        )?;
+        let delv = CategoricalFeature1g1::average(
+            features.iter().map(|a| a.delv.clone()),
+            weights,
+            ip.pseudocounts.del_v,
+        )?;
+        let delj = CategoricalFeature1g1::average(
+            features.iter().map(|a| a.delj.clone()),
+            weights,
+            ip.pseudocounts.del_j,
+        )?;
+        let deld = CategoricalFeature2g1::average(
+            features.iter().map(|a| a.deld.clone()),
+            weights,
+            ip.pseudocounts.del_d,
+        )?;
+        let deld2 = CategoricalFeature2g1::average(
+            features.iter().map(|a| a.deld2.clone()),
+            weights,
+            ip.pseudocounts.del_d,
+        )?;
+        let vdj = CategoricalFeature3::average(
+            features.iter().map(|a| a.vdj.clone()),
+            weights,
+            ip.pseudocounts.genes,
+        )?;
+        let d2 = CategoricalFeature1g1::average(
+            features.iter().map(|a| a.d2.clone()),
+            weights,
+            ip.pseudocounts.genes,
+        )?;
+
+        let model_d2 = model
+            .d2
+            .as_mut()
+            .ok_or(anyhow!("The model doesn't define a second D gene"))?;
+        if ip.infer_features.genes {
+            model_d2.p_d2_given_d1 = d2.clone().probas;
+        }
+        if ip.infer_features.del_d {
+            model_d2.p_del_d5_del_d3 = deld2.clone().probas;
+        }
+        let (p_dd, mc_dd) = insdd.get_parameters();
+        if ip.infer_features.ins_dd {
+            model_d2.p_ins_dd = p_dd;
+            model_d2.markov_chain_dd = mc_dd;
+        }
+
+        if ip.infer_features.genes {
+            model.set_p_vdj(&vdj.clone().probas)?;
+        }
+        if ip.infer_features.del_v {
+            model.p_del_v_given_v = delv.clone().probas;
+        }
+        if ip.infer_features.del_j {
+            model.p_del_j_given_j = delj.clone().probas;
+        }
+        if ip.infer_features.del_d {
+            model.p_del_d5_del_d3 = deld.clone().probas;
+        }
+        let (p_vd, mc_vd) = insvd.get_parameters();
+        if ip.infer_features.ins_vd {
+            model.p_ins_vd = p_vd;
+            model.markov_chain_vd = mc_vd;
+        }
+        let (p_dj, mc_dj) = insdj.get_parameters();
+        if ip.infer_features.ins_dj {
+            model.p_ins_dj = p_dj;
+            model.markov_chain_dj = mc_dj;
+        }
+
+        let sum_log_likelihood = features
+            .iter()
+            .zip(weights.iter())
+            .map(|(x, w)| w * x.log_likelihood.unwrap())
+            .sum();
+
+        // Now update the features vector
+        let mut new_features = Vec::new();
+        for error in errors {
+            new_features.push(Features {
+                vdj: vdj.clone(),
+                d2: d2.clone(),
+                delv: delv.clone(),
+                delj: delj.clone(),
+                deld: deld.clone(),
+                deld2: deld2.clone(),
+                insvd: insvd.clone(),
+                insdd: insdd.clone(),
+                insdj: insdj.clone(),
+                error: error.clone(),
+                log_likelihood: None,
+            });
+        }
+        model.initialize()?;
+        Ok((new_features, sum_log_likelihood))
+    }
+
+    /// Sum over all the scenarios compatible with the alignments. For each
+    /// choice of genes, the positions (end V, start D1, end D1, start D2,
+    /// end D2, start J) form a chain, summed exactly with a forward-backward pass.
+    pub fn infer(
+        &mut self,
+        sequence: &Sequence,
+        ip: &InferenceParameters,
+    ) -> Result<ResultInference> {
+        if sequence.sequence.is_protein() {
+            return Err(anyhow!(
+                "Amino-acid sequences are not supported by the VDDJ models"
+            ));
+        }
+
+        // small positive likelihood, in case the inference stops early
+        self.log_likelihood = Some((ip.min_likelihood).log2());
+
+        // Estimate the likelihood of all possible insertions
+        let Some(mut ins_vd) = FeatureVD::new(
+            sequence,
+            &self.insvd,
+            self.delv.dim().0,
+            self.deld.dim().0,
+            ip,
+        ) else {
+            return Ok(ResultInference::impossible());
+        };
+        let Some(mut ins_dd) = FeatureDD::new(
+            sequence,
+            &self.insdd,
+            self.deld.dim().1,
+            self.deld2.dim().0,
+            ip,
+        ) else {
+            return Ok(ResultInference::impossible());
+        };
+        let Some(mut ins_dj) = FeatureDJ::new(
+            sequence,
+            &self.insdj,
+            self.deld2.dim().1,
+            self.delj.dim().0,
+            ip,
+        ) else {
+            return Ok(ResultInference::impossible());
+        };
+
+        // Define the aggregated features for this sequence (each D gene can
+        // be the first or the second one)
+        let mut features_d1 = Vec::new();
+        let mut features_d2 = Vec::new();
+        for d_idx in 0..self.vdj.dim().1 {
+            let dals = sequence.get_specific_dgene(d_idx);
+            features_d1.push(AggregatedFeatureSpanD::new(
+                &dals,
+                &self.deld,
+                &self.error,
+                ip,
+            ));
+            features_d2.push(AggregatedFeatureSpanD::new(
+                &dals,
+                &self.deld2,
+                &self.error,
+                ip,
+            ));
+        }
+
+        let mut features_v = Vec::new();
+        for val in &sequence.v_genes {
+            features_v.push(AggregatedFeatureEndV::new(val, &self.delv, &self.error, ip));
+        }
+
+        let mut features_j = Vec::new();
+        for jal in &sequence.j_genes {
+            features_j.push(AggregatedFeatureStartJ::new(
+                jal,
+                &self.delj,
+                &self.error,
+                ip,
+            ));
+        }
+
+        let mut result = ResultInference::impossible();
+
+        // Main loop
+        for v in features_v.iter_mut().filter_map(|x| x.as_mut()) {
+            for j in features_j.iter_mut().filter_map(|x| x.as_mut()) {
+                for d1 in features_d1.iter_mut().filter_map(|x| x.as_mut()) {
+                    for d2 in features_d2.iter_mut().filter_map(|x| x.as_mut()) {
+                        self.infer_given_vddj(
+                            sequence,
+                            (v, d1, d2, j),
+                            (&mut ins_vd, &mut ins_dd, &mut ins_dj),
+                            ip,
+                            &mut result,
+                        )?;
+                    }
+                }
+            }
+        }
+
+        // disaggregate the insertion features
+        ins_vd.disaggregate(&sequence.sequence, &mut self.insvd, ip);
+        ins_dd.disaggregate(&sequence.sequence, &mut self.insdd, ip);
+        ins_dj.disaggregate(&sequence.sequence, &mut self.insdj, ip);
+
+        // disaggregate the v/d/j features
+        for (val, v) in sequence.v_genes.iter().zip(features_v.iter_mut()) {
+            if let Some(f) = v {
+                f.disaggregate(val, &mut self.delv, &mut self.error, ip);
+            }
+        }
+        for (jal, j) in sequence.j_genes.iter().zip(features_j.iter_mut()) {
+            if let Some(f) = j {
+                f.disaggregate(jal, &mut self.delj, &mut self.error, ip);
+            }
+        }
+        for f in features_d1.iter().flatten() {
+            f.disaggregate(
+                &sequence.get_specific_dgene(f.index),
+                &mut self.deld,
+                &mut self.error,
+                &mut result.best_event,
+                ip,
+            );
+        }
+        // the position of D2 is found with a stand-in event
+        let mut event_d2 = result.best_event.as_ref().and_then(|ev| {
+            ev.d2_index.map(|d2_index| InfEvent {
+                d_index: d2_index,
+                start_d: ev.start_d2,
+                end_d: ev.end_d2,
+                ..Default::default()
+            })
+        });
+        for f in features_d2.iter().flatten() {
+            f.disaggregate(
+                &sequence.get_specific_dgene(f.index),
+                &mut self.deld2,
+                &mut self.error,
+                &mut event_d2,
+                ip,
+            );
+        }
+        if let (Some(ev), Some(ev_d2)) = (&mut result.best_event, event_d2) {
+            ev.pos_d2 = ev_d2.pos_d;
+        }
+
+        // Divide all the proba by P(R) (the probability of the sequence)
+        if result.likelihood > 0. {
+            self.scale(result.likelihood)?;
+        } else {
+            return Ok(ResultInference::impossible());
+        }
+        result.error_rate = self.error.posterior_error_rate();
+
+        // add a small positive likelihood to deal with the case where result.likelihood is 0.
+        self.log_likelihood = Some((result.likelihood + ip.min_likelihood).log2());
+
+        Ok(result)
+    }
+
+    /// Forward-backward over the positions for a given choice of V, D1, D2, J
+    #[allow(clippy::type_complexity)]
+    pub fn infer_given_vddj(
+        &mut self,
+        sequence: &Sequence,
+        (feature_v, feature_d1, feature_d2, feature_j): (
+            &mut AggregatedFeatureEndV,
+            &mut AggregatedFeatureSpanD,
+            &mut AggregatedFeatureSpanD,
+            &mut AggregatedFeatureStartJ,
+        ),
+        (ins_vd, ins_dd, ins_dj): (&mut FeatureVD, &mut FeatureDD, &mut FeatureDJ),
+        ip: &InferenceParameters,
+        current_result: &mut ResultInference,
+    ) -> Result<()> {
+        let likelihood_genes =
+            self.vdj
+                .likelihood((feature_v.index, feature_d1.index, feature_j.index))
+                * self.d2.likelihood((feature_d2.index, feature_d1.index));
+        if likelihood_genes <= ip.min_likelihood {
+            return Ok(());
+        }
+
+        // ranges of ev, sd1, ed1, sd2, ed2, sj
+        let ranges = [
+            (
+                cmp::max(feature_v.start_v3, ins_vd.min_ev()),
+                cmp::min(feature_v.end_v3, ins_vd.max_ev()),
+            ),
+            (
+                cmp::max(feature_d1.start_d5, ins_vd.min_sd()),
+                cmp::min(feature_d1.end_d5, ins_vd.max_sd()),
+            ),
+            (
+                cmp::max(feature_d1.start_d3, ins_dd.min_ed()),
+                cmp::min(feature_d1.end_d3, ins_dd.max_ed()),
+            ),
+            (
+                cmp::max(feature_d2.start_d5, ins_dd.min_sd()),
+                cmp::min(feature_d2.end_d5, ins_dd.max_sd()),
+            ),
+            (
+                cmp::max(feature_d2.start_d3, ins_dj.min_ed()),
+                cmp::min(feature_d2.end_d3, ins_dj.max_ed()),
+            ),
+            (
+                cmp::max(feature_j.start_j5, ins_dj.min_sj()),
+                cmp::min(feature_j.end_j5, ins_dj.max_sj()),
+            ),
+        ];
+        if ranges.iter().any(|r| r.0 >= r.1) {
+            return Ok(());
+        }
+
+        // nucleotides at the edge of the V/J genes, for the Markov chains
+        let last_v_nucleotides: Vec<usize> = (ranges[0].0..ranges[0].1)
+            .map(|ev| {
+                feature_v
+                    .alignment
+                    .get_last_nucleotide((feature_v.end_v3 - ev - 1) as usize)
+            })
+            .collect();
+        let first_j_nucleotides: Vec<usize> = (ranges[5].0..ranges[5].1)
+            .map(|sj| {
+                feature_j
+                    .alignment
+                    .get_first_nucleotide((sj - feature_j.start_j5) as usize)
+            })
+            .collect();
+        let last_v_nucleotide = |ev: i64| last_v_nucleotides[(ev - ranges[0].0) as usize];
+        let first_j_nucleotide = |sj: i64| first_j_nucleotides[(sj - ranges[5].0) as usize];
+        let previous_nucleotide = |ed: i64| FeatureDD::previous_nucleotide(&sequence.sequence, ed);
+
+        let factors = [
+            transition(ranges[0], ranges[1], |ev, sd| {
+                Ok(feature_v.likelihood(ev).to_scalar()?
+                    * ins_vd
+                        .likelihood(ev, sd, last_v_nucleotide(ev))
+                        .to_scalar()?)
+            })?,
+            transition(ranges[1], ranges[2], |sd, ed| {
+                feature_d1.likelihood(sd, ed).to_scalar()
+            })?,
+            transition(ranges[2], ranges[3], |ed, sd| {
+                ins_dd
+                    .likelihood(ed, sd, previous_nucleotide(ed))
+                    .to_scalar()
+            })?,
+            transition(ranges[3], ranges[4], |sd, ed| {
+                feature_d2.likelihood(sd, ed).to_scalar()
+            })?,
+            transition(ranges[4], ranges[5], |ed, sj| {
+                Ok(ins_dj
+                    .likelihood(ed, sj, first_j_nucleotide(sj))
+                    .to_scalar()?
+                    * feature_j.likelihood(sj).to_scalar()?)
+            })?,
+        ];
+
+        // forward (alpha) and backward (beta) sums
+        let mut alpha = vec![Array1::ones(factors[0].dim().0)];
+        for m in &factors {
+            alpha.push(alpha.last().unwrap().dot(m));
+        }
+        let mut beta = vec![Array1::ones(factors[4].dim().1)];
+        for m in factors.iter().rev() {
+            beta.push(m.dot(beta.last().unwrap()));
+        }
+        beta.reverse();
+
+        let likelihood = likelihood_genes * alpha[5].sum();
+        if likelihood <= ip.min_likelihood {
+            return Ok(());
+        }
+        current_result.likelihood += likelihood;
+
+        if ip.store_best_event {
+            self.best_event_given_vddj(
+                &factors,
+                &ranges,
+                likelihood_genes,
+                (feature_v, feature_d1, feature_d2, feature_j),
+                current_result,
+                ip,
+            );
+        }
+
+        // posterior probability (times the likelihood) of each pair of
+        // consecutive positions
+        let posterior = |k: usize, x: i64, y: i64| {
+            likelihood_genes
+                * alpha[k][(x - ranges[k].0) as usize]
+                * factors[k][[(x - ranges[k].0) as usize, (y - ranges[k + 1].0) as usize]]
+                * beta[k + 1][(y - ranges[k + 1].0) as usize]
+        };
+
+        for ev in ranges[0].0..ranges[0].1 {
+            for sd in cmp::max(ev, ranges[1].0)..ranges[1].1 {
+                let ll = posterior(0, ev, sd);
+                if ll <= 0. {
+                    continue;
+                }
+                if ip.infer_features.del_v {
+                    feature_v.dirty_update(ev, ll);
+                }
+                if ip.infer_features.ins_vd {
+                    ins_vd.dirty_update(ev, sd, last_v_nucleotide(ev), ll);
+                }
+            }
+        }
+        for sd in ranges[1].0..ranges[1].1 {
+            for ed in cmp::max(sd, ranges[2].0)..ranges[2].1 {
+                let ll = posterior(1, sd, ed);
+                if ll > 0. && ip.infer_features.del_d {
+                    feature_d1.dirty_update(sd, ed, ll);
+                }
+            }
+        }
+        for ed in ranges[2].0..ranges[2].1 {
+            for sd in cmp::max(ed, ranges[3].0)..ranges[3].1 {
+                let ll = posterior(2, ed, sd);
+                if ll > 0. && ip.infer_features.ins_dd {
+                    ins_dd.dirty_update(ed, sd, previous_nucleotide(ed), ll);
+                }
+            }
+        }
+        for sd in ranges[3].0..ranges[3].1 {
+            for ed in cmp::max(sd, ranges[4].0)..ranges[4].1 {
+                let ll = posterior(3, sd, ed);
+                if ll > 0. && ip.infer_features.del_d {
+                    feature_d2.dirty_update(sd, ed, ll);
+                }
+            }
+        }
+        for ed in ranges[4].0..ranges[4].1 {
+            for sj in cmp::max(ed, ranges[5].0)..ranges[5].1 {
+                let ll = posterior(4, ed, sj);
+                if ll <= 0. {
+                    continue;
+                }
+                if ip.infer_features.del_j {
+                    feature_j.dirty_update(sj, ll);
+                }
+                if ip.infer_features.ins_dj {
+                    ins_dj.dirty_update(ed, sj, first_j_nucleotide(sj), ll);
+                }
+            }
+        }
+        if ip.infer_features.genes {
+            self.vdj.dirty_update(
+                (feature_v.index, feature_d1.index, feature_j.index),
+                likelihood,
+            );
+            self.d2
+                .dirty_update((feature_d2.index, feature_d1.index), likelihood);
+        }
+        Ok(())
+    }
+
+    /// Most likely positions for a given choice of genes (max-product version
+    /// of the forward pass), replace the best event if more likely
+    #[allow(clippy::type_complexity)]
+    fn best_event_given_vddj(
+        &self,
+        factors: &[Array2<f64>; 5],
+        ranges: &[(i64, i64); 6],
+        likelihood_genes: f64,
+        (feature_v, feature_d1, feature_d2, feature_j): (
+            &AggregatedFeatureEndV,
+            &AggregatedFeatureSpanD,
+            &AggregatedFeatureSpanD,
+            &AggregatedFeatureStartJ,
+        ),
+        current_result: &mut ResultInference,
+        ip: &InferenceParameters,
+    ) {
+        let mut delta = Array1::<f64>::ones(factors[0].dim().0);
+        let mut backtrack = Vec::new();
+        for m in factors {
+            let mut next = Array1::<f64>::zeros(m.dim().1);
+            let mut arg = vec![0; m.dim().1];
+            for ((x, y), &f) in m.indexed_iter() {
+                if delta[x] * f > next[y] {
+                    next[y] = delta[x] * f;
+                    arg[y] = x;
+                }
+            }
+            backtrack.push(arg);
+            delta = next;
+        }
+        let (mut idx, best) =
+            delta
+                .iter()
+                .copied()
+                .enumerate()
+                .fold((0, 0.), |acc, x| if x.1 > acc.1 { x } else { acc });
+        let likelihood = likelihood_genes * best;
+        if likelihood <= current_result.best_likelihood {
+            return;
+        }
+
+        let mut positions = [0; 6];
+        positions[5] = idx as i64 + ranges[5].0;
+        for k in (0..5).rev() {
+            idx = backtrack[k][idx];
+            positions[k] = idx as i64 + ranges[k].0;
+        }
+        let [ev, sd1, ed1, sd2, ed2, sj] = positions;
+
+        current_result.best_likelihood = likelihood;
+        // pos_d and pos_d2 are fixed when we disaggregate D
+        let event = InfEvent {
+            v_index: feature_v.index,
+            v_start_gene: feature_v.start_gene,
+            j_index: feature_j.index,
+            j_start_seq: feature_j.start_seq,
+            d_index: feature_d1.index,
+            end_v: ev,
+            start_d: sd1,
+            end_d: ed1,
+            d2_index: Some(feature_d2.index),
+            start_d2: sd2,
+            end_d2: ed2,
+            start_j: sj,
+            likelihood,
+            ..Default::default()
+        };
+        current_result.set_best_event(event, ip);
+    }
+
+    pub fn scale(&mut self, likelihood: f64) -> Result<()> {
+        self.vdj.scale_dirty(1. / likelihood);
+        self.d2.scale_dirty(1. / likelihood);
+        self.delv.scale_dirty(1. / likelihood);
+        self.delj.scale_dirty(1. / likelihood);
+        self.deld.scale_dirty(1. / likelihood);
+        self.deld2.scale_dirty(1. / likelihood);
+        self.insvd.scale_dirty(1. / likelihood);
+        self.insdd.scale_dirty(1. / likelihood);
+        self.insdj.scale_dirty(1. / likelihood);
+        self.error.scale_dirty(1. / likelihood);
+        Ok(())
+    }
+
+    /// Posterior marginals of the sequence, from the expected counts
+    /// (once `infer` has been called), for the first D gene
+    pub fn posterior_marginals(&self) -> PosteriorMarginals {
+        let p_vdj = &self.vdj.probas_dirty;
+        PosteriorMarginals {
+            v: p_vdj.sum_axis(Axis(2)).sum_axis(Axis(1)),
+            d: p_vdj.sum_axis(Axis(2)).sum_axis(Axis(0)),
+            j: p_vdj.sum_axis(Axis(1)).sum_axis(Axis(0)),
+            del_v: self.delv.probas_dirty.sum_axis(Axis(1)),
+            del_j: self.delj.probas_dirty.sum_axis(Axis(1)),
+            del_d: self.deld.probas_dirty.sum_axis(Axis(2)),
+            ins_vd: self.insvd.length_distribution_dirty.clone(),
+            ins_dj: self.insdj.length_distribution_dirty.clone(),
+        }
+    }
+
+    pub fn normalize(&mut self) -> Result<()> {
+        self.vdj = self.vdj.normalize()?;
+        self.d2 = self.d2.normalize()?;
+        self.delv = self.delv.normalize()?;
+        self.delj = self.delj.normalize()?;
+        self.deld = self.deld.normalize()?;
+        self.deld2 = self.deld2.normalize()?;
+        self.insvd = self.insvd.normalize()?;
+        self.insdd = self.insdd.normalize()?;
+        self.insdj = self.insdj.normalize()?;
+        Ok(())
+    }
+}
+
+impl Features {
+    /// Brute-force inference
+    /// for test-purpose only
+    pub fn infer_brute_force(
+        &mut self,
+        sequence: &Sequence,
+        ip: &InferenceParameters,
+    ) -> Result<ResultInference> {
+        if sequence.sequence.is_protein() {
+            return Err(anyhow!("The brute-force model doesn't work with proteins"));
+        }
+        let mut result = ResultInference::impossible();
+        let seq_len = sequence.sequence.len() as i64;
+        let d_in_sequence = |start: i64, end: i64| start >= 0 && start < seq_len && end <= seq_len;
+
+        for val in &sequence.v_genes {
+            for jal in &sequence.j_genes {
+                for dal1 in &sequence.d_genes {
+                    for dal2 in &sequence.d_genes {
+                        let ll_genes = self.vdj.likelihood((val.index, dal1.index, jal.index))
+                            * self.d2.likelihood((dal2.index, dal1.index));
+                        for delv in 0..self.delv.dim().0 {
+                            let v_end = difference_as_i64(val.end_seq, delv);
+                            let ll_v = ll_genes
+                                * self.delv.likelihood((delv, val.index))
+                                * self.error.likelihood_v(&ErrorVAlignment { val, del: delv });
+                            for (deld5, deld3) in
+                                itertools::iproduct!(0..self.deld.dim().0, 0..self.deld.dim().1)
+                            {
+                                let d1_start = dal1.pos + deld5 as i64;
+                                let d1_end = dal1.pos + (dal1.len() - deld3) as i64;
+                                if d1_start > d1_end
+                                    || d1_start < v_end
+                                    || d1_end < 1
+                                    || !d_in_sequence(d1_start, d1_end)
+                                {
+                                    continue;
+                                }
+                                let ins_vd = sequence.get_subsequence(v_end, d1_start);
+                                let last_v_nucleotide = val.get_last_nucleotide(delv);
+                                let ll_d1 = ll_v
+                                    * self
+                                        .insvd
+                                        .likelihood(&ins_vd, last_v_nucleotide)
+                                        .to_scalar()?
+                                    * self.deld.likelihood((deld5, deld3, dal1.index))
+                                    * self.error.likelihood_d(&ErrorDAlignment {
+                                        dal: dal1,
+                                        deld5,
+                                        deld3,
+                                    });
+                                if ll_d1 <= 0. {
+                                    continue;
+                                }
+                                for (deld2_5, deld2_3) in itertools::iproduct!(
+                                    0..self.deld2.dim().0,
+                                    0..self.deld2.dim().1
+                                ) {
+                                    let d2_start = dal2.pos + deld2_5 as i64;
+                                    let d2_end = dal2.pos + (dal2.len() - deld2_3) as i64;
+                                    if d2_start > d2_end
+                                        || d2_start < d1_end
+                                        || !d_in_sequence(d2_start, d2_end)
+                                    {
+                                        continue;
+                                    }
+                                    let ins_dd = sequence.get_subsequence(d1_end, d2_start);
+                                    let previous_nucleotide =
+                                        FeatureDD::previous_nucleotide(&sequence.sequence, d1_end);
+                                    let ll_d2 = ll_d1
+                                        * self
+                                            .insdd
+                                            .likelihood(&ins_dd, previous_nucleotide)
+                                            .to_scalar()?
+                                        * self.deld2.likelihood((deld2_5, deld2_3, dal2.index))
+                                        * self.error.likelihood_d(&ErrorDAlignment {
+                                            dal: dal2,
+                                            deld5: deld2_5,
+                                            deld3: deld2_3,
+                                        });
+                                    if ll_d2 <= 0. {
+                                        continue;
+                                    }
+                                    for delj in 0..self.delj.dim().0 {
+                                        let j_start = jal.start_seq as i64 - jal.start_gene as i64
+                                            + delj as i64;
+                                        if j_start < d2_end {
+                                            continue;
+                                        }
+                                        let ins_dj = sequence.get_subsequence(d2_end, j_start);
+                                        let first_j_nucleotide = jal.get_first_nucleotide(delj);
+                                        let ll = ll_d2
+                                            * self
+                                                .insdj
+                                                .likelihood(&ins_dj, first_j_nucleotide)
+                                                .to_scalar()?
+                                            * self.delj.likelihood((delj, jal.index))
+                                            * self
+                                                .error
+                                                .likelihood_j(&ErrorJAlignment { jal, del: delj });
+                                        if ll <= 0. {
+                                            continue;
+                                        }
+
+                                        result.likelihood += ll;
+                                        self.vdj
+                                            .dirty_update((val.index, dal1.index, jal.index), ll);
+                                        self.d2.dirty_update((dal2.index, dal1.index), ll);
+                                        self.delv.dirty_update((delv, val.index), ll);
+                                        self.delj.dirty_update((delj, jal.index), ll);
+                                        self.deld.dirty_update((deld5, deld3, dal1.index), ll);
+                                        self.deld2.dirty_update((deld2_5, deld2_3, dal2.index), ll);
+                                        self.insvd.dirty_update(&ins_vd, last_v_nucleotide, ll);
+                                        self.insdd.dirty_update(&ins_dd, previous_nucleotide, ll);
+                                        self.insdj.dirty_update(&ins_dj, first_j_nucleotide, ll);
+                                        self.error.dirty_update_v_fragment(
+                                            &ErrorVAlignment { val, del: delv },
+                                            ll,
+                                        );
+                                        self.error.dirty_update_j_fragment(
+                                            &ErrorJAlignment { jal, del: delj },
+                                            ll,
+                                        );
+                                        self.error.dirty_update_d_fragment(
+                                            &ErrorDAlignment {
+                                                dal: dal1,
+                                                deld5,
+                                                deld3,
+                                            },
+                                            ll,
+                                        );
+                                        self.error.dirty_update_d_fragment(
+                                            &ErrorDAlignment {
+                                                dal: dal2,
+                                                deld5: deld2_5,
+                                                deld3: deld2_3,
+                                            },
+                                            ll,
+                                        );
+
+                                        if ip.store_best_event && ll > result.best_likelihood {
+                                            let event = InfEvent {
+                                                v_index: val.index,
+                                                v_start_gene: val.v_start_gene(),
+                                                j_index: jal.index,
+                                                j_start_seq: jal.start_seq as i64
+                                                    - jal.start_gene as i64,
+                                                d_index: dal1.index,
+                                                end_v: v_end,
+                                                start_d: d1_start,
+                                                end_d: d1_end,
+                                                pos_d: dal1.pos,
+                                                d2_index: Some(dal2.index),
+                                                start_d2: d2_start,
+                                                end_d2: d2_end,
+                                                pos_d2: dal2.pos,
+                                                start_j: j_start,
+                                                likelihood: ll,
+                                                ..Default::default()
+                                            };
+                                            result.set_best_event(event, ip);
+                                            result.best_likelihood = ll;
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if result.likelihood > 0. {
+            self.scale(result.likelihood)?;
+            result.error_rate = self.error.posterior_error_rate();
+        }
+
+        // add a small positive likelihood to deal with the case where result.likelihood is 0.
+        self.log_likelihood = Some((result.likelihood + ip.min_likelihood).log2());
+
+        Ok(result)
+    }
+}
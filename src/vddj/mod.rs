@@ -0,0 +1,11 @@
+//! VDDJ model, a VDJ recombination with two D genes (observed in some IGH and
+//! TRD sequences).
+
+pub mod feature;
+pub mod inference;
+pub mod model;
+
+// Re-exporting for public API
+pub use self::feature::FeatureDD;
+pub use self::inference::Features;
+pub use self::model::{SecondD, SecondDEvent};
@@ -0,0 +1,361 @@
+use crate::shared::distributions::{DiscreteDistribution, MarkovDNA};
+use crate::shared::gene::Gene;
+use crate::shared::parser::{EventType, Marginal, ParserMarginals, ParserParams};
+use crate::shared::utils::{Normalize, Normalize2};
+use crate::shared::{DNAMarkovChain, Dna};
+use crate::vdj::Model;
+use anyhow::{anyhow, Result};
+use ndarray::{s, Array1, Array2, Array3, Axis};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Parameters of the second D gene of a `ModelStructure::VDDJ` model.
+/// Both D genes are chosen among the same D segments and share the ranges
+/// of deletions of the model. The DD insertions are read from D1 to D2.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct SecondD {
+    pub p_d2_given_d1: Array2<f64>,   // P(D2 | D1), dim: (d2, d1)
+    pub p_del_d5_del_d3: Array3<f64>, // P(del_d5, del_d3 | D2)
+    pub p_ins_dd: Array1<f64>,
+    pub markov_chain_dd: Arc<DNAMarkovChain>,
+    #[serde(skip)]
+    pub gen: Generative,
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct Generative {
+    d_d2_given_d1: Vec<DiscreteDistribution>,
+    d_del_d5_del_d3: Vec<DiscreteDistribution>,
+    d_ins_dd: DiscreteDistribution,
+    markov_dd: MarkovDNA,
+}
+
+/// Second D gene, deletions and DD insertions of a generated sequence
+pub struct SecondDEvent {
+    pub d_index: usize,
+    pub deld5: usize,
+    pub deld3: usize,
+    pub ins_dd: usize,
+}
+
+impl SecondD {
+    /// Default parameters for the second D: uniform choice of D2, deletions
+    /// and DD insertions distributed as the first D and the VD insertions
+    pub fn new(model: &Model) -> Result<SecondD> {
+        let nd = model.seg_ds.len();
+        let mut d2 = SecondD {
+            p_d2_given_d1: Array2::ones((nd, nd)),
+            p_del_d5_del_d3: model.p_del_d5_del_d3.clone(),
+            p_ins_dd: model.p_ins_vd.clone(),
+            markov_chain_dd: Arc::new(DNAMarkovChain::new(
+                &model.markov_chain_vd.transition_matrix,
+                false,
+            )?),
+            ..Default::default()
+        };
+        d2.initialize(model)?;
+        Ok(d2)
+    }
+
+    /// Check the dimensions, normalize the distributions and prepare the generation
+    pub fn initialize(&mut self, model: &Model) -> Result<()> {
+        let nd = model.seg_ds.len();
+        if self.p_d2_given_d1.dim() != (nd, nd) {
+            return Err(anyhow!(
+                "Wrong dimension for P(D2 | D1) (expected {} D genes)",
+                nd
+            ));
+        }
+        if self.p_del_d5_del_d3.dim() != model.p_del_d5_del_d3.dim() {
+            return Err(anyhow!(
+                "Wrong dimension for the deletions of the second D gene"
+            ));
+        }
+        if self.markov_chain_dd.reverse {
+            return Err(anyhow!("The DD insertions are read from D1 to D2"));
+        }
+        self.p_d2_given_d1 = self.p_d2_given_d1.normalize_distribution()?;
+        self.p_del_d5_del_d3 = self.p_del_d5_del_d3.normalize_distribution_double()?;
+        self.p_ins_dd = self.p_ins_dd.normalize_distribution()?;
+
+        self.gen.d_d2_given_d1 = self
+            .p_d2_given_d1
+            .axis_iter(Axis(1))
+            .map(|col| DiscreteDistribution::new(&col.to_vec()))
+            .collect::<Result<_>>()?;
+        self.gen.d_del_d5_del_d3 = (0..self.p_del_d5_del_d3.dim().2)
+            .map(|dd| {
+                DiscreteDistribution::new(
+                    &self
+                        .p_del_d5_del_d3
+                        .slice(s![.., .., dd])
+                        .iter()
+                        .copied()
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Result<_>>()?;
+        self.gen.d_ins_dd = DiscreteDistribution::new(&self.p_ins_dd.to_vec())?;
+        self.gen.markov_dd = MarkovDNA::new_higher_order(
+            &self.markov_chain_dd.transition_matrix,
+            &self.markov_chain_dd.higher_order,
+        )?;
+        Ok(())
+    }
+
+    pub fn uniform(&self) -> Result<SecondD> {
+        Ok(SecondD {
+            p_d2_given_d1: Array2::ones(self.p_d2_given_d1.dim()),
+            p_del_d5_del_d3: Array3::ones(self.p_del_d5_del_d3.dim()),
+            p_ins_dd: Array1::ones(self.p_ins_dd.dim()),
+            markov_chain_dd: Arc::new(
+                DNAMarkovChain::new(
+                    &Array2::ones(self.markov_chain_dd.transition_matrix.dim()),
+                    false,
+                )?
+                .with_order(self.markov_chain_dd.order())?,
+            ),
+            ..Default::default()
+        })
+    }
+
+    pub fn similar_to(&self, other: &SecondD) -> bool {
+        self.p_d2_given_d1
+            .relative_eq(&other.p_d2_given_d1, 1e-4, 1e-4)
+            && self
+                .p_del_d5_del_d3
+                .relative_eq(&other.p_del_d5_del_d3, 1e-4, 1e-4)
+            && self.p_ins_dd.relative_eq(&other.p_ins_dd, 1e-4, 1e-4)
+            && self.markov_chain_dd.transition_matrix.relative_eq(
+                &other.markov_chain_dd.transition_matrix,
+                1e-4,
+                1e-4,
+            )
+            && self.markov_chain_dd.order() == other.markov_chain_dd.order()
+    }
+
+    /// Draw the second D gene given the first one
+    pub fn generate<R: Rng>(&self, d1_index: usize, rng: &mut R) -> SecondDEvent {
+        let d_index = self.gen.d_d2_given_d1[d1_index].generate(rng);
+        let del_d = self.gen.d_del_d5_del_d3[d_index].generate(rng);
+        SecondDEvent {
+            d_index,
+            deld5: del_d / self.p_del_d5_del_d3.dim().1,
+            deld3: del_d % self.p_del_d5_del_d3.dim().1,
+            ins_dd: self.gen.d_ins_dd.generate(rng),
+        }
+    }
+
+    /// Nucleotides inserted between the two D genes, `previous_nucleotide` is
+    /// the last nucleotide before the insertion
+    pub fn generate_insertion<R: Rng>(
+        &mut self,
+        length: usize,
+        previous_nucleotide: u8,
+        rng: &mut R,
+    ) -> Dna {
+        self.gen
+            .markov_dd
+            .generate(length, previous_nucleotide, rng)
+    }
+
+    /// Adapt the parameters to a new list of D genes (see `Model::set_d_segments`)
+    pub fn set_d_segments(&self, old: &[Gene], new: &[Gene]) -> Result<SecondD> {
+        let index: Vec<Option<usize>> = new
+            .iter()
+            .map(|d| old.iter().position(|g| g.name == d.name))
+            .collect();
+        let (sdeld5, sdeld3, _) = self.p_del_d5_del_d3.dim();
+        let deld_default = self.p_del_d5_del_d3.sum_axis(Axis(2)) / self.p_del_d5_del_d3.sum();
+
+        let mut p_d2_given_d1 = Array2::<f64>::zeros((new.len(), new.len()));
+        let mut p_del_d5_del_d3 = Array3::<f64>::zeros((sdeld5, sdeld3, new.len()));
+        for (id2, i2) in index.iter().enumerate() {
+            for (id1, i1) in index.iter().enumerate() {
+                p_d2_given_d1[[id2, id1]] = match (i2, i1) {
+                    (Some(a), Some(b)) => self.p_d2_given_d1[[*a, *b]],
+                    _ => 1. / (new.len() as f64),
+                };
+            }
+            match i2 {
+                Some(a) => p_del_d5_del_d3
+                    .slice_mut(s![.., .., id2])
+                    .assign(&self.p_del_d5_del_d3.slice(s![.., .., *a])),
+                None => p_del_d5_del_d3
+                    .slice_mut(s![.., .., id2])
+                    .assign(&deld_default),
+            }
+        }
+        Ok(SecondD {
+            p_d2_given_d1,
+            p_del_d5_del_d3,
+            p_ins_dd: self.p_ins_dd.clone(),
+            markov_chain_dd: self.markov_chain_dd.clone(),
+            ..Default::default()
+        })
+    }
+
+    /// Events of the parameter file (`vdj::Model::write_params_righor`).
+    /// `IGoR` has no gene class for the D1-D2 junction, the `DD_genes`
+    /// insertion and dinucleotide events are righor-specific: the files can
+    /// only be read back by righor.
+    pub fn write_params(&self, model: &Model) -> String {
+        let mut result = "#GeneChoice;D_gene;Undefined_side;5;d2_gene\n".to_string();
+        result.push_str(&EventType::Genes(model.seg_ds.clone()).write());
+        result.push_str("#Deletion;D_gene;Three_prime;4;d2_3_del\n");
+        result.push_str(
+            &EventType::Numbers((model.range_del_d3.0..=model.range_del_d3.1).collect()).write(),
+        );
+        result.push_str("#Deletion;D_gene;Five_prime;4;d2_5_del\n");
+        result.push_str(
+            &EventType::Numbers((model.range_del_d5.0..=model.range_del_d5.1).collect()).write(),
+        );
+        result.push_str("#Insertion;DD_genes;Undefined_side;3;dd_ins\n");
+        result.push_str(&EventType::Numbers((0_i64..self.p_ins_dd.dim() as i64).collect()).write());
+        result.push_str(
+            "#DinucMarkov;DD_genes;Undefined_side;3;dd_dinucl\n\
+             %T;3\n\
+             %C;1\n\
+             %G;2\n\
+             %A;0\n",
+        );
+        result
+    }
+
+    /// Edges of the `IGoR` parameter file
+    pub fn write_edges(&self) -> String {
+        let dimd = self.p_d2_given_d1.dim().0;
+        let (dimdeld5, dimdeld3, _) = self.p_del_d5_del_d3.dim();
+        format!(
+            "%GeneChoice_D_gene_Undefined_side_prio6_size{dimd};\
+             GeneChoice_D_gene_Undefined_side_prio5_size{dimd}\n\
+             %GeneChoice_D_gene_Undefined_side_prio5_size{dimd};\
+             Deletion_D_gene_Three_prime_prio4_size{dimdeld3}\n\
+             %GeneChoice_D_gene_Undefined_side_prio5_size{dimd};\
+             Deletion_D_gene_Five_prime_prio4_size{dimdeld5}\n\
+             %Deletion_D_gene_Five_prime_prio4_size{dimdeld5};\
+             Deletion_D_gene_Three_prime_prio4_size{dimdeld3}\n"
+        )
+    }
+
+    /// Marginals of the `IGoR` marginal file
+    pub fn write_marginals(&self) -> Result<String> {
+        let marginal_d2 = Marginal::create(
+            vec!["d_gene"],
+            self.p_d2_given_d1.clone().permuted_axes((1, 0)).into_dyn(),
+        )
+        .write()?;
+
+        let p_d3_d5_d = self.p_del_d5_del_d3.clone().permuted_axes((1, 0, 2));
+        let p_deld5_given_d = p_d3_d5_d.sum_axis(Axis(0));
+        let p_deld3_given_deld5_d = (p_d3_d5_d.clone()
+            / p_deld5_given_d.broadcast(p_d3_d5_d.dim()).unwrap())
+        .mapv(|x| if x.is_nan() { 0.0 } else { x });
+        let marginal_deld5 = Marginal::create(
+            vec!["d2_gene"],
+            p_deld5_given_d.permuted_axes((1, 0)).into_dyn(),
+        )
+        .write()?;
+        let marginal_deld3 = Marginal::create(
+            vec!["d2_gene", "d2_5_del"],
+            p_deld3_given_deld5_d.permuted_axes((2, 1, 0)).into_dyn(),
+        )
+        .write()?;
+        let marginal_ddins =
+            Marginal::create(Vec::new(), self.p_ins_dd.clone().into_dyn()).write()?;
+        let marginal_dddinucl = Marginal::create(
+            Vec::new(),
+            self.markov_chain_dd
+                .transition_matrix
+                .iter()
+                .copied()
+                .collect::<Array1<f64>>()
+                .into_dyn(),
+        )
+        .write()?;
+        Ok(format!(
+            "@d2_gene\n\
+             {marginal_d2}\
+             @d2_5_del\n\
+             {marginal_deld5}\
+             @d2_3_del\n\
+             {marginal_deld3}\
+             @dd_ins\n\
+             {marginal_ddins}\
+             @dd_dinucl\n\
+             {marginal_dddinucl}"
+        ))
+    }
+
+    /// Load the second D from `IGoR` files, `None` if the model has a single D
+    pub fn load(model: &Model, pp: &ParserParams, pm: &ParserMarginals) -> Result<Option<SecondD>> {
+        let Some(d2_genes) = pp.params.get("d2_gene") else {
+            return Ok(None);
+        };
+        let names = |genes: &[Gene]| genes.iter().map(|g| g.name.clone()).collect::<Vec<_>>();
+        if names(&d2_genes.to_genes()?) != names(&model.seg_ds) {
+            return Err(anyhow!(
+                "The two D genes should be chosen among the same genes"
+            ));
+        }
+        for (key, range) in [
+            ("d2_3_del", model.range_del_d3),
+            ("d2_5_del", model.range_del_d5),
+        ] {
+            let numbers = pp
+                .params
+                .get(key)
+                .ok_or(anyhow!("Invalid {key}"))?
+                .to_numbers()?;
+            if numbers != (range.0..=range.1).collect::<Vec<_>>() {
+                return Err(anyhow!(
+                    "The deletions of the two D genes should have the same range ({key})"
+                ));
+            }
+        }
+        let marginal = |key: &str| {
+            pm.marginals
+                .get(key)
+                .map(|m| m.probabilities.clone())
+                .ok_or(anyhow!("Missing marginal {key}"))
+        };
+
+        let p_d1_d2: Array2<f64> = marginal("d2_gene")?
+            .into_dimensionality()
+            .map_err(|_e| anyhow!("Wrong format for d2_gene"))?;
+        // P(delD3, delD5 | D2) = P(delD3 | delD5, D2) * P(delD5 | D2)
+        let pdeld5: Array2<f64> = marginal("d2_5_del")?
+            .into_dimensionality()
+            .map_err(|_e| anyhow!("Wrong format for d2_5_del"))?;
+        let pdeld3: Array3<f64> = marginal("d2_3_del")?
+            .into_dimensionality()
+            .map_err(|_e| anyhow!("Wrong format for d2_3_del"))?;
+        let (ddim, d5dim, d3dim) = pdeld3.dim();
+        let mut p_del_d5_del_d3 = Array3::<f64>::zeros((d5dim, d3dim, ddim));
+        for dd in 0..ddim {
+            for d5 in 0..d5dim {
+                for d3 in 0..d3dim {
+                    p_del_d5_del_d3[[d5, d3, dd]] = pdeld3[[dd, d5, d3]] * pdeld5[[dd, d5]];
+                }
+            }
+        }
+
+        let mut d2 = SecondD {
+            p_d2_given_d1: p_d1_d2.t().to_owned(),
+            p_del_d5_del_d3,
+            p_ins_dd: marginal("dd_ins")?
+                .into_dimensionality()
+                .map_err(|_e| anyhow!("Wrong format for dd_ins"))?,
+            markov_chain_dd: Arc::new(DNAMarkovChain::new(
+                &marginal("dd_dinucl")?
+                    .into_shape_with_order((4, 4))
+                    .map_err(|_e| anyhow!("Wrong size for dd_dinucl"))?,
+                false,
+            )?),
+            ..Default::default()
+        };
+        d2.initialize(model)?;
+        Ok(Some(d2))
+    }
+}
@@ -1,3 +1,4 @@
+use crate::shared::errors::context_index;
 use crate::shared::sequence::Dna;
 use crate::shared::{DAlignment, VJAlignment};
 use crate::vdj::Model;
@@ -31,7 +32,15 @@ pub struct StaticEvent {
     pub deld5: usize,
     pub insvd: Dna,
     pub insdj: Dna,
+    // second D gene (`ModelStructure::VDDJ` only), inserted after D1
+    pub d2_index: Option<usize>,
+    pub d2_start_seq: i64, // start of the palindromic D2 gene in the sequence
+    pub deld2_3: usize,
+    pub deld2_5: usize,
+    pub insdd: Dna,
     pub errors: Vec<(usize, u8)>,
+    // (position in the error-free sequence, nb of deleted nucleotides, inserted nucleotides)
+    pub indels: Vec<(usize, usize, Dna)>,
 }
 
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
@@ -45,6 +54,7 @@ impl StaticEvent {
 		 nb. del. on D3: {},\n\
 		 nb. del. on J5: {},\n\
 		 V-D insertions: {},\n\
+		 {}\
 		 D-J insertions: {},\n\
 		 errors: {})",
             self.delv,
@@ -52,6 +62,17 @@ impl StaticEvent {
             self.deld3,
             self.delj,
             self.insvd.get_string(),
+            match self.d2_index {
+                Some(_) => format!(
+                    "nb. del. on D2 5: {},\n\
+		     nb. del. on D2 3: {},\n\
+		     D-D insertions: {},\n",
+                    self.deld2_5,
+                    self.deld2_3,
+                    self.insdd.get_string()
+                ),
+                None => String::new(),
+            },
             self.insdj.get_string(),
             if self.errors.is_empty() {
                 "None".to_string()
@@ -75,6 +96,11 @@ impl StaticEvent {
         seq.extend(&seq_v.extract_subsequence(0, seq_v.len() - self.delv));
         seq.extend(&self.insvd);
         seq.extend(&seq_d.extract_subsequence(self.deld5, seq_d.len() - self.deld3));
+        if let Some(d2_index) = self.d2_index {
+            let seq_d2: &Dna = m.seg_ds[d2_index].seq_with_pal.as_ref().unwrap();
+            seq.extend(&self.insdd);
+            seq.extend(&seq_d2.extract_subsequence(self.deld2_5, seq_d2.len() - self.deld2_3));
+        }
         seq.extend(&self.insdj);
         seq.extend(&seq_j.extract_subsequence(self.delj, seq_j.len()));
 
@@ -83,17 +109,77 @@ impl StaticEvent {
             seq.seq[*ii] = *nuc;
         }
 
+        // add indels, starting from the end so that positions stay valid
+        for (ii, nb_del, ins) in self.indels.iter().rev() {
+            seq.seq.splice(*ii..*ii + nb_del, ins.seq.iter().cloned());
+        }
+
         seq
     }
 
+    /// 5-mer germline context (see `context_index`) of each nucleotide of the
+    /// error-free sequence: the context of a gene nucleotide is read on the
+    /// gene itself (deleted nucleotides included), as during the inference,
+    /// inserted nucleotides have none.
+    pub fn germline_contexts(&self, m: &Model) -> Vec<Option<usize>> {
+        let gene_contexts = |seq: &Dna, start: usize, end: usize| -> Vec<Option<usize>> {
+            (start..end)
+                .map(|idx| Some(context_index(&seq.seq, idx)))
+                .collect()
+        };
+        let seq_v: &Dna = m.seg_vs[self.v_index].seq_with_pal.as_ref().unwrap();
+        let seq_j: &Dna = m.seg_js[self.j_index].seq_with_pal.as_ref().unwrap();
+        let seq_d: &Dna = m.seg_ds[self.d_index].seq_with_pal.as_ref().unwrap();
+        let mut contexts: Vec<Option<usize>> = Vec::new();
+        contexts.extend(gene_contexts(seq_v, 0, seq_v.len() - self.delv));
+        contexts.extend(vec![None; self.insvd.len()]);
+        contexts.extend(gene_contexts(
+            seq_d,
+            self.deld5,
+            (seq_d.len() - self.deld3).max(self.deld5),
+        ));
+        if let Some(d2_index) = self.d2_index {
+            let seq_d2: &Dna = m.seg_ds[d2_index].seq_with_pal.as_ref().unwrap();
+            contexts.extend(vec![None; self.insdd.len()]);
+            contexts.extend(gene_contexts(
+                seq_d2,
+                self.deld2_5,
+                (seq_d2.len() - self.deld2_3).max(self.deld2_5),
+            ));
+        }
+        contexts.extend(vec![None; self.insdj.len()]);
+        contexts.extend(gene_contexts(seq_j, self.delj, seq_j.len()));
+        contexts
+    }
+
+    /// Net length added by the indels, before and after the position `pos`
+    /// of the error-free sequence
+    fn indels_shift(&self, pos: usize) -> (i64, i64) {
+        let mut shift = (0, 0);
+        for (ii, nb_del, ins) in &self.indels {
+            let diff = ins.len() as i64 - *nb_del as i64;
+            if *ii < pos {
+                shift.0 += diff;
+            } else {
+                shift.1 += diff;
+            }
+        }
+        shift
+    }
+
     pub fn extract_cdr3(&self, full_sequence: &Dna, m: &Model) -> Dna {
         let vg = &m.seg_vs[self.v_index];
         let jg = &m.seg_js[self.j_index];
-        let mut end_cdr3 = full_sequence.len() - jg.seq.len() + jg.cdr3_pos.unwrap() + 3;
-        if vg.cdr3_pos.unwrap() > end_cdr3 {
-            end_cdr3 = vg.cdr3_pos.unwrap(); // if we cut too much we return the empty sequence
+        // indels are only generated outside of the CDR3
+        let (shift_v, shift_j) = self.indels_shift(vg.cdr3_pos.unwrap());
+        let start_cdr3 = (vg.cdr3_pos.unwrap() as i64 + shift_v) as usize;
+        let mut end_cdr3 = (full_sequence.len() as i64 - shift_j) as usize - jg.seq.len()
+            + jg.cdr3_pos.unwrap()
+            + 3;
+        if start_cdr3 > end_cdr3 {
+            end_cdr3 = start_cdr3; // if we cut too much we return the empty sequence
         }
-        full_sequence.extract_subsequence(vg.cdr3_pos.unwrap(), end_cdr3)
+        full_sequence.extract_subsequence(start_cdr3, end_cdr3)
     }
 
     pub fn to_cdr3(&self, m: &Model) -> Dna {
@@ -112,7 +198,7 @@ impl Event<'_> {
             v_start_gene: self
                 .v
                 .ok_or(anyhow!("Can't move that event to static"))?
-                .start_gene,
+                .v_start_gene(),
             delv: self.delv,
             j_index: self
                 .j
@@ -86,7 +86,7 @@ impl AggregatedFeatureEndV {
         for delv in 0..feat_delv.dim().0 {
             let v_end = difference_as_i64(v.end_seq, delv);
             let ll_delv = feat_delv.likelihood((delv, v.index));
-            let ll_v_err = feat_error.likelihood(v.errors(delv, 0));
+            let ll_v_err = feat_error.likelihood_v(&ErrorVAlignment { val: v, del: delv });
             let ll = ll_delv * ll_v_err;
             if ll > ip.min_likelihood {
                 let likelihood = match v.sequence_type {
@@ -109,7 +109,7 @@ impl AggregatedFeatureEndV {
             dirty_likelihood: RangeArray1::zeros(likelihoods.dim()),
             likelihood: likelihoods,
             index: v.index,
-            start_gene: v.start_gene,
+            start_gene: v.v_start_gene(),
             alignment: Arc::new(v.clone()),
         })
     }
@@ -135,8 +135,8 @@ impl AggregatedFeatureEndV {
     ) {
         for delv in 0..feat_delv.dim().0 {
             let v_end = difference_as_i64(v.end_seq, delv);
-            let ll =
-                feat_delv.likelihood((delv, v.index)) * feat_error.likelihood(v.errors(delv, 0));
+            let ll = feat_delv.likelihood((delv, v.index))
+                * feat_error.likelihood_v(&ErrorVAlignment { val: v, del: delv });
 
             if ll > ip.min_likelihood {
                 let dirty_proba = self.dirty_likelihood.get(v_end); // P(ev)
@@ -172,7 +172,7 @@ impl AggregatedFeatureStartJ {
         for delj in 0..feat_delj.dim().0 {
             let j_start = j.start_seq as i64 - j.start_gene as i64 + delj as i64;
             let ll_delj = feat_delj.likelihood((delj, j.index));
-            let ll_errj = feat_error.likelihood(j.errors(0, delj));
+            let ll_errj = feat_error.likelihood_j(&ErrorJAlignment { jal: j, del: delj });
             let ll = ll_delj * ll_errj;
             if ll > ip.min_likelihood {
                 let likelihood = match j.sequence_type {
@@ -221,8 +221,8 @@ impl AggregatedFeatureStartJ {
     ) {
         for delj in 0..feat_delj.dim().0 {
             let j_start = j.start_seq as i64 - j.start_gene as i64 + delj as i64;
-            let ll =
-                feat_delj.likelihood((delj, j.index)) * feat_error.likelihood(j.errors(0, delj));
+            let ll = feat_delj.likelihood((delj, j.index))
+                * feat_error.likelihood_j(&ErrorJAlignment { jal: j, del: delj });
             if ll > ip.min_likelihood {
                 let dirty_proba = self.dirty_likelihood.get(j_start);
                 if dirty_proba > 0. && ip.infer_features.del_j {
@@ -275,7 +275,11 @@ impl AggregatedFeatureSpanD {
                 }
 
                 let ll_deld = feat_deld.likelihood((deld5, deld3, d.index));
-                let ll_errord = feat_error.likelihood(d.errors(deld5, deld3));
+                let ll_errord = feat_error.likelihood_d(&ErrorDAlignment {
+                    dal: d,
+                    deld5,
+                    deld3,
+                });
 
                 let ll = ll_deld * ll_errord;
                 if ll > ip.min_likelihood {
@@ -352,7 +356,11 @@ impl AggregatedFeatureSpanD {
                 }
 
                 let likelihood = feat_deld.likelihood((deld5, deld3, d.index))
-                    * feat_error.likelihood(d.errors(deld5, deld3));
+                    * feat_error.likelihood_d(&ErrorDAlignment {
+                        dal: d,
+                        deld5,
+                        deld3,
+                    });
                 if likelihood > ip.min_likelihood {
                     let dirty_proba = self.dirty_likelihood.get((d_start, d_end));
                     let corrected_proba =
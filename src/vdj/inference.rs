@@ -1,23 +1,126 @@
+use crate::shared::distributions::DiscreteDistribution;
+use crate::shared::feature::{
+    CategoricalFeature1g1, CategoricalFeature2g1, CategoricalFeature3, Feature, InfEvent,
+    InsertionFeature, PosteriorMarginals, ResultInference,
+};
 use crate::shared::utils::difference_as_i64;
 use crate::shared::Modelable;
 use crate::shared::{errors::FeatureError, ErrorParameters, InferenceParameters};
-use crate::shared::{
-    feature::{
-        CategoricalFeature1g1, CategoricalFeature2g1, CategoricalFeature3, Feature, InfEvent,
-        InsertionFeature, ResultInference,
-    },
-    DNAMarkovChain,
-};
-use crate::shared::{ErrorDAlignment, ErrorJAlignment, ErrorVAlignment};
+use crate::shared::{DAlignment, ErrorDAlignment, ErrorJAlignment, ErrorVAlignment};
 use crate::vdj::{
     AggregatedFeatureEndV, AggregatedFeatureSpanD, AggregatedFeatureStartJ, FeatureDJ, FeatureVD,
     Model, Sequence,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use itertools::iproduct;
+use ndarray::{Array1, Array2, Axis};
+use rand::Rng;
 
 use std::cmp;
 use std::sync::Arc;
 
+/// Matrix of the factor between two consecutive positions of the scenario,
+/// (x, y) with `x` in `range_x`, `y` in `range_y` and x <= y.
+pub(crate) fn transition(
+    range_x: (i64, i64),
+    range_y: (i64, i64),
+    factor: impl Fn(i64, i64) -> Result<f64>,
+) -> Result<Array2<f64>> {
+    let mut m = Array2::zeros((
+        (range_x.1 - range_x.0) as usize,
+        (range_y.1 - range_y.0) as usize,
+    ));
+    for x in range_x.0..range_x.1 {
+        for y in cmp::max(x, range_y.0)..range_y.1 {
+            m[[(x - range_x.0) as usize, (y - range_y.0) as usize]] = factor(x, y)?;
+        }
+    }
+    Ok(m)
+}
+
+/// Draw an index with probability proportional to `weights` (normalized
+/// first, the likelihoods are often too small for `DiscreteDistribution`)
+fn draw<R: Rng>(weights: &[f64], rng: &mut R) -> Result<usize> {
+    let total: f64 = weights.iter().sum();
+    if total <= 0. {
+        return Err(anyhow!("Can't sample from a zero distribution"));
+    }
+    let normalized: Vec<f64> = weights.iter().map(|w| w / total).collect();
+    Ok(DiscreteDistribution::new(&normalized)?.generate(rng))
+}
+
+/// Chain of positions (end V, start D, end D, start J) for a given choice of
+/// V, D and J, with the factors between consecutive positions and the sums
+/// over the end of the chain (backward pass)
+struct PositionChain<'a> {
+    feature_v: &'a AggregatedFeatureEndV,
+    feature_d: &'a AggregatedFeatureSpanD,
+    feature_j: &'a AggregatedFeatureStartJ,
+    alignments_d: &'a [DAlignment],
+    likelihood_vdj: f64,
+    ranges: [(i64, i64); 4],
+    factors: [Array2<f64>; 3],
+    beta: [Array1<f64>; 4],
+    // maximum instead of the sum over the end of the chain
+    max_beta: [Array1<f64>; 4],
+}
+
+impl PositionChain<'_> {
+    fn likelihood(&self) -> f64 {
+        self.likelihood_vdj * self.beta[0].sum()
+    }
+
+    /// Upper bound on the likelihood of a single scenario of the chain
+    fn max_likelihood(&self) -> f64 {
+        self.likelihood_vdj * self.max_beta[0].fold(0., |a: f64, &b| a.max(b))
+    }
+
+    fn event(&self, [ev, sd, ed, sj]: [i64; 4], pos_d: i64, likelihood: f64) -> InfEvent {
+        InfEvent {
+            v_index: self.feature_v.index,
+            v_start_gene: self.feature_v.start_gene,
+            j_index: self.feature_j.index,
+            j_start_seq: self.feature_j.start_seq,
+            d_index: self.feature_d.index,
+            end_v: ev,
+            start_d: sd,
+            end_d: ed,
+            start_j: sj,
+            pos_d,
+            likelihood,
+            ..Default::default()
+        }
+    }
+}
+
+/// Same as `m.dot(v)`, with a maximum instead of the sum
+fn max_dot(m: &Array2<f64>, v: &Array1<f64>) -> Array1<f64> {
+    m.rows()
+        .into_iter()
+        .map(|row| {
+            row.iter()
+                .zip(v)
+                .fold(0., |acc: f64, (a, b)| acc.max(a * b))
+        })
+        .collect()
+}
+
+/// Likelihood that a scenario must exceed to enter the current top `k`
+pub(crate) fn threshold(top: &[InfEvent], k: usize) -> f64 {
+    if top.len() < k {
+        0.
+    } else {
+        top[k - 1].likelihood
+    }
+}
+
+/// Insert `event` in the top `k` scenarios (sorted by decreasing likelihood)
+pub(crate) fn insert_top(top: &mut Vec<InfEvent>, event: InfEvent, k: usize) {
+    let idx = top.partition_point(|e| e.likelihood >= event.likelihood);
+    top.insert(idx, event);
+    top.truncate(k);
+}
+
 #[derive(Default, Clone, Debug)]
 pub struct Features {
     pub delv: CategoricalFeature1g1,
@@ -26,6 +129,10 @@ pub struct Features {
     pub deld: CategoricalFeature2g1, // d5, d3, d
     pub insvd: InsertionFeature,
     pub insdj: InsertionFeature,
+    // P(ins_vd | V) and P(ins_dj | J) (`ModelStructure::VDJInsGivenGene`),
+    // `insvd` / `insdj` then only score the inserted nucleotides
+    pub insvd_given_v: Option<CategoricalFeature1g1>,
+    pub insdj_given_j: Option<CategoricalFeature1g1>,
     pub error: FeatureError,
     pub log_likelihood: Option<f64>,
 }
@@ -37,8 +144,30 @@ impl Features {
             delv: CategoricalFeature1g1::new(&model.p_del_v_given_v)?,
             delj: CategoricalFeature1g1::new(&model.p_del_j_given_j)?,
             deld: CategoricalFeature2g1::new(&model.p_del_d5_del_d3)?, // dim: (d5, d3, d)
-            insvd: InsertionFeature::new(&model.p_ins_vd, Arc::clone(&model.markov_chain_vd))?,
-            insdj: InsertionFeature::new(&model.p_ins_dj, Arc::clone(&model.markov_chain_dj))?,
+            insvd: match &model.p_ins_vd_given_v {
+                Some(p) => InsertionFeature::new_sequence_only(
+                    p.dim().0,
+                    Arc::clone(&model.markov_chain_vd),
+                )?,
+                None => InsertionFeature::new(&model.p_ins_vd, Arc::clone(&model.markov_chain_vd))?,
+            },
+            insdj: match &model.p_ins_dj_given_j {
+                Some(p) => InsertionFeature::new_sequence_only(
+                    p.dim().0,
+                    Arc::clone(&model.markov_chain_dj),
+                )?,
+                None => InsertionFeature::new(&model.p_ins_dj, Arc::clone(&model.markov_chain_dj))?,
+            },
+            insvd_given_v: model
+                .p_ins_vd_given_v
+                .as_ref()
+                .map(CategoricalFeature1g1::new)
+                .transpose()?,
+            insdj_given_j: model
+                .p_ins_dj_given_j
+                .as_ref()
+                .map(CategoricalFeature1g1::new)
+                .transpose()?,
             error: model.error.get_feature()?,
             log_likelihood: None,
         })
@@ -48,31 +177,71 @@ impl Features {
     /// Return the new features and the total log likelihood
     pub fn update(
         features: Vec<Features>,
+        weights: &[f64],
         model: &mut Model,
         ip: &InferenceParameters,
     ) -> Result<(Vec<Features>, f64)> {
         let errors = &mut ErrorParameters::update_error(
             features.iter().map(|a| a.error.clone()).collect(),
+            weights,
             &mut model.error,
         )?;
 
-        let insvd = InsertionFeature::average(
+        let mut insvd = InsertionFeature::average(
             features
                 .iter()
                 .zip(errors.iter())
                 .map(|(f, e)| f.insvd.correct_for_error(e).clone()),
+            weights,
+            ip.pseudocounts.ins_vd,
+            ip.pseudocounts.markov_vd,
         )?;
-        let insdj = InsertionFeature::average(
+        let mut insdj = InsertionFeature::average(
             features
                 .iter()
                 .zip(errors.iter())
                 .map(|(f, e)| f.insdj.correct_for_error(e).clone()),
+            weights,
+            ip.pseudocounts.ins_dj,
+            ip.pseudocounts.markov_dj,
         )?;
+        let insvd_given_v = match features[0].insvd_given_v {
+            Some(_) => Some(CategoricalFeature1g1::average(
+                features.iter().map(|a| a.insvd_given_v.clone().unwrap()),
+                weights,
+                ip.pseudocounts.ins_vd,
+            )?),
+            None => None,
+        };
+        let insdj_given_j = match features[0].insdj_given_j {
+            Some(_) => Some(CategoricalFeature1g1::average(
+                features.iter().map(|a| a.insdj_given_j.clone().unwrap()),
+                weights,
+                ip.pseudocounts.ins_dj,
+            )?),
+            None => None,
+        };
 
-        let delv = CategoricalFeature1g1::average(features.iter().map(|a| a.delv.clone()))?;
-        let delj = CategoricalFeature1g1::average(features.iter().map(|a| a.delj.clone()))?;
-        let deld = CategoricalFeature2g1::average(features.iter().map(|a| a.deld.clone()))?;
-        let vdj = CategoricalFeature3::average(features.iter().map(|a| a.vdj.clone()))?;
+        let delv = CategoricalFeature1g1::average(
+            features.iter().map(|a| a.delv.clone()),
+            weights,
+            ip.pseudocounts.del_v,
+        )?;
+        let delj = CategoricalFeature1g1::average(
+            features.iter().map(|a| a.delj.clone()),
+            weights,
+            ip.pseudocounts.del_j,
+        )?;
+        let deld = CategoricalFeature2g1::average(
+            features.iter().map(|a| a.deld.clone()),
+            weights,
+            ip.pseudocounts.del_d,
+        )?;
+        let vdj = CategoricalFeature3::average(
+            features.iter().map(|a| a.vdj.clone()),
+            weights,
+            ip.pseudocounts.genes,
+        )?;
 
         if ip.infer_features.genes {
             model.set_p_vdj(&vdj.clone().probas)?;
@@ -89,16 +258,39 @@ impl Features {
 
         let (p_vd, mc_vd) = insvd.get_parameters();
         if ip.infer_features.ins_vd {
-            model.p_ins_vd = p_vd;
-            model.markov_chain_vd = Arc::new(DNAMarkovChain::new(&mc_vd, false)?);
+            match &insvd_given_v {
+                Some(f) => model.p_ins_vd_given_v = Some(f.probas.clone()),
+                None => model.p_ins_vd = p_vd,
+            }
+            model.markov_chain_vd = mc_vd;
         }
         let (p_dj, mc_dj) = insdj.get_parameters();
         if ip.infer_features.ins_dj {
-            model.p_ins_dj = p_dj;
-            model.markov_chain_dj = Arc::new(DNAMarkovChain::new(&mc_dj, true)?);
+            match &insdj_given_j {
+                Some(f) => model.p_ins_dj_given_j = Some(f.probas.clone()),
+                None => model.p_ins_dj = p_dj,
+            }
+            model.markov_chain_dj = mc_dj;
+        }
+        // the conditioned lengths are not part of the insertion features
+        if insvd_given_v.is_some() {
+            insvd = InsertionFeature::new_sequence_only(
+                insvd.max_nb_insertions(),
+                insvd.transition.clone(),
+            )?;
+        }
+        if insdj_given_j.is_some() {
+            insdj = InsertionFeature::new_sequence_only(
+                insdj.max_nb_insertions(),
+                insdj.transition.clone(),
+            )?;
         }
 
-        let sum_log_likelihood = features.iter().map(|x| x.log_likelihood.unwrap()).sum();
+        let sum_log_likelihood = features
+            .iter()
+            .zip(weights.iter())
+            .map(|(x, w)| w * x.log_likelihood.unwrap())
+            .sum();
 
         // Now update the features vector
         let mut new_features = Vec::new();
@@ -110,6 +302,8 @@ impl Features {
                 deld: deld.clone(),
                 insvd: insvd.clone(),
                 insdj: insdj.clone(),
+                insvd_given_v: insvd_given_v.clone(),
+                insdj_given_j: insdj_given_j.clone(),
                 error: error.clone(),
                 log_likelihood: None,
             });
@@ -223,6 +417,7 @@ impl Features {
         } else {
             return Ok(ResultInference::impossible());
         }
+        result.error_rate = self.error.posterior_error_rate();
 
         // add a small positive likelihood to deal with the case where result.likelihood is 0.
         self.log_likelihood = Some((result.likelihood + ip.min_likelihood).log2());
@@ -291,9 +486,21 @@ impl Features {
                                             .likelihood(&ins_vd, last_v_nucleotide)
                                             .to_scalar()
                                             .unwrap()
-                                        * self.error.likelihood(val.errors(delv, 0))
-                                        * self.error.likelihood(jal.errors(0, delj))
-                                        * self.error.likelihood(dal.errors(deld5, deld3));
+                                        * self.likelihood_length_vd(ins_vd.len(), val.index)
+                                        * self.likelihood_length_dj(ins_dj.len(), jal.index)
+                                        * self.error.likelihood_v(&ErrorVAlignment {
+                                            val: &val,
+                                            del: delv,
+                                        })
+                                        * self.error.likelihood_j(&ErrorJAlignment {
+                                            jal: &jal,
+                                            del: delj,
+                                        })
+                                        * self.error.likelihood_d(&ErrorDAlignment {
+                                            dal: &dal,
+                                            deld5,
+                                            deld3,
+                                        });
 
                                     // println!(
                                     //     "{:.1e}\t{:.1e}\t{:.1e}\t{:.1e}\t{:.1e}\t{:.1e}\t{:.1e}",
@@ -316,6 +523,12 @@ impl Features {
                                         self.deld.dirty_update((deld5, deld3, dal.index), ll);
                                         self.insdj.dirty_update(&ins_dj, first_j_nucleotide, ll);
                                         self.insvd.dirty_update(&ins_vd, last_v_nucleotide, ll);
+                                        if let Some(f) = &mut self.insvd_given_v {
+                                            f.dirty_update((ins_vd.len(), val.index), ll);
+                                        }
+                                        if let Some(f) = &mut self.insdj_given_j {
+                                            f.dirty_update((ins_dj.len(), jal.index), ll);
+                                        }
                                         self.error.dirty_update_v_fragment(
                                             &ErrorVAlignment {
                                                 val: &val,
@@ -342,7 +555,7 @@ impl Features {
                                         if ip.store_best_event && (ll > result.best_likelihood) {
                                             let event = InfEvent {
                                                 v_index: val.index,
-                                                v_start_gene: val.start_gene,
+                                                v_start_gene: val.v_start_gene(),
                                                 j_index: jal.index,
                                                 j_start_seq: jal.start_seq as i64
                                                     - jal.start_gene as i64,
@@ -371,6 +584,7 @@ impl Features {
         // averaging correctly.
         if result.likelihood > 0. {
             self.scale(result.likelihood)?;
+            result.error_rate = self.error.posterior_error_rate();
         }
 
         // add a small positive likelihood to deal with the case where result.likelihood is 0.
@@ -427,7 +641,8 @@ impl Features {
                     feature_v
                         .alignment
                         .get_last_nucleotide((feature_v.end_v3 - ev - 1) as usize),
-                );
+                ) * self
+                    .likelihood_length_vd((sd - ev) as usize, feature_v.index);
 
                 if (likelihood_v.clone() * likelihood_ins_vd.clone() * likelihood_vdj).max()
                     < cutoff
@@ -452,7 +667,8 @@ impl Features {
                             feature_j
                                 .alignment
                                 .get_first_nucleotide((sj - feature_j.start_j5) as usize),
-                        );
+                        ) * self
+                            .likelihood_length_dj((sj - ed) as usize, feature_j.index);
 
                         let likelihood_j = feature_j.likelihood(sj);
                         let likelihood = likelihood_v.clone()
@@ -505,6 +721,12 @@ impl Features {
                                         .get_last_nucleotide((feature_v.end_v3 - ev - 1) as usize),
                                     likelihood.to_scalar()?,
                                 );
+                                if let Some(f) = &mut self.insvd_given_v {
+                                    f.dirty_update(
+                                        ((sd - ev) as usize, feature_v.index),
+                                        likelihood.to_scalar()?,
+                                    );
+                                }
                             }
                             if ip.infer_features.ins_dj {
                                 ins_dj.dirty_update(
@@ -515,6 +737,12 @@ impl Features {
                                         .get_first_nucleotide((sj - feature_j.start_j5) as usize),
                                     likelihood.to_scalar()?,
                                 );
+                                if let Some(f) = &mut self.insdj_given_j {
+                                    f.dirty_update(
+                                        ((sj - ed) as usize, feature_j.index),
+                                        likelihood.to_scalar()?,
+                                    );
+                                }
                             }
                             if ip.infer_features.genes {
                                 self.vdj.dirty_update(
@@ -539,18 +767,410 @@ impl Features {
         self.deld.scale_dirty(1. / likelihood);
         self.insvd.scale_dirty(1. / likelihood);
         self.insdj.scale_dirty(1. / likelihood);
+        if let Some(f) = &mut self.insvd_given_v {
+            f.scale_dirty(1. / likelihood);
+        }
+        if let Some(f) = &mut self.insdj_given_j {
+            f.scale_dirty(1. / likelihood);
+        }
         self.error.scale_dirty(1. / likelihood);
         Ok(())
     }
 
+    /// P(ins_vd | V), 1 if the VD insertion length doesn't depend on V
+    /// (it's then part of `insvd`)
+    fn likelihood_length_vd(&self, length: usize, v_index: usize) -> f64 {
+        match &self.insvd_given_v {
+            Some(f) if length < f.dim().0 => f.likelihood((length, v_index)),
+            Some(_) => 0.,
+            None => 1.,
+        }
+    }
+
+    /// P(ins_dj | J), 1 if the DJ insertion length doesn't depend on J
+    fn likelihood_length_dj(&self, length: usize, j_index: usize) -> f64 {
+        match &self.insdj_given_j {
+            Some(f) if length < f.dim().0 => f.likelihood((length, j_index)),
+            Some(_) => 0.,
+            None => 1.,
+        }
+    }
+
+    /// Posterior marginals of the sequence, from the expected counts
+    /// (once `infer` has been called)
+    pub fn posterior_marginals(&self) -> PosteriorMarginals {
+        let p_vdj = &self.vdj.probas_dirty;
+        PosteriorMarginals {
+            v: p_vdj.sum_axis(Axis(2)).sum_axis(Axis(1)),
+            d: p_vdj.sum_axis(Axis(2)).sum_axis(Axis(0)),
+            j: p_vdj.sum_axis(Axis(1)).sum_axis(Axis(0)),
+            del_v: self.delv.probas_dirty.sum_axis(Axis(1)),
+            del_j: self.delj.probas_dirty.sum_axis(Axis(1)),
+            del_d: self.deld.probas_dirty.sum_axis(Axis(2)),
+            ins_vd: self.insvd.length_distribution_dirty.clone(),
+            ins_dj: self.insdj.length_distribution_dirty.clone(),
+        }
+    }
+
     pub fn normalize(&mut self) -> Result<()> {
         self.vdj = self.vdj.normalize()?;
         self.delv = self.delv.normalize()?;
         self.delj = self.delj.normalize()?;
         self.deld = self.deld.normalize()?;
-        self.insvd = self.insvd.normalize()?;
-        self.insdj = self.insdj.normalize()?;
+        self.insvd = match &self.insvd_given_v {
+            Some(_) => InsertionFeature::new_sequence_only(
+                self.insvd.max_nb_insertions(),
+                self.insvd.transition.clone(),
+            )?,
+            None => self.insvd.normalize()?,
+        };
+        self.insdj = match &self.insdj_given_j {
+            Some(_) => InsertionFeature::new_sequence_only(
+                self.insdj.max_nb_insertions(),
+                self.insdj.transition.clone(),
+            )?,
+            None => self.insdj.normalize()?,
+        };
+        self.insvd_given_v = self
+            .insvd_given_v
+            .as_ref()
+            .map(|f| f.normalize())
+            .transpose()?;
+        self.insdj_given_j = self
+            .insdj_given_j
+            .as_ref()
+            .map(|f| f.normalize())
+            .transpose()?;
         self.error = self.error.clone();
         Ok(())
     }
 }
+
+impl Features {
+    /// Draw `nb_samples` scenarios from the posterior P(scenario | sequence),
+    /// without any pruning of the unlikely scenarios (`min_likelihood` and
+    /// `min_ratio_likelihood` of `ip` are ignored, the scenarios are only
+    /// limited by the alignments). The genes are drawn first, then the
+    /// positions (end V, start D, end D, start J) one after the other from the
+    /// backward sums, and finally the D alignment among the ones spanning the
+    /// chosen positions.
+    pub fn sample_posterior<R: Rng>(
+        &self,
+        sequence: &Sequence,
+        nb_samples: usize,
+        ip: &InferenceParameters,
+        rng: &mut R,
+    ) -> Result<Vec<InfEvent>> {
+        if sequence.sequence.is_protein() {
+            return Err(anyhow!(
+                "Posterior sampling is not available for amino-acid sequences"
+            ));
+        }
+        let ip = &InferenceParameters {
+            min_likelihood: 0.,
+            min_ratio_likelihood: 0.,
+            ..ip.clone()
+        };
+        self.with_position_chains(sequence, ip, |chains| {
+            if chains.is_empty() {
+                return Err(anyhow!("The sequence can't be generated by the model"));
+            }
+            let likelihoods: Vec<f64> = chains.iter().map(PositionChain::likelihood).collect();
+            let total: f64 = likelihoods.iter().sum();
+            let genes = DiscreteDistribution::new(
+                &likelihoods.iter().map(|l| l / total).collect::<Vec<_>>(),
+            )?;
+            (0..nb_samples)
+                .map(|_| self.sample_chain(&chains[genes.generate(rng)], ip, rng))
+                .collect()
+        })
+    }
+
+    /// Return the `k` most likely scenarios (genes, positions and D
+    /// alignment), sorted by decreasing likelihood. The search is exact: the
+    /// chains of positions are explored depth-first and a branch is dropped
+    /// as soon as the maximum over the rest of the chain can't beat the k-th
+    /// scenario found so far.
+    pub fn top_events(
+        &self,
+        sequence: &Sequence,
+        k: usize,
+        ip: &InferenceParameters,
+    ) -> Result<Vec<InfEvent>> {
+        if sequence.sequence.is_protein() {
+            return Err(anyhow!(
+                "The most likely scenarios are not available for amino-acid sequences"
+            ));
+        }
+        self.with_position_chains(sequence, ip, |chains| {
+            let mut chains: Vec<_> = chains.iter().collect();
+            chains.sort_by(|a, b| b.max_likelihood().total_cmp(&a.max_likelihood()));
+            let mut top = Vec::new();
+            for chain in chains {
+                if chain.max_likelihood() <= threshold(&top, k) {
+                    break;
+                }
+                self.top_chain_events(chain, k, ip, &mut top);
+            }
+            Ok(top)
+        })
+    }
+
+    /// Build the chains of positions of all the (V, D, J) that can produce
+    /// the sequence and pass them to `f` (no chains if the sequence is
+    /// impossible)
+    fn with_position_chains<T>(
+        &self,
+        sequence: &Sequence,
+        ip: &InferenceParameters,
+        f: impl FnOnce(&[PositionChain]) -> Result<T>,
+    ) -> Result<T> {
+        let (Some(ins_vd), Some(ins_dj)) = (
+            FeatureVD::new(
+                sequence,
+                &self.insvd,
+                self.delv.dim().0,
+                self.deld.dim().0,
+                ip,
+            ),
+            FeatureDJ::new(
+                sequence,
+                &self.insdj,
+                self.deld.dim().1,
+                self.delj.dim().0,
+                ip,
+            ),
+        ) else {
+            return f(&[]);
+        };
+
+        let features_v: Vec<_> = sequence
+            .v_genes
+            .iter()
+            .filter_map(|val| AggregatedFeatureEndV::new(val, &self.delv, &self.error, ip))
+            .collect();
+        let alignments_d: Vec<_> = (0..self.vdj.dim().1)
+            .map(|d_idx| sequence.get_specific_dgene(d_idx))
+            .collect();
+        let features_d: Vec<_> = alignments_d
+            .iter()
+            .filter_map(|dals| AggregatedFeatureSpanD::new(dals, &self.deld, &self.error, ip))
+            .collect();
+        let features_j: Vec<_> = sequence
+            .j_genes
+            .iter()
+            .filter_map(|jal| AggregatedFeatureStartJ::new(jal, &self.delj, &self.error, ip))
+            .collect();
+
+        let mut chains = Vec::new();
+        for v in &features_v {
+            for d in &features_d {
+                for j in &features_j {
+                    if let Some(chain) =
+                        self.position_chain(v, d, j, &alignments_d[d.index], &ins_vd, &ins_dj)?
+                    {
+                        chains.push(chain);
+                    }
+                }
+            }
+        }
+        f(&chains)
+    }
+
+    /// Factors and backward sums of the chain of positions for a choice of
+    /// V, D and J (None if the genes can't produce the sequence)
+    fn position_chain<'a>(
+        &self,
+        feature_v: &'a AggregatedFeatureEndV,
+        feature_d: &'a AggregatedFeatureSpanD,
+        feature_j: &'a AggregatedFeatureStartJ,
+        alignments_d: &'a [DAlignment],
+        ins_vd: &FeatureVD,
+        ins_dj: &FeatureDJ,
+    ) -> Result<Option<PositionChain<'a>>> {
+        let likelihood_vdj =
+            self.vdj
+                .likelihood((feature_v.index, feature_d.index, feature_j.index));
+        if likelihood_vdj <= 0. {
+            return Ok(None);
+        }
+
+        // ranges of ev, sd, ed, sj
+        let ranges = [
+            (
+                cmp::max(feature_v.start_v3, ins_vd.min_ev()),
+                cmp::min(feature_v.end_v3, ins_vd.max_ev()),
+            ),
+            (
+                cmp::max(feature_d.start_d5, ins_vd.min_sd()),
+                cmp::min(feature_d.end_d5, ins_vd.max_sd()),
+            ),
+            (
+                cmp::max(feature_d.start_d3, ins_dj.min_ed()),
+                cmp::min(feature_d.end_d3, ins_dj.max_ed()),
+            ),
+            (
+                cmp::max(feature_j.start_j5, ins_dj.min_sj()),
+                cmp::min(feature_j.end_j5, ins_dj.max_sj()),
+            ),
+        ];
+        if ranges.iter().any(|r| r.0 >= r.1) {
+            return Ok(None);
+        }
+
+        let factors = [
+            transition(ranges[0], ranges[1], |ev, sd| {
+                let last_v_nucleotide = feature_v
+                    .alignment
+                    .get_last_nucleotide((feature_v.end_v3 - ev - 1) as usize);
+                Ok(feature_v.likelihood(ev).to_scalar()?
+                    * ins_vd.likelihood(ev, sd, last_v_nucleotide).to_scalar()?
+                    * self.likelihood_length_vd((sd - ev) as usize, feature_v.index))
+            })?,
+            transition(ranges[1], ranges[2], |sd, ed| {
+                feature_d.likelihood(sd, ed).to_scalar()
+            })?,
+            transition(ranges[2], ranges[3], |ed, sj| {
+                let first_j_nucleotide = feature_j
+                    .alignment
+                    .get_first_nucleotide((sj - feature_j.start_j5) as usize);
+                Ok(ins_dj.likelihood(ed, sj, first_j_nucleotide).to_scalar()?
+                    * self.likelihood_length_dj((sj - ed) as usize, feature_j.index)
+                    * feature_j.likelihood(sj).to_scalar()?)
+            })?,
+        ];
+
+        let beta3 = Array1::ones(factors[2].dim().1);
+        let beta2 = factors[2].dot(&beta3);
+        let beta1 = factors[1].dot(&beta2);
+        let beta0 = factors[0].dot(&beta1);
+        let max_beta3 = Array1::ones(factors[2].dim().1);
+        let max_beta2 = max_dot(&factors[2], &max_beta3);
+        let max_beta1 = max_dot(&factors[1], &max_beta2);
+        let max_beta0 = max_dot(&factors[0], &max_beta1);
+        let chain = PositionChain {
+            feature_v,
+            feature_d,
+            feature_j,
+            alignments_d,
+            likelihood_vdj,
+            ranges,
+            factors,
+            beta: [beta0, beta1, beta2, beta3],
+            max_beta: [max_beta0, max_beta1, max_beta2, max_beta3],
+        };
+        Ok((chain.likelihood() > 0.).then_some(chain))
+    }
+
+    /// Draw the positions of the chain, then the D alignment
+    fn sample_chain<R: Rng>(
+        &self,
+        chain: &PositionChain,
+        ip: &InferenceParameters,
+        rng: &mut R,
+    ) -> Result<InfEvent> {
+        let mut idx = draw(chain.beta[0].as_slice().unwrap(), rng)?;
+        let mut positions = [chain.ranges[0].0 + idx as i64; 4];
+        let mut likelihood = chain.likelihood_vdj;
+        for k in 0..3 {
+            let row = chain.factors[k].row(idx);
+            let next = draw((&row * &chain.beta[k + 1]).as_slice().unwrap(), rng)?;
+            // the D factor is replaced by the likelihood of the chosen alignment
+            if k != 1 {
+                likelihood *= row[next];
+            }
+            idx = next;
+            positions[k + 1] = chain.ranges[k + 1].0 + idx as i64;
+        }
+        let [ev, sd, ed, sj] = positions;
+
+        let (alignments, weights) = self.d_alignments(chain, sd, ed, ip);
+        let chosen = draw(&weights, rng)?;
+        Ok(chain.event(
+            [ev, sd, ed, sj],
+            alignments[chosen],
+            likelihood * weights[chosen],
+        ))
+    }
+
+    /// Explore the scenarios of one chain, keeping the `k` most likely ones
+    /// in `top` (sorted by decreasing likelihood)
+    fn top_chain_events(
+        &self,
+        chain: &PositionChain,
+        k: usize,
+        ip: &InferenceParameters,
+        top: &mut Vec<InfEvent>,
+    ) {
+        let l0 = chain.likelihood_vdj;
+        for i0 in 0..chain.beta[0].len() {
+            if l0 * chain.max_beta[0][i0] <= threshold(top, k) {
+                continue;
+            }
+            for i1 in 0..chain.beta[1].len() {
+                let l1 = l0 * chain.factors[0][[i0, i1]];
+                if l1 * chain.max_beta[1][i1] <= threshold(top, k) {
+                    continue;
+                }
+                for i2 in 0..chain.beta[2].len() {
+                    // the D factor sums over the alignments, so it's an upper bound
+                    if l1 * chain.factors[1][[i1, i2]] * chain.max_beta[2][i2] <= threshold(top, k)
+                    {
+                        continue;
+                    }
+                    let sd = chain.ranges[1].0 + i1 as i64;
+                    let ed = chain.ranges[2].0 + i2 as i64;
+                    let (alignments, weights) = self.d_alignments(chain, sd, ed, ip);
+                    for i3 in 0..chain.beta[3].len() {
+                        let l3 = l1 * chain.factors[2][[i2, i3]];
+                        for (&pos_d, &weight) in alignments.iter().zip(&weights) {
+                            if l3 * weight <= threshold(top, k) {
+                                continue;
+                            }
+                            let event = chain.event(
+                                [
+                                    chain.ranges[0].0 + i0 as i64,
+                                    sd,
+                                    ed,
+                                    chain.ranges[3].0 + i3 as i64,
+                                ],
+                                pos_d,
+                                l3 * weight,
+                            );
+                            insert_top(top, event, k);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// D alignments (start in the sequence) spanning (sd, ed), with the
+    /// likelihood of the corresponding deletions and errors
+    fn d_alignments(
+        &self,
+        chain: &PositionChain,
+        sd: i64,
+        ed: i64,
+        ip: &InferenceParameters,
+    ) -> (Vec<i64>, Vec<f64>) {
+        let mut alignments = Vec::new();
+        let mut weights = Vec::new();
+        for dal in chain.alignments_d {
+            for (deld5, deld3) in iproduct!(0..self.deld.dim().0, 0..self.deld.dim().1) {
+                if dal.pos + deld5 as i64 != sd || dal.pos + (dal.len() - deld3) as i64 != ed {
+                    continue;
+                }
+                let ll = self.deld.likelihood((deld5, deld3, dal.index))
+                    * self
+                        .error
+                        .likelihood_d(&ErrorDAlignment { dal, deld5, deld3 });
+                if ll > ip.min_likelihood {
+                    alignments.push(dal.pos);
+                    weights.push(ll);
+                }
+            }
+        }
+        (alignments, weights)
+    }
+}
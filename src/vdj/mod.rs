@@ -4,6 +4,7 @@ pub mod event;
 pub mod feature;
 pub mod inference;
 pub mod model;
+pub mod productive;
 pub mod sequence;
 
 // Re-exporting for public API
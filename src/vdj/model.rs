@@ -4,8 +4,8 @@ use crate::shared::parser::{
     parse_file, parse_str, EventType, Marginal, ParserMarginals, ParserParams,
 };
 use crate::shared::sequence::Dna;
-use crate::shared::utils::send_warning;
 use crate::shared::utils::Normalize2;
+use crate::shared::utils::{send_warning, sequence_weights};
 use crate::shared::{
     self,
     distributions::{calc_steady_state_dist, DiscreteDistribution, MarkovDNA},
@@ -13,8 +13,9 @@ use crate::shared::{
 };
 use crate::shared::{
     utils::sorted_and_complete, utils::sorted_and_complete_0start, utils::Normalize,
-    utils::Normalize3, AlignmentParameters, AminoAcid, DAlignment, Features, InfEvent,
-    InferenceParameters, ModelGen, ModelStructure, RecordModel, ResultInference, VJAlignment,
+    utils::Normalize3, AlignmentParameters, AminoAcid, DAlignment, Features, GenerationConstraints,
+    InfEvent, InferenceParameters, ModelGen, ModelStructure, RecordModel, ResultInference,
+    VJAlignment,
 };
 use crate::shared::{DNAMarkovChain, ErrorParameters, Modelable};
 use crate::vdj::Features as FeaturesVDJ;
@@ -23,7 +24,7 @@ use crate::shared::sequence::SequenceType;
 use crate::vdj::sequence::{align_all_dgenes, align_all_jgenes, align_all_vgenes};
 use crate::vdj::{event::StaticEvent, Sequence};
 use anyhow::{anyhow, Result};
-use ndarray::{s, Array1, Array2, Array3, Axis};
+use ndarray::{s, Array1, Array2, Array3, Axis, Ix1, Ix2, Ix3};
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
 use pyo3::prelude::*;
 
@@ -31,7 +32,14 @@ use kdam::{tqdm, BarExt, TqdmParallelIterator};
 
 use std::sync::atomic::Ordering;
 
-use crate::{v_dj, vdj};
+use crate::graph::{
+    self,
+    inference::GRAPH_INFERENCE_ERROR,
+    model::{DJ_INS, D_3_DEL, D_5_DEL, D_GENE, J_5_DEL, J_CHOICE, VD_INS, V_3_DEL, V_CHOICE},
+    ModelGraph,
+};
+use crate::vddj::SecondD;
+use crate::{v_dj, vddj, vdj};
 use rand::rngs::SmallRng;
 use rand::Rng;
 use rand::SeedableRng;
@@ -39,9 +47,13 @@ use rand::SeedableRng;
 use crate::shared::sequence::DnaLike;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io::BufReader;
+use std::ops::Range;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::{cmp, fs::read_to_string, fs::File, io::Write};
 
 #[cfg_attr(all(feature = "py_binds", feature = "pyo3"), pyclass)]
@@ -60,12 +72,44 @@ pub enum EntrySequence {
 
 impl EntrySequence {
     pub fn align(&self, model: &Model, align_params: &AlignmentParameters) -> Result<Sequence> {
+        Ok(self.aligned(model, align_params)?.into_owned())
+    }
+
+    /// Same as `align`, but borrow the alignment if the sequence is already aligned.
+    /// Fail if the alignment was made with a model whose genes or deletion ranges
+    /// differ from `model` (the alignment is stale and must be recomputed).
+    pub fn aligned<'a>(
+        &'a self,
+        model: &Model,
+        align_params: &AlignmentParameters,
+    ) -> Result<Cow<'a, Sequence>> {
+        self.aligned_with_key(model, align_params, model.compute_alignment_key())
+    }
+
+    /// Same as `aligned`, with the `alignment_key` of `model` already computed
+    /// (avoid hashing the genes again for every sequence).
+    pub(crate) fn aligned_with_key<'a>(
+        &'a self,
+        model: &Model,
+        align_params: &AlignmentParameters,
+        key: u64,
+    ) -> Result<Cow<'a, Sequence>> {
         match self {
-            EntrySequence::Aligned(x) => Ok(x.clone()),
+            EntrySequence::Aligned(x) => {
+                if x.alignment_key != key {
+                    return Err(anyhow!(
+                        "The sequence was aligned with a different model (genes or \
+                         range of deletions changed), it needs to be re-aligned"
+                    ));
+                }
+                Ok(Cow::Borrowed(x))
+            }
             EntrySequence::NucleotideSequence(seq) => {
-                model.align_sequence(seq.clone(), align_params)
+                Ok(Cow::Owned(model.align_sequence(seq.clone(), align_params)?))
+            }
+            EntrySequence::NucleotideCDR3((seq, v, j)) => {
+                Ok(Cow::Owned(model.align_from_cdr3(seq, v, j)?))
             }
-            EntrySequence::NucleotideCDR3((seq, v, j)) => model.align_from_cdr3(seq, v, j),
         }
     }
 
@@ -132,11 +176,19 @@ pub struct Generative {
     d_vdj: DiscreteDistribution,
     d_ins_vd: DiscreteDistribution,
     d_ins_dj: DiscreteDistribution,
+    // empty when the insertion lengths don't depend on the genes
+    d_ins_vd_given_v: Vec<DiscreteDistribution>,
+    d_ins_dj_given_j: Vec<DiscreteDistribution>,
     d_del_v_given_v: Vec<DiscreteDistribution>,
     d_del_j_given_j: Vec<DiscreteDistribution>,
     d_del_d5_del_d3: Vec<DiscreteDistribution>,
     markov_vd: MarkovDNA,
     markov_dj: MarkovDNA,
+    // only for `ModelStructure::Graph`
+    d_graph: Vec<Vec<DiscreteDistribution>>,
+    // productive fraction, by set of conserved J residues
+    // (`get_norm_productive_exact`), emptied by `initialize`
+    pub(crate) norm_productive: Arc<Mutex<HashMap<String, f64>>>,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
@@ -158,9 +210,22 @@ pub struct Model {
     pub p_vdj: Array3<f64>,
     pub p_ins_vd: Array1<f64>,
     pub p_ins_dj: Array1<f64>,
+    // P(ins_vd | V) and P(ins_dj | J), only with `ModelStructure::VDJInsGivenGene`
+    // (`p_ins_vd` and `p_ins_dj` then contain the marginals)
+    #[serde(default)]
+    pub p_ins_vd_given_v: Option<Array2<f64>>,
+    #[serde(default)]
+    pub p_ins_dj_given_j: Option<Array2<f64>>,
     pub p_del_v_given_v: Array2<f64>,
     pub p_del_j_given_j: Array2<f64>,
     pub p_del_d5_del_d3: Array3<f64>, // P(del_d5, del_d3 | D)
+    // Arbitrary dependences between the events, only with `ModelStructure::Graph`
+    // (the other probabilities then contain the corresponding marginals)
+    #[serde(default)]
+    pub graph: Option<ModelGraph>,
+    // Second D gene, only with `ModelStructure::VDDJ`
+    #[serde(default)]
+    pub d2: Option<SecondD>,
     #[serde(skip)]
     pub gen: Generative,
     //    pub markov_coefficients_vd: Array2<f64>,
@@ -260,27 +325,7 @@ impl Modelable for Model {
 
     /// Save the model in a given directory (write 4 files)
     fn save_model(&self, directory: &Path) -> Result<()> {
-        let path = directory.join("model_params.txt");
-        let mut file = File::create(path)?;
-        let params = self.write_params()?;
-        file.write_all(params.as_bytes())?;
-
-        let path = directory.join("model_marginals.txt");
-        let mut file = File::create(path)?;
-        let marginals = self.write_marginals()?;
-        file.write_all(marginals.as_bytes())?;
-
-        let path = directory.join("V_gene_CDR3_anchors.csv");
-        let mut file = File::create(path)?;
-        let vanchors = self.write_v_anchors()?;
-        file.write_all(vanchors.as_bytes())?;
-
-        let path = directory.join("J_gene_CDR3_anchors.csv");
-        let mut file = File::create(path)?;
-        let janchors = self.write_j_anchors()?;
-        file.write_all(janchors.as_bytes())?;
-
-        Ok(())
+        self.save_model_with_params(directory, &self.write_params()?)
     }
 
     /// Save the data in json format
@@ -302,11 +347,17 @@ impl Modelable for Model {
     /// Return (`cdr3_nt`, `cdr3_aa`, `full_sequence`, `event`, `vname`, `jname`)
     fn generate<R: Rng>(&mut self, functional: bool, rng: &mut R) -> Result<GenerationResult> {
         let (mut full_seq, _, _, mut event) = self.generate_no_error(functional, rng);
+        let segments = self.segment_regions(&event, full_seq.len());
+        let contexts = event.germline_contexts(self);
+        // indels only in the V/J genes, outside of the CDR3
+        let allowed_indels = self.indels_regions(&event, full_seq.len());
 
         let mut generic_event = shared::StaticEvent::VDJ(event);
         // add errors
         self.error
-            .apply_to_sequence(&full_seq, &mut generic_event, rng);
+            .apply_to_sequence(&full_seq, &segments, &contexts, &mut generic_event, rng);
+        self.error
+            .apply_indels(&allowed_indels, &mut generic_event, rng);
         event = match generic_event.clone() {
             shared::StaticEvent::VDJ(x) => x,
             _ => unreachable!(),
@@ -359,20 +410,36 @@ impl Modelable for Model {
             p_d_given_vj: Array3::<f64>::ones(self.p_d_given_vj.dim()),
             p_ins_vd: Array1::<f64>::ones(self.p_ins_vd.dim()),
             p_ins_dj: Array1::<f64>::ones(self.p_ins_dj.dim()),
+            p_ins_vd_given_v: self
+                .p_ins_vd_given_v
+                .as_ref()
+                .map(|p| Array2::<f64>::ones(p.dim())),
+            p_ins_dj_given_j: self
+                .p_ins_dj_given_j
+                .as_ref()
+                .map(|p| Array2::<f64>::ones(p.dim())),
             p_del_v_given_v: Array2::<f64>::ones(self.p_del_v_given_v.dim()),
             p_del_j_given_j: Array2::<f64>::ones(self.p_del_j_given_j.dim()),
             p_del_d5_del_d3: Array3::<f64>::ones(self.p_del_d5_del_d3.dim()),
-            markov_chain_vd: Arc::new(DNAMarkovChain::new(
-                &Array2::<f64>::ones(self.markov_chain_vd.transition_matrix.dim()),
-                false,
-            )?),
-            markov_chain_dj: Arc::new(DNAMarkovChain::new(
-                &Array2::<f64>::ones(self.markov_chain_dj.transition_matrix.dim()),
-                true, // reversed
-            )?),
+            markov_chain_vd: Arc::new(
+                DNAMarkovChain::new(
+                    &Array2::<f64>::ones(self.markov_chain_vd.transition_matrix.dim()),
+                    false,
+                )?
+                .with_order(self.markov_chain_vd.order())?,
+            ),
+            markov_chain_dj: Arc::new(
+                DNAMarkovChain::new(
+                    &Array2::<f64>::ones(self.markov_chain_dj.transition_matrix.dim()),
+                    true, // reversed
+                )?
+                .with_order(self.markov_chain_dj.order())?,
+            ),
 
             //            markov_coefficients_vd: Array2::<f64>::ones(self.markov_coefficients_vd.dim()),
             // markov_coefficients_dj: Array2::<f64>::ones(self.markov_coefficients_dj.dim()),
+            graph: self.graph.as_ref().map(ModelGraph::uniform).transpose()?,
+            d2: self.d2.as_ref().map(SecondD::uniform).transpose()?,
             error: ErrorParameters::uniform(&self.error)?,
             model_type: self.model_type.clone(),
             ..Default::default()
@@ -384,6 +451,7 @@ impl Modelable for Model {
     /// Re-initialize the error model, normalize the parameters
     fn initialize(&mut self) -> Result<()> {
         self.sanitize_genes()?;
+        self.initialize_graph()?;
 
         self.p_vdj = self.p_vdj.normalize_distribution_3()?;
         self.set_p_vdj(&self.p_vdj.clone())?;
@@ -392,6 +460,8 @@ impl Modelable for Model {
         self.p_del_v_given_v = self.p_del_v_given_v.normalize_distribution()?;
         self.p_del_j_given_j = self.p_del_j_given_j.normalize_distribution()?;
         self.p_del_d5_del_d3 = self.p_del_d5_del_d3.normalize_distribution_double()?;
+        self.initialize_conditional_insertions()?;
+        self.initialize_second_d()?;
         // self.markov_coefficients_vd = self.markov_coefficients_vd.normalize_last()?;
         // self.markov_coefficients_dj = self.markov_coefficients_vd.normalize_last()?;
 
@@ -404,6 +474,7 @@ impl Modelable for Model {
     fn infer(
         &mut self,
         sequences: &[EntrySequence],
+        weights: Option<&[f64]>,
         features_opt: Option<Vec<Features>>,
         alignment_params: &AlignmentParameters,
         inference_params: &InferenceParameters,
@@ -419,6 +490,14 @@ impl Modelable for Model {
             ));
         }
 
+        if self.model_type == ModelStructure::Graph {
+            return Err(anyhow!(GRAPH_INFERENCE_ERROR));
+        }
+
+        inference_params.pseudocounts.check()?;
+
+        let weights = sequence_weights(weights, sequences.len())?;
+
         let mut ip = inference_params.clone();
 
         // no need to compute pgen or store best event if we're infering
@@ -428,48 +507,15 @@ impl Modelable for Model {
         let features = match features_opt {
             None => {
                 // Create new features object
-                vec![
-                    match self.model_type {
-                        ModelStructure::VDJ => Features::VDJ(vdj::Features::new(self)?),
-                        ModelStructure::VxDJ => Features::VxDJ(v_dj::Features::new(self)?),
-                    };
-                    sequences.len()
-                ]
+                vec![self.new_features()?; sequences.len()]
             }
             Some(feats) => feats,
         };
 
-        // error bar for the notebook, we need to chunk
-        let new_features: Result<Vec<_>> =
-            if crate::shared::utils::IN_NOTEBOOK.load(Ordering::SeqCst) {
-                let mut pb = tqdm!(total = sequences.len(), force_refresh = true);
-                let mut new_features: Vec<Result<_>> = vec![];
-                for (feat_c, seq_c) in features.chunks(100).zip(sequences.chunks(100)) {
-                    pb.update(100)?;
-                    new_features.extend(feat_c.iter().zip(seq_c.iter()).map(|(feat, sequence)| {
-                        let aligned = sequence.align(self, alignment_params)?;
-                        let mut new_feat = feat.clone();
-                        let _ = new_feat.infer(&aligned, &ip)?;
-                        Ok(new_feat)
-                    }));
-                }
-                new_features.into_iter().collect()
-            } else {
-                // not in a notebook, we can just use par_tqdm.
-                (&features, sequences)
-                    .into_par_iter()
-                    .tqdm()
-                    .map(|(feat, sequence)| {
-                        let aligned = sequence.align(self, alignment_params)?;
-                        let mut new_feat = feat.clone();
-                        let _ = new_feat.infer(&aligned, &ip)?;
-                        Ok(new_feat)
-                    })
-                    .collect()
-            };
+        let new_features = self.expectation(&features, sequences, alignment_params, &ip)?;
 
         // update the model and clean up the features
-        Features::update(new_features?, self, &ip)
+        Features::update(new_features, &weights, self, &ip)
     }
 
     /// Evaluate a sequence and return the result of the inference
@@ -479,64 +525,9 @@ impl Modelable for Model {
         alignment_params: &AlignmentParameters,
         inference_params: &InferenceParameters,
     ) -> Result<ResultInference> {
-        let mut ip = inference_params.clone();
-        if sequence.is_protein() {
-            ip.do_not_infer_features();
-        }
-
-        let mut features = match self.model_type {
-            ModelStructure::VDJ => Features::VDJ(vdj::Features::new(self)?),
-            ModelStructure::VxDJ => Features::VxDJ(v_dj::Features::new(self)?),
-        };
-
-        let aligned_sequence = sequence.align(self, alignment_params)?;
-        let mut result = features.infer(&aligned_sequence, &ip)?;
-        result.fill_event(self, &aligned_sequence)?;
-
-        // no error: likelihood = pgen
-        if self.error.no_error() {
-            result.pgen = result.likelihood;
-            return Ok(result);
-        }
-
-        // likelihood is 0, so pgen is also 0
-        if result.likelihood == 0. {
-            result.pgen = 0.;
-            return Ok(result);
-        }
-
-        // Otherwise, we need to compute the pgen of the reconstructed sequence
-        if ip.compute_pgen && ip.store_best_event {
-            let event = result
-                .get_best_event()
-                .ok_or(anyhow!("Error with event extraction during pgen inference"))?;
-            let cdr3_nt = event.clone().get_reconstructed_cdr3(self)?;
-            let cdr3: DnaLike = match aligned_sequence.sequence_type {
-                SequenceType::Dna => cdr3_nt.into(),
-                SequenceType::Protein => cdr3_nt.translate()?.into(),
-            };
-
-            // let seq_without_err = event.reconstructed_sequence.ok_or(anyhow!(
-            //     "Error with event reconstruction during pgen inference"
-            // ))?;
-
-            // full sequence, so default alignment parameters should be fine.
-            // except it's way too slow. Just do the CDR3 instead.
-            let aligned_seq = self.align_from_cdr3(
-                &cdr3,
-                &[self.seg_vs[event.v_index].clone()],
-                &[self.seg_js[event.j_index].clone()],
-            )?;
-
-            let mut features_pgen = match self.model_type {
-                ModelStructure::VDJ => Features::VDJ(vdj::Features::new(self)?),
-                ModelStructure::VxDJ => Features::VxDJ(v_dj::Features::new(self)?),
-            };
-            features_pgen.error_mut().remove_error()?; // remove the error
-
-            result.pgen = features_pgen.infer(&aligned_seq, &ip)?.likelihood;
-        }
-        Ok(result)
+        self.evaluate_with(sequence, alignment_params, inference_params, || {
+            self.new_features()
+        })
     }
 
     fn filter_vs(&self, vs: Vec<Gene>) -> Result<Model> {
@@ -546,6 +537,16 @@ impl Modelable for Model {
         let mut p_vdj = Array3::<f64>::zeros((vs.len(), dim.1, dim.2));
         m.seg_vs = Vec::new();
         m.p_del_v_given_v = Array2::<f64>::zeros((self.p_del_v_given_v.dim().0, vs.len()));
+        m.p_ins_vd_given_v = self
+            .p_ins_vd_given_v
+            .as_ref()
+            .map(|p| Array2::<f64>::zeros((p.dim().0, vs.len())));
+        if let Some(graph) = &self.graph {
+            let kept: Vec<usize> = (0..dim.0)
+                .filter(|&iv| vs.contains(&self.seg_vs[iv]))
+                .collect();
+            m.graph = Some(graph.filter(V_CHOICE, &kept)?);
+        }
 
         let mut iv_restr = 0;
         for iv in 0..dim.0 {
@@ -560,6 +561,9 @@ impl Modelable for Model {
                 for idelv in 0..self.p_del_v_given_v.dim().0 {
                     m.p_del_v_given_v[[idelv, iv_restr]] = self.p_del_v_given_v[[idelv, iv]];
                 }
+                if let (Some(p), Some(q)) = (&mut m.p_ins_vd_given_v, &self.p_ins_vd_given_v) {
+                    p.column_mut(iv_restr).assign(&q.column(iv));
+                }
                 iv_restr += 1;
             }
         }
@@ -577,6 +581,16 @@ impl Modelable for Model {
         m.p_vdj = Array3::<f64>::zeros((dim.0, dim.1, js.len()));
         m.seg_js = Vec::new();
         m.p_del_j_given_j = Array2::<f64>::zeros((self.p_del_j_given_j.dim().0, js.len()));
+        m.p_ins_dj_given_j = self
+            .p_ins_dj_given_j
+            .as_ref()
+            .map(|p| Array2::<f64>::zeros((p.dim().0, js.len())));
+        if let Some(graph) = &self.graph {
+            let kept: Vec<usize> = (0..dim.2)
+                .filter(|&ij| js.contains(&self.seg_js[ij]))
+                .collect();
+            m.graph = Some(graph.filter(J_CHOICE, &kept)?);
+        }
 
         let mut ij_restr = 0;
         for ij in 0..dim.2 {
@@ -591,6 +605,9 @@ impl Modelable for Model {
                 for idelj in 0..self.p_del_j_given_j.dim().0 {
                     m.p_del_j_given_j[[idelj, ij_restr]] = self.p_del_j_given_j[[idelj, ij]];
                 }
+                if let (Some(p), Some(q)) = (&mut m.p_ins_dj_given_j, &self.p_ins_dj_given_j) {
+                    p.column_mut(ij_restr).assign(&q.column(ij));
+                }
                 ij_restr += 1;
             }
         }
@@ -751,6 +768,7 @@ impl Modelable for Model {
             d_genes: Vec::new(),
             valid_alignment: true,
             sequence_type: cdr3_seq.sequence_type(),
+            alignment_key: self.compute_alignment_key(),
         };
 
         let align_params = AlignmentParameters::default();
@@ -772,6 +790,7 @@ impl Modelable for Model {
             d_genes: Vec::new(),
             valid_alignment: true,
             sequence_type: dna_seq.sequence_type(),
+            alignment_key: self.compute_alignment_key(),
         };
 
         // if we don't have v genes or j genes, don't try inferring the d gene
@@ -810,6 +829,22 @@ impl Modelable for Model {
             && (self.p_d_given_vj.relative_eq(&m.p_d_given_vj, 1e-4, 1e-4))
             && self.p_v.relative_eq(&m.p_v, 1e-4, 1e-4)
             && self.p_ins_dj.relative_eq(&m.p_ins_dj, 1e-4, 1e-4)
+            && match (&self.p_ins_vd_given_v, &m.p_ins_vd_given_v) {
+                (Some(a), Some(b)) => a.relative_eq(b, 1e-4, 1e-4),
+                (a, b) => a.is_none() && b.is_none(),
+            }
+            && match (&self.p_ins_dj_given_j, &m.p_ins_dj_given_j) {
+                (Some(a), Some(b)) => a.relative_eq(b, 1e-4, 1e-4),
+                (a, b) => a.is_none() && b.is_none(),
+            }
+            && match (&self.graph, &m.graph) {
+                (Some(a), Some(b)) => a.similar_to(b),
+                (a, b) => a.is_none() && b.is_none(),
+            }
+            && match (&self.d2, &m.d2) {
+                (Some(a), Some(b)) => a.similar_to(b),
+                (a, b) => a.is_none() && b.is_none(),
+            }
             && self
                 .p_del_v_given_v
                 .relative_eq(&m.p_del_v_given_v, 1e-4, 1e-4)
@@ -829,6 +864,20 @@ impl Modelable for Model {
                 1e-4,
                 1e-4,
             )
+            && (self.markov_chain_vd.order() == m.markov_chain_vd.order())
+            && (self.markov_chain_dj.order() == m.markov_chain_dj.order())
+            && self
+                .markov_chain_vd
+                .higher_order
+                .iter()
+                .zip(m.markov_chain_vd.higher_order.iter())
+                .all(|(a, b)| a.relative_eq(b, 1e-4, 1e-4))
+            && self
+                .markov_chain_dj
+                .higher_order
+                .iter()
+                .zip(m.markov_chain_dj.higher_order.iter())
+                .all(|(a, b)| a.relative_eq(b, 1e-4, 1e-4))
             && (self.range_del_v == m.range_del_v)
             && (self.range_del_j == m.range_del_j)
             && (self.range_del_d3 == m.range_del_d3)
@@ -841,6 +890,233 @@ impl Modelable for Model {
 }
 
 impl Model {
+    /// Features of the model, depending on the model structure
+    pub(crate) fn new_features(&self) -> Result<Features> {
+        Ok(match self.model_type {
+            ModelStructure::VDJ | ModelStructure::VDJInsGivenGene => {
+                Features::VDJ(vdj::Features::new(self)?)
+            }
+            ModelStructure::VxDJ => Features::VxDJ(v_dj::Features::new(self)?),
+            ModelStructure::Graph => Features::Graph(graph::Features::new(self)?),
+            ModelStructure::VDDJ => Features::VDDJ(Box::new(vddj::Features::new(self)?)),
+        })
+    }
+
+    /// Draw `nb_samples` recombination scenarios of the sequence from the
+    /// exact posterior distribution P(scenario | sequence)
+    pub fn sample_posterior<R: Rng>(
+        &self,
+        sequence: EntrySequence,
+        nb_samples: usize,
+        alignment_params: &AlignmentParameters,
+        inference_params: &InferenceParameters,
+        rng: &mut R,
+    ) -> Result<Vec<InfEvent>> {
+        let aligned_sequence = sequence.align(self, alignment_params)?;
+        let mut events = self.new_features()?.sample_posterior(
+            &aligned_sequence,
+            nb_samples,
+            inference_params,
+            rng,
+        )?;
+        for event in &mut events {
+            ResultInference::complete_event(event, self, &aligned_sequence)?;
+        }
+        Ok(events)
+    }
+
+    /// Return the model conditioned on the gene, insertion and deletion
+    /// constraints (the sequence-level predicates are ignored), together with
+    /// the probability of these constraints under the original model.
+    pub fn constrain(&self, constraints: &GenerationConstraints) -> Result<(Model, f64)> {
+        if constraints.ins_vj.is_some() {
+            return Err(anyhow!("VDJ models don't have VJ insertions"));
+        }
+        if constraints.d_genes.is_none()
+            && constraints.ins_vd.is_none()
+            && constraints.ins_dj.is_none()
+            && constraints.del_v.is_none()
+            && constraints.del_j.is_none()
+        {
+            return Ok((self.clone(), 1.));
+        }
+        if self.model_type == ModelStructure::Graph {
+            return Err(anyhow!(
+                "Constraints on the genes, insertions or deletions are not available for graph models"
+            ));
+        }
+
+        let mut m = self.clone();
+        let (nv, nd, nj) = self.p_vdj.dim();
+        // probability of the constraints given V, D, J (and independent of the genes)
+        let mut weight_v = Array1::<f64>::ones(nv);
+        let mut weight_d = Array1::<f64>::ones(nd);
+        let mut weight_j = Array1::<f64>::ones(nj);
+        let mut weight = 1.;
+
+        if let Some(ds) = &constraints.d_genes {
+            for (id, d) in self.seg_ds.iter().enumerate() {
+                if !ds.iter().any(|g| g.name == d.name) {
+                    weight_d[id] = 0.;
+                }
+            }
+        }
+        if let Some(range) = constraints.del_v {
+            let mask = range_mask(range, self.range_del_v.0, self.p_del_v_given_v.dim().0);
+            weight_v *= &condition_columns(&mut m.p_del_v_given_v, &mask);
+        }
+        if let Some(range) = constraints.del_j {
+            let mask = range_mask(range, self.range_del_j.0, self.p_del_j_given_j.dim().0);
+            weight_j *= &condition_columns(&mut m.p_del_j_given_j, &mask);
+        }
+        if let Some((min, max)) = constraints.ins_vd {
+            let mask = range_mask((min as i64, max as i64), 0, self.p_ins_vd.dim());
+            match &mut m.p_ins_vd_given_v {
+                Some(p) => weight_v *= &condition_columns(p, &mask),
+                None => weight *= condition_vector(&mut m.p_ins_vd, &mask),
+            }
+        }
+        if let Some((min, max)) = constraints.ins_dj {
+            let mask = range_mask((min as i64, max as i64), 0, self.p_ins_dj.dim());
+            match &mut m.p_ins_dj_given_j {
+                Some(p) => weight_j *= &condition_columns(p, &mask),
+                None => weight *= condition_vector(&mut m.p_ins_dj, &mask),
+            }
+        }
+
+        let mut p_vdj = self.p_vdj.clone();
+        for ((iv, id, ij), p) in p_vdj.indexed_iter_mut() {
+            *p *= weight_v[iv] * weight_d[id] * weight_j[ij];
+        }
+        let mass = p_vdj.sum();
+        if mass * weight <= 0. {
+            return Err(anyhow!("The constraints can't be satisfied by the model"));
+        }
+        m.set_p_vdj(&(p_vdj / mass))?;
+        m.initialize()?;
+        Ok((m, mass * weight))
+    }
+
+    /// Expectation step of the inference: run each feature on its sequence
+    /// (aligned with this model)
+    pub(crate) fn expectation(
+        &self,
+        features: &[Features],
+        sequences: &[EntrySequence],
+        alignment_params: &AlignmentParameters,
+        ip: &InferenceParameters,
+    ) -> Result<Vec<Features>> {
+        let key = self.compute_alignment_key();
+        // error bar for the notebook, we need to chunk
+        if crate::shared::utils::IN_NOTEBOOK.load(Ordering::SeqCst) {
+            let mut pb = tqdm!(total = sequences.len(), force_refresh = true);
+            let mut new_features: Vec<Result<_>> = vec![];
+            for (feat_c, seq_c) in features.chunks(100).zip(sequences.chunks(100)) {
+                pb.update(100)?;
+                new_features.extend(feat_c.iter().zip(seq_c.iter()).map(|(feat, sequence)| {
+                    let aligned = sequence.aligned_with_key(self, alignment_params, key)?;
+                    let mut new_feat = feat.clone();
+                    let _ = new_feat.infer(&aligned, ip)?;
+                    Ok(new_feat)
+                }));
+            }
+            new_features.into_iter().collect()
+        } else {
+            // not in a notebook, we can just use par_tqdm.
+            (features, sequences)
+                .into_par_iter()
+                .tqdm()
+                .map(|(feat, sequence)| {
+                    let aligned = sequence.aligned_with_key(self, alignment_params, key)?;
+                    let mut new_feat = feat.clone();
+                    let _ = new_feat.infer(&aligned, ip)?;
+                    Ok(new_feat)
+                })
+                .collect()
+        }
+    }
+
+    /// Evaluate a sequence with the features given by `new_features`
+    /// (the pgen is computed with the same features, without errors)
+    pub(crate) fn evaluate_with<F: Fn() -> Result<Features>>(
+        &self,
+        sequence: EntrySequence,
+        alignment_params: &AlignmentParameters,
+        inference_params: &InferenceParameters,
+        new_features: F,
+    ) -> Result<ResultInference> {
+        let mut ip = inference_params.clone();
+        if sequence.is_protein() {
+            ip.do_not_infer_features();
+        }
+
+        let mut features = new_features()?;
+
+        let aligned_sequence = sequence.align(self, alignment_params)?;
+        let mut result = features.infer(&aligned_sequence, &ip)?;
+        if result.likelihood > 0. && ip.infer_features.any() {
+            result.marginals = features.posterior_marginals();
+        }
+        result.fill_event(self, &aligned_sequence)?;
+
+        // the search is not available for amino-acid or degenerate sequences
+        // and for the VDDJ and Graph models, the top events are left empty
+        let top_events_available = !aligned_sequence.sequence.is_ambiguous()
+            && !matches!(
+                self.model_type,
+                ModelStructure::VDDJ | ModelStructure::Graph
+            );
+        if ip.store_top_k > 0 && top_events_available {
+            result.top_events =
+                new_features()?.top_events(&aligned_sequence, ip.store_top_k, &ip)?;
+            for event in &mut result.top_events {
+                ResultInference::complete_event(event, self, &aligned_sequence)?;
+            }
+        }
+
+        // no error: likelihood = pgen
+        if self.error.no_error() {
+            result.pgen = result.likelihood;
+            return Ok(result);
+        }
+
+        // likelihood is 0, so pgen is also 0
+        if result.likelihood == 0. {
+            result.pgen = 0.;
+            return Ok(result);
+        }
+
+        // Otherwise, we need to compute the pgen of the reconstructed sequence
+        if ip.compute_pgen && ip.store_best_event {
+            let event = result
+                .get_best_event()
+                .ok_or(anyhow!("Error with event extraction during pgen inference"))?;
+            let cdr3_nt = event.clone().get_reconstructed_cdr3(self)?;
+            let cdr3: DnaLike = match aligned_sequence.sequence_type {
+                SequenceType::Dna => cdr3_nt.into(),
+                SequenceType::Protein => cdr3_nt.translate()?.into(),
+            };
+
+            // let seq_without_err = event.reconstructed_sequence.ok_or(anyhow!(
+            //     "Error with event reconstruction during pgen inference"
+            // ))?;
+
+            // full sequence, so default alignment parameters should be fine.
+            // except it's way too slow. Just do the CDR3 instead.
+            let aligned_seq = self.align_from_cdr3(
+                &cdr3,
+                &[self.seg_vs[event.v_index].clone()],
+                &[self.seg_js[event.j_index].clone()],
+            )?;
+
+            let mut features_pgen = new_features()?;
+            features_pgen.error_mut().remove_error()?; // remove the error
+
+            result.pgen = features_pgen.infer(&aligned_seq, &ip)?.likelihood;
+        }
+        Ok(result)
+    }
+
     pub fn infer_brute_force(
         &mut self,
         sequences: &[EntrySequence],
@@ -851,17 +1127,14 @@ impl Model {
         if sequences.iter().any(EntrySequence::is_protein) {
             return Err(anyhow!("The brute-force model doesn't work with proteins"));
         }
+        if self.model_type == ModelStructure::Graph {
+            return Err(anyhow!(GRAPH_INFERENCE_ERROR));
+        }
 
         let features = match features_opt {
             None => {
                 // Create new features object
-                vec![
-                    match self.model_type {
-                        ModelStructure::VDJ => Features::VDJ(vdj::Features::new(self)?),
-                        ModelStructure::VxDJ => Features::VxDJ(v_dj::Features::new(self)?),
-                    };
-                    sequences.len()
-                ]
+                vec![self.new_features()?; sequences.len()]
             }
             Some(feats) => feats,
         };
@@ -870,17 +1143,23 @@ impl Model {
             .into_par_iter()
             .map(|(feat, sequence)| {
                 let aligned = sequence.align(self, alignment_params)?;
-                let new_feat = feat.clone();
-                let mut feat_vdj = match new_feat {
-                    Features::VDJ(x) => Ok(x),
-                    Features::VxDJ(_) => Err(anyhow!("Shouldn't happen.")),
-                }?;
-
-                let _ = feat_vdj.infer_brute_force(&aligned, inference_params)?;
-                Ok(Features::VDJ(feat_vdj))
+                match feat.clone() {
+                    Features::VDJ(mut x) => {
+                        let _ = x.infer_brute_force(&aligned, inference_params)?;
+                        Ok(Features::VDJ(x))
+                    }
+                    Features::VDDJ(mut x) => {
+                        let _ = x.infer_brute_force(&aligned, inference_params)?;
+                        Ok(Features::VDDJ(x))
+                    }
+                    Features::VxDJ(_) | Features::Graph(_) | Features::VJ(_) => {
+                        Err(anyhow!("Shouldn't happen."))
+                    }
+                }
             })
             .collect::<Result<Vec<_>>>()?;
-        Features::update(new_features, self, inference_params)
+        let weights = vec![1.; new_features.len()];
+        Features::update(new_features, &weights, self, inference_params)
     }
 
     pub fn evaluate_brute_force(
@@ -889,9 +1168,12 @@ impl Model {
         alignment_params: &AlignmentParameters,
         inference_params: &InferenceParameters,
     ) -> Result<ResultInference> {
-        let mut feature = FeaturesVDJ::new(self)?;
         let aligned = sequence.align(self, alignment_params)?;
-        let mut result = feature.infer_brute_force(&aligned, inference_params)?;
+        let mut result = if self.model_type == ModelStructure::VDDJ {
+            vddj::Features::new(self)?.infer_brute_force(&aligned, inference_params)?
+        } else {
+            FeaturesVDJ::new(self)?.infer_brute_force(&aligned, inference_params)?
+        };
         result.fill_event(self, &aligned)?;
         Ok(result)
     }
@@ -937,6 +1219,47 @@ impl Model {
     }
 
     pub fn write_marginals(&self) -> Result<String> {
+        if self.markov_chain_vd.order() > 1 || self.markov_chain_dj.order() > 1 {
+            send_warning(
+                "The IGoR format only stores first-order insertion Markov chains, \
+                 the higher orders are not saved (use the json format instead).\n",
+            );
+        }
+        let marginal_vddinucl = Marginal::create(
+            Vec::new(),
+            self.markov_chain_vd
+                .transition_matrix
+                .iter()
+                .copied()
+                .collect::<Array1<f64>>()
+                .into_dyn(),
+        )
+        .write()?;
+        let marginal_djdinucl = Marginal::create(
+            Vec::new(),
+            self.markov_chain_dj
+                .transition_matrix
+                .iter()
+                .copied()
+                .collect::<Array1<f64>>()
+                .into_dyn(),
+        )
+        .write()?;
+
+        if let Some(graph) = &self.graph {
+            let mut result = String::new();
+            for (event, name) in graph::EVENTS.iter().enumerate() {
+                result.push_str(&format!("@{name}\n{}", graph.write_marginal(event)?));
+            }
+            return Ok(format!(
+                "{result}\
+                 @vd_dinucl\n\
+                 {marginal_vddinucl}\
+                 @dj_dinucl\n\
+                 {marginal_djdinucl}"
+            ));
+        }
+
         let marginal_vs = Marginal::create(Vec::new(), self.p_v.clone().into_dyn()).write()?;
         let marginal_js = Marginal::create(
             vec!["v_choice"],
@@ -984,31 +1307,24 @@ impl Model {
             p_deld3_given_deld5_d.permuted_axes((2, 1, 0)).into_dyn(),
         )
         .write()?;
-        let marginal_vdins =
-            Marginal::create(Vec::new(), self.p_ins_vd.clone().into_dyn()).write()?;
-        let marginal_vddinucl = Marginal::create(
-            Vec::new(),
-            self.markov_chain_vd
-                .transition_matrix
-                .iter()
-                .copied()
-                .collect::<Array1<f64>>()
-                .into_dyn(),
-        )
+        let marginal_vdins = match &self.p_ins_vd_given_v {
+            Some(p) => {
+                Marginal::create(vec!["v_choice"], p.clone().permuted_axes((1, 0)).into_dyn())
+            }
+            None => Marginal::create(Vec::new(), self.p_ins_vd.clone().into_dyn()),
+        }
         .write()?;
-        let marginal_djins =
-            Marginal::create(Vec::new(), self.p_ins_dj.clone().into_dyn()).write()?;
-        let marginal_djdinucl = Marginal::create(
-            Vec::new(),
-            self.markov_chain_dj
-                .transition_matrix
-                .iter()
-                .copied()
-                .collect::<Array1<f64>>()
-                .into_dyn(),
-        )
+        let marginal_d2 = match &self.d2 {
+            Some(d2) => d2.write_marginals()?,
+            None => String::new(),
+        };
+        let marginal_djins = match &self.p_ins_dj_given_j {
+            Some(p) => {
+                Marginal::create(vec!["j_choice"], p.clone().permuted_axes((1, 0)).into_dyn())
+            }
+            None => Marginal::create(Vec::new(), self.p_ins_dj.clone().into_dyn()),
+        }
         .write()?;
-
         Ok(format!(
             "@v_choice\n\
 	     {marginal_vs}\
@@ -1031,11 +1347,58 @@ impl Model {
 	     @dj_ins\n\
 	     {marginal_djins}\
 	     @dj_dinucl\n\
-	     {marginal_djdinucl}"
+	     {marginal_djdinucl}\
+	     {marginal_d2}"
         ))
     }
 
+    /// Same as `save_model`, but models with a second D gene are saved too,
+    /// with righor-specific events (see `write_params_righor`). The files can
+    /// only be read back by righor, not by `IGoR`.
+    pub fn save_model_righor(&self, directory: &Path) -> Result<()> {
+        self.save_model_with_params(directory, &self.write_params_righor()?)
+    }
+
+    fn save_model_with_params(&self, directory: &Path, params: &str) -> Result<()> {
+        let path = directory.join("model_params.txt");
+        let mut file = File::create(path)?;
+        file.write_all(params.as_bytes())?;
+
+        let path = directory.join("model_marginals.txt");
+        let mut file = File::create(path)?;
+        let marginals = self.write_marginals()?;
+        file.write_all(marginals.as_bytes())?;
+
+        let path = directory.join("V_gene_CDR3_anchors.csv");
+        let mut file = File::create(path)?;
+        let vanchors = self.write_v_anchors()?;
+        file.write_all(vanchors.as_bytes())?;
+
+        let path = directory.join("J_gene_CDR3_anchors.csv");
+        let mut file = File::create(path)?;
+        let janchors = self.write_j_anchors()?;
+        file.write_all(janchors.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Parameter file in the `IGoR` format. `IGoR` has no event for the
+    /// insertions between two D genes, models with a second D gene are
+    /// rejected (use `write_params_righor`).
     pub fn write_params(&self) -> Result<String> {
+        if self.d2.is_some() {
+            return Err(anyhow!(
+                "IGoR can't represent a second D gene, use `write_params_righor` \
+                 or `save_model_righor` (files only readable by righor), or `save_json`"
+            ));
+        }
+        self.write_params_righor()
+    }
+
+    /// Parameter file in the `IGoR` format, extended with righor-specific
+    /// events for the second D gene (see `SecondD::write_params`). Same as
+    /// `write_params` for models with a single D gene.
+    pub fn write_params_righor(&self) -> Result<String> {
         let mut result = "@Event_list\n\
 			  #GeneChoice;V_gene;Undefined_side;7;v_choice\n"
             .to_string();
@@ -1081,6 +1444,43 @@ impl Model {
         let dimdeld5 = self.p_del_d5_del_d3.dim().0;
         let dimj = self.seg_js.len();
         let dimdelj = self.p_del_j_given_j.dim().0;
+        let dimvdins = self.p_ins_vd.dim();
+        let dimdjins = self.p_ins_dj.dim();
+        let mut edges_ins = String::new();
+        if self.p_ins_vd_given_v.is_some() {
+            edges_ins.push_str(&format!(
+                "%GeneChoice_V_gene_Undefined_side_prio7_size{dimv};\
+                 Insertion_VD_genes_Undefined_side_prio4_size{dimvdins}\n"
+            ));
+        }
+        if self.p_ins_dj_given_j.is_some() {
+            edges_ins.push_str(&format!(
+                "%GeneChoice_J_gene_Undefined_side_prio7_size{dimj};\
+                 Insertion_DJ_genes_Undefined_side_prio2_size{dimdjins}\n"
+            ));
+        }
+        let edges = match &self.graph {
+            Some(graph) => graph.write_edges(),
+            None => format!(
+                "%GeneChoice_V_gene_Undefined_side_prio7_size{dimv};\
+                 Deletion_V_gene_Three_prime_prio5_size{dimdelv}\n\
+                 %GeneChoice_D_gene_Undefined_side_prio6_size{dimd};\
+                 Deletion_D_gene_Three_prime_prio5_size{dimdeld3}\n\
+                 %GeneChoice_D_gene_Undefined_side_prio6_size{dimd};\
+                 Deletion_D_gene_Five_prime_prio5_size{dimdeld5}\n\
+                 %GeneChoice_J_gene_Undefined_side_prio7_size{dimj};\
+                 Deletion_J_gene_Five_prime_prio5_size{dimdelj}\n\
+                 %GeneChoice_J_gene_Undefined_side_prio7_size{dimj};\
+                 GeneChoice_D_gene_Undefined_side_prio6_size{dimd}\n\
+                 %Deletion_D_gene_Five_prime_prio5_size{dimdeld5};\
+                 Deletion_D_gene_Three_prime_prio5_size{dimdeld3}\n\
+                 {edges_ins}"
+            ),
+        };
+        let (params_d2, edges_d2) = match &self.d2 {
+            Some(d2) => (d2.write_params(self), d2.write_edges()),
+            None => (String::new(), String::new()),
+        };
         let error = self.error.write();
         result.push_str(&format!(
             "#DinucMarkov;VD_genes;Undefined_side;3;vd_dinucl\n\
@@ -1093,19 +1493,10 @@ impl Model {
 	     %C;1\n\
 	     %G;2\n\
 	     %A;0\n\
+	     {params_d2}\
 	     @Edges\n\
-	     %GeneChoice_V_gene_Undefined_side_prio7_size{dimv};\
-	     Deletion_V_gene_Three_prime_prio5_size{dimdelv}\n\
-	     %GeneChoice_D_gene_Undefined_side_prio6_size{dimd};\
-	     Deletion_D_gene_Three_prime_prio5_size{dimdeld3}\n\
-	     %GeneChoice_D_gene_Undefined_side_prio6_size{dimd};\
-	     Deletion_D_gene_Five_prime_prio5_size{dimdeld5}\n\
-	     %GeneChoice_J_gene_Undefined_side_prio7_size{dimj};\
-	     Deletion_J_gene_Five_prime_prio5_size{dimdelj}\n\
-	     %GeneChoice_J_gene_Undefined_side_prio7_size{dimj};\
-	     GeneChoice_D_gene_Undefined_side_prio6_size{dimd}\n\
-	     %Deletion_D_gene_Five_prime_prio5_size{dimdeld5};\
-	     Deletion_D_gene_Three_prime_prio5_size{dimdeld3}\n\
+	     {edges}\
+	     {edges_d2}\
 	     {error}"
         ));
         Ok(result)
@@ -1212,6 +1603,47 @@ impl Model {
             ));
         }
 
+        // Markov coefficients
+        model.markov_chain_vd = Arc::new(DNAMarkovChain::new(
+            &pm.marginals
+                .get("vd_dinucl")
+                .unwrap()
+                .probabilities
+                .clone()
+                .into_shape_with_order((4, 4))
+                .map_err(|_e| anyhow!("Wrong size for vd_dinucl"))?,
+            false,
+        )?);
+        model.markov_chain_dj = Arc::new(DNAMarkovChain::new(
+            &pm.marginals
+                .get("dj_dinucl")
+                .unwrap()
+                .probabilities
+                .clone()
+                .into_shape_with_order((4, 4))
+                .map_err(|_e| anyhow!("Wrong size for dj_dinucl"))?,
+            true,
+        )?);
+
+        // TODO: Need to deal with potential first nt bias in the file
+        // model.first_nt_bias_ins_vd =
+        //     Array1::from_vec(calc_steady_state_dist(&model.markov_coefficients_vd)?);
+        // model.first_nt_bias_ins_dj =
+        //     Array1::from_vec(calc_steady_state_dist(&model.markov_coefficients_dj)?);
+
+        model.error = pp.error.clone();
+        model.thymic_q = 9.41; // TODO: deal with this
+
+        // dependences that the standard model can't deal with: keep the
+        // full graph (generation and evaluation only)
+        let graph = ModelGraph::from_marginals(pm)?;
+        if !graph.is_standard() {
+            model.graph = Some(graph);
+            model.model_type = ModelStructure::Graph;
+            model.initialize()?;
+            return Ok(model);
+        }
+
         // Set the different probabilities for the model
         let pv = pm.marginals.get("v_choice").unwrap().probabilities.clone();
         let pd = pm.marginals.get("d_gene").unwrap().probabilities.clone();
@@ -1258,22 +1690,34 @@ impl Model {
 
         model.set_p_vdj(&model.p_vdj.clone())?;
 
-        model.p_ins_vd = pm
-            .marginals
-            .get("vd_ins")
-            .unwrap()
-            .probabilities
-            .clone()
-            .into_dimensionality()
-            .unwrap();
-        model.p_ins_dj = pm
-            .marginals
-            .get("dj_ins")
-            .unwrap()
-            .probabilities
-            .clone()
-            .into_dimensionality()
-            .unwrap();
+        // the insertion lengths can depend on the V (VD) / J (DJ) genes
+        let pinsvd = pm.marginals.get("vd_ins").unwrap().probabilities.clone();
+        let pinsdj = pm.marginals.get("dj_ins").unwrap().probabilities.clone();
+        if pinsvd.ndim() == 2 || pinsdj.ndim() == 2 {
+            model.model_type = ModelStructure::VDJInsGivenGene;
+        }
+        if pinsvd.ndim() == 2 {
+            let p: Array2<f64> = pinsvd
+                .into_dimensionality()
+                .map_err(|_e| anyhow!("Wrong format for vd_ins"))?;
+            model.p_ins_vd = p.sum_axis(Axis(0));
+            model.p_ins_vd_given_v = Some(p.t().to_owned());
+        } else {
+            model.p_ins_vd = pinsvd
+                .into_dimensionality()
+                .map_err(|_e| anyhow!("Wrong format for vd_ins"))?;
+        }
+        if pinsdj.ndim() == 2 {
+            let p: Array2<f64> = pinsdj
+                .into_dimensionality()
+                .map_err(|_e| anyhow!("Wrong format for dj_ins"))?;
+            model.p_ins_dj = p.sum_axis(Axis(0));
+            model.p_ins_dj_given_j = Some(p.t().to_owned());
+        } else {
+            model.p_ins_dj = pinsdj
+                .into_dimensionality()
+                .map_err(|_e| anyhow!("Wrong format for dj_ins"))?;
+        }
         model.p_del_v_given_v = pm
             .marginals
             .get("v_3_del")
@@ -1332,36 +1776,10 @@ impl Model {
             return Err(anyhow!("Wrong format for D3 deletions"));
         }
 
-        // Markov coefficients
-        model.markov_chain_vd = Arc::new(DNAMarkovChain::new(
-            &pm.marginals
-                .get("vd_dinucl")
-                .unwrap()
-                .probabilities
-                .clone()
-                .into_shape_with_order((4, 4))
-                .map_err(|_e| anyhow!("Wrong size for vd_dinucl"))?,
-            false,
-        )?);
-        model.markov_chain_dj = Arc::new(DNAMarkovChain::new(
-            &pm.marginals
-                .get("dj_dinucl")
-                .unwrap()
-                .probabilities
-                .clone()
-                .into_shape_with_order((4, 4))
-                .map_err(|_e| anyhow!("Wrong size for dj_dinucl"))?,
-            true,
-        )?);
-
-        // TODO: Need to deal with potential first nt bias in the file
-        // model.first_nt_bias_ins_vd =
-        //     Array1::from_vec(calc_steady_state_dist(&model.markov_coefficients_vd)?);
-        // model.first_nt_bias_ins_dj =
-        //     Array1::from_vec(calc_steady_state_dist(&model.markov_coefficients_dj)?);
-
-        model.error = pp.error.clone();
-        model.thymic_q = 9.41; // TODO: deal with this
+        model.d2 = SecondD::load(&model, pp, pm)?;
+        if model.d2.is_some() {
+            model.model_type = ModelStructure::VDDJ;
+        }
 
         model.initialize()?;
         Ok(model)
@@ -1378,6 +1796,30 @@ impl Model {
         }
     }
 
+    /// Hash of everything the alignment of a sequence depends on (gene names and
+    /// sequences, range of deletions), computed from the current state of the model.
+    /// Alignments store the key of the model that made them, so they can be reused
+    /// across inference rounds as long as it matches.
+    pub fn compute_alignment_key(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for genes in [&self.seg_vs, &self.seg_js, &self.seg_ds] {
+            genes.len().hash(&mut hasher);
+            for g in genes {
+                g.name.hash(&mut hasher);
+                g.cdr3_pos.hash(&mut hasher);
+                g.seq.seq.hash(&mut hasher);
+            }
+        }
+        (
+            self.range_del_v,
+            self.range_del_j,
+            self.range_del_d3,
+            self.range_del_d5,
+        )
+            .hash(&mut hasher);
+        hasher.finish()
+    }
+
     pub fn sanitize_genes(&mut self) -> Result<()> {
         // Trim the V/J nucleotides sequences at the CDR3 region (include F/W/C residues)
         // and append the maximum number of reverse palindromic insertions appended.
@@ -1404,11 +1846,29 @@ impl Model {
     }
 
     fn initialize_generative_model(&mut self) -> Result<()> {
+        self.clear_norm_productive();
         self.gen.d_vdj =
             DiscreteDistribution::new(&self.p_vdj.view().iter().copied().collect::<Vec<_>>())?;
         self.gen.d_ins_vd = DiscreteDistribution::new(&self.p_ins_vd.to_vec())?;
         self.gen.d_ins_dj = DiscreteDistribution::new(&self.p_ins_dj.to_vec())?;
 
+        self.gen.d_ins_vd_given_v = Vec::new();
+        if let Some(p) = &self.p_ins_vd_given_v {
+            for row in p.axis_iter(Axis(1)) {
+                self.gen
+                    .d_ins_vd_given_v
+                    .push(DiscreteDistribution::new(&row.to_vec())?);
+            }
+        }
+        self.gen.d_ins_dj_given_j = Vec::new();
+        if let Some(p) = &self.p_ins_dj_given_j {
+            for row in p.axis_iter(Axis(1)) {
+                self.gen
+                    .d_ins_dj_given_j
+                    .push(DiscreteDistribution::new(&row.to_vec())?);
+            }
+        }
+
         self.gen.d_del_v_given_v = Vec::new();
         for row in self.p_del_v_given_v.axis_iter(Axis(1)) {
             self.gen
@@ -1435,8 +1895,19 @@ impl Model {
                 .push(DiscreteDistribution::new(&d5d3)?);
         }
 
-        self.gen.markov_vd = MarkovDNA::new(&self.markov_chain_vd.transition_matrix.to_owned())?;
-        self.gen.markov_dj = MarkovDNA::new(&self.markov_chain_dj.transition_matrix.to_owned())?;
+        self.gen.d_graph = match &self.graph {
+            Some(graph) => graph.generative()?,
+            None => Vec::new(),
+        };
+
+        self.gen.markov_vd = MarkovDNA::new_higher_order(
+            &self.markov_chain_vd.transition_matrix,
+            &self.markov_chain_vd.higher_order,
+        )?;
+        self.gen.markov_dj = MarkovDNA::new_higher_order(
+            &self.markov_chain_dj.transition_matrix,
+            &self.markov_chain_dj.higher_order,
+        )?;
 
         Ok(())
     }
@@ -1455,6 +1926,10 @@ impl Model {
 
         let proba_v_default = 1. / (value.len() as f64);
         let delv_default = self.p_del_v_given_v.sum_axis(Axis(1)) / self.p_del_v_given_v.sum();
+        let mut new_p_ins_vd_given_v = self
+            .p_ins_vd_given_v
+            .as_ref()
+            .map(|p| Array2::<f64>::zeros([p.dim().0, value.len()]));
 
         for (iv, v) in value.iter().enumerate() {
             match self
@@ -1470,12 +1945,20 @@ impl Model {
                     new_p_del_v_given_v
                         .slice_mut(s![.., iv])
                         .assign(&self.p_del_v_given_v.slice_mut(s![.., index]));
+                    if let (Some(p), Some(q)) = (&mut new_p_ins_vd_given_v, &self.p_ins_vd_given_v)
+                    {
+                        p.column_mut(iv).assign(&q.column(index));
+                    }
                 }
                 None => {
                     new_p_vdj.slice_mut(s![iv, .., ..]).fill(proba_v_default);
                     new_p_del_v_given_v
                         .slice_mut(s![.., iv])
                         .assign(&delv_default);
+                    // new genes get the marginal insertion distribution
+                    if let Some(p) = &mut new_p_ins_vd_given_v {
+                        p.column_mut(iv).assign(&self.p_ins_vd);
+                    }
                 }
             }
         }
@@ -1487,6 +1970,7 @@ impl Model {
         self.seg_vs = value;
         self.set_p_vdj(&new_p_vdj)?;
         self.p_del_v_given_v = new_p_del_v_given_v;
+        self.p_ins_vd_given_v = new_p_ins_vd_given_v;
         self.initialize()?;
         Ok(())
     }
@@ -1505,6 +1989,10 @@ impl Model {
 
         let proba_j_default = 1. / (value.len() as f64);
         let delj_default = self.p_del_j_given_j.sum_axis(Axis(1)) / self.p_del_j_given_j.sum();
+        let mut new_p_ins_dj_given_j = self
+            .p_ins_dj_given_j
+            .as_ref()
+            .map(|p| Array2::<f64>::zeros([p.dim().0, value.len()]));
 
         for (ij, j) in value.iter().enumerate() {
             match self
@@ -1520,12 +2008,19 @@ impl Model {
                     new_p_del_j_given_j
                         .slice_mut(s![.., ij])
                         .assign(&self.p_del_j_given_j.slice_mut(s![.., index]));
+                    if let (Some(p), Some(q)) = (&mut new_p_ins_dj_given_j, &self.p_ins_dj_given_j)
+                    {
+                        p.column_mut(ij).assign(&q.column(index));
+                    }
                 }
                 None => {
                     new_p_vdj.slice_mut(s![.., .., ij]).fill(proba_j_default);
                     new_p_del_j_given_j
                         .slice_mut(s![.., ij])
                         .assign(&delj_default);
+                    if let Some(p) = &mut new_p_ins_dj_given_j {
+                        p.column_mut(ij).assign(&self.p_ins_dj);
+                    }
                 }
             }
         }
@@ -1537,6 +2032,7 @@ impl Model {
         self.seg_js = value;
         self.set_p_vdj(&new_p_vdj)?;
         self.p_del_j_given_j = new_p_del_j_given_j;
+        self.p_ins_dj_given_j = new_p_ins_dj_given_j;
         self.initialize()?;
         Ok(())
     }
@@ -1588,6 +2084,9 @@ impl Model {
         new_p_vdj = new_p_vdj.normalize_distribution_3()?;
         new_p_del_d5_del_d3 = new_p_del_d5_del_d3.normalize_distribution_double()?;
 
+        if let Some(d2) = &self.d2 {
+            self.d2 = Some(d2.set_d_segments(&self.seg_ds, &value)?);
+        }
         self.seg_ds = value;
         self.set_p_vdj(&new_p_vdj)?;
         self.p_del_d5_del_d3 = new_p_del_d5_del_d3;
@@ -1595,6 +2094,37 @@ impl Model {
         Ok(())
     }
 
+    /// V and J regions of the (error-free) generated sequence
+    fn segment_regions(&self, event: &StaticEvent, len_seq: usize) -> [Range<usize>; 2] {
+        let len_v = self.seg_vs[event.v_index]
+            .seq_with_pal
+            .as_ref()
+            .unwrap()
+            .len()
+            - event.delv;
+        let len_j = self.seg_js[event.j_index]
+            .seq_with_pal
+            .as_ref()
+            .unwrap()
+            .len()
+            - event.delj;
+        [0..len_v, len_seq - len_j..len_seq]
+    }
+
+    /// Regions of the (error-free) generated sequence where indels can appear:
+    /// the part of the V gene before the CDR3 and the part of the J gene after it
+    fn indels_regions(&self, event: &StaticEvent, len_seq: usize) -> Vec<Range<usize>> {
+        let vg = &self.seg_vs[event.v_index];
+        let jg = &self.seg_js[event.j_index];
+        let len_v = vg.seq_with_pal.as_ref().unwrap().len() - event.delv;
+        let len_j = jg.seq_with_pal.as_ref().unwrap().len() - event.delj;
+        let end_cdr3 = len_seq - jg.seq.len() + jg.cdr3_pos.unwrap() + 3;
+        vec![
+            0..vg.cdr3_pos.unwrap().min(len_v),
+            end_cdr3.max(len_seq - len_j)..len_seq,
+        ]
+    }
+
     /// Return (`full_seq`, `cdr3_seq`, `aa_seq`, `event`)
     pub fn generate_no_error<R: Rng>(
         &mut self,
@@ -1607,20 +2137,57 @@ impl Model {
                 ..Default::default()
             };
 
-            let vdj_index: usize = self.gen.d_vdj.generate(rng);
-            event.v_index = vdj_index / (self.p_vdj.dim().1 * self.p_vdj.dim().2);
-            // if the V gene is not functional and we're looking for functional seqs
-            if functional && !self.seg_vs[event.v_index].is_functional() {
-                continue;
-            }
-            event.j_index = vdj_index % self.p_dj.dim().1;
-            // same for J gene
-            if functional && !self.seg_js[event.j_index].is_functional() {
-                continue;
-            }
+            let (ins_vd, ins_dj) = if let Some(graph) = &self.graph {
+                let r = graph.generate(&self.gen.d_graph, rng);
+                event.v_index = r[V_CHOICE];
+                event.j_index = r[J_CHOICE];
+                if functional
+                    && !(self.seg_vs[event.v_index].is_functional()
+                        && self.seg_js[event.j_index].is_functional())
+                {
+                    continue;
+                }
+                event.d_index = r[D_GENE];
+                event.delv = r[V_3_DEL];
+                event.deld5 = r[D_5_DEL];
+                event.deld3 = r[D_3_DEL];
+                event.delj = r[J_5_DEL];
+                (r[VD_INS], r[DJ_INS])
+            } else {
+                let vdj_index: usize = self.gen.d_vdj.generate(rng);
+                event.v_index = vdj_index / (self.p_vdj.dim().1 * self.p_vdj.dim().2);
+                // if the V gene is not functional and we're looking for functional seqs
+                if functional && !self.seg_vs[event.v_index].is_functional() {
+                    continue;
+                }
+                event.j_index = vdj_index % self.p_dj.dim().1;
+                // same for J gene
+                if functional && !self.seg_js[event.j_index].is_functional() {
+                    continue;
+                }
+
+                event.d_index =
+                    (vdj_index % (self.p_vdj.dim().1 * self.p_vdj.dim().2)) / self.p_vdj.dim().2;
+
+                event.delv = self.gen.d_del_v_given_v[event.v_index].generate(rng);
+                let del_d: usize = self.gen.d_del_d5_del_d3[event.d_index].generate(rng);
+                event.deld5 = del_d / self.p_del_d5_del_d3.dim().1;
+                event.deld3 = del_d % self.p_del_d5_del_d3.dim().1;
+                event.delj = self.gen.d_del_j_given_j[event.j_index].generate(rng);
 
-            event.d_index =
-                (vdj_index % (self.p_vdj.dim().1 * self.p_vdj.dim().2)) / self.p_vdj.dim().2;
+                let ins_vd = if self.gen.d_ins_vd_given_v.is_empty() {
+                    self.gen.d_ins_vd.generate(rng)
+                } else {
+                    self.gen.d_ins_vd_given_v[event.v_index].generate(rng)
+                };
+                let ins_dj = if self.gen.d_ins_dj_given_j.is_empty() {
+                    self.gen.d_ins_dj.generate(rng)
+                } else {
+                    self.gen.d_ins_dj_given_j[event.j_index].generate(rng)
+                };
+                (ins_vd, ins_dj)
+            };
+            let second_d = self.d2.as_ref().map(|d2| d2.generate(event.d_index, rng));
 
             let seq_v_cdr3: &Dna = &self.seg_vs_sanitized[event.v_index];
             let seq_j_cdr3: &Dna = &self.seg_js_sanitized[event.j_index];
@@ -1628,22 +2195,20 @@ impl Model {
             let seq_d: &Dna = self.seg_ds[event.d_index].seq_with_pal.as_ref().unwrap();
             let seq_v: &Dna = self.seg_vs[event.v_index].seq_with_pal.as_ref().unwrap();
             let seq_j: &Dna = self.seg_js[event.j_index].seq_with_pal.as_ref().unwrap();
-
-            event.delv = self.gen.d_del_v_given_v[event.v_index].generate(rng);
-            let del_d: usize = self.gen.d_del_d5_del_d3[event.d_index].generate(rng);
-            event.deld5 = del_d / self.p_del_d5_del_d3.dim().1;
-            event.deld3 = del_d % self.p_del_d5_del_d3.dim().1;
-            event.delj = self.gen.d_del_j_given_j[event.j_index].generate(rng);
-
-            let ins_vd: usize = self.gen.d_ins_vd.generate(rng);
-            let ins_dj: usize = self.gen.d_ins_dj.generate(rng);
+            // length of the second D (with the DD insertions), if any
+            let len_d2 = second_d.as_ref().map_or(0, |e| {
+                self.seg_ds[e.d_index].seq_with_pal.as_ref().unwrap().len() + e.ins_dd
+                    - e.deld5
+                    - e.deld3
+            });
 
             let out_of_frame = (seq_v_cdr3.len() + seq_j_cdr3.len() - event.delv + seq_d.len()
                 - event.deld5
                 - event.deld3
                 - event.delj
                 + ins_vd
-                + ins_dj)
+                + ins_dj
+                + len_d2)
                 % 3
                 != 0;
             if functional && out_of_frame {
@@ -1663,11 +2228,32 @@ impl Model {
             event.insvd = ins_seq_vd.clone();
             event.v_start_gene = 0;
             event.d_start_seq = (seq_v.len() - event.delv - event.deld5 + ins_vd) as i64;
-            event.j_start_seq = (seq_v.len() - event.delv + ins_vd + ins_dj + seq_d.len()
+            event.j_start_seq = (seq_v.len() - event.delv + ins_vd + ins_dj + seq_d.len() + len_d2
                 - event.deld5
                 - event.deld3
                 - event.delj) as i64;
 
+            if let Some(e) = second_d {
+                // the DD insertions follow the last generated nucleotide
+                let end_d1 = seq_d.len() - event.deld3;
+                let previous_nucleotide = if end_d1 > event.deld5 {
+                    seq_d.seq[end_d1 - 1]
+                } else {
+                    ins_seq_vd.seq.last().copied().unwrap_or(end_v)
+                };
+                let ins_seq_dd = self.d2.as_mut().unwrap().generate_insertion(
+                    e.ins_dd,
+                    previous_nucleotide,
+                    rng,
+                );
+                event.d2_index = Some(e.d_index);
+                event.deld2_5 = e.deld5;
+                event.deld2_3 = e.deld3;
+                event.d2_start_seq =
+                    event.d_start_seq + (end_d1 + e.ins_dd) as i64 - e.deld5 as i64;
+                event.insdd = ins_seq_dd;
+            }
+
             // create the complete sequence:
             let full_seq = event.to_sequence(self);
 
@@ -1722,8 +2308,15 @@ impl Model {
         self.seg_js[event.j_index].name.clone()
     }
 
+    /// Name of the D gene, "D1+D2" when the event has two D genes
     pub fn get_d_gene(&self, event: &InfEvent) -> String {
-        self.seg_ds[event.d_index].name.clone()
+        match event.d2_index {
+            Some(d2_index) => format!(
+                "{}+{}",
+                self.seg_ds[event.d_index].name, self.seg_ds[d2_index].name
+            ),
+            None => self.seg_ds[event.d_index].name.clone(),
+        }
     }
 
     pub fn get_first_nt_bias_ins_vd(&self) -> Result<Vec<f64>> {
@@ -1734,6 +2327,20 @@ impl Model {
         calc_steady_state_dist(&self.markov_chain_dj.transition_matrix)
     }
 
+    /// Order of the Markov chains of the inserted nucleotides
+    pub fn get_markov_order(&self) -> usize {
+        self.markov_chain_vd.order()
+    }
+
+    /// Change the order of the Markov chains of the VD and DJ insertions.
+    /// The new transitions are initialized from the lower orders (the model
+    /// stays the same), the expectation-maximization then learns them.
+    pub fn set_markov_order(&mut self, order: usize) -> Result<()> {
+        self.markov_chain_vd = Arc::new(self.markov_chain_vd.with_order(order)?);
+        self.markov_chain_dj = Arc::new(self.markov_chain_dj.with_order(order)?);
+        self.initialize()
+    }
+
     fn make_d_genes_alignments(
         &self,
         seq: &Sequence,
@@ -1807,6 +2414,132 @@ impl Model {
         self.p_j_given_v = self.p_j_given_v.normalize_distribution()?;
         Ok(())
     }
+
+    /// With `ModelStructure::Graph`, check the graph and set the
+    /// distributions of the standard model to its marginals
+    fn initialize_graph(&mut self) -> Result<()> {
+        if self.model_type != ModelStructure::Graph {
+            if self.graph.is_some() {
+                return Err(anyhow!(
+                    "Arbitrary dependences between the events are only \
+                     supported by the Graph model structure"
+                ));
+            }
+            return Ok(());
+        }
+
+        let graph = self.graph.as_mut().ok_or(anyhow!(
+            "The Graph model structure needs the dependences between \
+             the events (load the model from IGoR files)"
+        ))?;
+        graph.normalize()?;
+
+        let range_size = |r: (i64, i64)| (r.1 - r.0 + 1) as usize;
+        for (event, size) in [
+            (V_CHOICE, self.seg_vs.len()),
+            (D_GENE, self.seg_ds.len()),
+            (J_CHOICE, self.seg_js.len()),
+            (V_3_DEL, range_size(self.range_del_v)),
+            (D_5_DEL, range_size(self.range_del_d5)),
+            (D_3_DEL, range_size(self.range_del_d3)),
+            (J_5_DEL, range_size(self.range_del_j)),
+        ] {
+            if graph.size(event) != size {
+                return Err(anyhow!(
+                    "Wrong dimension for {} in the graph (expected {})",
+                    graph::EVENTS[event],
+                    size
+                ));
+            }
+        }
+
+        self.p_vdj = graph
+            .marginal(&[V_CHOICE, D_GENE, J_CHOICE])
+            .into_dimensionality::<Ix3>()?;
+        self.p_del_v_given_v = graph
+            .marginal(&[V_3_DEL, V_CHOICE])
+            .into_dimensionality::<Ix2>()?;
+        self.p_del_j_given_j = graph
+            .marginal(&[J_5_DEL, J_CHOICE])
+            .into_dimensionality::<Ix2>()?;
+        self.p_del_d5_del_d3 = graph
+            .marginal(&[D_5_DEL, D_3_DEL, D_GENE])
+            .into_dimensionality::<Ix3>()?;
+        self.p_ins_vd = graph.marginal(&[VD_INS]).into_dimensionality::<Ix1>()?;
+        self.p_ins_dj = graph.marginal(&[DJ_INS]).into_dimensionality::<Ix1>()?;
+        Ok(())
+    }
+
+    /// With `ModelStructure::VDDJ`, create the second D gene if needed
+    /// (see `SecondD::new`) and check its parameters
+    fn initialize_second_d(&mut self) -> Result<()> {
+        if self.model_type != ModelStructure::VDDJ {
+            if self.d2.is_some() {
+                return Err(anyhow!(
+                    "A second D gene is only supported by the VDDJ model structure"
+                ));
+            }
+            return Ok(());
+        }
+        self.d2 = Some(match self.d2.take() {
+            Some(mut d2) => {
+                d2.initialize(self)?;
+                d2
+            }
+            None => SecondD::new(self)?,
+        });
+        Ok(())
+    }
+
+    /// Normalize P(ins_vd | V) and P(ins_dj | J) and update the marginals
+    /// `p_ins_vd` and `p_ins_dj`. With `ModelStructure::VDJInsGivenGene`,
+    /// P(ins_dj | J) is created from `p_ins_dj` if missing, P(ins_vd | V) is optional.
+    fn initialize_conditional_insertions(&mut self) -> Result<()> {
+        if self.model_type != ModelStructure::VDJInsGivenGene {
+            if self.p_ins_vd_given_v.is_some() || self.p_ins_dj_given_j.is_some() {
+                return Err(anyhow!(
+                    "Insertion lengths conditioned on the V/J genes are only \
+                     supported by the VDJInsGivenGene model structure"
+                ));
+            }
+            return Ok(());
+        }
+
+        if self.p_ins_dj_given_j.is_none() {
+            self.p_ins_dj_given_j = Some(
+                self.p_ins_dj
+                    .broadcast((self.seg_js.len(), self.p_ins_dj.dim()))
+                    .ok_or(anyhow!("Wrong dimension for p_ins_dj"))?
+                    .t()
+                    .to_owned(),
+            );
+        }
+
+        if let Some(p) = &self.p_ins_vd_given_v {
+            if p.dim().1 != self.seg_vs.len() {
+                return Err(anyhow!(
+                    "Wrong dimension for P(ins_vd | V) (expected {} V genes)",
+                    self.seg_vs.len()
+                ));
+            }
+            let p = p.normalize_distribution()?;
+            self.p_ins_vd = p.dot(&self.p_v);
+            self.p_ins_vd_given_v = Some(p);
+        }
+
+        if let Some(p) = &self.p_ins_dj_given_j {
+            if p.dim().1 != self.seg_js.len() {
+                return Err(anyhow!(
+                    "Wrong dimension for P(ins_dj | J) (expected {} J genes)",
+                    self.seg_js.len()
+                ));
+            }
+            let p = p.normalize_distribution()?;
+            self.p_ins_dj = p.dot(&self.p_dj.sum_axis(Axis(0)));
+            self.p_ins_dj_given_j = Some(p);
+        }
+        Ok(())
+    }
 }
 
 impl ModelGen for Model {
@@ -1817,3 +2550,50 @@ impl ModelGen for Model {
         self.seg_js.clone()
     }
 }
+
+/// Mask selecting the values in `min..=max`, for a distribution whose first
+/// element corresponds to the value `offset`
+fn range_mask((min, max): (i64, i64), offset: i64, len: usize) -> Array1<f64> {
+    Array1::from_iter((0..len).map(|ii| {
+        let value = ii as i64 + offset;
+        if value >= min && value <= max {
+            1.
+        } else {
+            0.
+        }
+    }))
+}
+
+/// Restrict each column of `p` (a conditional distribution) to `mask` and
+/// renormalize it, return the probability mass kept in each column
+/// (columns with no mass left are unchanged)
+fn condition_columns(p: &mut Array2<f64>, mask: &Array1<f64>) -> Array1<f64> {
+    Array1::from_iter(p.columns_mut().into_iter().map(|mut col| {
+        let total = col.sum();
+        let kept = (&col * mask).sum();
+        if kept > 0. {
+            col *= mask;
+            col /= kept;
+        }
+        if total > 0. {
+            kept / total
+        } else {
+            0.
+        }
+    }))
+}
+
+/// Same as `condition_columns` for a single distribution
+fn condition_vector(p: &mut Array1<f64>, mask: &Array1<f64>) -> f64 {
+    let total = p.sum();
+    let kept = (&*p * mask).sum();
+    if kept > 0. {
+        *p *= mask;
+        *p /= kept;
+    }
+    if total > 0. {
+        kept / total
+    } else {
+        0.
+    }
+}
@@ -0,0 +1,436 @@
+//! Exact probability that a sequence generated by the model (without errors)
+//! is productive.
+//!
+//! The CDR3 is read nucleotide by nucleotide by a small automaton that keeps
+//! the frame, the incomplete codon and the conditions on the amino-acids
+//! already read (first one a cysteine, no stop codon, last one conserved).
+//! The V gene and the VD insertions are pushed forward through the automaton
+//! (distribution over its states), the J gene, the DJ insertions and the D
+//! gene are pulled backward (probability to end up productive from each
+//! state), following the direction in which the insertions are generated.
+//! Heavily deleted genes are dealt with by two extra sets of states, one for
+//! the nucleotides preceding the CDR3 (when the V gene doesn't reach it),
+//! one for the nucleotides following it (when the J gene doesn't).
+
+use crate::shared::sequence::{codon_to_amino_acid, nucleotides_inv, Dna, NUCLEOTIDES};
+use crate::shared::DNAMarkovChain;
+use crate::vdj::Model;
+use anyhow::{anyhow, Result};
+use ndarray::{Array1, Array2};
+use std::collections::BTreeSet;
+
+/// Number of incomplete codons (0, 1 or 2 nucleotides)
+const NB_PARTIAL: usize = 21;
+/// First amino-acid not read yet / last one not conserved / last one conserved
+const NB_FLAGS: usize = 3;
+
+/// Automaton reading the CDR3. States: `Skip(r)` (r nucleotides before the
+/// start of the CDR3), `Normal(flag, partial codon)` and `Ended(k)` (k
+/// nucleotides read after the end of the CDR3). Every nucleotide read by an
+/// accepting state also opens an `Ended` branch, only the branch ending at
+/// the right position contributes to the final probability.
+struct Automaton {
+    max_skip: usize,
+    max_end: usize,
+    // for each state and nucleotide, the (at most two) next states
+    next: Vec<[[Option<usize>; 2]; 4]>,
+}
+
+impl Automaton {
+    fn new(conserved_j_residues: &[u8], max_skip: usize, max_end: usize) -> Automaton {
+        let mut automaton = Automaton {
+            max_skip,
+            max_end,
+            next: Vec::new(),
+        };
+        let codons: Vec<u8> = (0..64)
+            .map(|c| {
+                codon_to_amino_acid([
+                    NUCLEOTIDES[c / 16],
+                    NUCLEOTIDES[(c / 4) % 4],
+                    NUCLEOTIDES[c % 4],
+                ])
+            })
+            .collect();
+
+        let mut next = vec![[[None; 2]; 4]; automaton.nb_states()];
+        for r in 1..=max_skip {
+            let to = if r == 1 {
+                automaton.start()
+            } else {
+                automaton.skip(r - 1)
+            };
+            for transitions in &mut next[automaton.skip(r)] {
+                transitions[0] = Some(to);
+            }
+        }
+        for flag in 0..NB_FLAGS {
+            for partial in 0..NB_PARTIAL {
+                let state = automaton.normal(flag, partial);
+                for x in 0..4 {
+                    next[state][x][0] = match partial {
+                        0 => Some(automaton.normal(flag, 1 + x)),
+                        1..=4 => Some(automaton.normal(flag, 5 + 4 * (partial - 1) + x)),
+                        _ => {
+                            let aa = codons[4 * (partial - 5) + x];
+                            if aa == b'*' || (flag == 0 && aa != b'C') {
+                                None
+                            } else if conserved_j_residues.contains(&aa) {
+                                Some(automaton.normal(2, 0))
+                            } else {
+                                Some(automaton.normal(1, 0))
+                            }
+                        }
+                    };
+                    if automaton.is_accepting(state) && max_end > 0 {
+                        next[state][x][1] = Some(automaton.ended(1));
+                    }
+                }
+            }
+        }
+        for k in 1..max_end {
+            for transitions in &mut next[automaton.ended(k)] {
+                transitions[0] = Some(automaton.ended(k + 1));
+            }
+        }
+        automaton.next = next;
+        automaton
+    }
+
+    fn nb_states(&self) -> usize {
+        self.max_skip + NB_FLAGS * NB_PARTIAL + self.max_end
+    }
+
+    fn skip(&self, r: usize) -> usize {
+        r - 1
+    }
+
+    fn normal(&self, flag: usize, partial: usize) -> usize {
+        self.max_skip + flag * NB_PARTIAL + partial
+    }
+
+    fn ended(&self, k: usize) -> usize {
+        self.max_skip + NB_FLAGS * NB_PARTIAL + k - 1
+    }
+
+    fn start(&self) -> usize {
+        self.normal(0, 0)
+    }
+
+    /// Complete CDR3, in frame, starting with a cysteine and ending with a
+    /// conserved residue
+    fn is_accepting(&self, state: usize) -> bool {
+        state == self.normal(2, 0)
+    }
+
+    /// State before the first nucleotide following the V gene, when the CDR3
+    /// starts `skip` nucleotides later
+    fn initial(&self, skip: usize) -> usize {
+        if skip == 0 {
+            self.start()
+        } else {
+            self.skip(skip)
+        }
+    }
+
+    /// Distribution over the states after reading `x`
+    fn push(&self, dist: &Array1<f64>, x: usize) -> Array1<f64> {
+        let mut result = Array1::zeros(dist.len());
+        for (state, &p) in dist.iter().enumerate() {
+            if p != 0. {
+                for to in self.next[state][x].iter().flatten() {
+                    result[*to] += p;
+                }
+            }
+        }
+        result
+    }
+
+    /// Value of each state before reading `x`
+    fn pull(&self, values: &Array1<f64>, x: usize) -> Array1<f64> {
+        Array1::from_iter(
+            self.next
+                .iter()
+                .map(|n| n[x].iter().flatten().map(|to| values[*to]).sum::<f64>()),
+        )
+    }
+
+    fn push_sequence(&self, dist: &Array1<f64>, seq: &[u8]) -> Array1<f64> {
+        seq.iter()
+            .fold(dist.clone(), |d, &n| self.push(&d, nucleotides_inv(n)))
+    }
+
+    fn pull_sequence(&self, values: &Array1<f64>, seq: &[u8]) -> Array1<f64> {
+        seq.iter()
+            .rev()
+            .fold(values.clone(), |v, &n| self.pull(&v, nucleotides_inv(n)))
+    }
+
+    fn unit(&self, state: usize) -> Array1<f64> {
+        let mut v = Array1::zeros(self.nb_states());
+        v[state] = 1.;
+        v
+    }
+}
+
+/// Insertion Markov chain, with the contexts (last known nucleotides, at
+/// most `order`) used during the generation (see `MarkovDNA::generate`)
+struct InsertionChain {
+    order: usize,
+    // normalized transition matrices of order 1, 2, ...
+    matrices: Vec<Array2<f64>>,
+}
+
+impl InsertionChain {
+    fn new(chain: &DNAMarkovChain) -> InsertionChain {
+        let normalize = |m: &Array2<f64>| {
+            let mut m = m.clone();
+            for mut row in m.rows_mut() {
+                let sum = row.sum();
+                // same convention as `DiscreteDistribution`
+                if sum < 1e-10 {
+                    row.fill(0.25);
+                } else {
+                    row /= sum;
+                }
+            }
+            m
+        };
+        InsertionChain {
+            order: chain.order(),
+            matrices: std::iter::once(&chain.transition_matrix)
+                .chain(chain.higher_order.iter())
+                .map(normalize)
+                .collect(),
+        }
+    }
+
+    fn nb_contexts(&self) -> usize {
+        (1..=self.order).map(|k| 4usize.pow(k as u32)).sum()
+    }
+
+    /// Index of the context made of the `len` last nucleotides (`code` in base 4)
+    fn context(&self, len: usize, code: usize) -> usize {
+        (1..len).map(|k| 4usize.pow(k as u32)).sum::<usize>() + code
+    }
+
+    fn contexts(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (1..=self.order).flat_map(|len| (0..4usize.pow(len as u32)).map(move |code| (len, code)))
+    }
+
+    /// Propagate `init` (one vector per first nucleotide) through the
+    /// insertions, weighted by the distribution of their length. `step`
+    /// moves a vector through one inserted nucleotide, in the order of
+    /// generation.
+    fn propagate(
+        &self,
+        init: &[Array1<f64>],
+        p_length: &[f64],
+        step: impl Fn(&Array1<f64>, usize) -> Array1<f64>,
+    ) -> Array1<f64> {
+        let dim = init[0].len();
+        let mut current = vec![Array1::zeros(dim); self.nb_contexts()];
+        for (nt, vec) in init.iter().enumerate() {
+            current[self.context(1, nt)] = vec.clone();
+        }
+        let mut result = Array1::zeros(dim);
+        let last = p_length.iter().rposition(|&p| p > 0.).unwrap_or(0);
+        for (length, &p) in p_length.iter().enumerate().take(last + 1) {
+            if length > 0 {
+                let mut next = vec![Array1::zeros(dim); self.nb_contexts()];
+                for (len, code) in self.contexts() {
+                    let vec = &current[self.context(len, code)];
+                    if vec.iter().all(|&v| v == 0.) {
+                        continue;
+                    }
+                    let new_len = (len + 1).min(self.order);
+                    for x in 0..4 {
+                        let proba = self.matrices[len - 1][[code, x]];
+                        if proba > 0. {
+                            let new_code = (4 * code + x) % 4usize.pow(new_len as u32);
+                            next[self.context(new_len, new_code)].scaled_add(proba, &step(vec, x));
+                        }
+                    }
+                }
+                current = next;
+            }
+            if p > 0. {
+                for vec in &current {
+                    result.scaled_add(p, vec);
+                }
+            }
+        }
+        result
+    }
+}
+
+fn gene_sequence(gene: &crate::shared::Gene) -> Result<&Dna> {
+    let seq = gene
+        .seq_with_pal
+        .as_ref()
+        .ok_or(anyhow!("Palindromic sequences not created"))?;
+    if seq.seq.iter().any(|&n| nucleotides_inv(n) > 3) {
+        return Err(anyhow!(
+            "The gene {} contains ambiguous nucleotides, the productive fraction \
+             can't be computed exactly",
+            gene.name
+        ));
+    }
+    Ok(seq)
+}
+
+impl Model {
+    /// Probability that a sequence generated by the model (without errors)
+    /// is productive: functional V and J genes, CDR3 in frame, without stop
+    /// codon, starting with a cysteine and ending with one of the
+    /// `conserved_j_residues` (default "FVW"). Computed exactly from the
+    /// marginals and cached until the parameters change.
+    pub fn get_norm_productive_exact(&self, conserved_j_residues: Option<&str>) -> Result<f64> {
+        if self.graph.is_some() || self.d2.is_some() {
+            return Err(anyhow!(
+                "The exact productive fraction is not available for this model \
+                 structure, use the Monte Carlo estimate `get_norm_productive`"
+            ));
+        }
+        let residues: BTreeSet<u8> = conserved_j_residues.unwrap_or("FVW").bytes().collect();
+        let key: String = residues.iter().map(|&x| x as char).collect();
+        let cache = || {
+            self.gen
+                .norm_productive
+                .lock()
+                .map_err(|_| anyhow!("The cache of the productive fraction is poisoned"))
+        };
+        if let Some(norm) = cache()?.get(&key) {
+            return Ok(*norm);
+        }
+        let norm = self.compute_norm_productive(key.as_bytes())?;
+        cache()?.insert(key, norm);
+        Ok(norm)
+    }
+
+    /// Probability of generating the sequence among the productive ones,
+    /// `pgen / get_norm_productive_exact(conserved_j_residues)`
+    pub fn get_pgen_productive(
+        &self,
+        pgen: f64,
+        conserved_j_residues: Option<&str>,
+    ) -> Result<f64> {
+        Ok(pgen / self.get_norm_productive_exact(conserved_j_residues)?)
+    }
+
+    /// Drop the cached productive fractions, to call when the parameters change
+    pub(crate) fn clear_norm_productive(&mut self) {
+        self.gen.norm_productive = Default::default();
+    }
+
+    fn compute_norm_productive(&self, conserved_j_residues: &[u8]) -> Result<f64> {
+        let functional_vs: Vec<usize> = (0..self.seg_vs.len())
+            .filter(|&v| self.seg_vs[v].is_functional() && self.seg_vs[v].cdr3_pos.is_some())
+            .collect();
+        let functional_js: Vec<usize> = (0..self.seg_js.len())
+            .filter(|&j| self.seg_js[j].is_functional() && self.seg_js[j].cdr3_pos.is_some())
+            .collect();
+
+        // V: length kept after the deletions, and the part of the CDR3 it covers
+        let mut max_skip = 0;
+        for &v in &functional_vs {
+            let len = gene_sequence(&self.seg_vs[v])?.len();
+            for delv in 0..self.p_del_v_given_v.dim().0.min(len) {
+                let cdr3_pos = self.seg_vs[v].cdr3_pos.unwrap();
+                max_skip = max_skip.max(cdr3_pos.saturating_sub(len - delv));
+            }
+        }
+        // J: nucleotides of the gene after the end of the CDR3
+        let tail_j = |j: usize| -> i64 {
+            self.seg_js[j].seq.len() as i64 - self.seg_js[j].cdr3_pos.unwrap() as i64 - 3
+        };
+        let mut max_end = 0;
+        for &j in &functional_js {
+            let len = gene_sequence(&self.seg_js[j])?.len();
+            for delj in 0..self.p_del_j_given_j.dim().0.min(len) {
+                max_end = max_end.max((tail_j(j) - (len - delj) as i64).max(0) as usize);
+            }
+        }
+        let automaton = Automaton::new(conserved_j_residues, max_skip, max_end);
+        let dim = automaton.nb_states();
+
+        // V and VD insertions, pushed forward
+        let chain_vd = InsertionChain::new(&self.markov_chain_vd);
+        let mut after_vd = vec![Array1::zeros(dim); self.seg_vs.len()];
+        for &v in &functional_vs {
+            let seq = &gene_sequence(&self.seg_vs[v])?.seq;
+            let cdr3_pos = self.seg_vs[v].cdr3_pos.unwrap();
+            let mut init = vec![Array1::zeros(dim); 4];
+            for delv in 0..self.p_del_v_given_v.dim().0.min(seq.len()) {
+                let p = self.p_del_v_given_v[[delv, v]];
+                if p == 0. {
+                    continue;
+                }
+                let end = seq.len() - delv;
+                let dist = automaton.unit(automaton.initial(cdr3_pos.saturating_sub(end)));
+                let dist = automaton.push_sequence(&dist, &seq[cdr3_pos.min(end)..end]);
+                init[nucleotides_inv(seq[end - 1])].scaled_add(p, &dist);
+            }
+            let p_ins = match &self.p_ins_vd_given_v {
+                Some(p) => p.column(v).to_vec(),
+                None => self.p_ins_vd.to_vec(),
+            };
+            after_vd[v] = chain_vd.propagate(&init, &p_ins, |d, x| automaton.push(d, x));
+        }
+
+        // J and DJ insertions, pulled backward
+        let chain_dj = InsertionChain::new(&self.markov_chain_dj);
+        let accepting = automaton.unit(automaton.normal(2, 0));
+        let mut before_dj = vec![Array1::zeros(dim); self.seg_js.len()];
+        for &j in &functional_js {
+            let seq = &gene_sequence(&self.seg_js[j])?.seq;
+            let mut init = vec![Array1::zeros(dim); 4];
+            for delj in 0..self.p_del_j_given_j.dim().0.min(seq.len()) {
+                let p = self.p_del_j_given_j[[delj, j]];
+                if p == 0. {
+                    continue;
+                }
+                let in_cdr3 = (seq.len() - delj) as i64 - tail_j(j);
+                let values = if in_cdr3 >= 0 {
+                    automaton.pull_sequence(&accepting, &seq[delj..delj + in_cdr3 as usize])
+                } else {
+                    automaton.unit(automaton.ended((-in_cdr3) as usize))
+                };
+                init[nucleotides_inv(seq[delj])].scaled_add(p, &values);
+            }
+            let p_ins = match &self.p_ins_dj_given_j {
+                Some(p) => p.column(j).to_vec(),
+                None => self.p_ins_dj.to_vec(),
+            };
+            before_dj[j] = chain_dj.propagate(&init, &p_ins, |f, x| automaton.pull(f, x));
+        }
+
+        // D gene, between the two
+        let mut norm = 0.;
+        for (d, gene) in self.seg_ds.iter().enumerate() {
+            let seq = &gene_sequence(gene)?.seq;
+            for &j in &functional_js {
+                let mut before_d = Array1::zeros(dim);
+                for ((deld5, deld3), &p) in self
+                    .p_del_d5_del_d3
+                    .index_axis(ndarray::Axis(2), d)
+                    .indexed_iter()
+                {
+                    if p == 0. {
+                        continue;
+                    }
+                    let end = seq.len().saturating_sub(deld3).max(deld5.min(seq.len()));
+                    let segment = &seq[deld5.min(seq.len())..end];
+                    before_d.scaled_add(p, &automaton.pull_sequence(&before_dj[j], segment));
+                }
+                for &v in &functional_vs {
+                    let p_vdj = self.p_vdj[[v, d, j]];
+                    if p_vdj > 0. {
+                        norm += p_vdj * after_vd[v].dot(&before_d);
+                    }
+                }
+            }
+        }
+        Ok(norm)
+    }
+}
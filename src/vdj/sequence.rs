@@ -1,3 +1,4 @@
+use crate::shared::alignment::cigar_from_operations;
 use crate::shared::sequence::SequenceType;
 use crate::shared::DnaLike;
 use crate::shared::{utils::difference_as_i64, AlignmentParameters, DAlignment, Dna, VJAlignment};
@@ -16,6 +17,8 @@ pub struct Sequence {
     pub d_genes: Vec<DAlignment>,
     pub valid_alignment: bool,
     pub sequence_type: SequenceType,
+    // `alignment_key` of the model used for the alignment
+    pub alignment_key: u64,
 }
 
 impl Sequence {
@@ -133,6 +136,11 @@ pub fn align_all_vgenes(
             sequence_type: seq.sequence_type(),
             ..Default::default()
         };
+        v_alignment.set_cigar(cigar_from_operations(&alignment.operations, true));
+        if seq.is_protein() && !v_alignment.cigar.is_empty() {
+            // gapped alignments are not supported for amino-acid sequences
+            continue;
+        }
 
         v_alignment.precompute_errors_v(seq);
 
@@ -164,6 +172,10 @@ pub fn align_all_jgenes(
                 sequence_type: seq.sequence_type(),
                 ..Default::default()
             };
+            j_al.set_cigar(cigar_from_operations(&alignment.operations, false));
+            if seq.is_protein() && !j_al.cigar.is_empty() {
+                continue;
+            }
             j_al.precompute_errors_j(seq);
             j_aligns.push(j_al);
         }
@@ -14,6 +14,8 @@ pub struct StaticEvent {
     pub delj: usize,
     pub insvj: Dna,
     pub errors: Vec<(usize, u8)>,
+    // (position in the error-free sequence, nb of deleted nucleotides, inserted nucleotides)
+    pub indels: Vec<(usize, usize, Dna)>,
 }
 
 #[cfg(all(feature = "py_binds", feature = "pyo3"))]
@@ -56,17 +58,42 @@ impl StaticEvent {
             seq.seq[*ii] = *nuc;
         }
 
+        // add indels, starting from the end so that positions stay valid
+        for (ii, nb_del, ins) in self.indels.iter().rev() {
+            seq.seq.splice(*ii..*ii + nb_del, ins.seq.iter().cloned());
+        }
+
         seq
     }
 
+    /// Net length added by the indels, before and after the position `pos`
+    /// of the error-free sequence
+    fn indels_shift(&self, pos: usize) -> (i64, i64) {
+        let mut shift = (0, 0);
+        for (ii, nb_del, ins) in &self.indels {
+            let diff = ins.len() as i64 - *nb_del as i64;
+            if *ii < pos {
+                shift.0 += diff;
+            } else {
+                shift.1 += diff;
+            }
+        }
+        shift
+    }
+
     pub fn extract_cdr3(&self, full_sequence: &Dna, m: &Model) -> Dna {
         let vg = &m.seg_vs[self.v_index];
         let jg = &m.seg_js[self.j_index];
-        let mut end_cdr3 = full_sequence.len() - jg.seq.len() + jg.cdr3_pos.unwrap() + 3;
-        if vg.cdr3_pos.unwrap() > end_cdr3 {
-            end_cdr3 = vg.cdr3_pos.unwrap(); // if we cut too much we return the empty sequence
+        // indels are only generated outside of the CDR3
+        let (shift_v, shift_j) = self.indels_shift(vg.cdr3_pos.unwrap());
+        let start_cdr3 = (vg.cdr3_pos.unwrap() as i64 + shift_v) as usize;
+        let mut end_cdr3 = (full_sequence.len() as i64 - shift_j) as usize - jg.seq.len()
+            + jg.cdr3_pos.unwrap()
+            + 3;
+        if start_cdr3 > end_cdr3 {
+            end_cdr3 = start_cdr3; // if we cut too much we return the empty sequence
         }
-        full_sequence.extract_subsequence(vg.cdr3_pos.unwrap(), end_cdr3)
+        full_sequence.extract_subsequence(start_cdr3, end_cdr3)
     }
 
     pub fn to_cdr3(&self, m: &Model) -> Dna {
@@ -0,0 +1,142 @@
+use crate::shared::{
+    DnaLike, InferenceParameters, InsertionFeature, Likelihood, LikelihoodInsContainer,
+};
+use crate::vdj::Sequence;
+
+/// Contains the likelihood of the VJ insertions, for every end of the V gene
+/// and start of the J gene (and last nucleotide of the V gene)
+#[derive(Debug)]
+pub struct FeatureVJ {
+    // (end_v, start_j, previous_nuc)
+    likelihood: LikelihoodInsContainer,
+    dirty_likelihood: LikelihoodInsContainer,
+}
+
+impl FeatureVJ {
+    pub fn new(
+        sequence: &Sequence,
+        feat_insvj: &InsertionFeature,
+        delv_max: usize,
+        delj_max: usize,
+        ip: &InferenceParameters,
+    ) -> Option<FeatureVJ> {
+        if sequence.v_genes.is_empty() || sequence.j_genes.is_empty() {
+            return None;
+        }
+        let min_end_v =
+            sequence.v_genes.iter().map(|x| x.end_seq).min().unwrap() as i64 - delv_max as i64 + 1;
+        let max_end_v = sequence.v_genes.iter().map(|x| x.end_seq).max().unwrap() as i64;
+        let min_start_j = sequence
+            .j_genes
+            .iter()
+            .map(|x| x.start_seq as i64 - x.start_gene as i64)
+            .min()
+            .unwrap();
+        let max_start_j = sequence
+            .j_genes
+            .iter()
+            .map(|x| x.start_seq as i64 - x.start_gene as i64)
+            .max()
+            .unwrap()
+            + delj_max as i64
+            - 1;
+
+        let mut likelihoods = LikelihoodInsContainer::zeros(
+            (min_end_v, min_start_j),
+            (max_end_v + 1, max_start_j + 1),
+            sequence.sequence_type,
+        );
+
+        for ev in min_end_v..=max_end_v {
+            for sj in min_start_j..=max_start_j {
+                if sj >= 0
+                    && sj < sequence.sequence.len() as i64
+                    && sj >= ev
+                    && ((sj - ev) as usize) < feat_insvj.max_nb_insertions()
+                {
+                    let ins_vj = sequence.get_subsequence(ev, sj);
+
+                    for first_nucleotide in 0..4 {
+                        let likelihood = feat_insvj.likelihood(&ins_vj, first_nucleotide);
+                        if likelihood.max() > ip.min_likelihood {
+                            likelihoods.add_to((ev, sj), first_nucleotide, likelihood);
+                        }
+                    }
+                }
+            }
+        }
+
+        Some(FeatureVJ {
+            dirty_likelihood: LikelihoodInsContainer::zeros(
+                likelihoods.dim().0,
+                likelihoods.dim().1,
+                sequence.sequence_type,
+            ),
+            likelihood: likelihoods,
+        })
+    }
+
+    pub fn max_ev(&self) -> i64 {
+        self.likelihood.max().0
+    }
+
+    pub fn min_ev(&self) -> i64 {
+        self.likelihood.min().0
+    }
+
+    pub fn max_sj(&self) -> i64 {
+        self.likelihood.max().1
+    }
+
+    pub fn min_sj(&self) -> i64 {
+        self.likelihood.min().1
+    }
+
+    pub fn likelihood(&self, ev: i64, sj: i64, previous_nuc: usize) -> Likelihood {
+        self.likelihood.get((ev, sj), previous_nuc)
+    }
+
+    pub fn dirty_update(&mut self, ev: i64, sj: i64, previous_nuc: usize, likelihood: f64) {
+        self.dirty_likelihood
+            .add_to((ev, sj), previous_nuc, Likelihood::Scalar(likelihood));
+    }
+
+    pub fn disaggregate(
+        &self,
+        sequence: &DnaLike,
+        feat_insvj: &mut InsertionFeature,
+        ip: &InferenceParameters,
+    ) {
+        // the VJ insertions are inferred with the VD ones (same parameters)
+        if !ip.infer_features.ins_vd {
+            return;
+        }
+
+        // disaggregate only works with scalar
+        for ev in self.likelihood.min().0..self.likelihood.max().0 {
+            for sj in self.likelihood.min().1..self.likelihood.max().1 {
+                if sj >= 0
+                    && sj < sequence.len() as i64
+                    && sj >= ev
+                    && ((sj - ev) as usize) < feat_insvj.max_nb_insertions()
+                {
+                    let ins_vj = &sequence.extract_padded_subsequence(ev, sj);
+                    for previous_nucleotide in 0..4 {
+                        let ll = self
+                            .likelihood(ev, sj, previous_nucleotide)
+                            .to_scalar()
+                            .unwrap();
+                        let updated_ll = self
+                            .dirty_likelihood
+                            .get((ev, sj), previous_nucleotide)
+                            .to_scalar()
+                            .unwrap();
+                        if ll > ip.min_likelihood && updated_ll > 0. {
+                            feat_insvj.dirty_update(ins_vj, previous_nucleotide, updated_ll);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
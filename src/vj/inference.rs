@@ -0,0 +1,388 @@
+use crate::shared::feature::{Feature, PosteriorMarginals};
+use crate::shared::Modelable;
+use crate::shared::{errors::FeatureError, InferenceParameters, ResultInference};
+use crate::shared::{
+    CategoricalFeature1g1, CategoricalFeature2, ErrorParameters, InfEvent, InsertionFeature,
+};
+use crate::vdj::inference::{insert_top, threshold};
+use crate::vdj::{AggregatedFeatureEndV, AggregatedFeatureStartJ, Sequence};
+use crate::vj::feature::FeatureVJ;
+use crate::vj::Model;
+use anyhow::{anyhow, Result};
+use ndarray::Axis;
+use std::cmp;
+use std::sync::Arc;
+
+/// Features of the VJ model. The sequences are aligned with the inner VDJ
+/// model, but the D gene alignments are ignored (no D, no VD/DJ split).
+#[derive(Default, Clone, Debug)]
+pub struct Features {
+    pub vj: CategoricalFeature2, // v, j
+    pub delv: CategoricalFeature1g1,
+    pub delj: CategoricalFeature1g1,
+    pub insvj: InsertionFeature,
+    pub error: FeatureError,
+    pub log_likelihood: Option<f64>, // after each inference contains the log likelihood
+}
+
+impl Features {
+    /// Update the model from a vector of features and return an updated vector of features
+    pub fn update(
+        features: Vec<Features>,
+        weights: &[f64],
+        model: &mut Model,
+        ip: &InferenceParameters,
+    ) -> Result<(Vec<Features>, f64)> {
+        let errors = &mut ErrorParameters::update_error(
+            features.iter().map(|a| a.error.clone()).collect(),
+            weights,
+            &mut model.error,
+        )?;
+
+        // the VJ insertions use the VD parameters
+        let insvj = InsertionFeature::average(
+            features
+                .iter()
+                .zip(errors.iter())
+                .map(|(f, e)| f.insvj.correct_for_error(e).clone()),
+            weights,
+            ip.pseudocounts.ins_vd,
+            ip.pseudocounts.markov_vd,
+        )?;
+        let delv = CategoricalFeature1g1::average(
+            features.iter().map(|a| a.delv.clone()),
+            weights,
+            ip.pseudocounts.del_v,
+        )?;
+        let delj = CategoricalFeature1g1::average(
+            features.iter().map(|a| a.delj.clone()),
+            weights,
+            ip.pseudocounts.del_j,
+        )?;
+        let vj = CategoricalFeature2::average(
+            features.iter().map(|a| a.vj.clone()),
+            weights,
+            ip.pseudocounts.genes,
+        )?;
+
+        if ip.infer_features.del_v {
+            model.p_del_v_given_v = delv.clone().probas;
+        }
+        if ip.infer_features.del_j {
+            model.p_del_j_given_j = delj.clone().probas;
+        }
+        let (p_vj, mc_vj) = insvj.get_parameters();
+        if ip.infer_features.ins_vd {
+            model.p_ins_vj = p_vj;
+            model.markov_coefficients_vj = mc_vj.transition_matrix.clone();
+            // keep the higher orders of the Markov chain
+            model.inner.markov_chain_vd = mc_vj;
+        }
+        // also re-initialize the model
+        if ip.infer_features.genes {
+            model.set_p_vj(&vj.clone().probas)?;
+        } else {
+            model.initialize()?;
+        }
+
+        let sum_log_likelihood = features
+            .iter()
+            .zip(weights.iter())
+            .map(|(x, w)| w * x.log_likelihood.unwrap())
+            .sum();
+
+        // Now update the features vector
+        let mut new_features = Vec::new();
+        for error in errors {
+            new_features.push(Features {
+                vj: vj.clone(),
+                delv: delv.clone(),
+                delj: delj.clone(),
+                insvj: insvj.correct_for_error(error).clone(),
+                error: error.clone(),
+                log_likelihood: None,
+            });
+        }
+
+        Ok((new_features, sum_log_likelihood))
+    }
+
+    pub fn new(model: &Model) -> Result<Features> {
+        Ok(Features {
+            vj: CategoricalFeature2::new(&model.get_p_vj())?,
+            delv: CategoricalFeature1g1::new(&model.p_del_v_given_v)?,
+            delj: CategoricalFeature1g1::new(&model.p_del_j_given_j)?,
+            insvj: InsertionFeature::new(
+                &model.p_ins_vj,
+                Arc::clone(&model.inner.markov_chain_vd),
+            )?,
+            error: model.error.get_feature()?,
+            log_likelihood: None,
+        })
+    }
+
+    /// Core function, iterate over all realistic scenarios to compute the
+    /// likelihood of the sequence and update the parameters
+    pub fn infer(
+        &mut self,
+        sequence: &Sequence,
+        ip: &InferenceParameters,
+    ) -> Result<ResultInference> {
+        // small positive likelihood, in case the inference stops early
+        self.log_likelihood = Some((ip.min_likelihood).log2());
+
+        // Estimate the likelihood of all possible insertions
+        let Some(mut agg_ins_vj) = FeatureVJ::new(
+            sequence,
+            &self.insvj,
+            self.delv.dim().0,
+            self.delj.dim().0,
+            ip,
+        ) else {
+            return Ok(ResultInference::impossible());
+        };
+
+        // Define the aggregated features for this sequence:
+        let mut features_v = Vec::new();
+        for val in &sequence.v_genes {
+            features_v.push(AggregatedFeatureEndV::new(val, &self.delv, &self.error, ip));
+        }
+
+        let mut features_j = Vec::new();
+        for jal in &sequence.j_genes {
+            features_j.push(AggregatedFeatureStartJ::new(
+                jal,
+                &self.delj,
+                &self.error,
+                ip,
+            ));
+        }
+
+        let mut result = ResultInference::impossible();
+
+        // Main loop
+        for v in features_v.iter_mut().filter_map(|x| x.as_mut()) {
+            for j in features_j.iter_mut().filter_map(|x| x.as_mut()) {
+                self.infer_given_vj(v, j, &mut agg_ins_vj, ip, &mut result)?;
+            }
+        }
+
+        // disaggregate the v/j features
+        for (val, v) in sequence.v_genes.iter().zip(features_v.iter_mut()) {
+            match v {
+                Some(f) => f.disaggregate(val, &mut self.delv, &mut self.error, ip),
+                None => continue,
+            }
+        }
+        for (jal, j) in sequence.j_genes.iter().zip(features_j.iter_mut()) {
+            match j {
+                Some(f) => f.disaggregate(jal, &mut self.delj, &mut self.error, ip),
+                None => continue,
+            }
+        }
+
+        // disaggregate the insertion features
+        agg_ins_vj.disaggregate(&sequence.sequence, &mut self.insvj, ip);
+
+        if result.likelihood > 0. {
+            self.cleanup(result.likelihood)?;
+            result.error_rate = self.error.posterior_error_rate();
+        }
+        // add a small positive likelihood to deal with the case where result.likelihood is 0.
+        self.log_likelihood = Some((result.likelihood + ip.min_likelihood).log2());
+
+        Ok(result)
+    }
+
+    pub fn infer_given_vj(
+        &mut self,
+        feature_v: &mut AggregatedFeatureEndV,
+        feature_j: &mut AggregatedFeatureStartJ,
+        ins_vj: &mut FeatureVJ,
+        ip: &InferenceParameters,
+        current_result: &mut ResultInference,
+    ) -> Result<()> {
+        let likelihood_vj = self.vj.likelihood((feature_v.index, feature_j.index));
+
+        let mut cutoff = ip
+            .min_likelihood
+            .max(ip.min_ratio_likelihood * current_result.best_likelihood);
+
+        let (min_ev, max_ev) = (
+            cmp::max(feature_v.start_v3, ins_vj.min_ev()),
+            cmp::min(feature_v.end_v3, ins_vj.max_ev()),
+        );
+        let (min_sj, max_sj) = (
+            cmp::max(feature_j.start_j5, ins_vj.min_sj()),
+            cmp::min(feature_j.end_j5, ins_vj.max_sj()),
+        );
+
+        for ev in min_ev..max_ev {
+            let likelihood_v = feature_v.likelihood(ev);
+            if (likelihood_v.clone() * likelihood_vj).max() < cutoff {
+                continue;
+            }
+            let previous_nuc = feature_v
+                .alignment
+                .get_last_nucleotide((feature_v.end_v3 - ev - 1) as usize);
+            for sj in cmp::max(ev, min_sj)..max_sj {
+                let likelihood_ins_vj = ins_vj.likelihood(ev, sj, previous_nuc);
+                let likelihood_j = feature_j.likelihood(sj);
+                let likelihood =
+                    (likelihood_v.clone() * likelihood_ins_vj * likelihood_j * likelihood_vj)
+                        .to_scalar()?;
+
+                if likelihood > cutoff {
+                    current_result.likelihood += likelihood;
+                    if likelihood > current_result.best_likelihood {
+                        current_result.best_likelihood = likelihood;
+                        cutoff = (ip.min_likelihood)
+                            .max(ip.min_ratio_likelihood * current_result.best_likelihood);
+                        if ip.store_best_event {
+                            // the (empty) D gene of the inner model sits at the start of J
+                            let event = InfEvent {
+                                v_index: feature_v.index,
+                                v_start_gene: feature_v.start_gene,
+                                j_index: feature_j.index,
+                                j_start_seq: feature_j.start_seq,
+                                d_index: 0,
+                                end_v: ev,
+                                start_d: sj,
+                                end_d: sj,
+                                start_j: sj,
+                                pos_d: sj,
+                                likelihood,
+                                ..Default::default()
+                            };
+                            current_result.set_best_event(event, ip);
+                        }
+                    }
+                    if ip.infer_features.del_v {
+                        feature_v.dirty_update(ev, likelihood);
+                    }
+                    if ip.infer_features.del_j {
+                        feature_j.dirty_update(sj, likelihood);
+                    }
+                    if ip.infer_features.ins_vd {
+                        ins_vj.dirty_update(ev, sj, previous_nuc, likelihood);
+                    }
+                    if ip.infer_features.genes {
+                        self.vj
+                            .dirty_update((feature_v.index, feature_j.index), likelihood);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return the `k` most likely scenarios (genes, end of V and start of J),
+    /// sorted by decreasing likelihood
+    pub fn top_events(
+        &self,
+        sequence: &Sequence,
+        k: usize,
+        ip: &InferenceParameters,
+    ) -> Result<Vec<InfEvent>> {
+        if sequence.sequence.is_protein() {
+            return Err(anyhow!(
+                "The most likely scenarios are not available for amino-acid sequences"
+            ));
+        }
+        let mut top = Vec::new();
+        let Some(ins_vj) = FeatureVJ::new(
+            sequence,
+            &self.insvj,
+            self.delv.dim().0,
+            self.delj.dim().0,
+            ip,
+        ) else {
+            return Ok(top);
+        };
+        let features_v: Vec<_> = sequence
+            .v_genes
+            .iter()
+            .filter_map(|val| AggregatedFeatureEndV::new(val, &self.delv, &self.error, ip))
+            .collect();
+        let features_j: Vec<_> = sequence
+            .j_genes
+            .iter()
+            .filter_map(|jal| AggregatedFeatureStartJ::new(jal, &self.delj, &self.error, ip))
+            .collect();
+
+        for feature_v in &features_v {
+            for feature_j in &features_j {
+                let likelihood_vj = self.vj.likelihood((feature_v.index, feature_j.index));
+                for ev in cmp::max(feature_v.start_v3, ins_vj.min_ev())
+                    ..cmp::min(feature_v.end_v3, ins_vj.max_ev())
+                {
+                    let likelihood_v = feature_v.likelihood(ev).to_scalar()? * likelihood_vj;
+                    if likelihood_v <= threshold(&top, k) {
+                        continue;
+                    }
+                    let previous_nuc = feature_v
+                        .alignment
+                        .get_last_nucleotide((feature_v.end_v3 - ev - 1) as usize);
+                    for sj in cmp::max(cmp::max(ev, feature_j.start_j5), ins_vj.min_sj())
+                        ..cmp::min(feature_j.end_j5, ins_vj.max_sj())
+                    {
+                        let likelihood = likelihood_v
+                            * ins_vj.likelihood(ev, sj, previous_nuc).to_scalar()?
+                            * feature_j.likelihood(sj).to_scalar()?;
+                        if likelihood <= threshold(&top, k) {
+                            continue;
+                        }
+                        let event = InfEvent {
+                            v_index: feature_v.index,
+                            v_start_gene: feature_v.start_gene,
+                            j_index: feature_j.index,
+                            j_start_seq: feature_j.start_seq,
+                            d_index: 0,
+                            end_v: ev,
+                            start_d: sj,
+                            end_d: sj,
+                            start_j: sj,
+                            pos_d: sj,
+                            likelihood,
+                            ..Default::default()
+                        };
+                        insert_top(&mut top, event, k);
+                    }
+                }
+            }
+        }
+        Ok(top)
+    }
+
+    pub fn cleanup(&mut self, likelihood: f64) -> Result<()> {
+        // Compute the new marginals for the next round
+        self.vj.scale_dirty(1. / likelihood);
+        self.delv.scale_dirty(1. / likelihood);
+        self.delj.scale_dirty(1. / likelihood);
+        self.insvj.scale_dirty(1. / likelihood);
+        self.error.scale_dirty(1. / likelihood);
+        Ok(())
+    }
+
+    /// Posterior marginals of the sequence, from the expected counts
+    /// (once `infer` has been called), the VJ insertions are in `ins_vd`
+    pub fn posterior_marginals(&self) -> PosteriorMarginals {
+        PosteriorMarginals {
+            v: self.vj.probas_dirty.sum_axis(Axis(1)),
+            j: self.vj.probas_dirty.sum_axis(Axis(0)),
+            del_v: self.delv.probas_dirty.sum_axis(Axis(1)),
+            del_j: self.delj.probas_dirty.sum_axis(Axis(1)),
+            ins_vd: self.insvj.length_distribution_dirty.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn normalize(&mut self) -> Result<()> {
+        self.vj = self.vj.normalize()?;
+        self.delv = self.delv.normalize()?;
+        self.delj = self.delj.normalize()?;
+        self.insvj = self.insvj.normalize()?;
+        Ok(())
+    }
+}
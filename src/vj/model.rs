@@ -1,14 +1,15 @@
 use crate::shared::parser::{
     parse_file, parse_str, EventType, Marginal, ParserMarginals, ParserParams,
 };
-use crate::shared::utils::{sorted_and_complete, sorted_and_complete_0start};
+use crate::shared::utils::{sequence_weights, sorted_and_complete, sorted_and_complete_0start};
 use crate::shared::utils::{Normalize, Normalize2};
 use crate::shared::{
-    model::GenerationResult, AlignmentParameters, Dna, Gene, InfEvent, InferenceParameters,
-    ModelGen, RecordModel, ResultInference,
+    model::GenerationResult, AlignmentParameters, Dna, Gene, GenerationConstraints, InfEvent,
+    InferenceParameters, ModelGen, RecordModel, ResultInference,
 };
 use crate::shared::{DNAMarkovChain, ErrorParameters, Features, Modelable};
 use crate::vdj::{model::EntrySequence, Model as ModelVDJ, Sequence};
+use crate::vj;
 use anyhow::{anyhow, Result};
 use ndarray::s;
 use ndarray::{array, Array1, Array2, Array3, Axis};
@@ -68,6 +69,8 @@ impl Generator {
 
 // A VJ model is in practice a simplified VDJ model (without insDJ / D / delD3 / delD5)
 // So I use a VDJ model as the inner model, with a different parameter set.
+// The inner model is used for generation and alignment, while evaluation and
+// inference run the dedicated VJ dynamic programming (see `vj::inference`).
 #[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct Model {
     // The actual/real underlying model
@@ -288,21 +291,62 @@ impl Modelable for Model {
         inference_params: &InferenceParameters,
     ) -> Result<ResultInference> {
         self.inner
-            .evaluate(sequence, alignment_params, inference_params)
+            .evaluate_with(sequence, alignment_params, inference_params, || {
+                Ok(Features::VJ(vj::Features::new(self)?))
+            })
     }
 
     fn infer(
         &mut self,
         sequences: &[EntrySequence],
+        weights: Option<&[f64]>,
         features: Option<Vec<Features>>,
         alignment_params: &AlignmentParameters,
         inference_params: &InferenceParameters,
     ) -> Result<(Vec<Features>, f64)> {
-        let feats = self
+        if !sequences
+            .iter()
+            .all(EntrySequence::compatible_with_inference)
+        {
+            return Err(anyhow!(
+                "Cannot do inference when sequences have ambiguity. \
+				Ambiguous nucleotides (N) or protein sequence \
+				are out."
+            ));
+        }
+
+        inference_params.pseudocounts.check()?;
+
+        let weights = sequence_weights(weights, sequences.len())?;
+
+        let mut ip = inference_params.clone();
+
+        // no need to compute pgen or store best event if we're infering
+        ip.compute_pgen = false;
+        ip.store_best_event = false;
+
+        let features = match features {
+            None => vec![Features::VJ(vj::Features::new(self)?); sequences.len()],
+            Some(feats) => feats,
+        };
+
+        // the alignment is done by the inner model, the dynamic
+        // programming by the VJ features
+        let new_features = self
             .inner
-            .infer(sequences, features, alignment_params, inference_params)?;
-        self.update_outer_model()?;
-        Ok(feats)
+            .expectation(&features, sequences, alignment_params, &ip)?
+            .into_iter()
+            .map(|x| match x {
+                Features::VJ(f) => Ok(f),
+                _ => Err(anyhow!("Wrong type of features for a VJ model")),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let (feats, log_likelihood) = vj::Features::update(new_features, &weights, self, &ip)?;
+        Ok((
+            feats.into_iter().map(Features::VJ).collect(),
+            log_likelihood,
+        ))
     }
 
     // fn align_and_infer(
@@ -355,6 +399,32 @@ impl Modelable for Model {
 }
 
 impl Model {
+    /// Return the model conditioned on the insertion and deletion constraints,
+    /// together with the probability of these constraints under the original model.
+    pub fn constrain(&self, constraints: &GenerationConstraints) -> Result<(Model, f64)> {
+        if constraints.d_genes.is_some()
+            || constraints.ins_vd.is_some()
+            || constraints.ins_dj.is_some()
+        {
+            return Err(anyhow!(
+                "VJ models don't have D genes (use `ins_vj` for the insertions)"
+            ));
+        }
+        // the VJ insertions are stored as VD insertions in the inner model
+        let (inner, proba) = self.inner.constrain(&GenerationConstraints {
+            ins_vd: constraints.ins_vj,
+            ins_vj: None,
+            ..constraints.clone()
+        })?;
+        let mut m = Model {
+            inner,
+            ..Default::default()
+        };
+        m.update_outer_model()?;
+        m.initialize()?;
+        Ok((m, proba))
+    }
+
     /// Update the v segments and adapt the associated marginals
     pub fn set_v_segments(&mut self, value: Vec<Gene>) -> Result<()> {
         let [_, sj] = *self.get_p_vj().shape() else {
@@ -713,12 +783,14 @@ impl Model {
             p_del_v_given_v: self.p_del_v_given_v.clone(),
             p_del_j_given_j: self.p_del_j_given_j.clone(),
             p_del_d5_del_d3: array![[[1.]]], // one option, no deletion, empty D gene.
-            markov_chain_vd: Arc::new(DNAMarkovChain::new(
+            // keep the higher orders of the insertion Markov chain
+            markov_chain_vd: Arc::new(DNAMarkovChain::new_higher_order(
                 &self.markov_coefficients_vj.clone(),
+                &self.inner.markov_chain_vd.higher_order,
                 false,
             )?),
-            // just need to give it some value
-            markov_chain_dj: Arc::new(DNAMarkovChain::default()),
+            // never used (no DJ insertion), but must be a valid chain
+            markov_chain_dj: Arc::new(DNAMarkovChain::new(&Array2::from_elem((4, 4), 0.25), true)?),
             range_del_v: self.range_del_v,
             range_del_j: self.range_del_j,
             range_del_d3: (0, 0),
@@ -0,0 +1,71 @@
+use anyhow::Result;
+use std::process::Command;
+
+/// The binary, with the models of the repository
+fn righor_command() -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_righor"));
+    command.env(
+        "RIGHOR_MODEL_DIR",
+        concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/righor.data/data/righor_models"
+        ),
+    );
+    command
+}
+
+#[test]
+fn cli_generate() -> Result<()> {
+    let output = righor_command()
+        .args([
+            "generate",
+            "--species",
+            "human",
+            "--chain",
+            "trb",
+            "-n",
+            "10",
+            "--seed",
+            "42",
+            "--functional",
+        ])
+        .output()?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout)?;
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 11);
+    assert!(lines[0].starts_with("junction_aa\tv_gene"));
+    assert!(lines[1..].iter().all(|l| l.split('\t').count() == 5));
+    Ok(())
+}
+
+#[test]
+fn cli_unknown_option() -> Result<()> {
+    let output = righor_command()
+        .args([
+            "generate",
+            "--species",
+            "human",
+            "--chain",
+            "trb",
+            "--foo",
+            "1",
+        ])
+        .output()?;
+    assert!(!output.status.success());
+    Ok(())
+}
+
+#[test]
+fn cli_missing_model_dir() -> Result<()> {
+    let output = righor_command()
+        .env(
+            "RIGHOR_MODEL_DIR",
+            std::env::temp_dir().join("righor_no_models"),
+        )
+        .args(["generate", "--species", "human", "--chain", "trb"])
+        .output()?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("--model-dir"));
+    Ok(())
+}
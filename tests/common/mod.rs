@@ -3,7 +3,9 @@ use ndarray::array;
 use righor::shared::errors::ErrorConstantRate;
 use righor::shared::markov_chain::DNAMarkovChain;
 use righor::shared::ErrorParameters;
+use righor::shared::{AlignmentParameters, DnaLike, InferenceParameters};
 use righor::vdj;
+use righor::EntrySequence;
 use righor::Modelable;
 use std::sync::Arc;
 
@@ -305,3 +307,68 @@ pub fn less_simple_model_vdj() -> vdj::Model {
     model.initialize().unwrap();
     model
 }
+
+#[cfg(test)]
+#[allow(dead_code)]
+/// Simple model with a longer V gene, so that the aligner has room to place gaps
+pub fn long_v_model_vdj() -> Result<vdj::Model> {
+    let mut model = simple_model_vdj();
+    let prefix = "GACAGTCAGCTTACGGATCCAGTTGCAAGTCGATGCATCGTAACGGCTAGTCCAAGTTCGA\
+                  TTGCCATAGGCTCAGATGTACCGTTAGCAAGGTCTACGATCGGATTCAGCTAACTGCGTA";
+    let gv = &mut model.seg_vs[0];
+    gv.seq = righor::Dna::from_string(&format!("{}{}", prefix, gv.seq.get_string()))?;
+    gv.cdr3_pos = Some(gv.cdr3_pos.unwrap() + prefix.len());
+    model.initialize()?;
+    Ok(model)
+}
+
+#[cfg(test)]
+#[allow(dead_code)]
+/// Generate `nb_sequences` sequences with `model` (and its error model) and
+/// align them, keep only the valid alignments
+pub fn generate_alignments(
+    model: &vdj::Model,
+    nb_sequences: usize,
+    seed: u64,
+    alp: &AlignmentParameters,
+) -> Result<Vec<EntrySequence>> {
+    let mut generator = vdj::Generator::new(&model.clone(), Some(seed), None, None)?;
+    let mut alignments = Vec::new();
+    for _ in 0..nb_sequences {
+        let s = righor::Dna::from_string(&generator.generate(false)?.full_seq)?;
+        let aligned = model.align_sequence(DnaLike::from_dna(s), alp)?;
+        if aligned.valid_alignment {
+            alignments.push(EntrySequence::Aligned(aligned));
+        }
+    }
+    Ok(alignments)
+}
+
+#[cfg(test)]
+#[allow(dead_code)]
+/// Infer the error model of `model` on `alignments` (`nb_rounds` rounds),
+/// starting from `initial_error`. Check that the inferred error model
+/// survives the IGoR format (`load` parses the error section) and return it.
+pub fn infer_error_model(
+    model: &vdj::Model,
+    alignments: &[EntrySequence],
+    initial_error: ErrorParameters,
+    nb_rounds: usize,
+    alp: &AlignmentParameters,
+    load: impl Fn(&[String]) -> Result<ErrorParameters>,
+) -> Result<ErrorParameters> {
+    let mut inferred = model.clone();
+    inferred.error = initial_error;
+    inferred.initialize()?;
+    let ifp = InferenceParameters::default();
+    for _ in 0..nb_rounds {
+        inferred.infer(alignments, None, None, alp, &ifp)?;
+    }
+
+    let lines: Vec<String> = inferred.error.write().lines().map(str::to_string).collect();
+    assert!(ErrorParameters::similar(
+        load(&lines)?,
+        inferred.error.clone()
+    ));
+    Ok(inferred.error)
+}
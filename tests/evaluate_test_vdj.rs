@@ -3,11 +3,15 @@ use righor::shared::likelihood::Vector16;
 use righor::shared::{AlignmentParameters, DnaLike, InferenceParameters};
 mod common;
 use kdam::tqdm;
-use righor::shared::errors::ErrorConstantRate;
+use righor::shared::errors::{context_to_string, ErrorConstantRate, ErrorContextRate};
 use righor::shared::likelihood::Likelihood;
 
 use itertools::Itertools;
-use ndarray::array;
+use ndarray::{array, s, Axis, IxDyn};
+use righor::graph::model::{J_5_DEL, J_CHOICE, V_CHOICE};
+use righor::graph::ModelGraph;
+use righor::shared::io::{AirrReader, AirrRearrangement, AirrSegment};
+use righor::shared::parser::{parse_str, ParserMarginals};
 use righor::shared::DNAMarkovChain;
 use righor::shared::ErrorParameters;
 use righor::shared::ModelStructure;
@@ -478,3 +482,665 @@ fn evaluate_simple_model_vdj() -> Result<()> {
     }
     Ok(())
 }
+
+#[test]
+fn evaluate_stream_simple_model_vdj() -> Result<()> {
+    let mut model = common::simple_model_vdj();
+    model.error = ErrorParameters::ConstantRate(ErrorConstantRate::new(0.1));
+    model.initialize()?;
+    let mut generator = righor::vdj::Generator::new(&model.clone(), Some(42), None, None)?;
+    let model = righor::Model::VDJ(model);
+
+    let ifp = InferenceParameters {
+        min_likelihood: 0.,
+        min_ratio_likelihood: 0.,
+        ..Default::default()
+    };
+    let alp = AlignmentParameters::default();
+
+    let sequences = (0..23)
+        .map(|_| {
+            let s = Dna::from_string(&generator.generate(false)?.full_seq)?;
+            Ok(EntrySequence::NucleotideSequence(DnaLike::from_dna(s)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // chunks smaller than the number of sequences, results should stay in order
+    let results = model
+        .evaluate_stream(sequences.clone(), &alp, &ifp, 5)
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(results.len(), sequences.len());
+    for (seq, compact) in sequences.into_iter().zip(results) {
+        let full = model.evaluate(seq, &alp, &ifp)?;
+        assert!((full.likelihood - compact.likelihood).abs() < 1e-12);
+        assert!((full.pgen - compact.pgen).abs() < 1e-12);
+        assert_eq!(
+            full.human_readable.map(|h| h.v_name),
+            compact.v_name.clone()
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn read_fasta_stream() -> Result<()> {
+    let content = ">seq1 some description\nacgt\nAAC\n\n>seq2\nTTTT\n";
+    let records =
+        righor::shared::io::FastaReader::new(content.as_bytes()).collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        records,
+        vec![
+            ("seq1".to_string(), "ACGTAAC".to_string()),
+            ("seq2".to_string(), "TTTT".to_string())
+        ]
+    );
+    assert!(righor::shared::io::FastaReader::new("ACGT\n".as_bytes())
+        .next()
+        .unwrap()
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn evaluate_airr_simple_model_vdj() -> Result<()> {
+    let mut model = common::simple_model_vdj();
+    model.error = ErrorParameters::ConstantRate(ErrorConstantRate::new(0.));
+    model.initialize()?;
+    let mut generator = righor::vdj::Generator::new(&model.clone(), Some(42), None, None)?;
+    let vdj_model = model.clone();
+    let model = righor::Model::VDJ(model);
+    let alp = AlignmentParameters::default();
+    let ifp = InferenceParameters::default_evaluate();
+
+    // germline part of the gene in the sequence
+    let germline = |genes: &Vec<righor::Gene>, name: &str, s: &AirrSegment| {
+        genes
+            .iter()
+            .filter(|g| g.name == name)
+            .map(|g| {
+                g.seq.get_string()
+                    [s.germline_start..s.germline_start + s.sequence_end - s.sequence_start]
+                    .to_string()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    for ii in 0..20 {
+        let s = generator.generate(false)?.full_seq;
+        let seq = EntrySequence::NucleotideSequence(DnaLike::from_dna(Dna::from_string(&s)?));
+        let result = model.evaluate(seq, &alp, &ifp)?;
+        let airr = AirrRearrangement::from_result(&ii.to_string(), &result, &model)?;
+        assert_eq!(airr.sequence, s);
+
+        let v = airr.v.clone().unwrap();
+        let j = airr.j.clone().unwrap();
+        assert!(germline(&vdj_model.seg_vs, &airr.v_call, &v)
+            .contains(&s[v.sequence_start..v.sequence_end].to_string()));
+        assert!(germline(&vdj_model.seg_js, &airr.j_call, &j)
+            .contains(&s[j.sequence_start..j.sequence_end].to_string()));
+        assert_eq!(j.sequence_end, s.len());
+
+        // V + np1 (+ D + np2) + J cover the whole sequence
+        let mut reconstructed = s[..v.sequence_end].to_string() + &airr.np1;
+        if let Some(d) = airr.d.clone() {
+            assert!(germline(&vdj_model.seg_ds, &airr.d_call, &d)
+                .contains(&s[d.sequence_start..d.sequence_end].to_string()));
+            reconstructed += &(s[d.sequence_start..d.sequence_end].to_string() + &airr.np2);
+        }
+        reconstructed += &s[j.sequence_start..];
+        assert_eq!(reconstructed, s);
+
+        let record = airr.to_record();
+        assert_eq!(record.len(), righor::shared::io::AIRR_COLUMNS.len());
+        assert_eq!(record[19], "1"); // v_sequence_start
+    }
+    Ok(())
+}
+
+#[test]
+fn evaluate_airr_gapped_alignment() -> Result<()> {
+    let mut model = common::long_v_model_vdj()?;
+    let mut generator = righor::vdj::Generator::new(&model.clone(), Some(13), None, None)?;
+    let full_seq = generator.generate_without_errors(false).full_seq;
+    model.error = ErrorParameters::ConstantRate(ErrorConstantRate::new(0.01));
+    model.initialize()?;
+    let vgene = model.seg_vs[0].seq.get_string();
+    let model = righor::Model::VDJ(model);
+    let alp = AlignmentParameters {
+        max_indels: 5,
+        ..AlignmentParameters::default_evaluate()
+    };
+    let ifp = InferenceParameters::default_evaluate();
+
+    // one nucleotide of the V gene is missing from the sequence
+    let s = format!("{}{}", &full_seq[..50], &full_seq[51..]);
+    let seq = EntrySequence::NucleotideSequence(DnaLike::from_dna(Dna::from_string(&s)?));
+    let result = model.evaluate(seq, &alp, &ifp)?;
+    let airr = AirrRearrangement::from_result("gapped", &result, &model)?;
+    let v = airr.v.clone().unwrap();
+    assert_eq!(v.cigar.iter().filter(|(c, _)| *c == 'D').count(), 1);
+    let record = airr.to_record();
+    assert!(record[15].starts_with("50M1D")); // v_cigar
+
+    // the aligned sequences have the same length, the gap faces the deleted nucleotide
+    assert_eq!(airr.sequence_alignment.replace('-', ""), s);
+    assert_eq!(airr.sequence_alignment.len(), airr.germline_alignment.len());
+    assert_eq!(airr.sequence_alignment.find('-'), Some(50));
+    assert_eq!(
+        &airr.germline_alignment[..v.sequence_end + 1],
+        &vgene[..v.sequence_end + 1]
+    );
+    Ok(())
+}
+
+#[test]
+fn evaluate_airr_two_d_genes() -> Result<()> {
+    let mut model = common::less_simple_model_vdj();
+    model.model_type = ModelStructure::VDDJ;
+    model.error = ErrorParameters::ConstantRate(ErrorConstantRate::new(0.));
+    model.initialize()?;
+    model.d2.as_mut().unwrap().p_d2_given_d1 = array![[0.2, 0.6], [0.8, 0.4]];
+    model.initialize()?;
+    let mut generator = righor::vdj::Generator::new(&model.clone(), Some(3), None, None)?;
+    let vdj_model = model.clone();
+    let model = righor::Model::VDJ(model);
+    let alp = AlignmentParameters::default();
+    let ifp = InferenceParameters::default_evaluate();
+
+    let mut nb_two_d = 0;
+    for ii in 0..20 {
+        let s = generator.generate(false)?.full_seq;
+        let seq = EntrySequence::NucleotideSequence(DnaLike::from_dna(Dna::from_string(&s)?));
+        let result = model.evaluate(seq, &alp, &ifp)?;
+        let airr = AirrRearrangement::from_result(&ii.to_string(), &result, &model)?;
+        let event = result.best_event.unwrap();
+        let Some(d2_index) = event.d2_index else {
+            assert!(airr.d2.is_none() && airr.d2_call.is_empty());
+            continue;
+        };
+        nb_two_d += 1;
+
+        // each D gene has its own fields
+        assert_eq!(airr.d_call, vdj_model.seg_ds[event.d_index].name);
+        assert_eq!(airr.d2_call, vdj_model.seg_ds[d2_index].name);
+        let (v, d, d2, j) = (
+            airr.v.clone().unwrap(),
+            airr.d.clone().unwrap(),
+            airr.d2.clone().unwrap(),
+            airr.j.clone().unwrap(),
+        );
+        let gene_d2 = vdj_model.seg_ds[d2_index].seq.get_string();
+        assert_eq!(
+            s[d2.sequence_start..d2.sequence_end],
+            gene_d2[d2.germline_start..d2.germline_start + d2.sequence_end - d2.sequence_start]
+        );
+
+        // V + np1 + D + np2 + D2 + np3 + J cover the whole sequence
+        let reconstructed = [
+            &s[..v.sequence_end],
+            &airr.np1,
+            &s[d.sequence_start..d.sequence_end],
+            &airr.np2,
+            &s[d2.sequence_start..d2.sequence_end],
+            &airr.np3,
+            &s[j.sequence_start..],
+        ]
+        .concat();
+        assert_eq!(reconstructed, s);
+
+        let record = airr.to_record();
+        assert_eq!(record.len(), righor::shared::io::AIRR_COLUMNS.len());
+        assert_eq!(record[8], airr.d2_call); // d2_call
+    }
+    assert!(nb_two_d > 0);
+    Ok(())
+}
+
+#[test]
+fn read_airr_real_model() -> Result<()> {
+    let model = righor::Model::load_from_name(
+        "human",
+        "trb",
+        None,
+        Path::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/righor.data/data/righor_models"
+        )),
+    )?;
+    let content = "sequence_id\tsequence\tjunction\tv_call\tj_call\tproductive\tduplicate_count
+full\tGAAGCCCAAGTGACCCAGAACCCAAGATACC\t\t\t\tT\t
+cdr3\t\tTGTGCCAGCAGTTTTCTGGCTTATTTCGCACCGGGGAGCTGTTTTTT\tTRBV27*01,TRBV27*02\tTRBJ2-2\tT\t3
+unknown\t\tTGTGCC\tTRBV999*01\tTRBJ1-1*01\tT\t1
+nonprod\t\tTGTGCCAGCAGTTTT\tTRBV27*01\tTRBJ2-2*01\tF\t2
+";
+    let mut reader = AirrReader::new(content.as_bytes(), &model, false, false)?;
+    let records = reader.by_ref().collect::<Result<Vec<_>>>()?;
+    assert_eq!(reader.skipped(), ["unknown".to_string()]);
+    assert_eq!(
+        records
+            .iter()
+            .map(|r| r.sequence_id.as_str())
+            .collect::<Vec<_>>(),
+        vec!["full", "cdr3", "nonprod"]
+    );
+    assert!(matches!(
+        records[0].sequence,
+        EntrySequence::NucleotideSequence(_)
+    ));
+    assert_eq!(records[0].duplicate_count, 1);
+    assert_eq!(records[1].duplicate_count, 3);
+    match &records[1].sequence {
+        EntrySequence::NucleotideCDR3((_, v, j)) => {
+            assert_eq!(
+                v.iter().map(|g| g.name.clone()).collect::<Vec<_>>(),
+                vec!["TRBV27*01"]
+            );
+            assert!(!j.is_empty() && j.iter().all(|g| g.name.starts_with("TRBJ2-2")));
+        }
+        _ => panic!("The junction should be used"),
+    }
+
+    let productive =
+        AirrReader::new(content.as_bytes(), &model, true, true)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(productive.len(), 1);
+    assert_eq!(productive[0].sequence_id, "cdr3");
+    Ok(())
+}
+
+#[test]
+fn evaluate_context_error_model() -> Result<()> {
+    let mut model = common::simple_model_vdj();
+    model.model_type = ModelStructure::VDJ;
+    model.error = ErrorParameters::ConstantRate(ErrorConstantRate::new(0.1));
+    model.initialize()?;
+    let mut model_context = model.clone();
+    model_context.error = ErrorContextRate::from_error_rate(0.1)?.into();
+
+    let mut generator = righor::vdj::Generator::new(&model.clone(), Some(42), None, None)?;
+    let ifp = InferenceParameters {
+        min_likelihood: 0.,
+        min_ratio_likelihood: 0.,
+        ..Default::default()
+    };
+    let alp = AlignmentParameters::default();
+    let sequences = (0..20)
+        .map(|_| {
+            let s = Dna::from_string(&generator.generate(false)?.full_seq)?;
+            Ok(EntrySequence::Aligned(
+                model.align_sequence(DnaLike::from_dna(s), &alp)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // a context-independent table is the constant error model
+    for s in &sequences {
+        let constant = model.evaluate(s.clone(), &alp, &ifp)?.likelihood;
+        let context = model_context.evaluate(s.clone(), &alp, &ifp)?.likelihood;
+        assert!((constant - context).abs() <= 1e-9 * constant);
+    }
+
+    // with hotspots (WRC), the dynamic programming still matches the brute force
+    let mut error = ErrorContextRate::from_error_rate(0.05)?;
+    for ctx in 0..righor::shared::errors::NB_CONTEXTS {
+        let kmer = context_to_string(ctx).into_bytes();
+        if b"AT".contains(&kmer[0]) && b"AG".contains(&kmer[1]) && kmer[2] == b'C' {
+            error.mutability[ctx] = 0.4;
+        }
+    }
+    model_context.error = error.into();
+    let mut model_context_vxdj = model_context.clone();
+    model_context_vxdj.model_type = ModelStructure::VxDJ;
+    for s in &sequences {
+        let result = model_context.evaluate(s.clone(), &alp, &ifp)?.likelihood;
+        let result_vxdj = model_context_vxdj
+            .evaluate(s.clone(), &alp, &ifp)?
+            .likelihood;
+        let result_brute_force = model_context
+            .evaluate_brute_force(s, &alp, &ifp)?
+            .likelihood;
+        assert!((result - result_vxdj).abs() <= 1e-9 * result);
+        assert!((result - result_brute_force).abs() <= 1e-9 * result);
+    }
+    Ok(())
+}
+
+#[test]
+fn evaluate_error_rate_and_mismatches() -> Result<()> {
+    let mut model = common::simple_model_vdj();
+    model.model_type = ModelStructure::VDJ;
+    model.error = ErrorParameters::ConstantRate(ErrorConstantRate::new(0.1));
+    model.initialize()?;
+    let mut model_vxdj = model.clone();
+    model_vxdj.model_type = ModelStructure::VxDJ;
+
+    let mut generator = righor::vdj::Generator::new(&model.clone(), Some(42), None, None)?;
+    let ifp = InferenceParameters {
+        min_likelihood: 0.,
+        min_ratio_likelihood: 0.,
+        ..Default::default()
+    };
+    let alp = AlignmentParameters::default();
+    let mut mean_error_rate = 0.;
+    let nb_sequences = 100;
+    for _ in 0..nb_sequences {
+        let s = Dna::from_string(&generator.generate(false)?.full_seq)?;
+        let als = EntrySequence::Aligned(model.align_sequence(DnaLike::from_dna(s), &alp)?);
+        let result = model.evaluate(als.clone(), &alp, &ifp)?;
+        let result_vxdj = model_vxdj.evaluate(als.clone(), &alp, &ifp)?;
+        let result_brute_force = model.evaluate_brute_force(&als, &alp, &ifp)?;
+
+        let error_rate = result.error_rate.unwrap();
+        assert!((error_rate - result_vxdj.error_rate.unwrap()).abs() < 1e-9);
+        assert!((error_rate - result_brute_force.error_rate.unwrap()).abs() < 1e-9);
+        mean_error_rate += error_rate / nb_sequences as f64;
+
+        // all the differences with the reconstructed sequence are in the genes
+        let event = result.best_event.unwrap();
+        let full_seq = event.full_sequence.unwrap();
+        let reconstructed = event.reconstructed_sequence.unwrap();
+        let nb_differences = full_seq
+            .seq
+            .iter()
+            .zip(reconstructed.seq.iter())
+            .filter(|(a, b)| a != b)
+            .count();
+        assert_eq!(
+            nb_differences,
+            event.nb_errors_v.unwrap() + event.nb_errors_d.unwrap() + event.nb_errors_j.unwrap()
+        );
+    }
+    println!("{}", mean_error_rate);
+    assert!((mean_error_rate - 0.1).abs() < 0.03);
+    Ok(())
+}
+
+#[test]
+fn evaluate_graph_model() -> Result<()> {
+    let mut model = common::less_simple_model_vdj();
+    // match the dimension of P(deld5, deld3 | D), needed for the IGoR format
+    model.range_del_d5 = (-1, 1);
+    model.range_del_d3 = (-1, 3);
+    model.model_type = ModelStructure::VDJ;
+    model.initialize()?;
+
+    // same model, but the J deletions "depend" on the V gene (identical
+    // distributions for both V genes)
+    let pm = ParserMarginals::parse(parse_str(&model.write_marginals()?)?)?;
+    let mut events = ModelGraph::from_marginals(&pm)?.events;
+    let delj = events.iter_mut().find(|x| x.event == J_5_DEL).unwrap();
+    let shape = delj.probabilities.shape().to_vec();
+    delj.parents = vec![V_CHOICE, J_CHOICE];
+    delj.probabilities = delj
+        .probabilities
+        .broadcast(IxDyn(&[model.seg_vs.len(), shape[0], shape[1]]))
+        .unwrap()
+        .to_owned();
+    let graph = ModelGraph::new(events)?;
+    assert!(!graph.is_standard());
+
+    let mut model_graph = model.clone();
+    model_graph.graph = Some(graph);
+    model_graph.model_type = ModelStructure::Graph;
+    model_graph.initialize()?;
+    assert!(model_graph.p_vdj.relative_eq(&model.p_vdj, 1e-6, 1e-6));
+    assert!(model_graph
+        .p_del_j_given_j
+        .relative_eq(&model.p_del_j_given_j, 1e-6, 1e-6));
+
+    // the evaluation gives the same result as the standard model
+    let mut generator = righor::vdj::Generator::new(&model_graph.clone(), Some(12), None, None)?;
+    let alp = AlignmentParameters::default();
+    let ifp = InferenceParameters {
+        min_likelihood: 0.,
+        min_ratio_likelihood: 0.,
+        ..Default::default()
+    };
+    let mut alignments = Vec::new();
+    for _ in 0..20 {
+        let s = Dna::from_string(&generator.generate_without_errors(false).full_seq)?;
+        let seq = EntrySequence::Aligned(model.align_sequence(DnaLike::from_dna(s), &alp)?);
+        let result = model.evaluate(seq.clone(), &alp, &ifp)?;
+        let result_graph = model_graph.evaluate(seq.clone(), &alp, &ifp)?;
+        assert!(result.likelihood > 0.);
+        assert!((result.likelihood - result_graph.likelihood).abs() <= 1e-9 * result.likelihood);
+        assert!((result.pgen - result_graph.pgen).abs() <= 1e-9 * result.pgen);
+        alignments.push(seq);
+    }
+
+    // but the inference is not available
+    assert!(model_graph
+        .infer(&alignments, None, None, &alp, &ifp)
+        .is_err());
+
+    // the graph is saved in the IGoR format
+    let loaded = righor::vdj::Model::load_from_str(
+        &model_graph.write_params()?,
+        &model_graph.write_marginals()?,
+        &model_graph.write_v_anchors()?,
+        &model_graph.write_j_anchors()?,
+    )?;
+    assert_eq!(loaded.model_type, ModelStructure::Graph);
+    assert!(loaded
+        .graph
+        .as_ref()
+        .unwrap()
+        .similar_to(model_graph.graph.as_ref().unwrap()));
+
+    // a real dependence: the first V gene is never followed by J deletions
+    let mut events = loaded.graph.clone().unwrap().events;
+    let delj = events.iter_mut().find(|x| x.event == J_5_DEL).unwrap();
+    delj.probabilities
+        .index_axis_mut(Axis(0), 0)
+        .mapv_inplace(|_| 0.);
+    let zero_del = (-model.range_del_j.0) as usize;
+    delj.probabilities.slice_mut(s![0, .., zero_del]).fill(1.);
+    let mut model_graph = loaded.clone();
+    model_graph.graph = Some(ModelGraph::new(events)?);
+    model_graph.initialize()?;
+    let mut rng = StdRng::seed_from_u64(3);
+    let mut nb_deletions = [0, 0];
+    for _ in 0..200 {
+        let (_, _, _, event) = model_graph.generate_no_error(false, &mut rng);
+        if event.delj != zero_del {
+            nb_deletions[event.v_index] += 1;
+        }
+    }
+    assert_eq!(nb_deletions[0], 0);
+    assert!(nb_deletions[1] > 0);
+    Ok(())
+}
+
+#[test]
+fn sample_posterior_simple_model() -> Result<()> {
+    for model_type in [ModelStructure::VDJ, ModelStructure::VxDJ] {
+        let mut model = common::simple_model_vdj();
+        model.model_type = model_type;
+        model.error = ErrorParameters::ConstantRate(ErrorConstantRate::new(0.1));
+        model.initialize()?;
+
+        let ifp = InferenceParameters {
+            min_likelihood: 0.,
+            min_ratio_likelihood: 0.,
+            ..Default::default()
+        };
+        let alp = AlignmentParameters::default();
+
+        let mut generator = righor::vdj::Generator::new(&model.clone(), Some(3), None, None)?;
+        let mut rng = StdRng::seed_from_u64(5);
+        for _ in 0..5 {
+            let s = righor::Dna::from_string(&generator.generate(false)?.full_seq)?;
+            let als =
+                EntrySequence::Aligned(model.align_sequence(DnaLike::from_dna(s.clone()), &alp)?);
+            let result = model.evaluate(als.clone(), &alp, &ifp)?;
+            let nb_samples = 5000;
+            // the pruning thresholds of the default parameters are ignored
+            let events = model.sample_posterior(
+                als,
+                nb_samples,
+                &alp,
+                &InferenceParameters::default(),
+                &mut rng,
+            )?;
+
+            // empirical frequency of each scenario vs. its posterior probability
+            let mut counts = std::collections::HashMap::new();
+            for ev in &events {
+                assert!(ev.reconstructed_sequence.is_some());
+                let key = (
+                    ev.v_index,
+                    ev.v_start_gene,
+                    ev.d_index,
+                    ev.pos_d,
+                    ev.j_index,
+                    ev.end_v,
+                    ev.start_d,
+                    ev.end_d,
+                    ev.start_j,
+                );
+                counts.entry(key).or_insert((0, ev.likelihood)).0 += 1;
+            }
+            let total: f64 = counts.values().map(|(_, ll)| ll).sum();
+            assert!(total <= result.likelihood * (1. + 1e-9));
+            for (count, ll) in counts.values() {
+                let freq = *count as f64 / nb_samples as f64;
+                assert!((freq - ll / result.likelihood).abs() < 0.025);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn top_events_simple_model() -> Result<()> {
+    for model_type in [ModelStructure::VDJ, ModelStructure::VxDJ] {
+        let mut model = common::simple_model_vdj();
+        model.model_type = model_type;
+        model.error = ErrorParameters::ConstantRate(ErrorConstantRate::new(0.1));
+        model.initialize()?;
+
+        let alp = AlignmentParameters::default();
+        let mut generator = righor::vdj::Generator::new(&model.clone(), Some(7), None, None)?;
+        for _ in 0..5 {
+            let s = righor::Dna::from_string(&generator.generate(false)?.full_seq)?;
+            let als =
+                EntrySequence::Aligned(model.align_sequence(DnaLike::from_dna(s.clone()), &alp)?);
+
+            // keeping all the scenarios recovers the full likelihood
+            let ifp = InferenceParameters {
+                min_likelihood: 0.,
+                min_ratio_likelihood: 0.,
+                store_top_k: usize::MAX,
+                ..Default::default()
+            };
+            let all = model.evaluate(als.clone(), &alp, &ifp)?;
+            let total: f64 = all.top_probabilities().iter().sum();
+            assert!((total - 1.).abs() < 1e-9);
+            assert!(all
+                .top_events
+                .windows(2)
+                .all(|w| w[0].likelihood >= w[1].likelihood));
+
+            // the top 5 are the first 5 of the full list
+            let top = model.evaluate(
+                als,
+                &alp,
+                &InferenceParameters {
+                    store_top_k: 5,
+                    ..ifp.clone()
+                },
+            )?;
+            assert_eq!(top.top_events.len(), 5);
+            for (ev, ev_all) in top.top_events.iter().zip(&all.top_events) {
+                assert!((ev.likelihood - ev_all.likelihood).abs() <= 1e-12 * ev.likelihood);
+                assert!(ev.reconstructed_sequence.is_some());
+            }
+        }
+    }
+
+    // no search with two D genes or degenerate sequences, the evaluation still works
+    let mut model = common::less_simple_model_vdj();
+    let ifp = InferenceParameters {
+        store_top_k: 5,
+        ..Default::default()
+    };
+    let mut generator = righor::vdj::Generator::new(&model.clone(), Some(7), None, None)?;
+    let s = generator.generate(false)?.full_seq;
+    let degenerate = format!("{}N{}", &s[..10], &s[11..]);
+    let result = model.evaluate(
+        EntrySequence::NucleotideSequence(DnaLike::from_string(&degenerate, "dna")?),
+        &AlignmentParameters::default(),
+        &ifp,
+    )?;
+    assert!(result.likelihood > 0.);
+    assert!(result.top_events.is_empty());
+
+    model.model_type = ModelStructure::VDDJ;
+    model.initialize()?;
+    let mut generator = righor::vdj::Generator::new(&model.clone(), Some(7), None, None)?;
+    let s = generator.generate(false)?.full_seq;
+    let result = model.evaluate(
+        EntrySequence::NucleotideSequence(DnaLike::from_string(&s, "dna")?),
+        &AlignmentParameters::default(),
+        &ifp,
+    )?;
+    assert!(result.likelihood > 0.);
+    assert!(result.top_events.is_empty());
+    Ok(())
+}
+
+#[test]
+fn posterior_marginals_simple_model() -> Result<()> {
+    for model_type in [ModelStructure::VDJ, ModelStructure::VxDJ] {
+        let mut model = common::simple_model_vdj();
+        model.model_type = model_type;
+        model.error = ErrorParameters::ConstantRate(ErrorConstantRate::new(0.1));
+        model.initialize()?;
+
+        let alp = AlignmentParameters::default();
+        let ifp = InferenceParameters {
+            min_likelihood: 0.,
+            min_ratio_likelihood: 0.,
+            store_top_k: usize::MAX,
+            ..Default::default()
+        };
+        let mut generator = righor::vdj::Generator::new(&model.clone(), Some(11), None, None)?;
+        for _ in 0..5 {
+            let s = righor::Dna::from_string(&generator.generate(false)?.full_seq)?;
+            let als =
+                EntrySequence::Aligned(model.align_sequence(DnaLike::from_dna(s.clone()), &alp)?);
+            let result = model.evaluate(als, &alp, &ifp)?;
+            let marginals = result.marginals.clone().unwrap();
+
+            // same marginals from the list of all the scenarios
+            let mut p_d = vec![0.; marginals.d.len()];
+            let mut p_ins_vd = vec![0.; marginals.ins_vd.len()];
+            let mut p_ins_dj = vec![0.; marginals.ins_dj.len()];
+            for (ev, p) in result.top_events.iter().zip(result.top_probabilities()) {
+                p_d[ev.d_index] += p;
+                p_ins_vd[(ev.start_d - ev.end_v) as usize] += p;
+                p_ins_dj[(ev.start_j - ev.end_d) as usize] += p;
+            }
+            for (marginal, expected) in [
+                (&marginals.d, p_d),
+                (&marginals.ins_vd, p_ins_vd),
+                (&marginals.ins_dj, p_ins_dj),
+            ] {
+                for (x, y) in marginal.iter().zip(expected) {
+                    assert!((x - y).abs() < 1e-9);
+                }
+            }
+            for marginal in [
+                &marginals.v,
+                &marginals.j,
+                &marginals.del_v,
+                &marginals.del_j,
+            ] {
+                assert!((marginal.sum() - 1.).abs() < 1e-9);
+            }
+            assert!((marginals.del_d.sum() - 1.).abs() < 1e-9);
+            let (p_v, p_d, p_j) = result.best_genes_posterior();
+            assert!(p_v.is_some() && p_d.is_some() && p_j.is_some());
+        }
+    }
+    Ok(())
+}
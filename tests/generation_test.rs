@@ -34,3 +34,115 @@ fn test_generation_simple_model() -> Result<()> {
     }
     Ok(())
 }
+
+#[test]
+fn test_constrained_generation() -> Result<()> {
+    let model = righor::Model::VDJ(common::simple_model_vdj());
+    let d1 = model.get_d_segments()?[0].clone();
+    let constraints = righor::GenerationConstraints {
+        d_genes: Some(vec![d1]),
+        ins_vd: Some((1, 3)),
+        del_v: Some((0, 2)),
+        junction_aa_motif: Some("^C".to_string()),
+        ..Default::default()
+    };
+    let mut gen =
+        righor::Generator::new_with_constraints(&model, Some(3), None, None, constraints)?;
+    for _ in 0..500 {
+        let result = gen.generate_without_errors(false)?;
+        let righor::shared::StaticEvent::VDJ(event) = result.recombination_event else {
+            panic!("VDJ model generated a VJ event")
+        };
+        assert_eq!(event.d_index, 0);
+        assert!((1..=3).contains(&event.insvd.len()));
+        // range_del_v starts at -2
+        assert!((0..=2).contains(&(event.delv as i64 - 2)));
+        assert!(result.junction_aa.unwrap().starts_with('C'));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_constrained_generation_probability() -> Result<()> {
+    let model = righor::Model::VDJ(common::simple_model_vdj());
+    let d1 = model.get_d_segments()?[0].clone();
+    let nb_seqs = 20000;
+
+    // Monte-Carlo estimate with an unconstrained generator
+    let mut gen = righor::Generator::new(&model, Some(0), None, None)?;
+    let results = (0..nb_seqs)
+        .map(|_| gen.generate_without_errors(false))
+        .collect::<Result<Vec<_>>>()?;
+    let conditioned = |r: &&righor::shared::model::GenerationResult| match &r.recombination_event {
+        righor::shared::StaticEvent::VDJ(e) => e.d_index == 0 && (1..=3).contains(&e.insvd.len()),
+        righor::shared::StaticEvent::VJ(_) => false,
+    };
+    let length = results
+        .iter()
+        .find_map(|r| r.junction_aa.as_ref())
+        .unwrap()
+        .len();
+    let nb_conditioned = results.iter().filter(conditioned).count();
+    let nb_accepted = results
+        .iter()
+        .filter(conditioned)
+        .filter(|r| r.junction_aa.as_ref().is_some_and(|aa| aa.len() == length))
+        .count();
+
+    let constraints = righor::GenerationConstraints {
+        d_genes: Some(vec![d1]),
+        ins_vd: Some((1, 3)),
+        junction_aa_length: Some(length),
+        ..Default::default()
+    };
+    let mut gen =
+        righor::Generator::new_with_constraints(&model, Some(1), None, None, constraints)?;
+    for [aa, _, _, _] in gen.generate_many_without_errors(2000, false)? {
+        assert_eq!(aa.len(), length);
+    }
+    assert!((gen.conditioning_probability() - nb_conditioned as f64 / nb_seqs as f64).abs() < 0.01);
+    assert!((gen.constrained_probability() - nb_accepted as f64 / nb_seqs as f64).abs() < 0.01);
+    Ok(())
+}
+
+#[test]
+fn test_norm_productive_exact_vs_monte_carlo() -> Result<()> {
+    let model_dir = std::path::Path::new(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/righor.data/data/righor_models"
+    ));
+    let models = vec![
+        righor::Model::VDJ(common::simple_model_vdj()),
+        righor::Model::load_from_name("human", "trb", None, model_dir)?,
+        righor::Model::load_from_name("human", "igh", None, model_dir)?,
+        righor::Model::load_from_name("human", "tra", None, model_dir)?,
+    ];
+    let nb_samples = 200_000;
+    for model in models {
+        for residues in [None, Some("ACDEFGHIKLMNPQRSTVWY")] {
+            let exact = model.get_norm_productive_exact(residues)?;
+            let monte_carlo = model.get_norm_productive(Some(nb_samples), residues, Some(0));
+            let sigma = (exact * (1. - exact) / nb_samples as f64).sqrt();
+            assert!((exact - monte_carlo).abs() < 5. * sigma + 1e-12);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_norm_productive_cache() -> Result<()> {
+    let mut model = righor::Model::VDJ(common::simple_model_vdj());
+    let norm = model.get_norm_productive_exact(None)?;
+    assert_eq!(model.get_norm_productive_exact(Some("WVF"))?, norm);
+    assert_eq!(model.get_pgen_productive(1e-10, None)?, 1e-10 / norm);
+
+    // changing the parameters drops the cached value
+    let p_ins_vd = model.get_p_ins_vd()?.mapv(|_| 1.);
+    model.set_p_ins_vd(p_ins_vd.clone())?;
+    let mut expected = righor::Model::VDJ(common::simple_model_vdj());
+    expected.set_p_ins_vd(p_ins_vd)?;
+    let new_norm = model.get_norm_productive_exact(None)?;
+    assert_ne!(new_norm, norm);
+    assert_eq!(new_norm, expected.get_norm_productive_exact(None)?);
+    Ok(())
+}
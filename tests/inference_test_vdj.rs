@@ -1,7 +1,11 @@
 use anyhow::Result;
 use kdam::tqdm;
-use ndarray::{array, Axis};
-use righor::shared::errors::ErrorConstantRate;
+use ndarray::{array, Array2, Axis};
+use rand::SeedableRng;
+use righor::shared::errors::{
+    context_to_string, ErrorConstantRate, ErrorContextRate, ErrorIndelRate, ErrorSegmentRate,
+    ErrorSubstitutionMatrix, NB_CONTEXTS,
+};
 use righor::shared::DnaLike;
 use righor::shared::ErrorParameters;
 use righor::shared::ModelStructure;
@@ -9,6 +13,7 @@ use righor::shared::{AlignmentParameters, InferenceParameters};
 use righor::EntrySequence;
 use righor::Modelable;
 use std::path::Path;
+use std::sync::Arc;
 mod common;
 
 #[test]
@@ -26,8 +31,8 @@ fn infer_comparison_v_dj_vdj_simple_model() -> Result<()> {
         alignments.push(EntrySequence::Aligned(als));
     }
 
-    model.infer(&alignments.clone(), None, &alp, &ifp)?;
-    model2.infer(&alignments.clone(), None, &alp, &ifp_2)?;
+    model.infer(&alignments.clone(), None, None, &alp, &ifp)?;
+    model2.infer(&alignments.clone(), None, None, &alp, &ifp_2)?;
 
     assert!(model.p_ins_vd.abs_diff_eq(&model2.p_ins_vd, 1e-12));
     Ok(())
@@ -84,7 +89,7 @@ fn infer_vs_brute_force() -> Result<()> {
     let mut inferred_model2 = model.uniform()?;
     //    inferred_model2.error_rate = 0.;
     for _ in 0..2 {
-        inferred_model2.infer(&alignments.clone(), None, &alp, &ifp)?;
+        inferred_model2.infer(&alignments.clone(), None, None, &alp, &ifp)?;
     }
 
     println!("{:?}", inferred_model.p_ins_vd);
@@ -154,7 +159,7 @@ fn full_inference_simple_model() -> Result<()> {
     let mut inferred_model = model.uniform()?;
     inferred_model.error = ErrorParameters::ConstantRate(ErrorConstantRate::new(0.));
     for ii in 0..20 {
-        let ll = inferred_model.infer(&alignments.clone(), None, &alp, &ifp)?;
+        let ll = inferred_model.infer(&alignments.clone(), None, None, &alp, &ifp)?;
         println!(
             "----- {}  {:2.e} ----\n {:?}",
             ii,
@@ -170,3 +175,785 @@ fn full_inference_simple_model() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn infer_weights_vs_duplicates() -> Result<()> {
+    let mut model = common::simple_model_vdj();
+    model.error = ErrorParameters::ConstantRate(ErrorConstantRate::new(0.1));
+    model.initialize()?;
+    let mut generator = righor::vdj::Generator::new(&model.clone(), Some(12), None, None)?;
+    let ifp = InferenceParameters::default();
+    let alp = AlignmentParameters::default();
+
+    let mut alignments = Vec::new();
+    for _ in 0..5 {
+        let s = righor::Dna::from_string(&generator.generate(false)?.full_seq)?;
+        let als = model.align_sequence(DnaLike::from_dna(s.clone()), &alp)?;
+        alignments.push(EntrySequence::Aligned(als));
+    }
+    let weights = vec![1., 3., 2., 1., 1.];
+    let duplicated: Vec<EntrySequence> = alignments
+        .iter()
+        .zip(weights.iter())
+        .flat_map(|(s, &w)| vec![s.clone(); w as usize])
+        .collect();
+
+    for model_type in [ModelStructure::VDJ, ModelStructure::VxDJ] {
+        let mut model_weighted = model.clone();
+        model_weighted.model_type = model_type.clone();
+        let mut model_duplicated = model_weighted.clone();
+
+        let (_, ll_weighted) =
+            model_weighted.infer(&alignments, Some(&weights), None, &alp, &ifp)?;
+        let (_, ll_duplicated) = model_duplicated.infer(&duplicated, None, None, &alp, &ifp)?;
+
+        assert!((ll_weighted - ll_duplicated).abs() < 1e-8 * ll_duplicated.abs());
+        assert!(model_weighted
+            .p_vdj
+            .abs_diff_eq(&model_duplicated.p_vdj, 1e-10));
+        assert!(model_weighted
+            .p_ins_vd
+            .abs_diff_eq(&model_duplicated.p_ins_vd, 1e-10));
+        assert!(model_weighted
+            .p_del_v_given_v
+            .abs_diff_eq(&model_duplicated.p_del_v_given_v, 1e-10));
+        assert!(ErrorParameters::similar(
+            model_weighted.error.clone(),
+            model_duplicated.error.clone()
+        ));
+    }
+
+    // wrong number of weights
+    assert!(model
+        .clone()
+        .infer(&alignments, Some(&[1., 2.]), None, &alp, &ifp)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn fit_simple_model() -> Result<()> {
+    let model_vdj = common::simple_model_vdj();
+    let mut generator = righor::vdj::Generator::new(&model_vdj.clone(), Some(12), None, None)?;
+    let alp = AlignmentParameters::default();
+    let ifp = InferenceParameters::default();
+    let sequences = (0..20)
+        .map(|_| {
+            Ok(EntrySequence::NucleotideSequence(DnaLike::from_dna(
+                righor::Dna::from_string(&generator.generate(false)?.full_seq)?,
+            )))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // without tolerance, run exactly max_iterations rounds
+    let checkpoint = std::env::temp_dir().join("righor_fit_checkpoint.json");
+    let mut model = righor::Model::VDJ(model_vdj.clone());
+    let fit_params = righor::shared::FitParameters {
+        tolerance: f64::NEG_INFINITY,
+        max_iterations: 3,
+        checkpoint_every: 2,
+        checkpoint_path: Some(checkpoint.to_string_lossy().to_string()),
+        ..Default::default()
+    };
+    let (_, result) = model.fit(&sequences, None, None, None, &alp, &ifp, &fit_params)?;
+    assert_eq!(result.log_likelihoods.len(), 3);
+    assert!(!result.converged);
+    // EM never decreases the likelihood
+    for w in result.log_likelihoods.windows(2) {
+        assert!(w[1] >= w[0] - 1e-6 * w[0].abs());
+    }
+    // the checkpoint was written after the second round
+    let saved = righor::Model::load_json(&checkpoint)?;
+    std::fs::remove_file(&checkpoint)?;
+    assert_eq!(saved.get_model_type(), model.get_model_type());
+
+    // same result as calling infer by hand
+    let mut model2 = righor::Model::VDJ(model_vdj);
+    let mut features = None;
+    for ll in &result.log_likelihoods {
+        let (f, ll2) = model2.infer(&sequences, None, features, &alp, &ifp)?;
+        features = Some(f);
+        assert!((ll - ll2).abs() < 1e-8 * ll.abs());
+    }
+
+    // with a large tolerance, stop after two rounds
+    let fit_params = righor::shared::FitParameters {
+        tolerance: 1e10,
+        ..Default::default()
+    };
+    let (_, result) = model.fit(&sequences, None, None, None, &alp, &ifp, &fit_params)?;
+    assert_eq!(result.log_likelihoods.len(), 2);
+    assert!(result.converged);
+    Ok(())
+}
+
+#[test]
+fn infer_reuse_alignments() -> Result<()> {
+    let mut model = common::simple_model_vdj();
+    let mut generator = righor::vdj::Generator::new(&model.clone(), Some(3), None, None)?;
+    let ifp = InferenceParameters::default();
+    let alp = AlignmentParameters::default();
+    let alignments = (0..10)
+        .map(|_| {
+            let s = righor::Dna::from_string(&generator.generate(false)?.full_seq)?;
+            Ok(EntrySequence::Aligned(
+                model.align_sequence(DnaLike::from_dna(s), &alp)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // the alignments stay valid between rounds and for models with the same genes
+    let mut uniform = model.uniform()?;
+    model.infer(&alignments, None, None, &alp, &ifp)?;
+    model.infer(&alignments, None, None, &alp, &ifp)?;
+    uniform.infer(&alignments, None, None, &alp, &ifp)?;
+
+    // changing the genes or the deletion ranges invalidates them (even without
+    // re-initializing the model)
+    let mut renamed = model.clone();
+    renamed.seg_vs[0].name = "other_name".to_string();
+    assert!(renamed.infer(&alignments, None, None, &alp, &ifp).is_err());
+    let mut shifted = model.clone();
+    shifted.range_del_v.1 -= 1;
+    assert!(shifted.evaluate(alignments[0].clone(), &alp, &ifp).is_err());
+    Ok(())
+}
+
+#[test]
+fn fit_validation_early_stopping() -> Result<()> {
+    let model_vdj = common::simple_model_vdj();
+    let mut generator = righor::vdj::Generator::new(&model_vdj.clone(), Some(7), None, None)?;
+    let alp = AlignmentParameters::default();
+    let ifp = InferenceParameters::default();
+    let mut generate = |n: usize| {
+        (0..n)
+            .map(|_| {
+                Ok(EntrySequence::NucleotideSequence(DnaLike::from_dna(
+                    righor::Dna::from_string(&generator.generate(false)?.full_seq)?,
+                )))
+            })
+            .collect::<Result<Vec<_>>>()
+    };
+    let training = generate(5)?;
+    let validation = generate(20)?;
+
+    // the validation log-likelihood is computed with the updated model
+    let mut model = righor::Model::VDJ(model_vdj.uniform()?);
+    let fit_params = righor::shared::FitParameters {
+        tolerance: f64::NEG_INFINITY,
+        max_iterations: 4,
+        ..Default::default()
+    };
+    let (_, result) = model.fit(
+        &training,
+        None,
+        None,
+        Some(&validation),
+        &alp,
+        &ifp,
+        &fit_params,
+    )?;
+    assert_eq!(result.validation_log_likelihoods.len(), 4);
+    let expected = model.mean_log_likelihood(&validation, &alp, &ifp)?;
+    assert!((expected - result.validation_log_likelihoods[3]).abs() < 1e-8 * expected.abs());
+
+    // without early stopping, the validation log-likelihood ends up decreasing
+    // (few training sequences), find the first round where it does
+    let mut model = righor::Model::VDJ(model_vdj.uniform()?);
+    let fit_params = righor::shared::FitParameters {
+        tolerance: f64::NEG_INFINITY,
+        max_iterations: 30,
+        ..Default::default()
+    };
+    let (_, result) = model.fit(
+        &training,
+        None,
+        None,
+        Some(&validation),
+        &alp,
+        &ifp,
+        &fit_params,
+    )?;
+    let val = &result.validation_log_likelihoods;
+    let stop_round = val
+        .windows(2)
+        .position(|w| w[1] < w[0])
+        .expect("the validation log-likelihood should decrease")
+        + 2;
+
+    // with early stopping, the fit stops at that round and the model of the
+    // previous round (best validation log-likelihood) is kept
+    let mut model = righor::Model::VDJ(model_vdj.uniform()?);
+    let fit_params = righor::shared::FitParameters {
+        tolerance: f64::NEG_INFINITY,
+        max_iterations: 30,
+        early_stopping: true,
+        ..Default::default()
+    };
+    let (_, result) = model.fit(
+        &training,
+        None,
+        None,
+        Some(&validation),
+        &alp,
+        &ifp,
+        &fit_params,
+    )?;
+    assert!(result.stopped_early);
+    assert_eq!(result.validation_log_likelihoods.len(), stop_round);
+    let best = val[stop_round - 2];
+    let kept = model.mean_log_likelihood(&validation, &alp, &ifp)?;
+    assert!((kept - best).abs() < 1e-8 * best.abs());
+
+    let mut best_model = righor::Model::VDJ(model_vdj.uniform()?);
+    let fit_params = righor::shared::FitParameters {
+        tolerance: f64::NEG_INFINITY,
+        max_iterations: stop_round - 1,
+        ..Default::default()
+    };
+    best_model.fit(&training, None, None, None, &alp, &ifp, &fit_params)?;
+    match (&model, &best_model) {
+        (righor::Model::VDJ(m), righor::Model::VDJ(b)) => {
+            assert!(m.p_vdj.abs_diff_eq(&b.p_vdj, 1e-12));
+            assert!(m.p_ins_vd.abs_diff_eq(&b.p_ins_vd, 1e-12));
+            assert!(m.p_del_v_given_v.abs_diff_eq(&b.p_del_v_given_v, 1e-12));
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+#[test]
+fn infer_pseudocounts() -> Result<()> {
+    let model = common::simple_model_vdj();
+    let mut generator = righor::vdj::Generator::new(&model.clone(), Some(5), None, None)?;
+    let alp = AlignmentParameters::default();
+    let alignments = (0..5)
+        .map(|_| {
+            let s = righor::Dna::from_string(&generator.generate(false)?.full_seq)?;
+            Ok(EntrySequence::Aligned(
+                model.align_sequence(DnaLike::from_dna(s), &alp)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // maximum likelihood, rare events get a very small probability
+    let mut model_ml = model.uniform()?;
+    model_ml.infer(
+        &alignments,
+        None,
+        None,
+        &alp,
+        &InferenceParameters::default(),
+    )?;
+    fn min<'a>(x: impl Iterator<Item = &'a f64>) -> f64 {
+        x.cloned().fold(f64::INFINITY, f64::min)
+    }
+
+    // pseudocounts pull the rare events up
+    let ifp = InferenceParameters {
+        pseudocounts: righor::shared::Pseudocounts::uniform(0.1),
+        ..Default::default()
+    };
+    let mut model_map = model.uniform()?;
+    model_map.infer(&alignments, None, None, &alp, &ifp)?;
+    assert!(min(model_map.p_vdj.iter()) > min(model_ml.p_vdj.iter()));
+    assert!(min(model_map.p_ins_vd.iter()) > min(model_ml.p_ins_vd.iter()));
+    assert!(min(model_map.p_del_v_given_v.iter()) > min(model_ml.p_del_v_given_v.iter()));
+
+    // a very strong prior keeps the (uniform) distribution
+    let ifp = InferenceParameters {
+        pseudocounts: righor::shared::Pseudocounts::uniform(1e9),
+        ..Default::default()
+    };
+    let uniform = model.uniform()?;
+    let mut model_prior = model.uniform()?;
+    model_prior.infer(&alignments, None, None, &alp, &ifp)?;
+    assert!(model_prior.p_ins_dj.abs_diff_eq(&uniform.p_ins_dj, 1e-6));
+    assert!(model_prior
+        .p_del_d5_del_d3
+        .abs_diff_eq(&uniform.p_del_d5_del_d3, 1e-6));
+
+    // negative pseudocounts are rejected
+    let ifp = InferenceParameters {
+        pseudocounts: righor::shared::Pseudocounts::uniform(-1.),
+        ..Default::default()
+    };
+    assert!(model
+        .clone()
+        .infer(&alignments, None, None, &alp, &ifp)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn bootstrap_simple_model() -> Result<()> {
+    let model_vdj = common::simple_model_vdj();
+    let mut generator = righor::vdj::Generator::new(&model_vdj.clone(), Some(21), None, None)?;
+    let alp = AlignmentParameters::default();
+    let ifp = InferenceParameters::default();
+    let sequences = (0..10)
+        .map(|_| {
+            Ok(EntrySequence::NucleotideSequence(DnaLike::from_dna(
+                righor::Dna::from_string(&generator.generate(false)?.full_seq)?,
+            )))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let model = righor::Model::VDJ(model_vdj);
+    let fit_params = righor::shared::FitParameters {
+        max_iterations: 2,
+        ..Default::default()
+    };
+    let bootstrap_params = righor::shared::BootstrapParameters {
+        n_resamples: 6,
+        ..Default::default()
+    };
+
+    let result = model.bootstrap(&sequences, None, &alp, &ifp, &fit_params, &bootstrap_params)?;
+    let p_ins_vd = result.get("p_ins_vd").unwrap();
+    assert_eq!(p_ins_vd.shape(), &[3, model.get_p_ins_vd()?.len()]);
+    assert_eq!(
+        result.get("p_vdj").unwrap().shape()[1..],
+        *model.get_p_vdj()?.shape()
+    );
+    // the resamples differ
+    assert!(p_ins_vd
+        .lanes(ndarray::Axis(0))
+        .into_iter()
+        .any(|lane| lane[0] < lane[2]));
+    // quantiles are ordered
+    for (name, q) in &result.parameters {
+        for lane in q.lanes(ndarray::Axis(0)) {
+            assert!(lane[0] <= lane[1] && lane[1] <= lane[2], "{name}");
+        }
+    }
+
+    // same seed, same result
+    let again = model.bootstrap(&sequences, None, &alp, &ifp, &fit_params, &bootstrap_params)?;
+    for ((n1, q1), (n2, q2)) in result.parameters.iter().zip(&again.parameters) {
+        assert_eq!(n1, n2);
+        assert!(q1.abs_diff_eq(q2, 1e-12));
+    }
+    Ok(())
+}
+
+#[test]
+fn infer_context_error_model() -> Result<()> {
+    // hotspots (WRC) mutate much more than the rest of the sequence
+    let is_hotspot = |ctx: usize| {
+        let kmer = context_to_string(ctx).into_bytes();
+        b"AT".contains(&kmer[0]) && b"AG".contains(&kmer[1]) && kmer[2] == b'C'
+    };
+    let mut error = ErrorContextRate::from_error_rate(0.02)?;
+    for ctx in (0..NB_CONTEXTS).filter(|&c| is_hotspot(c)) {
+        error.mutability[ctx] = 0.3;
+    }
+    let mut model = common::simple_model_vdj();
+    model.error = error.into();
+    model.initialize()?;
+
+    let alp = AlignmentParameters::default();
+    let alignments = common::generate_alignments(&model, 300, 12, &alp)?;
+    let inferred = common::infer_error_model(
+        &model,
+        &alignments,
+        ErrorContextRate::from_error_rate(0.05)?.into(),
+        3,
+        &alp,
+        |lines| Ok(ErrorContextRate::load(lines)?.into()),
+    )?;
+    let ErrorParameters::ContextRate(inferred_error) = &inferred else {
+        panic!("The error model type changed during inference");
+    };
+    let mean = |hotspot: bool| {
+        let values = (0..NB_CONTEXTS)
+            .filter(|&c| is_hotspot(c) == hotspot && !context_to_string(c).contains('N'))
+            .filter(|&c| inferred_error.mutability[c] != 0.05)
+            .map(|c| inferred_error.mutability[c])
+            .collect::<Vec<_>>();
+        values.iter().sum::<f64>() / values.len() as f64
+    };
+    assert!(mean(true) > 0.15);
+    assert!(mean(false) < 0.05);
+    Ok(())
+}
+
+#[test]
+fn infer_substitution_matrix() -> Result<()> {
+    // transitions (A <-> G, C <-> T) are ten times more likely than transversions
+    let mut matrix = [[0.005; 4]; 4];
+    for (g, t) in [(0, 2), (1, 3), (2, 0), (3, 1)] {
+        matrix[g][t] = 0.05;
+        matrix[g][g] = 1. - 0.05 - 2. * 0.005;
+    }
+    let mut model = common::simple_model_vdj();
+    model.error = ErrorSubstitutionMatrix::new(matrix)?.into();
+    model.initialize()?;
+
+    let alp = AlignmentParameters::default();
+    let alignments = common::generate_alignments(&model, 300, 7, &alp)?;
+
+    // a constant-rate matrix gives the same likelihood as the constant error model
+    let ifp = InferenceParameters::default();
+    let mut model_constant = model.clone();
+    model_constant.error = ErrorConstantRate::new(0.05).into();
+    let mut model_matrix = model.clone();
+    model_matrix.error = ErrorSubstitutionMatrix::from_error_rate(0.05)?.into();
+    for s in alignments.iter().take(10) {
+        let l1 = model_constant.evaluate(s.clone(), &alp, &ifp)?.likelihood;
+        let l2 = model_matrix.evaluate(s.clone(), &alp, &ifp)?.likelihood;
+        assert!((l1 - l2).abs() <= 1e-9 * l1);
+    }
+
+    let inferred = common::infer_error_model(
+        &model,
+        &alignments,
+        model_matrix.error.clone(),
+        3,
+        &alp,
+        |lines| Ok(ErrorSubstitutionMatrix::load(lines)?.into()),
+    )?;
+    let ErrorParameters::SubstitutionMatrix(inferred) = &inferred else {
+        panic!("The error model type changed during inference");
+    };
+    for (g, t) in [(0, 2), (1, 3), (2, 0), (3, 1)] {
+        for o in (0..4).filter(|&o| o != g && o != t) {
+            assert!(inferred.matrix[g][t] > 3. * inferred.matrix[g][o]);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn infer_indel_error_model() -> Result<()> {
+    let mut model = common::long_v_model_vdj()?;
+    model.error = ErrorIndelRate::new(0., 0.01)?.into();
+    model.initialize()?;
+    let alp = AlignmentParameters {
+        max_indels: 5,
+        ..Default::default()
+    };
+
+    // ungapped sequences are aligned as before, gapped ones get a cigar
+    let mut generator = righor::vdj::Generator::new(&model.clone(), Some(13), None, None)?;
+    let full_seq = generator.generate_without_errors(false).full_seq;
+    let seq = DnaLike::from_dna(righor::Dna::from_string(&full_seq)?);
+    let ungapped = model.align_sequence(seq.clone(), &alp)?;
+    let reference = model.align_sequence(seq, &AlignmentParameters::default())?;
+    assert!(ungapped.v_genes[0].cigar.is_empty());
+    assert_eq!(ungapped.v_genes[0].errors, reference.v_genes[0].errors);
+    let deleted = format!("{}{}", &full_seq[..50], &full_seq[51..]);
+    let gapped =
+        model.align_sequence(DnaLike::from_dna(righor::Dna::from_string(&deleted)?), &alp)?;
+    let val = &gapped.v_genes[0];
+    assert_eq!((val.nb_deletions(), val.nb_insertions()), (1, 0));
+    assert!(val.cigar_string().starts_with("50M1D"));
+    // the deleted nucleotide is the only error (for the right V deletion)
+    assert_eq!(val.errors.iter().min(), Some(&1));
+    assert_eq!(val.v_start_gene(), 1);
+
+    // the indels stay outside of the CDR3
+    for _ in 0..100 {
+        let result = generator.generate(false)?;
+        let righor::shared::StaticEvent::VDJ(mut event) = result.recombination_event else {
+            panic!("Wrong event type");
+        };
+        event.indels = vec![];
+        assert_eq!(event.to_cdr3(&model).get_string(), result.junction_nt);
+    }
+
+    let alignments = common::generate_alignments(&model, 400, 13, &alp)?;
+    assert!(alignments
+        .iter()
+        .any(|s| matches!(s, EntrySequence::Aligned(x) if x.v_genes[0].nb_indels() > 0)));
+    let inferred = common::infer_error_model(
+        &model,
+        &alignments,
+        ErrorIndelRate::new(0.05, 0.05)?.into(),
+        5,
+        &alp,
+        |lines| Ok(ErrorIndelRate::load(lines)?.into()),
+    )?;
+    let ErrorParameters::IndelRate(inferred) = &inferred else {
+        panic!("The error model type changed during inference");
+    };
+    assert!((inferred.indel_rate - 0.01).abs() < 0.004);
+    assert!(inferred.error_rate < 0.005);
+    Ok(())
+}
+
+#[test]
+fn infer_segment_error_model() -> Result<()> {
+    // hypermutated V gene, almost error-free J
+    let mut model = common::simple_model_vdj();
+    model.error = ErrorSegmentRate::new(0.08, 0.02, 0.005)?.into();
+    model.initialize()?;
+
+    let alp = AlignmentParameters::default();
+    let alignments = common::generate_alignments(&model, 500, 21, &alp)?;
+    let inferred = common::infer_error_model(
+        &model,
+        &alignments,
+        ErrorSegmentRate::new(0.1, 0.1, 0.1)?.into(),
+        5,
+        &alp,
+        |lines| Ok(ErrorSegmentRate::load(lines)?.into()),
+    )?;
+    let (v_rate, _, j_rate) = inferred.segment_error_rates()?;
+    assert!((v_rate - 0.08).abs() < 0.02);
+    assert!(j_rate < 0.02);
+    assert!(v_rate > 3. * j_rate);
+    Ok(())
+}
+
+#[test]
+fn infer_higher_order_markov() -> Result<()> {
+    // VD insertions where a nucleotide tends to copy the one two positions before,
+    // which a first-order chain cannot capture
+    let mut model = common::simple_model_vdj();
+    model.set_markov_order(2)?;
+    let mut chain = (*model.markov_chain_vd).clone();
+    chain.higher_order[0] =
+        Array2::from_shape_fn((16, 4), |(ctx, nt)| if ctx / 4 == nt { 0.7 } else { 0.1 });
+    model.markov_chain_vd = Arc::new(chain.reinitialize()?);
+    model.initialize()?;
+    assert_eq!(model.get_markov_order(), 2);
+
+    // the dynamic programming still agrees with the brute force
+    let mut generator = righor::vdj::Generator::new(&model.clone(), Some(7), None, None)?;
+    let alp = AlignmentParameters::default();
+    let ifp = InferenceParameters {
+        min_likelihood: 0.,
+        min_ratio_likelihood: 0.,
+        ..Default::default()
+    };
+    let alignments = (0..2000)
+        .map(|_| {
+            let s = righor::Dna::from_string(&generator.generate_without_errors(false).full_seq)?;
+            Ok(EntrySequence::Aligned(
+                model.align_sequence(DnaLike::from_dna(s), &alp)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for s in alignments.iter().take(20) {
+        let result = model.evaluate(s.clone(), &alp, &ifp)?.likelihood;
+        let result_brute_force = model.evaluate_brute_force(s, &alp, &ifp)?.likelihood;
+        assert!((result - result_brute_force).abs() <= 1e-9 * result);
+    }
+
+    // start from the first-order chain, the EM learns the second order
+    let mut model_infer = model.clone();
+    model_infer.set_markov_order(1)?;
+    model_infer.set_markov_order(2)?;
+    let ifp = InferenceParameters::default();
+    for _ in 0..5 {
+        model_infer.infer(&alignments, None, None, &alp, &ifp)?;
+    }
+    assert_eq!(model_infer.get_markov_order(), 2);
+    let learned = &model_infer.markov_chain_vd.higher_order[0];
+    let mean_copy: f64 = (0..16).map(|ctx| learned[[ctx, ctx / 4]]).sum::<f64>() / 16.;
+    println!("{}", mean_copy);
+    assert!(mean_copy > 0.5);
+
+    // degenerate and amino-acid sequences fall back on the first-order chain
+    let mut model_first_order = model.clone();
+    model_first_order.set_markov_order(1)?;
+    let degenerate = DnaLike::from_string("ACNGTN", "dna")?;
+    for first in 0..4 {
+        let ll = model
+            .markov_chain_vd
+            .likelihood(&degenerate, first)
+            .to_scalar()?;
+        let ll_first_order = model_first_order
+            .markov_chain_vd
+            .likelihood(&degenerate, first)
+            .to_scalar()?;
+        assert!(ll.is_finite() && ll > 0.);
+        assert_eq!(ll, ll_first_order);
+    }
+    let mut rng = rand::rngs::SmallRng::seed_from_u64(3);
+    let (_, cdr3, _, event) = model.generate_no_error(true, &mut rng);
+    let protein = EntrySequence::NucleotideCDR3((
+        DnaLike::from_amino_acid(cdr3.translate()?),
+        vec![model.seg_vs[event.v_index].clone()],
+        vec![model.seg_js[event.j_index].clone()],
+    ));
+    let result = model.evaluate(protein.clone(), &alp, &ifp)?.likelihood;
+    let result_first_order = model_first_order.evaluate(protein, &alp, &ifp)?.likelihood;
+    assert!(result.is_finite() && result > 0.);
+    assert!((result - result_first_order).abs() <= 1e-9 * result);
+
+    // the json format keeps the higher orders
+    let path =
+        std::env::temp_dir().join(format!("righor_markov_order_{}.json", std::process::id()));
+    model_infer.save_json(&path)?;
+    let loaded = righor::vdj::Model::load_json(&path);
+    std::fs::remove_file(&path)?;
+    let loaded = loaded?;
+    assert_eq!(loaded.get_markov_order(), 2);
+    assert!(loaded.similar_to(model_infer));
+    Ok(())
+}
+
+#[test]
+fn infer_insertions_given_gene() -> Result<()> {
+    // the DJ insertions are much longer with the second J gene
+    let mut model = common::less_simple_model_vdj();
+    // match the dimension of P(deld5, deld3 | D), needed for the IGoR format
+    model.range_del_d5 = (-1, 1);
+    model.range_del_d3 = (-1, 3);
+    model.model_type = ModelStructure::VDJInsGivenGene;
+    model.p_ins_dj_given_j = Some(array![
+        [0.7, 0.05],
+        [0.2, 0.05],
+        [0.1, 0.1],
+        [0., 0.2],
+        [0., 0.3],
+        [0., 0.2],
+        [0., 0.1]
+    ]);
+    model.initialize()?;
+
+    // the dynamic programming still agrees with the brute force
+    let mut generator = righor::vdj::Generator::new(&model.clone(), Some(3), None, None)?;
+    let alp = AlignmentParameters::default();
+    let ifp = InferenceParameters {
+        min_likelihood: 0.,
+        min_ratio_likelihood: 0.,
+        ..Default::default()
+    };
+    let alignments = (0..2000)
+        .map(|_| {
+            let s = righor::Dna::from_string(&generator.generate_without_errors(false).full_seq)?;
+            Ok(EntrySequence::Aligned(
+                model.align_sequence(DnaLike::from_dna(s), &alp)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for s in alignments.iter().take(20) {
+        let result = model.evaluate(s.clone(), &alp, &ifp)?.likelihood;
+        let result_brute_force = model.evaluate_brute_force(s, &alp, &ifp)?.likelihood;
+        assert!((result - result_brute_force).abs() <= 1e-9 * result);
+    }
+
+    // start from the same insertion profile for both J genes
+    let mut model_infer = model.clone();
+    model_infer.p_ins_dj_given_j = None;
+    model_infer.initialize()?;
+    let ifp = InferenceParameters::default();
+    for _ in 0..5 {
+        model_infer.infer(&alignments, None, None, &alp, &ifp)?;
+    }
+    let learned = model_infer.p_ins_dj_given_j.clone().unwrap();
+    println!("{:?}", learned);
+    assert!(learned[[0, 0]] > 0.5);
+    assert!(learned[[0, 1]] < 0.2);
+
+    // the dependence is saved in the IGoR format
+    let loaded = righor::vdj::Model::load_from_str(
+        &model_infer.write_params()?,
+        &model_infer.write_marginals()?,
+        &model_infer.write_v_anchors()?,
+        &model_infer.write_j_anchors()?,
+    )?;
+    assert_eq!(loaded.model_type, ModelStructure::VDJInsGivenGene);
+    assert!(loaded.p_ins_vd_given_v.is_none());
+    assert!(loaded
+        .p_ins_dj_given_j
+        .unwrap()
+        .relative_eq(&learned, 1e-6, 1e-6));
+    assert!(loaded
+        .p_ins_dj
+        .relative_eq(&model_infer.p_ins_dj, 1e-6, 1e-6));
+    Ok(())
+}
+
+#[test]
+fn infer_two_d_genes() -> Result<()> {
+    let mut model = common::less_simple_model_vdj();
+    // match the dimension of P(deld5, deld3 | D), needed for the IGoR format
+    model.range_del_d5 = (-1, 1);
+    model.range_del_d3 = (-1, 3);
+    model.model_type = ModelStructure::VDDJ;
+    model.error = ErrorParameters::ConstantRate(ErrorConstantRate::new(0.05));
+    model.initialize()?;
+    model.d2.as_mut().unwrap().p_d2_given_d1 = array![[0.2, 0.6], [0.8, 0.4]];
+    model.initialize()?;
+
+    // the generated sequences contain both D genes
+    let mut rng = rand::rngs::SmallRng::seed_from_u64(7);
+    let (full_seq, _, _, event) = model.generate_no_error(false, &mut rng);
+    assert!(event.d2_index.is_some());
+    assert_eq!(event.to_sequence(&model), full_seq);
+
+    // the dynamic programming agrees with the brute force
+    let mut generator = righor::vdj::Generator::new(&model.clone(), Some(11), None, None)?;
+    let alp = AlignmentParameters::default();
+    let ifp = InferenceParameters {
+        min_likelihood: 0.,
+        min_ratio_likelihood: 0.,
+        ..Default::default()
+    };
+    let alignments = (0..5)
+        .map(|_| {
+            let s = righor::Dna::from_string(&generator.generate(false)?.full_seq)?;
+            Ok(EntrySequence::Aligned(
+                model.align_sequence(DnaLike::from_dna(s), &alp)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for s in &alignments {
+        let result = model.evaluate(s.clone(), &alp, &ifp)?;
+        let result_brute_force = model.evaluate_brute_force(s, &alp, &ifp)?;
+        assert!(
+            (result.likelihood - result_brute_force.likelihood).abs() <= 1e-9 * result.likelihood
+        );
+        let (event, event_brute_force) = (
+            result.best_event.unwrap(),
+            result_brute_force.best_event.unwrap(),
+        );
+        // the best event likelihoods differ slightly (D alignments with the
+        // same span are aggregated), but the scenario is the same
+        assert_eq!(
+            (event.end_v, event.start_d, event.end_d),
+            (
+                event_brute_force.end_v,
+                event_brute_force.start_d,
+                event_brute_force.end_d
+            )
+        );
+        assert_eq!(
+            (event.start_d2, event.end_d2, event.start_j),
+            (
+                event_brute_force.start_d2,
+                event_brute_force.end_d2,
+                event_brute_force.start_j
+            )
+        );
+        assert_eq!(event.d2_index, event_brute_force.d2_index);
+        assert_eq!(event.pos_d2, event_brute_force.pos_d2);
+    }
+
+    // same for the expectation-maximization
+    let mut inferred = model.uniform()?;
+    let mut inferred_brute_force = model.uniform()?;
+    inferred.infer(&alignments, None, None, &alp, &ifp)?;
+    inferred_brute_force.infer_brute_force(&alignments, None, &alp, &ifp)?;
+    assert!(inferred.similar_to(inferred_brute_force));
+
+    // the second D can't be saved in the IGoR format, only in the righor extension
+    assert!(model.write_params().is_err());
+    let loaded = righor::vdj::Model::load_from_str(
+        &model.write_params_righor()?,
+        &model.write_marginals()?,
+        &model.write_v_anchors()?,
+        &model.write_j_anchors()?,
+    )?;
+    assert_eq!(loaded.model_type, ModelStructure::VDDJ);
+    assert!(loaded
+        .d2
+        .as_ref()
+        .unwrap()
+        .similar_to(model.d2.as_ref().unwrap()));
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use anyhow::Result;
+use righor::shared::{AlignmentParameters, InferenceParameters, Modelable};
+use righor::{Dna, EntrySequence};
+use std::path::Path;
+
+fn load_tra_model() -> Result<righor::vj::Model> {
+    righor::vj::Model::load_from_name(
+        "human",
+        "tra",
+        None,
+        Path::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/righor.data/data/righor_models"
+        )),
+    )
+}
+
+#[test]
+fn evaluate_vj_native_vs_wrapper() -> Result<()> {
+    let model = load_tra_model()?;
+    let mut generator = righor::vj::Generator::new(&model, Some(42), None, None)?;
+    let alp = AlignmentParameters::default();
+    let ip = InferenceParameters {
+        compute_pgen: true,
+        store_best_event: true,
+        ..Default::default()
+    };
+
+    for _ in 0..20 {
+        let seq = Dna::from_string(&generator.generate(false)?.full_seq)?;
+        let es = EntrySequence::NucleotideSequence(seq.into());
+        // the native VJ dynamic programming
+        let native = model.evaluate(es.clone(), &alp, &ip)?;
+        // the inner VDJ model, with an empty D gene
+        let wrapper = model.inner.evaluate(es, &alp, &ip)?;
+
+        assert!((native.likelihood - wrapper.likelihood).abs() <= 1e-8 * wrapper.likelihood);
+        assert!((native.pgen - wrapper.pgen).abs() <= 1e-8 * wrapper.pgen);
+        let (native_event, wrapper_event) =
+            (native.best_event.unwrap(), wrapper.best_event.unwrap());
+        assert_eq!(native_event.v_index, wrapper_event.v_index);
+        assert_eq!(native_event.j_index, wrapper_event.j_index);
+        assert_eq!(native_event.end_v, wrapper_event.end_v);
+        assert_eq!(native_event.start_j, wrapper_event.start_j);
+        let (native_marginals, wrapper_marginals) =
+            (native.marginals.unwrap(), wrapper.marginals.unwrap());
+        for (x, y) in native_marginals.v.iter().zip(&wrapper_marginals.v) {
+            assert!((x - y).abs() <= 1e-8);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn infer_vj_native_vs_wrapper() -> Result<()> {
+    let mut model = load_tra_model()?;
+    let mut generator = righor::vj::Generator::new(&model, Some(12), None, None)?;
+    let alp = AlignmentParameters::default();
+    let ip = InferenceParameters::default();
+
+    let mut alignments = Vec::new();
+    for _ in 0..50 {
+        let seq = Dna::from_string(&generator.generate(false)?.full_seq)?;
+        let es = EntrySequence::NucleotideSequence(seq.into());
+        alignments.push(EntrySequence::Aligned(es.align(&model.inner, &alp)?));
+    }
+
+    let mut wrapper = model.inner.clone();
+    let (_, ll_native) = model.infer(&alignments, None, None, &alp, &ip)?;
+    let (_, ll_wrapper) = wrapper.infer(&alignments, None, None, &alp, &ip)?;
+
+    assert!((ll_native - ll_wrapper).abs() <= 1e-6 * ll_wrapper.abs());
+    assert!(model.p_ins_vj.abs_diff_eq(&wrapper.p_ins_vd, 1e-10));
+    assert!(model
+        .p_del_v_given_v
+        .abs_diff_eq(&wrapper.p_del_v_given_v, 1e-10));
+    assert!(model
+        .p_del_j_given_j
+        .abs_diff_eq(&wrapper.p_del_j_given_j, 1e-10));
+    assert!(model.p_v.abs_diff_eq(&wrapper.p_v, 1e-10));
+    Ok(())
+}
+
+#[test]
+fn top_events_vj() -> Result<()> {
+    let model = load_tra_model()?;
+    let mut generator = righor::vj::Generator::new(&model, Some(3), None, None)?;
+    let alp = AlignmentParameters::default();
+    let ip = InferenceParameters {
+        store_top_k: 10,
+        ..Default::default()
+    };
+
+    for _ in 0..10 {
+        let seq = Dna::from_string(&generator.generate(false)?.full_seq)?;
+        let result = model.evaluate(EntrySequence::NucleotideSequence(seq.into()), &alp, &ip)?;
+        assert_eq!(result.top_events.len(), 10);
+        // a VJ scenario is fully specified by the genes and the positions
+        let (best, top) = (result.best_event.clone().unwrap(), &result.top_events[0]);
+        assert_eq!(best.v_index, top.v_index);
+        assert_eq!(best.j_index, top.j_index);
+        assert_eq!(best.end_v, top.end_v);
+        assert_eq!(best.start_j, top.start_j);
+        assert!((best.likelihood - top.likelihood).abs() <= 1e-12 * best.likelihood);
+        assert!(result.top_probabilities().iter().sum::<f64>() <= 1. + 1e-9);
+    }
+    Ok(())
+}